@@ -1,11 +1,13 @@
 import { useEffect, useState, FC } from 'react'
 import 'modern-css-reset'
 import { ThemeProvider, createTheme } from '@mui/material/styles'
-import { Box, Stack, Typography } from '@mui/material'
+import { Box, Button, Stack, Typography } from '@mui/material'
 import { Label, NewTodoPayload, Todo, NewLabelPayload, UpdateTodoPayload } from './types/todo'
+import { Credentials, User } from './types/user'
 import TodoList from './components/TodoList'
 import TodoForm from './components/TodoForm'
 import SideNav from './components/SideNav'
+import LoginForm from './components/LoginForm'
 import {
   addTodoItem,
   deleteTodoItem,
@@ -13,30 +15,50 @@ import {
   updateTodoItem
 } from './lib/api/todo'
 import { addLabelItem, deleteLabelItem, getLabelItems } from './lib/api/label'
+import { getMe, login, logout, register } from './lib/api/auth'
 
-const TodoApp: FC = () => {
+type TodoAppProps = {
+  user: User
+  onLogout: () => void
+}
+
+const TodoApp: FC<TodoAppProps> = ({ user, onLogout }) => {
   const [todos, setTodos] = useState<Todo[]>([])
+  const [nextCursor, setNextCursor] = useState<number | null>(null)
   const [labels, setLabels] = useState<Label []>([])
   const [filterLabelId, setFilterLabelId] = useState<number | null>(null)
 
+  // 変更したあとは最初のページから取得し直す
+  const reloadTodos = async () => {
+    const page = await getTodoItems()
+    setTodos(page.items)
+    setNextCursor(page.next_cursor)
+  }
+
+  const onLoadMore = async () => {
+    if (nextCursor === null) return
+
+    const page = await getTodoItems(nextCursor)
+    setTodos((prev) => [...prev, ...page.items])
+    // カーソルが進まなければ、同じページを取り続けないよう打ち切る
+    setNextCursor(page.next_cursor === nextCursor ? null : page.next_cursor)
+  }
+
   const onSubmit = async (payload: NewTodoPayload) => {
     if (!payload.text) return
 
     await addTodoItem(payload)
-    const todos = await getTodoItems()
-    setTodos(todos)
+    await reloadTodos()
   }
 
   const onUpdate = async (updateTodo: UpdateTodoPayload) => {
     await updateTodoItem(updateTodo)
-    const todos = await getTodoItems()
-    setTodos(todos)
+    await reloadTodos()
   }
 
   const onDelete = async (id: number) => {
     await deleteTodoItem(id)
-    const todos = await getTodoItems()
-    setTodos(todos)
+    await reloadTodos()
   }
 
   const onSelectLabel = (label: Label | null) => {
@@ -63,8 +85,9 @@ const TodoApp: FC = () => {
 
   useEffect(() => {
       ;(async() => {
-        const todos = await getTodoItems()
-        setTodos(todos)
+        const page = await getTodoItems()
+        setTodos(page.items)
+        setNextCursor(page.next_cursor)
         const labelResponse = await getLabelItems()
         setLabels(labelResponse)
       }) ()
@@ -87,6 +110,9 @@ const TodoApp: FC = () => {
         }}
       >
         <Typography variant='h1'>Todo App</Typography>
+        <Box sx={{ flexGrow: 1 }} />
+        <Typography>{user.email}</Typography>
+        <Button onClick={onLogout}>logout</Button>
       </Box>
       <Box
         sx={{
@@ -125,6 +151,9 @@ const TodoApp: FC = () => {
               onUpdate={onUpdate}
               onDelete={onDelete}
             />
+            {nextCursor !== null && (
+              <Button onClick={onLoadMore}>Load more</Button>
+            )}
           </Stack>
         </Box>
       </Box>
@@ -144,9 +173,48 @@ const theme = createTheme({
 })
 
 const App: FC = () => {
+  // ログインしているかを確かめている間は undefined
+  const [user, setUser] = useState<User | null | undefined>(undefined)
+
+  useEffect(() => {
+      ;(async() => {
+        setUser(await getMe())
+      }) ()
+  }, [])
+
+  const onLogin = async (credentials: Credentials) => {
+    const user = await login(credentials)
+    setUser(user)
+    return user ? null : 'Invalid email or password'
+  }
+
+  const onRegister = async (credentials: Credentials) => {
+    try {
+      await register(credentials)
+    } catch {
+      return 'Enter a valid email and a password of at least 8 characters'
+    }
+    // 登録済みのメールアドレスでも register は成功するため、ログインできるかで確かめる
+    const user = await login(credentials)
+    setUser(user)
+    return user ? null : 'Could not log in. If the email is already registered, check the password'
+  }
+
+  const onLogout = async () => {
+    await logout()
+    setUser(null)
+  }
+
   return (
     <ThemeProvider theme={theme}>
-      <TodoApp />
+      {user === null && (
+        <Box sx={{ display: 'flex', justifyContent: 'center', p: 1, mt: 10 }}>
+          <Box maxWidth={400} width="100%">
+            <LoginForm onLogin={onLogin} onRegister={onRegister} />
+          </Box>
+        </Box>
+      )}
+      {user && <TodoApp key={user.id} user={user} onLogout={onLogout} />}
     </ThemeProvider>
   )
 }
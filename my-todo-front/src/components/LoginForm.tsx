@@ -0,0 +1,61 @@
+import { FC, useState } from 'react'
+import { Alert, Box, Button, Paper, Stack, TextField, Typography } from '@mui/material'
+import type { Credentials } from '../types/user'
+
+type Props = {
+    onLogin: (credentials: Credentials) => Promise<string | null>
+    onRegister: (credentials: Credentials) => Promise<string | null>
+}
+
+// 失敗したときは、onLogin と onRegister が返したメッセージを表示する
+const LoginForm: FC<Props> = ({ onLogin, onRegister }) => {
+    const [email, setEmail] = useState('')
+    const [password, setPassword] = useState('')
+    const [error, setError] = useState<string | null>(null)
+
+    const submitHandler = async (
+        submit: (credentials: Credentials) => Promise<string | null>
+    ) => {
+        if (!email || !password) return
+
+        setError(await submit({ email, password }))
+    }
+
+    return (
+        <Paper elevation={2}>
+            <Box sx={{ p: 2 }}>
+                <Stack spacing={2}>
+                    <Typography variant="h2">login</Typography>
+                    {error && <Alert severity="error">{error}</Alert>}
+                    <TextField
+                        label="email"
+                        type="email"
+                        variant="filled"
+                        value={email}
+                        onChange={(e) => setEmail(e.target.value)}
+                        fullWidth
+                    />
+                    <TextField
+                        label="password"
+                        type="password"
+                        variant="filled"
+                        value={password}
+                        onChange={(e) => setPassword(e.target.value)}
+                        helperText="at least 8 characters to register"
+                        fullWidth
+                    />
+                    <Stack direction="row" spacing={2} justifyContent="flex-end">
+                        <Button onClick={() => submitHandler(onRegister)} color="secondary">
+                            register
+                        </Button>
+                        <Button onClick={() => submitHandler(onLogin)}>
+                            login
+                        </Button>
+                    </Stack>
+                </Stack>
+            </Box>
+        </Paper>
+    )
+}
+
+export default LoginForm
@@ -0,0 +1,59 @@
+import type { Credentials, User } from '../../types/user'
+
+// ログインしていなければ null を返す
+export const getMe = async () => {
+    const res = await fetch('http://localhost:3000/auth/me', { credentials: 'include' })
+    if (res.status === 401) {
+        return null
+    }
+    if (!res.ok) {
+        throw new Error('get me request failed')
+    }
+    const json: User = await res.json()
+    return json
+}
+
+// 登録済みのメールアドレスかどうかに関わらず同じ応答が返るため、続けて login で確かめる
+export const register = async (payload: Credentials) => {
+    const res = await fetch('http://localhost:3000/auth/register', {
+        credentials: 'include',
+        method: 'POST',
+        headers: {
+            'Content-Type': 'application/json'
+        },
+        body: JSON.stringify(payload)
+    })
+    if (!res.ok) {
+        throw new Error('register request failed')
+    }
+}
+
+// メールアドレスかパスワードが違う場合は null を返す
+export const login = async (payload: Credentials) => {
+    const res = await fetch('http://localhost:3000/auth/login', {
+        credentials: 'include',
+        method: 'POST',
+        headers: {
+            'Content-Type': 'application/json'
+        },
+        body: JSON.stringify(payload)
+    })
+    if (res.status === 401) {
+        return null
+    }
+    if (!res.ok) {
+        throw new Error('login request failed')
+    }
+    const json: User = await res.json()
+    return json
+}
+
+export const logout = async () => {
+    const res = await fetch('http://localhost:3000/auth/logout', {
+        credentials: 'include',
+        method: 'POST',
+    })
+    if (!res.ok) {
+        throw new Error('logout request failed')
+    }
+}
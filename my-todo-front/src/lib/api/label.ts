@@ -1,7 +1,7 @@
-import type { Label, NewLabelPayload } from '../../types/todo'
+import type { Label, NewLabelPayload, UpdateLabelPayload } from '../../types/todo'
 
 export const getLabelItems = async () => {
-    const res = await fetch('http://localhost:3000/labels')
+    const res = await fetch('http://localhost:3000/labels', { credentials: 'include' })
     if (!res.ok) {
         throw new Error('get label request failed')
     }
@@ -11,6 +11,7 @@ export const getLabelItems = async () => {
 
 export const addLabelItem = async (payload: NewLabelPayload) => {
     const res = await fetch('http://localhost:3000/labels', {
+        credentials: 'include',
         method: 'POST',
         headers: {
             'Content-Type': 'application/json'
@@ -24,8 +25,26 @@ export const addLabelItem = async (payload: NewLabelPayload) => {
     return json
 }
 
+export const updateLabelItem = async (label: UpdateLabelPayload) => {
+    const { id, ...updateLabel } = label
+    const res = await fetch(`http://localhost:3000/labels/${id}`, {
+        credentials: 'include',
+        method: 'PATCH',
+        headers: {
+            'Content-Type': 'application/json'
+        },
+        body: JSON.stringify(updateLabel)
+    })
+    if (!res.ok) {
+        throw new Error('update label request failed')
+    }
+    const json: Label = await res.json()
+    return json
+}
+
 export const deleteLabelItem = async (id: number) => {
     const res = await fetch(`http://localhost:3000/labels/${id}`, {
+        credentials: 'include',
         method: 'DELETE',
     })
     if (!res.ok) {
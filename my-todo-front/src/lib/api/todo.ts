@@ -1,7 +1,8 @@
-import type { NewTodoPayload, Todo, UpdateTodoPayload } from '../../types/todo'
+import type { NewTodoPayload, Todo, TodoPage, UpdateTodoPayload } from '../../types/todo'
 
 export const addTodoItem = async (payload: NewTodoPayload) => {
     const res = await fetch(`http://localhost:3000/todos`, {
+        credentials: 'include',
         method: 'POST',
         headers: {
             'Content-Type': 'application/json',
@@ -15,22 +16,29 @@ export const addTodoItem = async (payload: NewTodoPayload) => {
     return json
 }
 
-export const getTodoItems = async () => {
-    const res = await fetch('http://localhost:3000/todos')
+// 1 ページ分だけ取得する。続きは next_cursor を after に渡して取得する
+export const getTodoItems = async (after: number | null = null) => {
+    const query: string = after === null ? '' : `?after=${after}`
+    const res = await fetch(`http://localhost:3000/todos${query}`, { credentials: 'include' })
     if (!res.ok) {
         throw new Error('get todo request failed')
     }
-    const json: Todo[] = await res.json()
+    const json: TodoPage = await res.json()
     return json
 }
 
 export const updateTodoItem = async (todo: UpdateTodoPayload) => {
-    const { id, ...updateTodo } = todo
+    const { id, version, ...updateTodo } = todo
+    const headers: Record<string, string> = {
+        'Content-Type': 'application/json'
+    }
+    if (version !== undefined) {
+        headers['If-Match'] = `"${version}"`
+    }
     const res = await fetch(`http://localhost:3000/todos/${id}`, {
+        credentials: 'include',
         method: 'PATCH',
-        headers: {
-            'Content-Type': 'application/json'
-        },
+        headers,
         body: JSON.stringify(updateTodo),
     })
     if (!res.ok) {
@@ -42,6 +50,7 @@ export const updateTodoItem = async (todo: UpdateTodoPayload) => {
 
 export const deleteTodoItem = async (id: number) => {
     const res = await fetch(`http://localhost:3000/todos/${id}`, {
+        credentials: 'include',
         method: 'DELETE'
     })
     if (!res.ok) {
@@ -2,26 +2,87 @@ export type Todo = {
     id: number
     text: string
     completed: boolean
+    version: number
+    created_at: string
+    updated_at: string
+    completed_at: string | null
+    deleted_at: string | null
+    // "YYYY-MM-DD" または RFC 3339 の日時
+    due_at: string | null
+    remind_at: string | null
+    reminded_at: string | null
+    recurrence: Recurrence | null
+    priority: Priority
+    parent_id: number | null
+    // 未完了の Todo の完了を待っている
+    blocked: boolean
+    // ?tree=true や /todos/:id/children で取得したときだけ入る
+    children?: Todo[]
     labels: Label[]
 }
 
+export type Priority = 'none' | 'low' | 'medium' | 'high' | 'urgent'
+
+export type Recurrence = {
+    series_id: number
+    occurrence: number
+    // "FREQ=WEEKLY;BYDAY=MO,WE" のような RRULE。止めた系列では null
+    rrule: string | null
+}
+
+export type TodoPage = {
+    items: Todo[]
+    next_cursor: number | null
+    total: number
+}
+
 export type NewTodoPayload = {
     text: string
     labels: number[]
+    due_at?: string
+    remind_at?: string
+    rrule?: string
+    priority?: Priority
+    parent_id?: number
 }
 
 export type UpdateTodoPayload = {
     id: number
+    version?: number
     text?: string
     completed?: boolean
     labels?: number[]
+    due_at?: string | null
+    remind_at?: string | null
+    rrule?: string | null
+    priority?: Priority
+    parent_id?: number | null
+    // completed を true にするとき、未完了の子孫もまとめて完了にする
+    complete_children?: boolean
 }
 
+// before か after のどちらか一方を指定する
+export type MoveTodoPayload =
+    | { id: number; before: number }
+    | { id: number; after: number }
+
 export type Label = {
     id: number
     name: string
+    color: string | null
+    description: string | null
+    created_at: string
 }
 
 export type NewLabelPayload = {
     name: string
+    color?: string
+    description?: string
+}
+
+export type UpdateLabelPayload = {
+    id: number
+    name?: string
+    color?: string
+    description?: string
 }
\ No newline at end of file
@@ -0,0 +1,10 @@
+export type User = {
+    id: number
+    email: string
+    created_at: string
+}
+
+export type Credentials = {
+    email: string
+    password: string
+}
@@ -0,0 +1,5 @@
+ALTER TABLE todos
+    ADD COLUMN text_search TSVECTOR
+        GENERATED ALWAYS AS (to_tsvector('simple', text)) STORED;
+
+CREATE INDEX todos_text_search_idx ON todos USING GIN (text_search);
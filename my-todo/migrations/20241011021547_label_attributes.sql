@@ -0,0 +1,3 @@
+ALTER TABLE labels
+    ADD COLUMN color       TEXT,
+    ADD COLUMN description TEXT;
@@ -0,0 +1,8 @@
+DELETE
+FROM todo_labels a
+    USING todo_labels b
+WHERE a.todo_id = b.todo_id
+  AND a.label_id = b.label_id
+  AND a.id > b.id;
+
+CREATE UNIQUE INDEX todo_labels_todo_id_label_id_idx ON todo_labels (todo_id, label_id);
@@ -0,0 +1,13 @@
+CREATE TYPE todo_event_kind AS ENUM ('created', 'updated', 'deleted', 'restored', 'purged');
+
+-- 完全に削除された Todo の履歴も残すため、todos への外部キーは張らない
+CREATE TABLE todo_events
+(
+    id         SERIAL PRIMARY KEY,
+    todo_id    INTEGER         NOT NULL,
+    kind       todo_event_kind NOT NULL,
+    changes    JSONB           NOT NULL,
+    created_at TIMESTAMPTZ     NOT NULL DEFAULT now()
+);
+
+CREATE INDEX todo_events_todo_id_idx ON todo_events (todo_id, id);
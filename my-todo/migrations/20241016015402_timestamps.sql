@@ -0,0 +1,10 @@
+ALTER TABLE todos
+    ADD COLUMN created_at   TIMESTAMPTZ NOT NULL DEFAULT now(),
+    ADD COLUMN updated_at   TIMESTAMPTZ NOT NULL DEFAULT now(),
+    ADD COLUMN completed_at TIMESTAMPTZ;
+
+-- 既に完了している Todo は完了日時が分からないため、移行した日時を入れる
+UPDATE todos SET completed_at = now() WHERE completed;
+
+ALTER TABLE labels
+    ADD COLUMN created_at TIMESTAMPTZ NOT NULL DEFAULT now();
@@ -0,0 +1,9 @@
+-- 日付だけの期限は due_date、時刻まで決まった期限は due_at に入れる
+ALTER TABLE todos
+    ADD COLUMN due_date DATE,
+    ADD COLUMN due_at   TIMESTAMPTZ,
+    ADD CONSTRAINT todos_due_check CHECK (due_date IS NULL OR due_at IS NULL);
+
+-- 期限での絞り込み (overdue / today / week) に使う
+CREATE INDEX todos_due_date_idx ON todos (due_date);
+CREATE INDEX todos_due_at_idx ON todos (due_at);
@@ -0,0 +1,7 @@
+ALTER TABLE todos
+    ADD COLUMN remind_at   TIMESTAMPTZ,
+    ADD COLUMN reminded_at TIMESTAMPTZ;
+
+-- リマインダーのスケジューラが未送信のものを探すのに使う
+CREATE INDEX todos_pending_reminder_idx ON todos (remind_at)
+    WHERE reminded_at IS NULL AND deleted_at IS NULL;
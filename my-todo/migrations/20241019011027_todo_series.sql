@@ -0,0 +1,14 @@
+-- 繰り返しの Todo の系列。rrule が NULL の系列は繰り返しを止めている
+CREATE TABLE todo_series
+(
+    id         SERIAL PRIMARY KEY,
+    rrule      TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+ALTER TABLE todos
+    ADD COLUMN series_id  INTEGER REFERENCES todo_series (id),
+    ADD COLUMN occurrence INTEGER,
+    ADD CONSTRAINT todos_series_check CHECK ((series_id IS NULL) = (occurrence IS NULL));
+
+CREATE UNIQUE INDEX todos_series_occurrence_idx ON todos (series_id, occurrence);
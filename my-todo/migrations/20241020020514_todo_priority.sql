@@ -0,0 +1,8 @@
+-- 宣言順がそのまま大小になるため、低い順に並べる
+CREATE TYPE todo_priority AS ENUM ('none', 'low', 'medium', 'high', 'urgent');
+
+ALTER TABLE todos
+    ADD COLUMN priority todo_priority NOT NULL DEFAULT 'none';
+
+-- 優先度での並び替えに使う
+CREATE INDEX todos_priority_idx ON todos (priority, id);
@@ -0,0 +1,15 @@
+-- 手動の並び順。間隔を空けて振っておき、移動した Todo には前後の間の値を入れる
+ALTER TABLE todos
+    ADD COLUMN position BIGINT;
+
+UPDATE todos
+SET position = ranked.rank * 1024
+FROM (SELECT id, row_number() OVER (ORDER BY id) AS rank FROM todos) AS ranked
+WHERE todos.id = ranked.id;
+
+-- アプリからは末尾の位置を指定して追加する
+ALTER TABLE todos
+    ALTER COLUMN position SET NOT NULL,
+    ALTER COLUMN position SET DEFAULT 0;
+
+CREATE INDEX todos_position_idx ON todos (position, id);
@@ -0,0 +1,6 @@
+-- 子の Todo (サブタスク)。親を完全に削除すると、子は親のない Todo になる
+ALTER TABLE todos
+    ADD COLUMN parent_id INTEGER REFERENCES todos (id) ON DELETE SET NULL,
+    ADD CONSTRAINT todos_parent_check CHECK (parent_id <> id);
+
+CREATE INDEX todos_parent_id_idx ON todos (parent_id);
@@ -0,0 +1,11 @@
+-- todo_id の Todo は depends_on の Todo が完了するまで進められない
+-- どちらかを完全に削除すると依存関係も消える
+CREATE TABLE todo_dependencies
+(
+    todo_id    INTEGER NOT NULL REFERENCES todos (id) ON DELETE CASCADE,
+    depends_on INTEGER NOT NULL REFERENCES todos (id) ON DELETE CASCADE,
+    PRIMARY KEY (todo_id, depends_on),
+    CONSTRAINT todo_dependencies_self_check CHECK (todo_id <> depends_on)
+);
+
+CREATE INDEX todo_dependencies_depends_on_idx ON todo_dependencies (depends_on);
@@ -0,0 +1,39 @@
+-- Todo とラベルはユーザーごとに持ち、他のユーザーからは見えない
+ALTER TABLE todos
+    ADD COLUMN owner_id INTEGER REFERENCES users (id) ON DELETE CASCADE;
+ALTER TABLE labels
+    ADD COLUMN owner_id INTEGER REFERENCES users (id) ON DELETE CASCADE;
+
+-- 完全に削除した Todo の履歴も所有者だけが読めるよう、履歴にも所有者を残す
+ALTER TABLE todo_events
+    ADD COLUMN owner_id INTEGER REFERENCES users (id) ON DELETE CASCADE;
+
+-- 既存の行は、最初に登録した誰かではなく、ログインできない移行用のユーザーのものにする
+-- password_hash は PHC 形式でないため、どのパスワードとも一致しない
+-- 行があるときだけ作る。持ち主に渡すときは、管理者が次のように付け替える
+--   UPDATE todos SET owner_id = <user_id> WHERE owner_id = <legacy_id>;
+--   UPDATE labels SET owner_id = <user_id> WHERE owner_id = <legacy_id>;
+--   UPDATE todo_events SET owner_id = <user_id> WHERE owner_id = <legacy_id>;
+INSERT INTO users (email, password_hash)
+    SELECT 'legacy-owner@localhost', '!'
+        WHERE EXISTS (SELECT 1 FROM todos)
+            OR EXISTS (SELECT 1 FROM labels)
+            OR EXISTS (SELECT 1 FROM todo_events)
+    ON CONFLICT (email) DO NOTHING;
+
+UPDATE todos SET owner_id = (SELECT id FROM users WHERE email = 'legacy-owner@localhost');
+UPDATE labels SET owner_id = (SELECT id FROM users WHERE email = 'legacy-owner@localhost');
+UPDATE todo_events SET owner_id = (SELECT id FROM users WHERE email = 'legacy-owner@localhost');
+
+ALTER TABLE todos
+    ALTER COLUMN owner_id SET NOT NULL;
+ALTER TABLE labels
+    ALTER COLUMN owner_id SET NOT NULL;
+ALTER TABLE todo_events
+    ALTER COLUMN owner_id SET NOT NULL;
+
+CREATE INDEX todos_owner_id_idx ON todos (owner_id);
+CREATE INDEX todo_events_owner_id_idx ON todo_events (owner_id, todo_id);
+
+-- ラベル名の重複はユーザーごとに確かめる
+CREATE UNIQUE INDEX labels_owner_id_name_key ON labels (owner_id, name);
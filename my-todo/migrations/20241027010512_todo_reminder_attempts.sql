@@ -0,0 +1,5 @@
+-- 送信を試みた回数と、次に送信を試みてよい日時
+-- スケジューラは送信前に remind_next_attempt_at を進めて行を取り出し、他のスケジューラと二重に送らない
+ALTER TABLE todos
+    ADD COLUMN remind_attempts        INTEGER NOT NULL DEFAULT 0,
+    ADD COLUMN remind_next_attempt_at TIMESTAMPTZ;
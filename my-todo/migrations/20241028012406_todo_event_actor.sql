@@ -0,0 +1,3 @@
+-- 誰が変更したかを残す。この列を足す前の履歴と、ユーザーを削除したあとの履歴は NULL になる
+ALTER TABLE todo_events
+    ADD COLUMN actor_id INTEGER REFERENCES users (id) ON DELETE SET NULL;
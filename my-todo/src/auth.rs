@@ -0,0 +1,221 @@
+use argon2::{
+    password_hash::{rand_core::OsRng, rand_core::RngCore, SaltString},
+    Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
+};
+use axum::http::{header, HeaderMap, HeaderValue};
+use chrono::Duration;
+use sha2::{Digest, Sha256};
+use std::env;
+
+/// セッションのトークンを入れる Cookie の名前
+pub const SESSION_COOKIE: &str = "session";
+
+/// API トークンの先頭に付ける文字列。JWT と見分けるために使う
+pub const API_TOKEN_PREFIX: &str = "mt_";
+
+/// 一覧で API トークンを見分けられるよう、先頭から残す文字数
+const API_TOKEN_PREFIX_LEN: usize = 11;
+
+/// ユーザーがいないときに照合する、hash_password と同じパラメータのハッシュ
+const DUMMY_PASSWORD_HASH: &str =
+    "$argon2id$v=19$m=19456,t=2,p=1$Pea/kVafYSNb9M0DikmU0Q$3uQ2UqcBrawAhzc9en4sjCm2IezvLBVtWVBZcYIVd+Q";
+
+/// セッションの有効期間
+pub fn session_ttl() -> Duration {
+    Duration::days(30)
+}
+
+/// argon2id で、ランダムなソルトを付けた PHC 形式の文字列にする
+pub fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .unwrap()
+        .to_string()
+}
+
+/// ハッシュが壊れている場合も一致しない扱いにする
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+}
+
+/// ログインのパスワードを照合する。hash はユーザーがいなければ None
+/// 応答時間からユーザーがいるかどうかを知られないよう、いなくても固定のハッシュと照合する
+pub fn verify_login(password: &str, hash: Option<&str>) -> bool {
+    check_login(password, hash, verify_password)
+}
+
+fn check_login(password: &str, hash: Option<&str>, verify: impl Fn(&str, &str) -> bool) -> bool {
+    match hash {
+        Some(hash) => verify(password, hash),
+        None => {
+            verify(password, DUMMY_PASSWORD_HASH);
+            false
+        }
+    }
+}
+
+/// 推測できない 256 bit のトークンを 16 進数で返す
+pub fn new_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// `mt_` で始まる API トークンを返す
+pub fn new_api_token() -> String {
+    format!("{}{}", API_TOKEN_PREFIX, new_token())
+}
+
+/// 一覧に表示するための API トークンの先頭
+pub fn api_token_prefix(token: &str) -> String {
+    token.chars().take(API_TOKEN_PREFIX_LEN).collect()
+}
+
+/// `Authorization: Bearer` で送られた API トークンを取り出す。JWT なら None を返す
+pub fn api_token(headers: &HeaderMap) -> Option<&str> {
+    crate::jwt::bearer_token(headers)?
+        .ok()
+        .filter(|token| token.starts_with(API_TOKEN_PREFIX))
+}
+
+/// DB が漏れてもセッションを乗っ取れないよう、トークンはハッシュにして保存する
+pub fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// `Cookie` ヘッダからセッションのトークンを取り出す
+pub fn session_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(name, _)| *name == SESSION_COOKIE)
+        .map(|(_, token)| token)
+        .filter(|token| !token.is_empty())
+}
+
+/// セッションの Cookie の属性
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CookieConfig {
+    /// HTTPS でだけ送らせる。HTTP で動かす手元の開発環境でだけ外す
+    pub secure: bool,
+}
+
+impl Default for CookieConfig {
+    fn default() -> Self {
+        CookieConfig { secure: true }
+    }
+}
+
+impl CookieConfig {
+    /// `SESSION_COOKIE_SECURE=false` で Secure を外す。既定は付ける
+    pub fn from_env() -> anyhow::Result<Self> {
+        let secure = env::var("SESSION_COOKIE_SECURE").map_or(Ok(true), |secure| secure.parse())?;
+        Ok(CookieConfig { secure })
+    }
+
+    /// JavaScript から読めないセッションの Cookie。token が None なら Cookie を消す
+    pub fn session_cookie(&self, token: Option<&str>) -> HeaderValue {
+        let (token, max_age) = match token {
+            Some(token) => (token, session_ttl().num_seconds()),
+            None => ("", 0),
+        };
+        let secure = if self.secure { "; Secure" } else { "" };
+        HeaderValue::from_str(&format!(
+            "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax{}",
+            SESSION_COOKIE, token, max_age, secure
+        ))
+        .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn should_verify_password() {
+        let hash = hash_password("correct horse");
+        assert!(hash.starts_with("$argon2id$"));
+        assert!(verify_password("correct horse", &hash));
+        assert!(!verify_password("battery staple", &hash));
+        assert!(!verify_password("correct horse", "not a hash"));
+        // ソルトが毎回変わる
+        assert_ne!(hash, hash_password("correct horse"));
+    }
+
+    #[test]
+    fn should_verify_login_without_user() {
+        let calls = std::cell::RefCell::new(vec![]);
+        let verify = |_: &str, hash: &str| {
+            calls.borrow_mut().push(hash.to_string());
+            true
+        };
+        // ユーザーがいなくても照合は行い、結果に関わらず一致しない扱いにする
+        assert!(!check_login("correct horse", None, verify));
+        assert_eq!(vec![DUMMY_PASSWORD_HASH.to_string()], *calls.borrow());
+        assert!(check_login("correct horse", Some("hash"), verify));
+        assert_eq!("hash", calls.borrow()[1]);
+
+        // 固定のハッシュも、実際のハッシュと同じコストで照合される
+        let dummy = PasswordHash::new(DUMMY_PASSWORD_HASH).unwrap();
+        let hash = hash_password("correct horse");
+        let hash = PasswordHash::new(&hash).unwrap();
+        assert_eq!(hash.algorithm, dummy.algorithm);
+        assert_eq!(hash.params, dummy.params);
+        assert!(!verify_login("correct horse", None));
+    }
+
+    #[test]
+    fn should_parse_session_cookie() {
+        let token = new_token();
+        assert_eq!(64, token.len());
+        assert_ne!(token, new_token());
+
+        let mut headers = HeaderMap::new();
+        assert_eq!(None, session_token(&headers));
+        headers.insert(
+            header::COOKIE,
+            HeaderValue::from_str(&format!("theme=dark; session={}", token)).unwrap(),
+        );
+        assert_eq!(Some(token.as_str()), session_token(&headers));
+        headers.insert(header::COOKIE, HeaderValue::from_static("session="));
+        assert_eq!(None, session_token(&headers));
+
+        assert_eq!(
+            "session=; Path=/; Max-Age=0; HttpOnly; SameSite=Lax; Secure",
+            CookieConfig::default().session_cookie(None)
+        );
+        assert_eq!(
+            "session=; Path=/; Max-Age=0; HttpOnly; SameSite=Lax",
+            CookieConfig { secure: false }.session_cookie(None)
+        );
+    }
+
+    #[test]
+    fn should_parse_api_token() {
+        let token = new_api_token();
+        assert!(token.starts_with("mt_"));
+        assert_eq!(&token[..11], api_token_prefix(&token));
+
+        let mut headers = HeaderMap::new();
+        assert_eq!(None, api_token(&headers));
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_str(&format!("Bearer {}", token)).unwrap(),
+        );
+        assert_eq!(Some(token.as_str()), api_token(&headers));
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("Bearer eyJ.a.b"),
+        );
+        assert_eq!(None, api_token(&headers));
+    }
+}
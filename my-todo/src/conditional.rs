@@ -0,0 +1,213 @@
+use axum::{
+    body::{self, BoxBody, Bytes},
+    http::{header, HeaderMap, HeaderValue, Method, Request, Response, StatusCode},
+    BoxError,
+};
+use sha2::{Digest, Sha256};
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tower::{Layer, Service};
+
+/// GET のレスポンスに ETag を付け、`If-None-Match` が一致すれば 304 を返すレイヤー
+///
+/// ハンドラが ETag を付けていればそれを使い、なければ本文の SHA-256 から強い ETag を作る
+/// Last-Modified は返さないため、`If-Modified-Since` は評価しない
+/// レスポンスはログインしているユーザーごとに変わるため、共有キャッシュには保存させず、
+/// 使う前に必ず検証させる
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConditionalGetLayer;
+
+/// ブラウザには保存させるが、使うたびに ETag で検証させる
+const CACHE_CONTROL: &str = "private, no-cache";
+
+/// ユーザーはセッションの Cookie か Authorization ヘッダで決まる
+const VARY: &str = "Authorization, Cookie";
+
+impl<S> Layer<S> for ConditionalGetLayer {
+    type Service = ConditionalGet<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ConditionalGet { inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ConditionalGet<S> {
+    inner: S,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for ConditionalGet<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>> + Clone + Send + 'static,
+    S::Future: Send,
+    ReqBody: Send + 'static,
+    ResBody: http_body::Body<Data = Bytes> + Send + 'static,
+    ResBody::Error: Into<BoxError>,
+{
+    type Response = Response<BoxBody>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        // poll_ready 済みのサービスを使うため、clone したものと入れ替える
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        let is_get = req.method() == Method::GET;
+        let if_none_match = req.headers().get(header::IF_NONE_MATCH).cloned();
+
+        Box::pin(async move {
+            let res = inner.call(req).await?;
+            if !is_get || res.status() != StatusCode::OK {
+                return Ok(res.map(body::boxed));
+            }
+
+            let (mut parts, res_body) = res.into_parts();
+            parts.headers.insert(
+                header::CACHE_CONTROL,
+                HeaderValue::from_static(CACHE_CONTROL),
+            );
+            parts
+                .headers
+                .insert(header::VARY, HeaderValue::from_static(VARY));
+            let res_body = match parts.headers.get(header::ETAG) {
+                Some(_) => body::boxed(res_body),
+                None => {
+                    let bytes = match hyper::body::to_bytes(res_body).await {
+                        Ok(bytes) => bytes,
+                        Err(e) => {
+                            tracing::error!("failed to read response body: {}", e.into());
+                            let mut res = Response::new(body::boxed(body::Empty::new()));
+                            *res.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                            return Ok(res);
+                        }
+                    };
+                    parts.headers.insert(header::ETAG, etag(&bytes));
+                    body::boxed(body::Full::from(bytes))
+                }
+            };
+
+            let is_not_modified = match (&if_none_match, parts.headers.get(header::ETAG)) {
+                (Some(if_none_match), Some(etag)) => matches(if_none_match, etag),
+                _ => false,
+            };
+            if is_not_modified {
+                return Ok(not_modified(&parts.headers));
+            }
+            Ok(Response::from_parts(parts, res_body))
+        })
+    }
+}
+
+fn etag(bytes: &[u8]) -> HeaderValue {
+    let digest = Sha256::digest(bytes);
+    HeaderValue::from_str(&format!(r#""{:x}""#, digest)).unwrap()
+}
+
+/// `If-None-Match` は弱い比較なので `W/` を除いて比べる
+fn matches(if_none_match: &HeaderValue, etag: &HeaderValue) -> bool {
+    let (if_none_match, etag) = match (if_none_match.to_str(), etag.to_str()) {
+        (Ok(if_none_match), Ok(etag)) => (if_none_match, etag),
+        _ => return false,
+    };
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+    let etag = opaque(etag);
+    if_none_match
+        .split(',')
+        .any(|tag| tag.trim() == "*" || opaque(tag) == etag)
+}
+
+/// 304 には本文を付けず、キャッシュの検証に使うヘッダだけを残す
+fn not_modified(headers: &HeaderMap) -> Response<BoxBody> {
+    let mut res = Response::new(body::boxed(body::Empty::new()));
+    *res.status_mut() = StatusCode::NOT_MODIFIED;
+    for name in [header::ETAG, header::CACHE_CONTROL, header::VARY] {
+        if let Some(value) = headers.get(&name) {
+            res.headers_mut().insert(name, value.clone());
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::{body::Body, response::Headers, routing::get, Router};
+    use tower::ServiceExt;
+
+    fn app() -> Router {
+        Router::new()
+            .route("/", get(|| async { "Hello, World!" }))
+            .route(
+                "/versioned",
+                get(|| async { (Headers(vec![(header::ETAG, r#""1""#)]), "versioned") }),
+            )
+            .layer(ConditionalGetLayer)
+    }
+
+    fn build_req(path: &str, if_none_match: Option<&str>) -> Request<Body> {
+        let mut builder = Request::builder().uri(path);
+        if let Some(if_none_match) = if_none_match {
+            builder = builder.header(header::IF_NONE_MATCH, if_none_match);
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn should_return_not_modified_if_none_match() {
+        let res = app().oneshot(build_req("/", None)).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let etag = res.headers()[header::ETAG].to_str().unwrap().to_string();
+        assert_eq!(etag, etag_of("Hello, World!"));
+
+        assert_private(&res);
+
+        let res = app().oneshot(build_req("/", Some(&etag))).await.unwrap();
+        assert_eq!(StatusCode::NOT_MODIFIED, res.status());
+        assert_eq!(etag, res.headers()[header::ETAG]);
+        assert_private(&res);
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert!(bytes.is_empty());
+
+        let weak = format!(r#""other", W/{}"#, etag);
+        let res = app().oneshot(build_req("/", Some(&weak))).await.unwrap();
+        assert_eq!(StatusCode::NOT_MODIFIED, res.status());
+
+        let res = app()
+            .oneshot(build_req("/", Some(r#""other""#)))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_keep_etag_set_by_handler() {
+        let res = app().oneshot(build_req("/versioned", None)).await.unwrap();
+        assert_eq!(r#""1""#, res.headers()[header::ETAG]);
+        assert_private(&res);
+
+        let res = app()
+            .oneshot(build_req("/versioned", Some(r#""1""#)))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::NOT_MODIFIED, res.status());
+        assert_private(&res);
+    }
+
+    /// 共有キャッシュが他のユーザーに返さないよう、ユーザーごとのレスポンスとして扱わせる
+    fn assert_private<B>(res: &Response<B>) {
+        assert_eq!("private, no-cache", res.headers()[header::CACHE_CONTROL]);
+        assert_eq!("Authorization, Cookie", res.headers()[header::VARY]);
+    }
+
+    fn etag_of(body: &str) -> String {
+        etag(body.as_bytes()).to_str().unwrap().to_string()
+    }
+}
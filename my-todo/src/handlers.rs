@@ -1,18 +1,455 @@
+use crate::jwt::{Claims, JwtError};
+use crate::repositories::{
+    user::{Scope, User, UserRepository},
+    ParentError, RepositoryError,
+};
 use axum::{
     async_trait,
-    extract::{FromRequest, RequestParts},
-    http::StatusCode,
+    body::Bytes,
+    extract::{rejection::BytesRejection, Extension, FromRequest, RequestParts},
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
     BoxError, Json,
 };
-use serde::de::DeserializeOwned;
-use validator::Validate;
+use chrono::Utc;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{json, Map, Value};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::Infallible;
+use std::sync::Arc;
+use thiserror::Error;
+use uuid::Uuid;
+use validator::{Validate, ValidationErrors, ValidationErrorsKind};
 
+pub mod auth;
 pub mod label;
 pub mod todo;
+pub mod token;
+
+/// ハンドラから返すエラー
+/// リポジトリのエラーやリクエストの検証エラーを HTTP のステータスに対応付ける
+#[derive(Debug, Error)]
+pub enum AppError {
+    #[error(transparent)]
+    Repository(#[from] RepositoryError),
+    #[error("Expected request with `Content-Type: application/json`")]
+    UnsupportedMediaType,
+    #[error(transparent)]
+    Body(#[from] BytesRejection),
+    #[error("Json parse error: [{0}]")]
+    Json(#[from] serde_json::Error),
+    #[error("Validation error: [{0}]")]
+    Validation(#[from] ValidationErrors),
+    #[error("Unauthorized: [{0}]")]
+    Unauthorized(&'static str),
+    #[error(transparent)]
+    InvalidToken(#[from] JwtError),
+    #[error("Insufficient scope: [{0}] is required")]
+    InsufficientScope(Scope),
+    #[error("API tokens can not be used for this route")]
+    TokenNotAllowed,
+}
+
+/// RFC 7807 の problem+json として返すエラーの本文
+/// type, title, status, detail 以外の項目は extensions に入れる
+#[derive(Debug, Serialize)]
+struct Problem {
+    #[serde(rename = "type")]
+    type_: &'static str,
+    title: &'static str,
+    status: u16,
+    detail: String,
+    #[serde(flatten)]
+    extensions: Map<String, Value>,
+}
+
+impl Problem {
+    fn new(status: StatusCode, detail: String) -> Self {
+        Self {
+            type_: "about:blank",
+            title: status.canonical_reason().unwrap_or_default(),
+            status: status.as_u16(),
+            detail,
+            extensions: Map::new(),
+        }
+    }
+
+    fn with_type(mut self, type_: &'static str) -> Self {
+        self.type_ = type_;
+        self
+    }
+
+    fn with(mut self, key: &str, value: Value) -> Self {
+        self.extensions.insert(key.to_string(), value);
+        self
+    }
+}
+
+impl IntoResponse for Problem {
+    fn into_response(self) -> Response {
+        let status = StatusCode::from_u16(self.status).unwrap();
+        let mut res = (status, Json(self)).into_response();
+        res.headers_mut().insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/problem+json"),
+        );
+        res
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct FieldError {
+    code: Cow<'static, str>,
+    message: Option<Cow<'static, str>>,
+    params: HashMap<Cow<'static, str>, Value>,
+}
+
+/// ValidationErrors を項目名 -> エラーの一覧に変換する
+/// 入れ子の構造体は `parent.child`、配列は `items[0].child` の形の項目名にする
+fn field_errors(
+    errors: &ValidationErrors,
+    prefix: &str,
+    accm: &mut BTreeMap<String, Vec<FieldError>>,
+) {
+    for (field, kind) in errors.errors() {
+        let path = format!("{}{}", prefix, field);
+        match kind {
+            ValidationErrorsKind::Field(errors) => {
+                let errors = errors.iter().map(|e| FieldError {
+                    code: e.code.clone(),
+                    message: e.message.clone(),
+                    params: e.params.clone(),
+                });
+                accm.entry(path).or_default().extend(errors);
+            }
+            ValidationErrorsKind::Struct(errors) => {
+                field_errors(errors, &format!("{}.", path), accm);
+            }
+            ValidationErrorsKind::List(list) => {
+                for (index, errors) in list {
+                    field_errors(errors, &format!("{}[{}].", path, index), accm);
+                }
+            }
+        }
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let detail = self.to_string();
+        let problem = match &self {
+            AppError::Repository(RepositoryError::NotFound(_)) => {
+                Problem::new(StatusCode::NOT_FOUND, detail)
+            }
+            AppError::Repository(RepositoryError::Duplicate(id)) => {
+                Problem::new(StatusCode::CONFLICT, detail).with("id", json!(id))
+            }
+            AppError::Repository(RepositoryError::InUse(todos)) => {
+                Problem::new(StatusCode::CONFLICT, detail).with("todos", json!(todos))
+            }
+            AppError::Repository(RepositoryError::UnknownLabels(ids)) => {
+                // 入力の誤りとして、検証エラーと同じ形で返す
+                let errors = json!({
+                    "labels": [{
+                        "code": "unknown_label",
+                        "message": "Label does not exist",
+                        "params": { "ids": ids },
+                    }],
+                });
+                Problem::new(StatusCode::UNPROCESSABLE_ENTITY, detail)
+                    .with_type("/problems/validation-error")
+                    .with("errors", errors)
+            }
+            AppError::Repository(RepositoryError::InvalidParent(id, reason)) => {
+                let code = match reason {
+                    ParentError::NotFound => "unknown_parent",
+                    ParentError::Cycle => "cyclic_parent",
+                    ParentError::TooDeep(_) => "too_deep",
+                };
+                let errors = json!({
+                    "parent_id": [{
+                        "code": code,
+                        "message": reason.to_string(),
+                        "params": { "id": id },
+                    }],
+                });
+                Problem::new(StatusCode::UNPROCESSABLE_ENTITY, detail)
+                    .with_type("/problems/validation-error")
+                    .with("errors", errors)
+            }
+            AppError::Repository(RepositoryError::DependencyCycle(cycle)) => {
+                Problem::new(StatusCode::CONFLICT, detail).with("cycle", json!(cycle))
+            }
+            AppError::Repository(RepositoryError::VersionMismatch(version)) => {
+                Problem::new(StatusCode::PRECONDITION_FAILED, detail)
+                    .with("version", json!(version))
+            }
+            AppError::Repository(RepositoryError::Unexpected(_)) => {
+                // 詳細はレスポンスに含めず、ログと突き合わせるための id だけを返す
+                let correlation_id = Uuid::new_v4().to_string();
+                tracing::error!(%correlation_id, "{}", detail);
+                Problem::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Unexpected error occurred".to_string(),
+                )
+                .with("correlation_id", json!(correlation_id))
+            }
+            AppError::UnsupportedMediaType => {
+                Problem::new(StatusCode::UNSUPPORTED_MEDIA_TYPE, detail)
+            }
+            AppError::Body(_) => Problem::new(StatusCode::BAD_REQUEST, detail),
+            AppError::Json(e) => {
+                // 構文の誤りは 400、型や必須項目の誤りは 422 とする
+                let status = if e.is_data() {
+                    StatusCode::UNPROCESSABLE_ENTITY
+                } else {
+                    StatusCode::BAD_REQUEST
+                };
+                let problem =
+                    Problem::new(status, e.to_string()).with_type("/problems/invalid-json");
+                if e.line() > 0 {
+                    problem
+                        .with("line", json!(e.line()))
+                        .with("column", json!(e.column()))
+                } else {
+                    problem
+                }
+            }
+            AppError::Unauthorized(_) => Problem::new(StatusCode::UNAUTHORIZED, detail),
+            AppError::InvalidToken(_) => Problem::new(StatusCode::UNAUTHORIZED, detail),
+            AppError::InsufficientScope(scope) => {
+                Problem::new(StatusCode::FORBIDDEN, detail).with("scope", json!(scope))
+            }
+            AppError::TokenNotAllowed => Problem::new(StatusCode::FORBIDDEN, detail),
+            AppError::Validation(e) => {
+                let mut errors = BTreeMap::new();
+                field_errors(e, "", &mut errors);
+                let fields: Vec<&str> = errors.keys().map(String::as_str).collect();
+                Problem::new(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    format!("Invalid fields: {}", fields.join(", ")),
+                )
+                .with_type("/problems/validation-error")
+                .with("errors", json!(errors))
+            }
+        };
+        let challenge = match &self {
+            AppError::Unauthorized(_) => Some(r#"Bearer realm="my-todo""#.to_string()),
+            AppError::InvalidToken(e) => Some(format!(
+                r#"Bearer realm="my-todo", error="invalid_token", error_description="{}""#,
+                e.to_string().replace('"', "'")
+            )),
+            AppError::InsufficientScope(scope) => Some(format!(
+                r#"Bearer realm="my-todo", error="insufficient_scope", scope="{}""#,
+                scope
+            )),
+            _ => None,
+        };
+        let mut res = problem.into_response();
+        // RFC 6750 に従い、トークンの送り方と失敗の理由を伝える
+        if let Some(challenge) = challenge.and_then(|value| HeaderValue::from_str(&value).ok()) {
+            res.headers_mut()
+                .insert(header::WWW_AUTHENTICATE, challenge);
+        }
+        res
+    }
+}
+
+/// `If-Match` ヘッダで指定された version
+/// ヘッダがない場合や `*` の場合は None になる
+///
+/// ETag は `"<version>"` の形で返しているため、弱い ETag や解釈できない値は一致しない扱いとする
+/// 複数指定された場合は先頭の version だけを見る
+#[derive(Debug)]
+pub struct IfMatch(pub Option<i32>);
+
+impl IfMatch {
+    fn parse(value: &str) -> Self {
+        if value.trim() == "*" {
+            return IfMatch(None);
+        }
+        let version = value
+            .split(',')
+            .map(str::trim)
+            .filter(|tag| !tag.starts_with("W/"))
+            .find_map(|tag| tag.strip_prefix('"')?.strip_suffix('"')?.parse().ok());
+        // version は 1 から始まるため、0 はどの Todo とも一致しない
+        IfMatch(Some(version.unwrap_or(0)))
+    }
+}
+
+#[async_trait]
+impl<B> FromRequest<B> for IfMatch
+where
+    B: Send,
+{
+    type Rejection = Infallible;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let value = req
+            .headers()
+            .and_then(|headers| headers.get(header::IF_MATCH))
+            .map(|value| value.to_str().unwrap_or_default());
+        Ok(value.map(IfMatch::parse).unwrap_or(IfMatch(None)))
+    }
+}
+
+/// AuthUser がリポジトリの型によらずにユーザーを引けるよう、Extension に入れておく
+#[async_trait]
+pub trait SessionStore: Send + Sync + 'static {
+    async fn current_user(&self, token: &str) -> Result<Option<User>, RepositoryError>;
+    async fn find_user(&self, id: i32) -> Result<Option<User>, RepositoryError>;
+    async fn use_api_token(
+        &self,
+        token: &str,
+    ) -> Result<Option<(User, Vec<Scope>)>, RepositoryError>;
+}
+
+#[async_trait]
+impl<U: UserRepository> SessionStore for U {
+    async fn current_user(&self, token: &str) -> Result<Option<User>, RepositoryError> {
+        self.find_by_session(&crate::auth::hash_token(token), Utc::now())
+            .await
+    }
+
+    async fn find_user(&self, id: i32) -> Result<Option<User>, RepositoryError> {
+        self.find(id).await
+    }
+
+    async fn use_api_token(
+        &self,
+        token: &str,
+    ) -> Result<Option<(User, Vec<Scope>)>, RepositoryError> {
+        self.use_token(&crate::auth::hash_token(token), Utc::now())
+            .await
+    }
+}
+
+/// JwtAuthLayer が検証した Bearer トークンのクレーム
+/// トークンが送られていなければ 401 を返す
+#[async_trait]
+impl<B> FromRequest<B> for Claims
+where
+    B: Send,
+{
+    type Rejection = AppError;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        req.extensions()
+            .and_then(|extensions| extensions.get::<Claims>())
+            .cloned()
+            .ok_or(AppError::Unauthorized("Bearer token required"))
+    }
+}
+
+/// Bearer トークン、API トークン、セッションの Cookie のどれかでログインしているユーザー
+/// ログインしていなければ 401 を返すため、引数に取ったハンドラはログインが必須になる
+/// トークンの sub はユーザーの id として扱う
+///
+/// API トークンは、ルートが Extension で宣言した Scope を持つ場合だけ使え、なければ 403 を返す
+/// Scope を宣言していないルートでは API トークンを使えない
+#[derive(Debug)]
+pub struct AuthUser(pub User);
+
+/// API トークンでログインしたときの、トークンのスコープ
+/// AuthUser が Extension に入れるため、ハンドラでは AuthUser より後の引数に取る
+#[derive(Debug, Clone)]
+pub struct TokenScopes(pub Vec<Scope>);
+
+impl TokenScopes {
+    /// 入力によって、ルートで宣言したもの以外の Scope が必要になるときに確かめる
+    /// API トークンでなければ、どの Scope も持っているものとして扱う
+    pub fn require(scopes: &Option<Extension<TokenScopes>>, scope: Scope) -> Result<(), AppError> {
+        match scopes {
+            Some(Extension(TokenScopes(scopes))) if !scopes.contains(&scope) => {
+                Err(AppError::InsufficientScope(scope))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[async_trait]
+impl<B> FromRequest<B> for AuthUser
+where
+    B: Send,
+{
+    type Rejection = AppError;
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let sessions = req
+            .extensions()
+            .and_then(|extensions| extensions.get::<Arc<dyn SessionStore>>())
+            .cloned()
+            .expect("SessionStore extension is missing");
+        if let Ok(claims) = Claims::from_request(req).await {
+            let id = claims.sub.parse().ok();
+            let user = match id {
+                Some(id) => sessions.find_user(id).await?,
+                None => None,
+            };
+            return user
+                .map(AuthUser)
+                .ok_or(AppError::Unauthorized("Unknown user in token"));
+        }
+
+        let api_token = req
+            .headers()
+            .and_then(crate::auth::api_token)
+            .map(str::to_string);
+        if let Some(token) = api_token {
+            let (user, scopes) = sessions
+                .use_api_token(&token)
+                .await?
+                .ok_or(AppError::Unauthorized("API token is invalid or revoked"))?;
+            let required = req
+                .extensions()
+                .and_then(|extensions| extensions.get::<Scope>())
+                .copied();
+            return match required {
+                Some(scope) if scopes.contains(&scope) => {
+                    if let Some(extensions) = req.extensions_mut() {
+                        extensions.insert(TokenScopes(scopes));
+                    }
+                    Ok(AuthUser(user))
+                }
+                Some(scope) => Err(AppError::InsufficientScope(scope)),
+                None => Err(AppError::TokenNotAllowed),
+            };
+        }
+
+        let token = req
+            .headers()
+            .and_then(crate::auth::session_token)
+            .map(str::to_string)
+            .ok_or(AppError::Unauthorized("Login required"))?;
+        let user = sessions
+            .current_user(&token)
+            .await?
+            .ok_or(AppError::Unauthorized("Session is expired or invalid"))?;
+        Ok(AuthUser(user))
+    }
+}
 
 #[derive(Debug)]
 pub struct ValidateJson<T>(T);
 
+fn is_json_content_type<B>(req: &RequestParts<B>) -> bool {
+    let mime = req
+        .headers()
+        .and_then(|headers| headers.get(header::CONTENT_TYPE))
+        .and_then(|content_type| content_type.to_str().ok())
+        .and_then(|content_type| content_type.parse::<mime::Mime>().ok());
+    match mime {
+        Some(mime) => {
+            mime.type_() == "application"
+                && (mime.subtype() == "json" || mime.suffix().is_some_and(|name| name == "json"))
+        }
+        None => false,
+    }
+}
+
 #[async_trait]
 impl<T, B> FromRequest<B> for ValidateJson<T>
 where
@@ -21,17 +458,167 @@ where
     B::Data: Send,
     B::Error: Into<BoxError>,
 {
-    type Rejection = (StatusCode, String);
+    type Rejection = AppError;
 
+    // serde_json のエラー (行・列) を返すため、Json extractor を使わずに読み込む
     async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
-        let Json(value) = Json::<T>::from_request(req).await.map_err(|rejection| {
-            let message = format!("Json parse error: [{}]", rejection);
-            (StatusCode::BAD_REQUEST, message)
-        })?;
-        value.validate().map_err(|rejection| {
-            let message = format!("Validation error: [{}]", rejection).replace('\n', ", ");
-            (StatusCode::BAD_REQUEST, message)
-        })?;
+        if !is_json_content_type(req) {
+            return Err(AppError::UnsupportedMediaType);
+        }
+        let bytes = Bytes::from_request(req).await?;
+        let value: T = serde_json::from_slice(&bytes)?;
+        value.validate()?;
         Ok(ValidateJson(value))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn res_to_json(res: Response) -> serde_json::Value {
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[tokio::test]
+    async fn should_map_repository_error_to_status() {
+        let res = AppError::from(RepositoryError::NotFound(1)).into_response();
+        assert_eq!(StatusCode::NOT_FOUND, res.status());
+
+        let res = AppError::from(RepositoryError::Duplicate(2)).into_response();
+        assert_eq!(StatusCode::CONFLICT, res.status());
+        assert_eq!(2, res_to_json(res).await["id"]);
+
+        let res = AppError::from(RepositoryError::InUse(vec![3, 4])).into_response();
+        assert_eq!(StatusCode::CONFLICT, res.status());
+        assert_eq!(json!([3, 4]), res_to_json(res).await["todos"]);
+
+        let res = AppError::from(RepositoryError::VersionMismatch(3)).into_response();
+        assert_eq!(StatusCode::PRECONDITION_FAILED, res.status());
+        assert_eq!(3, res_to_json(res).await["version"]);
+
+        let res = AppError::from(RepositoryError::UnknownLabels(vec![5])).into_response();
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, res.status());
+        assert_eq!(
+            json!([5]),
+            res_to_json(res).await["errors"]["labels"][0]["params"]["ids"]
+        );
+
+        let res =
+            AppError::from(RepositoryError::InvalidParent(6, ParentError::Cycle)).into_response();
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, res.status());
+        assert_eq!(
+            "cyclic_parent",
+            res_to_json(res).await["errors"]["parent_id"][0]["code"]
+        );
+
+        let res = AppError::from(RepositoryError::DependencyCycle(vec![7, 8, 7])).into_response();
+        assert_eq!(StatusCode::CONFLICT, res.status());
+        assert_eq!(json!([7, 8, 7]), res_to_json(res).await["cycle"]);
+    }
+
+    #[tokio::test]
+    async fn should_render_problem_json() {
+        let res = AppError::from(RepositoryError::NotFound(1)).into_response();
+        assert_eq!(
+            "application/problem+json",
+            res.headers().get(header::CONTENT_TYPE).unwrap()
+        );
+        assert_eq!(
+            json!({
+                "type": "about:blank",
+                "title": "Not Found",
+                "status": 404,
+                "detail": "Not Found, id is 1",
+            }),
+            res_to_json(res).await
+        );
+
+        let res = AppError::Unauthorized("Login required").into_response();
+        assert_eq!(StatusCode::UNAUTHORIZED, res.status());
+        assert_eq!(
+            r#"Bearer realm="my-todo""#,
+            res.headers()[header::WWW_AUTHENTICATE]
+        );
+        assert_eq!(
+            "Unauthorized: [Login required]",
+            res_to_json(res).await["detail"]
+        );
+
+        let res = AppError::InsufficientScope(Scope::TodosWrite).into_response();
+        assert_eq!(StatusCode::FORBIDDEN, res.status());
+        assert_eq!(
+            r#"Bearer realm="my-todo", error="insufficient_scope", scope="todos:write""#,
+            res.headers()[header::WWW_AUTHENTICATE]
+        );
+        assert_eq!("todos:write", res_to_json(res).await["scope"]);
+    }
+
+    #[tokio::test]
+    async fn should_render_validation_errors_by_field() {
+        #[derive(Debug, Validate)]
+        struct Payload {
+            #[validate(length(min = 1, message = "Can not be Empty"))]
+            text: String,
+            #[validate(range(min = 1))]
+            count: i32,
+        }
+
+        let e = Payload {
+            text: "".to_string(),
+            count: 0,
+        }
+        .validate()
+        .unwrap_err();
+        let res = AppError::from(e).into_response();
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, res.status());
+
+        let body = res_to_json(res).await;
+        assert_eq!("/problems/validation-error", body["type"]);
+        assert_eq!("Invalid fields: count, text", body["detail"]);
+        assert_eq!(
+            json!([{
+                "code": "length",
+                "message": "Can not be Empty",
+                "params": { "min": 1, "value": "" },
+            }]),
+            body["errors"]["text"]
+        );
+        assert_eq!("range", body["errors"]["count"][0]["code"]);
+    }
+
+    #[tokio::test]
+    async fn should_render_json_error_with_position() {
+        let e = serde_json::from_str::<Value>("{\n  \"text\": }").unwrap_err();
+        let res = AppError::from(e).into_response();
+        assert_eq!(StatusCode::BAD_REQUEST, res.status());
+
+        let body = res_to_json(res).await;
+        assert_eq!("/problems/invalid-json", body["type"]);
+        assert_eq!(2, body["line"]);
+        assert_eq!(11, body["column"]);
+    }
+
+    #[tokio::test]
+    async fn should_hide_unexpected_error() {
+        let res = AppError::from(RepositoryError::Unexpected(
+            "connection refused".to_string(),
+        ))
+        .into_response();
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, res.status());
+
+        let body = res_to_json(res).await;
+        assert_eq!(500, body["status"]);
+        assert!(Uuid::parse_str(body["correlation_id"].as_str().unwrap()).is_ok());
+        assert!(!body.to_string().contains("connection refused"));
+    }
+
+    #[test]
+    fn should_parse_if_match() {
+        assert_eq!(Some(3), IfMatch::parse(r#""3""#).0);
+        assert_eq!(Some(4), IfMatch::parse(r#"W/"3", "4""#).0);
+        assert_eq!(None, IfMatch::parse("*").0);
+        assert_eq!(Some(0), IfMatch::parse(r#""abc""#).0);
+    }
+}
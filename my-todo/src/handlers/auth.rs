@@ -0,0 +1,83 @@
+use super::{AppError, AuthUser, ValidateJson};
+use crate::auth::{self, CookieConfig};
+use crate::repositories::user::{normalize_email, LoginUser, RegisterUser, UserRepository};
+use axum::{
+    extract::Extension,
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::Utc;
+use std::sync::Arc;
+
+/// 登録済みのメールアドレスかどうかを知られないよう、登録できたかどうかに関わらず
+/// 同じ 202 を返す。ユーザーの情報はログインして受け取る
+pub async fn register<U: UserRepository>(
+    ValidateJson(payload): ValidateJson<RegisterUser>,
+    Extension(repository): Extension<Arc<U>>,
+) -> Result<StatusCode, AppError> {
+    // ハッシュの計算は重いため、非同期のタスクを止めないよう別のスレッドで行う
+    let password = payload.password;
+    let password_hash = tokio::task::spawn_blocking(move || auth::hash_password(&password))
+        .await
+        .unwrap();
+    repository
+        .create(normalize_email(&payload.email), password_hash)
+        .await?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+/// メールアドレスとパスワードのどちらが誤っているかは区別せずに 401 を返す
+pub async fn login<U: UserRepository>(
+    ValidateJson(payload): ValidateJson<LoginUser>,
+    Extension(repository): Extension<Arc<U>>,
+    Extension(cookie): Extension<CookieConfig>,
+) -> Result<Response, AppError> {
+    let found = repository
+        .find_by_email(&normalize_email(&payload.email))
+        .await?;
+    let password = payload.password;
+    let password_hash = found.as_ref().map(|(_, hash)| hash.clone());
+    let verified = tokio::task::spawn_blocking(move || {
+        auth::verify_login(&password, password_hash.as_deref())
+    })
+    .await
+    .unwrap();
+    let user = match found {
+        Some((user, _)) if verified => user,
+        _ => return Err(AppError::Unauthorized("Invalid email or password")),
+    };
+
+    let token = auth::new_token();
+    repository
+        .create_session(
+            user.id,
+            auth::hash_token(&token),
+            Utc::now() + auth::session_ttl(),
+        )
+        .await?;
+    let mut res = (StatusCode::OK, Json(user)).into_response();
+    res.headers_mut()
+        .insert(header::SET_COOKIE, cookie.session_cookie(Some(&token)));
+    Ok(res)
+}
+
+/// ログインしているユーザーを返す
+pub async fn me(AuthUser(user): AuthUser) -> impl IntoResponse {
+    (StatusCode::OK, Json(user))
+}
+
+/// ログインしていなくても、Cookie を消して 204 を返す
+pub async fn logout<U: UserRepository>(
+    headers: HeaderMap,
+    Extension(repository): Extension<Arc<U>>,
+    Extension(cookie): Extension<CookieConfig>,
+) -> Result<Response, AppError> {
+    if let Some(token) = auth::session_token(&headers) {
+        repository.delete_session(&auth::hash_token(token)).await?;
+    }
+    let mut res = StatusCode::NO_CONTENT.into_response();
+    res.headers_mut()
+        .insert(header::SET_COOKIE, cookie.session_cookie(None));
+    Ok(res)
+}
@@ -1,47 +1,69 @@
-use super::ValidateJson;
-use crate::repositories::label::LabelRepository;
+use super::{AppError, AuthUser, ValidateJson};
+use crate::repositories::label::{CreateLabel, LabelRepository, UpdateLabel};
 use axum::{
-    extract::{Extension, Path},
+    extract::{Extension, Path, Query},
     http::StatusCode,
     response::IntoResponse,
     Json,
 };
-use serde::{Deserialize, Serialize};
+use serde::Deserialize;
 use std::sync::Arc;
-use validator::Validate;
 
 pub async fn create_label<T: LabelRepository>(
+    AuthUser(user): AuthUser,
     ValidateJson(payload): ValidateJson<CreateLabel>,
     Extension(repository): Extension<Arc<T>>,
-) -> Result<impl IntoResponse, StatusCode> {
-    let label = repository
-        .create(payload.name)
-        .await
-        .or(Err(StatusCode::INTERNAL_SERVER_ERROR))?;
+) -> Result<impl IntoResponse, AppError> {
+    let repository = repository.for_owner(user.id);
+    let label = repository.create(payload).await?;
     Ok((StatusCode::CREATED, Json(label)))
 }
 
+pub async fn find_label<T: LabelRepository>(
+    AuthUser(user): AuthUser,
+    Path(id): Path<i32>,
+    Extension(repository): Extension<Arc<T>>,
+) -> Result<impl IntoResponse, AppError> {
+    let repository = repository.for_owner(user.id);
+    let label = repository.find(id).await?;
+    Ok((StatusCode::OK, Json(label)))
+}
+
 pub async fn all_label<T: LabelRepository>(
+    AuthUser(user): AuthUser,
     Extension(repository): Extension<Arc<T>>,
-) -> Result<impl IntoResponse, StatusCode> {
-    let labels = repository.all().await.unwrap();
+) -> Result<impl IntoResponse, AppError> {
+    let repository = repository.for_owner(user.id);
+    let labels = repository.all().await?;
     Ok((StatusCode::OK, Json(labels)))
 }
 
-pub async fn delete_label<T: LabelRepository>(
+pub async fn update_label<T: LabelRepository>(
+    AuthUser(user): AuthUser,
     Path(id): Path<i32>,
+    ValidateJson(payload): ValidateJson<UpdateLabel>,
     Extension(repository): Extension<Arc<T>>,
-) -> StatusCode {
-    repository
-        .delete(id)
-        .await
-        .map(|_| StatusCode::NO_CONTENT)
-        .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+) -> Result<impl IntoResponse, AppError> {
+    let repository = repository.for_owner(user.id);
+    let label = repository.update(id, payload).await?;
+    Ok((StatusCode::OK, Json(label)))
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Validate)]
-pub struct CreateLabel {
-    #[validate(length(min = 1, message = "Can not be empty"))]
-    #[validate(length(max = 100, message = "Over text length"))]
-    name: String,
+#[derive(Debug, Default, Deserialize)]
+pub struct DeleteLabelQuery {
+    #[serde(default)]
+    force: bool,
+}
+
+/// Todo に紐付いているラベルは 409 と紐付いている Todo の id を返す
+/// `?force=true` の場合は Todo から外して削除する
+pub async fn delete_label<T: LabelRepository>(
+    AuthUser(user): AuthUser,
+    Path(id): Path<i32>,
+    Query(query): Query<DeleteLabelQuery>,
+    Extension(repository): Extension<Arc<T>>,
+) -> Result<StatusCode, AppError> {
+    let repository = repository.for_owner(user.id);
+    repository.delete(id, query.force).await?;
+    Ok(StatusCode::NO_CONTENT)
 }
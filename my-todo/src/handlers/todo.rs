@@ -1,59 +1,238 @@
-use super::ValidateJson;
-use crate::repositories::todo::{CreateTodo, TodoRepository, UpdateTodo};
+use super::{AppError, AuthUser, IfMatch, TokenScopes, ValidateJson};
+use crate::repositories::{
+    label::{validate_unique_names, CreateLabel, LabelRepository},
+    todo::{
+        CreateTodo, MoveTodo, Pagination, SearchQuery, TodoEntity, TodoFilter, TodoRepository,
+        UpdateTodo,
+    },
+    unit_of_work::Commit,
+    user::Scope,
+    RepositoryError,
+};
 use axum::{
-    extract::{Extension, Path},
-    http::StatusCode,
-    response::IntoResponse,
+    extract::{Extension, Path, Query},
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
     Json,
 };
+use serde::Deserialize;
 use std::sync::Arc;
+use validator::Validate;
+
+/// Todo の version を ETag として付けて返す
+fn with_etag(status: StatusCode, todo: TodoEntity) -> Response {
+    let etag = HeaderValue::from_str(&format!(r#""{}""#, todo.version)).unwrap();
+    let mut res = (status, Json(todo)).into_response();
+    res.headers_mut().insert(header::ETAG, etag);
+    res
+}
 
-pub async fn create_todo<T: TodoRepository>(
-    ValidateJson(payload): ValidateJson<CreateTodo>,
+/// If-Match が現在の version と一致しなかった場合は、現在の Todo を 412 で返す
+async fn precondition_failed<T: TodoRepository>(
+    repository: &T,
+    id: i32,
+) -> Result<Response, AppError> {
+    let todo = repository.find(id).await?;
+    Ok(with_etag(StatusCode::PRECONDITION_FAILED, todo))
+}
+
+/// Todo の作成。new_labels のラベルも作って紐付ける
+#[derive(Debug, Deserialize, Validate)]
+pub struct CreateTodoWithLabels {
+    /// 検証エラーの項目名を CreateTodo と同じにするため、入れ子にせず別に検証する
+    #[serde(flatten)]
+    todo: CreateTodo,
+    #[serde(default)]
+    #[validate]
+    #[validate(custom(function = "validate_unique_names", message = "Duplicate label name"))]
+    new_labels: Vec<CreateLabel>,
+}
+
+/// ラベルの作成と Todo の作成を 1 つの作業単位にまとめ、どちらかに失敗したら両方を取り消す
+pub async fn create_todo<T: TodoRepository, L: LabelRepository<UnitOfWork = T::UnitOfWork>>(
+    AuthUser(user): AuthUser,
+    scopes: Option<Extension<TokenScopes>>,
+    ValidateJson(payload): ValidateJson<CreateTodoWithLabels>,
     Extension(repository): Extension<Arc<T>>,
-) -> Result<impl IntoResponse, StatusCode> {
-    let todo = repository
-        .create(payload)
-        .await
-        .or(Err(StatusCode::NOT_FOUND))?;
+    Extension(label_repository): Extension<Arc<L>>,
+) -> Result<impl IntoResponse, AppError> {
+    payload.todo.validate()?;
+    let repository = repository.for_owner(user.id);
+    if payload.new_labels.is_empty() {
+        let todo = repository.create(payload.todo).await?;
+        return Ok((StatusCode::CREATED, Json(todo)));
+    }
+    TokenScopes::require(&scopes, Scope::LabelsWrite)?;
+    let label_repository = label_repository.for_owner(user.id);
+
+    let mut uow = repository.begin().await?;
+    let mut todo = payload.todo;
+    for label in payload.new_labels {
+        let label = label_repository.create_in(&mut uow, label).await?;
+        todo.add_label(label.id);
+    }
+    let todo = repository.create_in(&mut uow, todo).await?;
+    uow.commit().await?;
 
     Ok((StatusCode::CREATED, Json(todo)))
 }
 
 pub async fn find_todo<T: TodoRepository>(
+    AuthUser(user): AuthUser,
     Path(id): Path<i32>,
     Extension(repository): Extension<Arc<T>>,
-) -> Result<impl IntoResponse, StatusCode> {
-    let todo = repository.find(id).await.or(Err(StatusCode::NOT_FOUND))?;
-    Ok((StatusCode::OK, Json(todo)))
+) -> Result<impl IntoResponse, AppError> {
+    let repository = repository.for_owner(user.id);
+    let todo = repository.find(id).await?;
+    Ok(with_etag(StatusCode::OK, todo))
 }
 
 pub async fn all_todo<T: TodoRepository>(
+    AuthUser(user): AuthUser,
+    Query(filter): Query<TodoFilter>,
+    Query(pagination): Query<Pagination>,
+    Extension(repository): Extension<Arc<T>>,
+) -> Result<impl IntoResponse, AppError> {
+    let repository = repository.for_owner(user.id);
+    let page = repository.all(filter, pagination).await?;
+    Ok((StatusCode::OK, Json(page)))
+}
+
+pub async fn search_todo<T: TodoRepository>(
+    AuthUser(user): AuthUser,
+    Query(query): Query<SearchQuery>,
     Extension(repository): Extension<Arc<T>>,
-) -> Result<impl IntoResponse, StatusCode> {
-    let todos = repository.all().await.unwrap();
-    Ok((StatusCode::OK, Json(todos)))
+) -> Result<impl IntoResponse, AppError> {
+    let repository = repository.for_owner(user.id);
+    let hits = repository.search(query).await?;
+    Ok((StatusCode::OK, Json(hits)))
 }
 
 pub async fn update_todo<T: TodoRepository>(
+    AuthUser(user): AuthUser,
     Path(id): Path<i32>,
+    IfMatch(version): IfMatch,
     ValidateJson(payload): ValidateJson<UpdateTodo>,
     Extension(repository): Extension<Arc<T>>,
-) -> Result<impl IntoResponse, StatusCode> {
-    let todo = repository
-        .update(id, payload)
-        .await
-        .or(Err(StatusCode::NOT_FOUND))?;
-    Ok((StatusCode::OK, Json(todo)))
+) -> Result<Response, AppError> {
+    let repository = repository.for_owner(user.id);
+    match repository.update(id, payload, version).await {
+        Err(RepositoryError::VersionMismatch(_)) => precondition_failed(&repository, id).await,
+        result => Ok(with_etag(StatusCode::OK, result?)),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteTodoQuery {
+    /// true の場合はゴミ箱を経由せずに完全に削除する
+    #[serde(default)]
+    purge: bool,
 }
 
 pub async fn delete_todo<T: TodoRepository>(
+    AuthUser(user): AuthUser,
+    Path(id): Path<i32>,
+    Query(query): Query<DeleteTodoQuery>,
+    IfMatch(version): IfMatch,
+    Extension(repository): Extension<Arc<T>>,
+) -> Result<Response, AppError> {
+    let repository = repository.for_owner(user.id);
+    let result = if query.purge {
+        repository.purge(id, version).await
+    } else {
+        repository.delete(id, version).await
+    };
+    match result {
+        Err(RepositoryError::VersionMismatch(_)) => precondition_failed(&repository, id).await,
+        result => {
+            result?;
+            Ok(StatusCode::NO_CONTENT.into_response())
+        }
+    }
+}
+
+pub async fn trash_todo<T: TodoRepository>(
+    AuthUser(user): AuthUser,
+    Query(pagination): Query<Pagination>,
+    Extension(repository): Extension<Arc<T>>,
+) -> Result<impl IntoResponse, AppError> {
+    let repository = repository.for_owner(user.id);
+    let page = repository.trash(pagination).await?;
+    Ok((StatusCode::OK, Json(page)))
+}
+
+pub async fn restore_todo<T: TodoRepository>(
+    AuthUser(user): AuthUser,
+    Path(id): Path<i32>,
+    Extension(repository): Extension<Arc<T>>,
+) -> Result<Response, AppError> {
+    let repository = repository.for_owner(user.id);
+    let todo = repository.restore(id).await?;
+    Ok(with_etag(StatusCode::OK, todo))
+}
+
+pub async fn move_todo<T: TodoRepository>(
+    AuthUser(user): AuthUser,
+    Path(id): Path<i32>,
+    ValidateJson(payload): ValidateJson<MoveTodo>,
+    Extension(repository): Extension<Arc<T>>,
+) -> Result<Response, AppError> {
+    let repository = repository.for_owner(user.id);
+    let todo = repository.reorder(id, payload).await?;
+    Ok(with_etag(StatusCode::OK, todo))
+}
+
+pub async fn children_todo<T: TodoRepository>(
+    AuthUser(user): AuthUser,
+    Path(id): Path<i32>,
+    Extension(repository): Extension<Arc<T>>,
+) -> Result<impl IntoResponse, AppError> {
+    let repository = repository.for_owner(user.id);
+    let children = repository.children(id).await?;
+    Ok((StatusCode::OK, Json(children)))
+}
+
+pub async fn add_dependency<T: TodoRepository>(
+    AuthUser(user): AuthUser,
+    Path((id, other)): Path<(i32, i32)>,
+    Extension(repository): Extension<Arc<T>>,
+) -> Result<Response, AppError> {
+    let repository = repository.for_owner(user.id);
+    let todo = repository.add_dependency(id, other).await?;
+    Ok(with_etag(StatusCode::OK, todo))
+}
+
+pub async fn remove_dependency<T: TodoRepository>(
+    AuthUser(user): AuthUser,
+    Path((id, other)): Path<(i32, i32)>,
+    Extension(repository): Extension<Arc<T>>,
+) -> Result<Response, AppError> {
+    let repository = repository.for_owner(user.id);
+    let todo = repository.remove_dependency(id, other).await?;
+    Ok(with_etag(StatusCode::OK, todo))
+}
+
+/// 依存関係のグラフを Graphviz の DOT 形式で返す
+pub async fn graph_todo<T: TodoRepository>(
+    AuthUser(user): AuthUser,
+    Extension(repository): Extension<Arc<T>>,
+) -> Result<Response, AppError> {
+    let repository = repository.for_owner(user.id);
+    let graph = repository.dependency_graph().await?;
+    let mut res = (StatusCode::OK, graph.to_string()).into_response();
+    res.headers_mut().insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("text/vnd.graphviz; charset=utf-8"),
+    );
+    Ok(res)
+}
+
+pub async fn history_todo<T: TodoRepository>(
+    AuthUser(user): AuthUser,
     Path(id): Path<i32>,
     Extension(repository): Extension<Arc<T>>,
-) -> StatusCode {
-    repository
-        .delete(id)
-        .await
-        .map(|_| StatusCode::NO_CONTENT)
-        .unwrap_or(StatusCode::NOT_FOUND)
+) -> Result<impl IntoResponse, AppError> {
+    let repository = repository.for_owner(user.id);
+    let events = repository.history(id).await?;
+    Ok((StatusCode::OK, Json(events)))
 }
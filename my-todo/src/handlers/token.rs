@@ -0,0 +1,53 @@
+use super::{AppError, AuthUser, ValidateJson};
+use crate::auth;
+use crate::repositories::user::{ApiToken, CreateToken, UserRepository};
+use axum::{
+    extract::{Extension, Path},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use serde::Serialize;
+use std::sync::Arc;
+
+/// 作ったときだけ、トークンそのものを返す
+#[derive(Debug, Serialize)]
+struct CreatedToken {
+    token: String,
+    #[serde(flatten)]
+    api_token: ApiToken,
+}
+
+pub async fn create_token<U: UserRepository>(
+    AuthUser(user): AuthUser,
+    ValidateJson(payload): ValidateJson<CreateToken>,
+    Extension(repository): Extension<Arc<U>>,
+) -> Result<impl IntoResponse, AppError> {
+    let token = auth::new_api_token();
+    let api_token = repository
+        .create_token(
+            user.id,
+            payload,
+            auth::api_token_prefix(&token),
+            auth::hash_token(&token),
+        )
+        .await?;
+    Ok((StatusCode::CREATED, Json(CreatedToken { token, api_token })))
+}
+
+pub async fn all_token<U: UserRepository>(
+    AuthUser(user): AuthUser,
+    Extension(repository): Extension<Arc<U>>,
+) -> Result<impl IntoResponse, AppError> {
+    let tokens = repository.all_tokens(user.id).await?;
+    Ok((StatusCode::OK, Json(tokens)))
+}
+
+pub async fn delete_token<U: UserRepository>(
+    AuthUser(user): AuthUser,
+    Path(id): Path<i32>,
+    Extension(repository): Extension<Arc<U>>,
+) -> Result<impl IntoResponse, AppError> {
+    repository.delete_token(user.id, id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,353 @@
+use crate::auth::API_TOKEN_PREFIX;
+use crate::handlers::AppError;
+use axum::{
+    body::{self, BoxBody},
+    http::{header, HeaderMap, Request, Response},
+    response::IntoResponse,
+};
+use jsonwebtoken::{
+    jwk::{AlgorithmParameters, JwkSet, KeyAlgorithm},
+    Algorithm, DecodingKey, Validation,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    env, fs,
+    future::Future,
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use thiserror::Error;
+use tower::{Layer, Service};
+
+/// `JWT_AUDIENCE` を指定しないときに求める aud
+const DEFAULT_AUDIENCE: &str = "my-todo";
+
+/// 検証済みの JWT のクレーム
+/// aud は検証に使うだけなので持たない
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Claims {
+    pub sub: String,
+    pub exp: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nbf: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub iat: Option<i64>,
+}
+
+#[derive(Debug, Error)]
+pub enum JwtError {
+    #[error("Expected `Authorization: Bearer <token>`")]
+    Malformed,
+    #[error("Unsupported algorithm: {0:?}")]
+    UnsupportedAlgorithm(Algorithm),
+    #[error("No key to verify the token")]
+    UnknownKey,
+    #[error("Invalid token: {0}")]
+    Invalid(#[from] jsonwebtoken::errors::Error),
+}
+
+/// HS256 の共通鍵と、JWKS にある RS256 の公開鍵で JWT を検証する
+/// 鍵がひとつもなければ、どのトークンも受け付けない
+#[derive(Clone)]
+pub struct JwtVerifier {
+    hs256: Option<DecodingKey>,
+    /// (kid, 公開鍵) の組
+    rs256: Vec<(Option<String>, DecodingKey)>,
+    audience: String,
+}
+
+impl JwtVerifier {
+    pub fn new(audience: &str) -> Self {
+        JwtVerifier {
+            hs256: None,
+            rs256: vec![],
+            audience: audience.to_string(),
+        }
+    }
+
+    pub fn with_secret(mut self, secret: &[u8]) -> Self {
+        self.hs256 = Some(DecodingKey::from_secret(secret));
+        self
+    }
+
+    /// RSA 以外の鍵と、alg が RS256 以外の鍵は読み飛ばす
+    pub fn with_jwks(mut self, jwks: &JwkSet) -> anyhow::Result<Self> {
+        for jwk in &jwks.keys {
+            let is_rs256 = jwk
+                .common
+                .key_algorithm
+                .is_none_or(|alg| alg == KeyAlgorithm::RS256);
+            if !is_rs256 || !matches!(jwk.algorithm, AlgorithmParameters::RSA(_)) {
+                continue;
+            }
+            self.rs256
+                .push((jwk.common.key_id.clone(), DecodingKey::from_jwk(jwk)?));
+        }
+        Ok(self)
+    }
+
+    pub fn with_jwks_file(self, path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let jwks: JwkSet = serde_json::from_str(&fs::read_to_string(path)?)?;
+        self.with_jwks(&jwks)
+    }
+
+    /// `JWT_SECRET` で HS256 の共通鍵を、`JWT_JWKS_PATH` で RS256 の JWKS ファイルを指定する
+    /// aud は `JWT_AUDIENCE` で指定し、既定は my-todo
+    pub fn from_env() -> anyhow::Result<Self> {
+        let audience = env::var("JWT_AUDIENCE").unwrap_or(DEFAULT_AUDIENCE.to_string());
+        let mut verifier = JwtVerifier::new(&audience);
+        if let Ok(secret) = env::var("JWT_SECRET") {
+            verifier = verifier.with_secret(secret.as_bytes());
+        }
+        if let Ok(path) = env::var("JWT_JWKS_PATH") {
+            verifier = verifier.with_jwks_file(path)?;
+        }
+        Ok(verifier)
+    }
+
+    /// 署名と exp, nbf, aud を検証する。時刻のずれは 60 秒まで許す
+    /// 鍵の種類を取り違えないよう、ヘッダの alg に対応する鍵だけを使う
+    pub fn verify(&self, token: &str) -> Result<Claims, JwtError> {
+        let header = jsonwebtoken::decode_header(token)?;
+        let key = match header.alg {
+            Algorithm::HS256 => self.hs256.as_ref(),
+            Algorithm::RS256 => match &header.kid {
+                Some(kid) => self
+                    .rs256
+                    .iter()
+                    .find(|(id, _)| id.as_ref() == Some(kid))
+                    .map(|(_, key)| key),
+                None if self.rs256.len() == 1 => Some(&self.rs256[0].1),
+                None => None,
+            },
+            alg => return Err(JwtError::UnsupportedAlgorithm(alg)),
+        }
+        .ok_or(JwtError::UnknownKey)?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_required_spec_claims(&["exp", "aud", "sub"]);
+        validation.set_audience(&[&self.audience]);
+        validation.validate_nbf = true;
+        let data = jsonwebtoken::decode::<Claims>(token, key, &validation)?;
+        Ok(data.claims)
+    }
+}
+
+/// `Authorization` ヘッダから Bearer のトークンを取り出す。ヘッダがなければ None を返す
+pub fn bearer_token(headers: &HeaderMap) -> Option<Result<&str, JwtError>> {
+    let value = headers.get(header::AUTHORIZATION)?;
+    let token = value
+        .to_str()
+        .ok()
+        .and_then(|value| value.split_once(' '))
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+        .map(|(_, token)| token.trim())
+        .filter(|token| !token.is_empty())
+        .ok_or(JwtError::Malformed);
+    Some(token)
+}
+
+/// `Authorization: Bearer` の JWT を検証し、クレームをリクエストの extensions に入れるレイヤー
+///
+/// ヘッダがない場合と API トークンの場合はそのまま通し、ハンドラの extractor に任せる
+/// ヘッダがあって検証できなければ、ハンドラを呼ばずに 401 を返す
+#[derive(Clone)]
+pub struct JwtAuthLayer {
+    verifier: Arc<JwtVerifier>,
+}
+
+impl JwtAuthLayer {
+    pub fn new(verifier: JwtVerifier) -> Self {
+        JwtAuthLayer {
+            verifier: Arc::new(verifier),
+        }
+    }
+}
+
+impl<S> Layer<S> for JwtAuthLayer {
+    type Service = JwtAuth<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        JwtAuth {
+            inner,
+            verifier: self.verifier.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct JwtAuth<S> {
+    inner: S,
+    verifier: Arc<JwtVerifier>,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for JwtAuth<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>> + Clone + Send + 'static,
+    S::Future: Send,
+    ReqBody: Send + 'static,
+    ResBody: http_body::Body<Data = axum::body::Bytes> + Send + 'static,
+    ResBody::Error: Into<axum::BoxError>,
+{
+    type Response = Response<BoxBody>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<ReqBody>) -> Self::Future {
+        // poll_ready 済みのサービスを使うため、clone したものと入れ替える
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        let claims = bearer_token(req.headers())
+            .filter(|token| !matches!(token, Ok(token) if token.starts_with(API_TOKEN_PREFIX)))
+            .map(|token| token.and_then(|token| self.verifier.verify(token)))
+            .transpose();
+        Box::pin(async move {
+            match claims {
+                Ok(Some(claims)) => {
+                    req.extensions_mut().insert(claims);
+                }
+                Ok(None) => {}
+                Err(e) => return Ok(AppError::from(e).into_response()),
+            }
+            let res = inner.call(req).await?;
+            Ok(res.map(body::boxed))
+        })
+    }
+}
+
+#[cfg(test)]
+pub mod test_utils {
+    use super::*;
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::Value;
+
+    /// テストで HS256 のトークンを作る共通鍵
+    pub const SECRET: &[u8] = b"test-secret";
+    /// testdata/jwks.json の鍵の kid
+    pub const KID: &str = "test-rs256";
+
+    /// テスト用の共通鍵と testdata/jwks.json の公開鍵を使う
+    pub fn verifier() -> JwtVerifier {
+        JwtVerifier::new(DEFAULT_AUDIENCE)
+            .with_secret(SECRET)
+            .with_jwks_file(concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/jwks.json"))
+            .unwrap()
+    }
+
+    pub fn mint_hs256(claims: &Value) -> String {
+        let key = EncodingKey::from_secret(SECRET);
+        jsonwebtoken::encode(&Header::new(Algorithm::HS256), claims, &key).unwrap()
+    }
+
+    pub fn mint_rs256(claims: &Value) -> String {
+        let pem = include_bytes!("../testdata/jwt_rs256.pem");
+        let key = EncodingKey::from_rsa_pem(pem).unwrap();
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = Some(KID.to_string());
+        jsonwebtoken::encode(&header, claims, &key).unwrap()
+    }
+
+    /// sub のユーザーとして、今から 1 時間有効なクレーム
+    pub fn claims(sub: &str) -> Value {
+        let now = chrono::Utc::now().timestamp();
+        serde_json::json!({ "sub": sub, "aud": DEFAULT_AUDIENCE, "iat": now, "exp": now + 3600 })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::test_utils::*;
+    use super::*;
+    use axum::http::HeaderValue;
+    use jsonwebtoken::{errors::ErrorKind, EncodingKey, Header};
+
+    #[test]
+    fn should_verify_hs256_and_rs256() {
+        let verifier = verifier();
+        for token in [mint_hs256(&claims("1")), mint_rs256(&claims("1"))] {
+            let claims = verifier.verify(&token).unwrap();
+            assert_eq!("1", claims.sub);
+        }
+
+        // 鍵がなければ受け付けない
+        let res = JwtVerifier::new(DEFAULT_AUDIENCE).verify(&mint_hs256(&claims("1")));
+        assert!(matches!(res, Err(JwtError::UnknownKey)));
+        let res = JwtVerifier::new(DEFAULT_AUDIENCE).verify(&mint_rs256(&claims("1")));
+        assert!(matches!(res, Err(JwtError::UnknownKey)));
+    }
+
+    #[test]
+    fn should_reject_invalid_tokens() {
+        let verifier = verifier();
+        let now = chrono::Utc::now().timestamp();
+        let kind = |res: Result<Claims, JwtError>| match res {
+            Err(JwtError::Invalid(e)) => e.into_kind(),
+            res => panic!("unexpected result: {:?}", res),
+        };
+
+        let mut expired = claims("1");
+        expired["exp"] = (now - 3600).into();
+        let res = verifier.verify(&mint_hs256(&expired));
+        assert!(matches!(kind(res), ErrorKind::ExpiredSignature));
+
+        let mut immature = claims("1");
+        immature["nbf"] = (now + 3600).into();
+        let res = verifier.verify(&mint_rs256(&immature));
+        assert!(matches!(kind(res), ErrorKind::ImmatureSignature));
+
+        let mut other_audience = claims("1");
+        other_audience["aud"] = "other".into();
+        let res = verifier.verify(&mint_hs256(&other_audience));
+        assert!(matches!(kind(res), ErrorKind::InvalidAudience));
+
+        let mut no_audience = claims("1");
+        no_audience.as_object_mut().unwrap().remove("aud");
+        let res = verifier.verify(&mint_hs256(&no_audience));
+        assert!(matches!(kind(res), ErrorKind::MissingRequiredClaim(_)));
+
+        let key = EncodingKey::from_secret(b"other-secret");
+        let token = jsonwebtoken::encode(&Header::default(), &claims("1"), &key).unwrap();
+        assert!(matches!(
+            kind(verifier.verify(&token)),
+            ErrorKind::InvalidSignature
+        ));
+
+        let token = jsonwebtoken::encode(&Header::new(Algorithm::HS512), &claims("1"), &key);
+        let res = verifier.verify(&token.unwrap());
+        assert!(matches!(
+            res,
+            Err(JwtError::UnsupportedAlgorithm(Algorithm::HS512))
+        ));
+
+        assert!(matches!(
+            kind(verifier.verify("not a token")),
+            ErrorKind::InvalidToken
+        ));
+    }
+
+    #[test]
+    fn should_parse_bearer_token() {
+        let mut headers = HeaderMap::new();
+        assert!(bearer_token(&headers).is_none());
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("bearer abc"),
+        );
+        assert!(matches!(bearer_token(&headers), Some(Ok("abc"))));
+        for value in ["Basic abc", "Bearer", "Bearer  "] {
+            headers.insert(header::AUTHORIZATION, HeaderValue::from_static(value));
+            assert!(
+                matches!(bearer_token(&headers), Some(Err(JwtError::Malformed))),
+                "{}",
+                value
+            );
+        }
+    }
+}
@@ -1,24 +1,43 @@
-use hyper::header::CONTENT_TYPE;
-use repositories::label::LabelRepository;
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE, ETAG, IF_MATCH, IF_NONE_MATCH, WWW_AUTHENTICATE};
+use hyper::Method;
+use repositories::{label::LabelRepository, user::UserRepository};
+mod auth;
+mod conditional;
 mod handlers;
+mod jwt;
+mod reminders;
 mod repositories;
+use crate::auth::CookieConfig;
+use crate::conditional::ConditionalGetLayer;
 use crate::handlers::{
-    label::{all_label, create_label, delete_label},
-    todo::{all_todo, create_todo, delete_todo, find_todo, update_todo},
+    auth::{login, logout, me, register},
+    label::{all_label, create_label, delete_label, find_label, update_label},
+    todo::{
+        add_dependency, all_todo, children_todo, create_todo, delete_todo, find_todo, graph_todo,
+        history_todo, move_todo, remove_dependency, restore_todo, search_todo, trash_todo,
+        update_todo,
+    },
+    token::{all_token, create_token, delete_token},
+    SessionStore,
 };
+use crate::jwt::{JwtAuthLayer, JwtVerifier};
+use crate::reminders::{ReminderScheduler, SystemClock};
 use crate::repositories::{
     label::LabelRepositoryForDb,
+    reminder::ReminderRepositoryForDb,
     todo::{TodoRepository, TodoRepositoryForDb},
+    user::{Scope, UserRepositoryForDb},
 };
 use axum::{
     extract::Extension,
+    handler::Handler,
     routing::{delete, get, post},
     Router,
 };
 use dotenv::dotenv;
 use sqlx::PgPool;
 use std::{env, net::SocketAddr, sync::Arc};
-use tower_http::cors::{Any, CorsLayer, Origin};
+use tower_http::cors::{CorsLayer, Origin};
 
 #[tokio::main]
 async fn main() {
@@ -34,9 +53,22 @@ async fn main() {
     let pool = PgPool::connect(database_url)
         .await
         .unwrap_or_else(|_| panic!("fail connect database, url is [{}]", database_url));
+
+    ReminderScheduler::new(
+        ReminderRepositoryForDb::new(pool.clone()),
+        reminders::sink_from_env().expect("invalid reminder sink config"),
+        Arc::new(SystemClock),
+        reminders::interval_from_env().expect("invalid [REMINDER_INTERVAL_SECS]"),
+    )
+    .with_timeout(reminders::timeout_from_env().expect("invalid [REMINDER_TIMEOUT_SECS]"))
+    .spawn();
+
     let app = create_app(
         TodoRepositoryForDb::new(pool.clone()),
         LabelRepositoryForDb::new(pool.clone()),
+        UserRepositoryForDb::new(pool.clone()),
+        JwtVerifier::from_env().expect("invalid JWT config"),
+        CookieConfig::from_env().expect("invalid [SESSION_COOKIE_SECURE]"),
     );
     let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
 
@@ -48,31 +80,102 @@ async fn main() {
         .unwrap();
 }
 
-fn create_app<Todo: TodoRepository, Label: LabelRepository>(
+/// API トークンでルートを使うときに必要なスコープ
+const TODOS_READ: Extension<Scope> = Extension(Scope::TodosRead);
+const TODOS_WRITE: Extension<Scope> = Extension(Scope::TodosWrite);
+const LABELS_READ: Extension<Scope> = Extension(Scope::LabelsRead);
+const LABELS_WRITE: Extension<Scope> = Extension(Scope::LabelsWrite);
+
+/// スコープを宣言していないルートでは API トークンを使えない
+fn create_app<
+    Todo: TodoRepository,
+    Label: LabelRepository<UnitOfWork = Todo::UnitOfWork>,
+    User: UserRepository,
+>(
     todo_repository: Todo,
     label_repository: Label,
+    user_repository: User,
+    jwt_verifier: JwtVerifier,
+    cookie_config: CookieConfig,
 ) -> Router {
+    let sessions: Arc<dyn SessionStore> = Arc::new(user_repository.clone());
     Router::new()
         .route("/", get(root))
-        .route("/todos", post(create_todo::<Todo>).get(all_todo::<Todo>))
+        .route("/auth/register", post(register::<User>))
+        .route("/auth/login", post(login::<User>))
+        .route("/auth/logout", post(logout::<User>))
+        .route("/auth/me", get(me))
+        .route("/tokens", post(create_token::<User>).get(all_token::<User>))
+        .route("/tokens/:id", delete(delete_token::<User>))
+        .route(
+            "/todos",
+            post(create_todo::<Todo, Label>.layer(TODOS_WRITE))
+                .get(all_todo::<Todo>.layer(TODOS_READ)),
+        )
+        .route("/todos/search", get(search_todo::<Todo>.layer(TODOS_READ)))
+        .route("/todos/trash", get(trash_todo::<Todo>.layer(TODOS_READ)))
+        .route(
+            "/todos/graph.dot",
+            get(graph_todo::<Todo>.layer(TODOS_READ)),
+        )
         .route(
             "/todos/:id",
-            get(find_todo::<Todo>)
-                .delete(delete_todo::<Todo>)
-                .patch(update_todo::<Todo>),
+            get(find_todo::<Todo>.layer(TODOS_READ))
+                .delete(delete_todo::<Todo>.layer(TODOS_WRITE))
+                .patch(update_todo::<Todo>.layer(TODOS_WRITE)),
+        )
+        .route(
+            "/todos/:id/restore",
+            post(restore_todo::<Todo>.layer(TODOS_WRITE)),
+        )
+        .route(
+            "/todos/:id/history",
+            get(history_todo::<Todo>.layer(TODOS_READ)),
+        )
+        .route(
+            "/todos/:id/move",
+            post(move_todo::<Todo>.layer(TODOS_WRITE)),
+        )
+        .route(
+            "/todos/:id/children",
+            get(children_todo::<Todo>.layer(TODOS_READ)),
+        )
+        .route(
+            "/todos/:id/dependencies/:other",
+            post(add_dependency::<Todo>.layer(TODOS_WRITE))
+                .delete(remove_dependency::<Todo>.layer(TODOS_WRITE)),
         )
         .route(
             "/labels",
-            post(create_label::<Label>).get(all_label::<Label>),
+            post(create_label::<Label>.layer(LABELS_WRITE))
+                .get(all_label::<Label>.layer(LABELS_READ)),
+        )
+        .route(
+            "/labels/:id",
+            get(find_label::<Label>.layer(LABELS_READ))
+                .delete(delete_label::<Label>.layer(LABELS_WRITE))
+                .patch(update_label::<Label>.layer(LABELS_WRITE)),
         )
-        .route("/labels/:id", delete(delete_label::<Label>))
         .layer(Extension(Arc::new(todo_repository)))
         .layer(Extension(Arc::new(label_repository)))
+        .layer(Extension(Arc::new(user_repository)))
+        .layer(Extension(sessions))
+        .layer(Extension(cookie_config))
+        .layer(JwtAuthLayer::new(jwt_verifier))
+        .layer(ConditionalGetLayer)
         .layer(
             CorsLayer::new()
                 .allow_origin(Origin::exact("http://localhost:3001".parse().unwrap()))
-                .allow_methods(Any)
-                .allow_headers(vec![CONTENT_TYPE]),
+                // セッションの Cookie を送れるよう、ワイルドカードを使わずに列挙する
+                .allow_methods(vec![
+                    Method::GET,
+                    Method::POST,
+                    Method::PATCH,
+                    Method::DELETE,
+                ])
+                .allow_credentials(true)
+                .allow_headers(vec![AUTHORIZATION, CONTENT_TYPE, IF_MATCH, IF_NONE_MATCH])
+                .expose_headers(vec![ETAG, WWW_AUTHENTICATE]),
         )
 }
 
@@ -84,8 +187,12 @@ async fn root() -> &'static str {
 mod test {
     use super::*;
     use crate::repositories::{
-        label::test_utils::LabelRepositoryForMemory,
-        todo::{test_utils::TodoRepositoryForMemory, CreateTodo, TodoEntity},
+        label::{test_utils::LabelRepositoryForMemory, CreateLabel, Label},
+        test_utils::MemoryStore,
+        todo::{
+            test_utils::TodoRepositoryForMemory, CreateTodo, TodoEntity, TodoPage, TodoSearchHit,
+        },
+        user::{test_utils::UserRepositoryForMemory, User},
     };
     use axum::{
         body::Body,
@@ -94,11 +201,23 @@ mod test {
     };
     use tower::ServiceExt;
 
+    /// テストのリクエストが使うセッションのトークン
+    const TOKEN: &str = "test-session";
+
+    fn logged_in() -> UserRepositoryForMemory {
+        UserRepositoryForMemory::with_session(TOKEN)
+    }
+
+    fn session_cookie() -> String {
+        format!("{}={}", crate::auth::SESSION_COOKIE, TOKEN)
+    }
+
     fn build_todo_req_with_json(path: &str, method: Method, json_body: String) -> Request<Body> {
         Request::builder()
             .uri(path)
             .method(method)
             .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+            .header(header::COOKIE, session_cookie())
             .body(Body::from(json_body))
             .unwrap()
     }
@@ -107,10 +226,19 @@ mod test {
         Request::builder()
             .uri(path)
             .method(method)
+            .header(header::COOKIE, session_cookie())
             .body(Body::empty())
             .unwrap()
     }
 
+    async fn res_to_label(res: Response) -> Label {
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body: String = String::from_utf8(bytes.to_vec()).unwrap();
+        let label: Label = serde_json::from_str(&body)
+            .unwrap_or_else(|_| panic!("cannot convert Label instance. body {}", body));
+        label
+    }
+
     async fn res_to_todo(res: Response) -> TodoEntity {
         let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
         let body: String = String::from_utf8(bytes.to_vec()).unwrap();
@@ -124,15 +252,436 @@ mod test {
         let todo_repository = TodoRepositoryForMemory::default();
         let label_repository = LabelRepositoryForMemory::default();
         let req = Request::builder().uri("/").body(Body::empty()).unwrap();
-        let res = create_app(todo_repository, label_repository)
-            .oneshot(req)
-            .await
-            .unwrap();
+        let res = create_app(
+            todo_repository,
+            label_repository,
+            logged_in(),
+            jwt::test_utils::verifier(),
+            CookieConfig::default(),
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
         let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
         let body = String::from_utf8(bytes.to_vec()).unwrap();
         assert_eq!(body, "Hello, World!");
     }
 
+    #[tokio::test]
+    async fn should_require_login() {
+        let app = create_app(
+            TodoRepositoryForMemory::default(),
+            LabelRepositoryForMemory::default(),
+            logged_in(),
+            jwt::test_utils::verifier(),
+            CookieConfig::default(),
+        );
+        for path in ["/todos", "/labels", "/auth/me"] {
+            let req = Request::builder().uri(path).body(Body::empty()).unwrap();
+            let res = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(StatusCode::UNAUTHORIZED, res.status(), "{}", path);
+        }
+
+        // 知らないトークンは使えない
+        let req = Request::builder()
+            .uri("/todos")
+            .header(header::COOKIE, "session=unknown")
+            .body(Body::empty())
+            .unwrap();
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_register_login_and_logout() {
+        let app = create_app(
+            TodoRepositoryForMemory::default(),
+            LabelRepositoryForMemory::default(),
+            UserRepositoryForMemory::default(),
+            jwt::test_utils::verifier(),
+            CookieConfig::default(),
+        );
+        let credentials = r#"{ "email": "Alice@Example.com", "password": "correct horse" }"#;
+        // 登録済みのメールアドレスでも、同じ応答を返す
+        for _ in 0..2 {
+            let req =
+                build_todo_req_with_json("/auth/register", Method::POST, credentials.to_string());
+            let res = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(StatusCode::ACCEPTED, res.status());
+            let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+            assert!(bytes.is_empty());
+        }
+
+        for body in [
+            r#"{ "email": "not an email", "password": "correct horse" }"#,
+            r#"{ "email": "bob@example.com", "password": "short" }"#,
+        ] {
+            let req = build_todo_req_with_json("/auth/register", Method::POST, body.to_string());
+            let res = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, res.status(), "{}", body);
+        }
+
+        for body in [
+            r#"{ "email": "alice@example.com", "password": "battery staple" }"#,
+            r#"{ "email": "bob@example.com", "password": "correct horse" }"#,
+        ] {
+            let req = build_todo_req_with_json("/auth/login", Method::POST, body.to_string());
+            let res = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(StatusCode::UNAUTHORIZED, res.status(), "{}", body);
+            assert!(res.headers().get(header::SET_COOKIE).is_none());
+        }
+
+        let req = build_todo_req_with_json("/auth/login", Method::POST, credentials.to_string());
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let set_cookie = res.headers()[header::SET_COOKIE].to_str().unwrap();
+        assert!(set_cookie.contains("HttpOnly"));
+        assert!(set_cookie.contains("; Secure"));
+        let cookie = set_cookie.split(';').next().unwrap().to_string();
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let user: User = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!("alice@example.com", user.email);
+
+        let with_cookie = |method: Method, path: &str| {
+            Request::builder()
+                .uri(path)
+                .method(method)
+                .header(header::COOKIE, cookie.as_str())
+                .body(Body::empty())
+                .unwrap()
+        };
+        let res = app
+            .clone()
+            .oneshot(with_cookie(Method::GET, "/auth/me"))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(user, serde_json::from_slice::<User>(&bytes).unwrap());
+        let res = app
+            .clone()
+            .oneshot(with_cookie(Method::GET, "/todos"))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+
+        let res = app
+            .clone()
+            .oneshot(with_cookie(Method::POST, "/auth/logout"))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::NO_CONTENT, res.status());
+        assert!(res.headers()[header::SET_COOKIE]
+            .to_str()
+            .unwrap()
+            .contains("Max-Age=0"));
+        let res = app
+            .oneshot(with_cookie(Method::GET, "/auth/me"))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_authenticate_with_jwt() {
+        use crate::jwt::test_utils::{claims, mint_hs256, mint_rs256};
+
+        let app = create_app(
+            TodoRepositoryForMemory::default(),
+            LabelRepositoryForMemory::default(),
+            logged_in(),
+            jwt::test_utils::verifier(),
+            CookieConfig::default(),
+        );
+        let with_bearer = |path: &str, token: &str| {
+            Request::builder()
+                .uri(path)
+                .header(header::AUTHORIZATION, format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        // Cookie がなくても、ユーザーの id を sub に持つトークンで使える
+        for token in [mint_hs256(&claims("1")), mint_rs256(&claims("1"))] {
+            let res = app
+                .clone()
+                .oneshot(with_bearer("/todos", &token))
+                .await
+                .unwrap();
+            assert_eq!(StatusCode::OK, res.status());
+            let res = app
+                .clone()
+                .oneshot(with_bearer("/auth/me", &token))
+                .await
+                .unwrap();
+            let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+            let user: User = serde_json::from_slice(&bytes).unwrap();
+            assert_eq!(1, user.id);
+        }
+
+        let now = chrono::Utc::now().timestamp();
+        let mut expired = claims("1");
+        expired["exp"] = (now - 3600).into();
+        let mut immature = claims("1");
+        immature["nbf"] = (now + 3600).into();
+        let mut other_audience = claims("1");
+        other_audience["aud"] = "other".into();
+        for token in [
+            mint_hs256(&expired),
+            mint_rs256(&immature),
+            mint_hs256(&other_audience),
+            "not a token".to_string(),
+        ] {
+            let res = app
+                .clone()
+                .oneshot(with_bearer("/todos", &token))
+                .await
+                .unwrap();
+            assert_eq!(StatusCode::UNAUTHORIZED, res.status(), "{}", token);
+            let challenge = res.headers()[header::WWW_AUTHENTICATE].to_str().unwrap();
+            assert!(
+                challenge.starts_with(r#"Bearer realm="my-todo", error="invalid_token""#),
+                "{}",
+                challenge
+            );
+        }
+
+        // 検証できても、いないユーザーのトークンは使えない
+        let res = app
+            .clone()
+            .oneshot(with_bearer("/todos", &mint_hs256(&claims("2"))))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, res.status());
+
+        let req = Request::builder()
+            .uri("/todos")
+            .body(Body::empty())
+            .unwrap();
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, res.status());
+        assert_eq!(
+            r#"Bearer realm="my-todo""#,
+            res.headers()[header::WWW_AUTHENTICATE]
+        );
+    }
+
+    #[tokio::test]
+    async fn should_limit_api_tokens_by_scope() {
+        let app = create_app(
+            TodoRepositoryForMemory::default(),
+            LabelRepositoryForMemory::default(),
+            logged_in(),
+            jwt::test_utils::verifier(),
+            CookieConfig::default(),
+        );
+        for body in [
+            r#"{ "name": "ci", "scopes": [] }"#,
+            r#"{ "name": "ci", "scopes": ["todos:delete"] }"#,
+        ] {
+            let req = build_todo_req_with_json("/tokens", Method::POST, body.to_string());
+            let res = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, res.status(), "{}", body);
+        }
+
+        let req = build_todo_req_with_json(
+            "/tokens",
+            Method::POST,
+            r#"{ "name": "ci", "scopes": ["todos:read"] }"#.to_string(),
+        );
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::CREATED, res.status());
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        let token = body["token"].as_str().unwrap().to_string();
+        assert!(token.starts_with("mt_"));
+        assert_eq!(token[..11], body["prefix"]);
+        assert_eq!(serde_json::json!(["todos:read"]), body["scopes"]);
+
+        let with_token = |method: Method, path: &str| {
+            Request::builder()
+                .uri(path)
+                .method(method)
+                .header(header::AUTHORIZATION, format!("Bearer {}", token))
+                .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                .body(Body::from(r#"{ "text": "todo", "labels": [] }"#))
+                .unwrap()
+        };
+        let res = app
+            .clone()
+            .oneshot(with_token(Method::GET, "/todos"))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+
+        // スコープのないルートと、スコープを宣言していないルートは 403
+        for (method, path) in [
+            (Method::POST, "/todos"),
+            (Method::GET, "/labels"),
+            (Method::GET, "/tokens"),
+        ] {
+            let res = app.clone().oneshot(with_token(method, path)).await.unwrap();
+            assert_eq!(StatusCode::FORBIDDEN, res.status(), "{}", path);
+        }
+        let res = app
+            .clone()
+            .oneshot(with_token(Method::POST, "/todos"))
+            .await
+            .unwrap();
+        assert_eq!(
+            r#"Bearer realm="my-todo", error="insufficient_scope", scope="todos:write""#,
+            res.headers()[header::WWW_AUTHENTICATE]
+        );
+
+        // 一覧ではトークンそのものを返さず、使った日時を返す
+        let req = build_todo_req_with_empty(Method::GET, "/tokens");
+        let res = app.clone().oneshot(req).await.unwrap();
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let tokens: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(1, tokens.as_array().unwrap().len());
+        assert!(tokens[0].get("token").is_none());
+        assert!(tokens[0]["last_used_at"].is_string());
+
+        let path = format!("/tokens/{}", tokens[0]["id"]);
+        let req = build_todo_req_with_empty(Method::DELETE, &path);
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::NO_CONTENT, res.status());
+        let res = app
+            .clone()
+            .oneshot(with_token(Method::GET, "/todos"))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::UNAUTHORIZED, res.status());
+
+        // Todo と一緒にラベルを作るときは、ラベルのスコープも要る
+        let req = build_todo_req_with_json(
+            "/tokens",
+            Method::POST,
+            r#"{ "name": "ci", "scopes": ["todos:write"] }"#.to_string(),
+        );
+        let res = app.clone().oneshot(req).await.unwrap();
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        let token = body["token"].as_str().unwrap().to_string();
+        for (json, status) in [
+            (r#"{ "text": "todo", "labels": [] }"#, StatusCode::CREATED),
+            (
+                r#"{ "text": "todo", "labels": [], "new_labels": [{ "name": "ci" }] }"#,
+                StatusCode::FORBIDDEN,
+            ),
+        ] {
+            let req = Request::builder()
+                .uri("/todos")
+                .method(Method::POST)
+                .header(header::AUTHORIZATION, format!("Bearer {}", token))
+                .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                .body(Body::from(json))
+                .unwrap();
+            let res = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(status, res.status(), "{}", json);
+        }
+    }
+
+    #[tokio::test]
+    async fn should_isolate_users() {
+        let store = MemoryStore::default();
+        let todo_repository = TodoRepositoryForMemory::with_store(store.clone());
+        let label_repository = LabelRepositoryForMemory::with_store(store);
+        let label = label_repository
+            .create(CreateLabel::new("label".to_string()))
+            .await
+            .unwrap();
+        todo_repository
+            .create(CreateTodo::new("mine".to_string(), vec![label.id]))
+            .await
+            .unwrap();
+        let app = create_app(
+            todo_repository,
+            label_repository,
+            logged_in(),
+            jwt::test_utils::verifier(),
+            CookieConfig::default(),
+        );
+
+        // 別のユーザーでログインする
+        let credentials = r#"{ "email": "bob@example.com", "password": "correct horse" }"#;
+        let req = build_todo_req_with_json("/auth/register", Method::POST, credentials.to_string());
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::ACCEPTED, res.status());
+        let req = build_todo_req_with_json("/auth/login", Method::POST, credentials.to_string());
+        let res = app.clone().oneshot(req).await.unwrap();
+        let set_cookie = res.headers()[header::SET_COOKIE].to_str().unwrap();
+        let cookie = set_cookie.split(';').next().unwrap().to_string();
+        let as_bob = |method: Method, path: &str, body: &str| {
+            Request::builder()
+                .uri(path)
+                .method(method)
+                .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                .header(header::COOKIE, cookie.as_str())
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+
+        for (method, path, body) in [
+            (Method::GET, "/todos/1", ""),
+            (Method::PATCH, "/todos/1", r#"{ "text": "stolen" }"#),
+            (Method::DELETE, "/todos/1", ""),
+            (Method::GET, "/todos/1/history", ""),
+            (Method::GET, "/labels/1", ""),
+            (Method::PATCH, "/labels/1", r#"{ "name": "stolen" }"#),
+            (Method::DELETE, "/labels/1", ""),
+        ] {
+            let res = app
+                .clone()
+                .oneshot(as_bob(method, path, body))
+                .await
+                .unwrap();
+            assert_eq!(StatusCode::NOT_FOUND, res.status(), "{}", path);
+        }
+        let res = app
+            .clone()
+            .oneshot(as_bob(Method::GET, "/todos", ""))
+            .await
+            .unwrap();
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let page: TodoPage = serde_json::from_slice(&bytes).unwrap();
+        assert!(page.items.is_empty());
+        let res = app
+            .clone()
+            .oneshot(as_bob(Method::GET, "/labels", ""))
+            .await
+            .unwrap();
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert!(serde_json::from_slice::<Vec<Label>>(&bytes)
+            .unwrap()
+            .is_empty());
+
+        // 他のユーザーのラベルは付けられないが、同じ名前のラベルは作れる
+        let body = r#"{ "text": "theirs", "labels": [1] }"#;
+        let res = app
+            .clone()
+            .oneshot(as_bob(Method::POST, "/todos", body))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, res.status());
+        let res = app
+            .clone()
+            .oneshot(as_bob(Method::POST, "/labels", r#"{ "name": "label" }"#))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::CREATED, res.status());
+        assert_ne!(label.id, res_to_label(res).await.id);
+
+        // 元のユーザーからは変わらずに見える
+        let res = app
+            .oneshot(build_todo_req_with_empty(Method::GET, "/todos/1"))
+            .await
+            .unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let todo = res_to_todo(res).await;
+        assert_eq!(("mine", vec![label]), (todo.text.as_str(), todo.labels));
+    }
+
     #[tokio::test]
     async fn should_crate_todo() {
         let expected = TodoEntity::new(1, "should_return_create_todo".to_string());
@@ -144,14 +693,175 @@ mod test {
             Method::POST,
             r#"{ "text": "should_return_create_todo", "labels": [] }"#.to_string(),
         );
-        let res = create_app(todo_repository, label_repository)
-            .oneshot(req)
-            .await
-            .unwrap();
+        let res = create_app(
+            todo_repository,
+            label_repository,
+            logged_in(),
+            jwt::test_utils::verifier(),
+            CookieConfig::default(),
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
         let todo = res_to_todo(res).await;
         assert_eq!(expected, todo);
     }
 
+    #[tokio::test]
+    async fn should_reject_invalid_todo() {
+        let todo_repository = TodoRepositoryForMemory::default();
+        let label_repository = LabelRepositoryForMemory::default();
+        let app = create_app(
+            todo_repository,
+            label_repository,
+            logged_in(),
+            jwt::test_utils::verifier(),
+            CookieConfig::default(),
+        );
+
+        let req = build_todo_req_with_json(
+            "/todos",
+            Method::POST,
+            r#"{ "text": "", "labels": [] }"#.to_string(),
+        );
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, res.status());
+        assert_eq!(
+            "application/problem+json",
+            res.headers().get(header::CONTENT_TYPE).unwrap()
+        );
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!("length", body["errors"]["text"][0]["code"]);
+
+        let req = build_todo_req_with_json(
+            "/todos",
+            Method::POST,
+            r#"{ "text": "todo", "labels": [99] }"#.to_string(),
+        );
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, res.status());
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(
+            serde_json::json!([99]),
+            body["errors"]["labels"][0]["params"]["ids"]
+        );
+
+        let req = build_todo_req_with_json(
+            "/todos",
+            Method::POST,
+            r#"{ "text": "todo", "labels": [ }"#.to_string(),
+        );
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::BAD_REQUEST, res.status());
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(1, body["line"]);
+
+        let req = Request::builder()
+            .uri("/todos")
+            .method(Method::POST)
+            .header(header::COOKIE, session_cookie())
+            .body(Body::from(r#"{ "text": "todo", "labels": [] }"#))
+            .unwrap();
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::UNSUPPORTED_MEDIA_TYPE, res.status());
+
+        let req = build_todo_req_with_empty(Method::GET, "/todos/1");
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_create_todo_with_new_labels() {
+        let store = MemoryStore::default();
+        let app = create_app(
+            TodoRepositoryForMemory::with_store(store.clone()),
+            LabelRepositoryForMemory::with_store(store),
+            logged_in(),
+            jwt::test_utils::verifier(),
+            CookieConfig::default(),
+        );
+        let count = |path: &'static str| {
+            let app = app.clone();
+            async move {
+                let req = build_todo_req_with_empty(Method::GET, path);
+                let res = app.oneshot(req).await.unwrap();
+                let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+                let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+                match body.get("items") {
+                    Some(items) => items.as_array().unwrap().len(),
+                    None => body.as_array().unwrap().len(),
+                }
+            }
+        };
+
+        let req = build_todo_req_with_json(
+            "/todos",
+            Method::POST,
+            r#"{ "text": "todo", "labels": [], "new_labels": [{ "name": "work" }] }"#.to_string(),
+        );
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::CREATED, res.status());
+        let todo = res_to_todo(res).await;
+        let names: Vec<&str> = todo
+            .labels
+            .iter()
+            .map(|label| label.name.as_str())
+            .collect();
+        assert_eq!(vec!["work"], names);
+
+        // ラベルか Todo のどちらかを作れなければ、どちらも作らない
+        for (json, status) in [
+            (
+                r#"{ "text": "todo", "labels": [], "new_labels": [{ "name": "home" }, { "name": "work" }] }"#,
+                StatusCode::CONFLICT,
+            ),
+            (
+                r#"{ "text": "todo", "labels": [99], "new_labels": [{ "name": "home" }] }"#,
+                StatusCode::UNPROCESSABLE_ENTITY,
+            ),
+        ] {
+            let req = build_todo_req_with_json("/todos", Method::POST, json.to_string());
+            let res = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(status, res.status(), "{}", json);
+        }
+        assert_eq!((1, 1), (count("/todos").await, count("/labels").await));
+
+        // 検証エラーの項目名は、Todo は CreateTodo と同じ、ラベルは配列の位置で返す
+        for (json, field) in [
+            (r#"{ "text": "", "labels": [] }"#, "text"),
+            (
+                r#"{ "text": "todo", "labels": [], "new_labels": [{ "name": "" }] }"#,
+                "new_labels[0].name",
+            ),
+        ] {
+            let req = build_todo_req_with_json("/todos", Method::POST, json.to_string());
+            let res = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, res.status());
+            let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+            assert!(body["errors"].get(field).is_some(), "{}", body);
+        }
+
+        // 同じ名前を一度に作ろうとすると、取り消されて存在しないラベルの id ではなく検証エラーを返す
+        let req = build_todo_req_with_json(
+            "/todos",
+            Method::POST,
+            r#"{ "text": "todo", "labels": [], "new_labels": [{ "name": "home" }, { "name": "home" }] }"#
+                .to_string(),
+        );
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, res.status());
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        let error = &body["errors"]["new_labels"][0];
+        assert_eq!("duplicate_name", error["code"], "{}", body);
+        assert_eq!("home", error["params"]["name"], "{}", body);
+        assert_eq!((1, 1), (count("/todos").await, count("/labels").await));
+    }
+
     #[tokio::test]
     async fn should_find_todo() {
         let expected = TodoEntity::new(1, "should_find_todo".to_string());
@@ -164,11 +874,22 @@ mod test {
             .await
             .expect("faild create todo");
         let req = build_todo_req_with_empty(Method::GET, "/todos/1");
-        let res = create_app(todo_repository, label_repository)
-            .oneshot(req)
-            .await
-            .unwrap();
-        let todo = res_to_todo(res).await;
+        let res = create_app(
+            todo_repository,
+            label_repository,
+            logged_in(),
+            jwt::test_utils::verifier(),
+            CookieConfig::default(),
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        // 日時は RFC 3339 で返す
+        assert_eq!("1970-01-01T00:00:00Z", body["created_at"]);
+        assert_eq!(serde_json::Value::Null, body["completed_at"]);
+        let todo: TodoEntity = serde_json::from_value(body).unwrap();
 
         assert_eq!(expected, todo);
     }
@@ -185,21 +906,366 @@ mod test {
             .await
             .expect("faild create todo");
         let req = build_todo_req_with_empty(Method::GET, "/todos");
-        let res = create_app(todo_repository, label_repository)
-            .oneshot(req)
-            .await
-            .unwrap();
+        let res = create_app(
+            todo_repository,
+            label_repository,
+            logged_in(),
+            jwt::test_utils::verifier(),
+            CookieConfig::default(),
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
         let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
         let body: String = String::from_utf8(bytes.to_vec()).unwrap();
-        let todos: Vec<TodoEntity> = serde_json::from_str(&body)
-            .unwrap_or_else(|_| panic!("cannot convert Todo instance. body {}", body));
+        let page: TodoPage = serde_json::from_str(&body)
+            .unwrap_or_else(|_| panic!("cannot convert TodoPage instance. body {}", body));
+
+        assert_eq!(vec![expected], page.items);
+        assert_eq!(None, page.next_cursor);
+        assert_eq!(1, page.total);
+    }
+
+    #[tokio::test]
+    async fn should_get_todos_by_cursor() {
+        let todo_repository = TodoRepositoryForMemory::default();
+        let label_repository = LabelRepositoryForMemory::default();
+        for text in ["todo 1", "todo 2", "todo 3"] {
+            todo_repository
+                .create(CreateTodo::new(text.to_string(), vec![]))
+                .await
+                .expect("faild create todo");
+        }
+        let req = build_todo_req_with_empty(Method::GET, "/todos?limit=2&after=3");
+        let res = create_app(
+            todo_repository,
+            label_repository,
+            logged_in(),
+            jwt::test_utils::verifier(),
+            CookieConfig::default(),
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body: String = String::from_utf8(bytes.to_vec()).unwrap();
+        let page: TodoPage = serde_json::from_str(&body)
+            .unwrap_or_else(|_| panic!("cannot convert TodoPage instance. body {}", body));
+
+        assert_eq!(
+            vec![
+                TodoEntity::new(2, "todo 2".to_string()),
+                TodoEntity::new(1, "todo 1".to_string()),
+            ],
+            page.items
+        );
+        assert_eq!(None, page.next_cursor);
+        assert_eq!(3, page.total);
+    }
+
+    #[tokio::test]
+    async fn should_get_todos_by_due() {
+        let todo_repository = TodoRepositoryForMemory::default();
+        let label_repository = LabelRepositoryForMemory::default();
+        let app = create_app(
+            todo_repository,
+            label_repository,
+            logged_in(),
+            jwt::test_utils::verifier(),
+            CookieConfig::default(),
+        );
+        // Memory 実装の現在日時は 1970-01-01T00:00:00Z (東京では 09:00)
+        for body in [
+            r#"{ "text": "past", "labels": [], "due_at": "1969-12-31" }"#,
+            r#"{ "text": "noon", "labels": [], "due_at": "1970-01-01T12:00:00+09:00" }"#,
+            r#"{ "text": "none", "labels": [] }"#,
+        ] {
+            let req = build_todo_req_with_json("/todos", Method::POST, body.to_string());
+            let res = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(StatusCode::CREATED, res.status());
+        }
+
+        let ids = |path: &'static str| {
+            let app = app.clone();
+            async move {
+                let req = build_todo_req_with_empty(Method::GET, path);
+                let res = app.oneshot(req).await.unwrap();
+                let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+                let page: TodoPage = serde_json::from_slice(&bytes).unwrap();
+                page.items.iter().map(|todo| todo.id).collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(vec![1], ids("/todos?due=overdue").await);
+        assert_eq!(vec![2], ids("/todos?due=today&tz=Asia/Tokyo").await);
+        assert_eq!(vec![2], ids("/todos?due=week&tz=Asia/Tokyo").await);
+
+        let req = build_todo_req_with_empty(Method::GET, "/todos/2");
+        let res = app.clone().oneshot(req).await.unwrap();
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!("1970-01-01T03:00:00Z", body["due_at"]);
+
+        // 変更でもオフセットは残さず、UTC で返す
+        let req = build_todo_req_with_json(
+            "/todos/2",
+            Method::PATCH,
+            r#"{ "due_at": "1970-01-01T08:00:00-05:00" }"#.to_string(),
+        );
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!("1970-01-01T13:00:00Z", body["due_at"]);
+
+        let req = build_todo_req_with_empty(Method::GET, "/todos?due=today&tz=Asia/Nowhere");
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, res.status());
+        let req = build_todo_req_with_json(
+            "/todos",
+            Method::POST,
+            r#"{ "text": "todo", "labels": [], "due_at": "1970-01-01T12:00:00" }"#.to_string(),
+        );
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_get_todos_sorted_by_priority() {
+        let todo_repository = TodoRepositoryForMemory::default();
+        let label_repository = LabelRepositoryForMemory::default();
+        let app = create_app(
+            todo_repository,
+            label_repository,
+            logged_in(),
+            jwt::test_utils::verifier(),
+            CookieConfig::default(),
+        );
+        for body in [
+            r#"{ "text": "someday", "labels": [] }"#,
+            r#"{ "text": "now", "labels": [], "priority": "urgent" }"#,
+            r#"{ "text": "soon", "labels": [], "priority": "medium" }"#,
+        ] {
+            let req = build_todo_req_with_json("/todos", Method::POST, body.to_string());
+            let res = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(StatusCode::CREATED, res.status());
+        }
+
+        let req = build_todo_req_with_empty(Method::GET, "/todos?sort=-priority,created_at");
+        let res = app.clone().oneshot(req).await.unwrap();
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let page: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        let priorities: Vec<&str> = page["items"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|todo| todo["priority"].as_str().unwrap())
+            .collect();
+        assert_eq!(vec!["urgent", "medium", "none"], priorities);
+
+        let req = build_todo_req_with_empty(Method::GET, "/todos?sort=title");
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, res.status());
+        let req = build_todo_req_with_json(
+            "/todos",
+            Method::POST,
+            r#"{ "text": "todo", "labels": [], "priority": "asap" }"#.to_string(),
+        );
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_move_todo() {
+        let todo_repository = TodoRepositoryForMemory::default();
+        let label_repository = LabelRepositoryForMemory::default();
+        for i in 1..=3 {
+            todo_repository
+                .create(CreateTodo::new(format!("todo {}", i), vec![]))
+                .await
+                .expect("faild create todo");
+        }
+        let app = create_app(
+            todo_repository,
+            label_repository,
+            logged_in(),
+            jwt::test_utils::verifier(),
+            CookieConfig::default(),
+        );
+
+        let req = build_todo_req_with_json(
+            "/todos/3/move",
+            Method::POST,
+            r#"{ "before": 1 }"#.to_string(),
+        );
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!(3, res_to_todo(res).await.id);
+
+        let req = build_todo_req_with_empty(Method::GET, "/todos?sort=position");
+        let res = app.clone().oneshot(req).await.unwrap();
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let page: TodoPage = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(
+            vec![3, 1, 2],
+            page.items.iter().map(|todo| todo.id).collect::<Vec<_>>()
+        );
+
+        for (body, status) in [
+            ("{}", StatusCode::UNPROCESSABLE_ENTITY),
+            (
+                r#"{ "before": 1, "after": 2 }"#,
+                StatusCode::UNPROCESSABLE_ENTITY,
+            ),
+            (r#"{ "after": 99 }"#, StatusCode::NOT_FOUND),
+        ] {
+            let req = build_todo_req_with_json("/todos/3/move", Method::POST, body.to_string());
+            let res = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(status, res.status(), "{}", body);
+        }
+    }
+
+    #[tokio::test]
+    async fn should_get_todo_tree() {
+        let todo_repository = TodoRepositoryForMemory::default();
+        let label_repository = LabelRepositoryForMemory::default();
+        let app = create_app(
+            todo_repository,
+            label_repository,
+            logged_in(),
+            jwt::test_utils::verifier(),
+            CookieConfig::default(),
+        );
+        for body in [
+            r#"{ "text": "project", "labels": [] }"#,
+            r#"{ "text": "design", "labels": [], "parent_id": 1 }"#,
+            r#"{ "text": "draw", "labels": [], "parent_id": 2 }"#,
+        ] {
+            let req = build_todo_req_with_json("/todos", Method::POST, body.to_string());
+            let res = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(StatusCode::CREATED, res.status());
+        }
+
+        let req = build_todo_req_with_empty(Method::GET, "/todos/1/children");
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let children: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(2, children[0]["id"]);
+        assert_eq!(3, children[0]["children"][0]["id"]);
+
+        let req = build_todo_req_with_empty(Method::GET, "/todos?tree=true");
+        let res = app.clone().oneshot(req).await.unwrap();
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let page: TodoPage = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(1, page.items.len());
+        assert_eq!(3, page.items[0].children[0].children[0].id);
+
+        let req = build_todo_req_with_json(
+            "/todos/1",
+            Method::PATCH,
+            r#"{ "parent_id": 3 }"#.to_string(),
+        );
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, res.status());
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!("cyclic_parent", body["errors"]["parent_id"][0]["code"]);
+
+        let req = build_todo_req_with_empty(Method::GET, "/todos/9/children");
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_manage_dependencies() {
+        let todo_repository = TodoRepositoryForMemory::default();
+        let label_repository = LabelRepositoryForMemory::default();
+        let app = create_app(
+            todo_repository,
+            label_repository,
+            logged_in(),
+            jwt::test_utils::verifier(),
+            CookieConfig::default(),
+        );
+        for text in ["design", "implement"] {
+            let body = format!(r#"{{ "text": "{}", "labels": [] }}"#, text);
+            let req = build_todo_req_with_json("/todos", Method::POST, body);
+            let res = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(StatusCode::CREATED, res.status());
+        }
+
+        let req = build_todo_req_with_empty(Method::POST, "/todos/2/dependencies/1");
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!(r#""2""#, res.headers().get(ETAG).unwrap());
+        let todo = res_to_todo(res).await;
+        assert!(todo.blocked);
+
+        let req = build_todo_req_with_empty(Method::POST, "/todos/1/dependencies/2");
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::CONFLICT, res.status());
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(serde_json::json!([1, 2, 1]), body["cycle"]);
+
+        let req = build_todo_req_with_empty(Method::GET, "/todos/graph.dot");
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!(
+            "text/vnd.graphviz; charset=utf-8",
+            res.headers().get(CONTENT_TYPE).unwrap()
+        );
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let dot = String::from_utf8(bytes.to_vec()).unwrap();
+        assert!(dot.starts_with("digraph todos {"));
+        assert!(dot.contains("    1 -> 2;\n"));
+
+        let req = build_todo_req_with_empty(Method::DELETE, "/todos/2/dependencies/1");
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        assert!(!res_to_todo(res).await.blocked);
+        let req = build_todo_req_with_empty(Method::DELETE, "/todos/2/dependencies/1");
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_search_todos() {
+        let todo_repository = TodoRepositoryForMemory::default();
+        let label_repository = LabelRepositoryForMemory::default();
+        for text in ["buy milk", "write report"] {
+            todo_repository
+                .create(CreateTodo::new(text.to_string(), vec![]))
+                .await
+                .expect("faild create todo");
+        }
+        let req = build_todo_req_with_empty(Method::GET, "/todos/search?q=milk");
+        let res = create_app(
+            todo_repository,
+            label_repository,
+            logged_in(),
+            jwt::test_utils::verifier(),
+            CookieConfig::default(),
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body: String = String::from_utf8(bytes.to_vec()).unwrap();
+        let hits: Vec<TodoSearchHit> = serde_json::from_str(&body)
+            .unwrap_or_else(|_| panic!("cannot convert TodoSearchHit instance. body {}", body));
 
-        assert_eq!(vec![expected], todos);
+        assert_eq!(1, hits.len());
+        assert_eq!(TodoEntity::new(1, "buy milk".to_string()), hits[0].todo);
+        assert_eq!("buy <b>milk</b>", hits[0].snippet);
     }
 
     #[tokio::test]
     async fn should_update_todo() {
-        let expected = TodoEntity::new(1, "should_update_todo".to_string());
+        let expected = TodoEntity {
+            version: 2,
+            ..TodoEntity::new(1, "should_update_todo".to_string())
+        };
 
         let labels = vec![];
         let todo_repository = TodoRepositoryForMemory::default();
@@ -219,15 +1285,174 @@ mod test {
             "#
             .to_string(),
         );
-        let res = create_app(todo_repository, label_repository)
-            .oneshot(req)
-            .await
-            .unwrap();
+        let res = create_app(
+            todo_repository,
+            label_repository,
+            logged_in(),
+            jwt::test_utils::verifier(),
+            CookieConfig::default(),
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
         let todo = res_to_todo(res).await;
 
         assert_eq!(expected, todo);
     }
 
+    #[tokio::test]
+    async fn should_update_todo_only_if_match() {
+        let todo_repository = TodoRepositoryForMemory::default();
+        let label_repository = LabelRepositoryForMemory::default();
+        todo_repository
+            .create(CreateTodo::new("before_update_todo".to_string(), vec![]))
+            .await
+            .expect("faild create todo");
+        let app = create_app(
+            todo_repository,
+            label_repository,
+            logged_in(),
+            jwt::test_utils::verifier(),
+            CookieConfig::default(),
+        );
+
+        // find
+        let req = build_todo_req_with_empty(Method::GET, "/todos/1");
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(r#""1""#, res.headers()[header::ETAG]);
+
+        // update (If-Match が一致)
+        let mut req = build_todo_req_with_json(
+            "/todos/1",
+            Method::PATCH,
+            r#"{ "text": "should_update_todo" }"#.to_string(),
+        );
+        req.headers_mut()
+            .insert(header::IF_MATCH, r#""1""#.parse().unwrap());
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!(r#""2""#, res.headers()[header::ETAG]);
+
+        // update (古い version)
+        let mut req = build_todo_req_with_json(
+            "/todos/1",
+            Method::PATCH,
+            r#"{ "text": "stale" }"#.to_string(),
+        );
+        req.headers_mut()
+            .insert(header::IF_MATCH, r#""1""#.parse().unwrap());
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::PRECONDITION_FAILED, res.status());
+        assert_eq!(r#""2""#, res.headers()[header::ETAG]);
+        let todo = res_to_todo(res).await;
+        assert_eq!("should_update_todo", todo.text);
+        assert_eq!(2, todo.version);
+
+        // delete (古い version)
+        let mut req = build_todo_req_with_empty(Method::DELETE, "/todos/1");
+        req.headers_mut()
+            .insert(header::IF_MATCH, r#""1""#.parse().unwrap());
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::PRECONDITION_FAILED, res.status());
+
+        // delete
+        let mut req = build_todo_req_with_empty(Method::DELETE, "/todos/1");
+        req.headers_mut()
+            .insert(header::IF_MATCH, r#""2""#.parse().unwrap());
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::NO_CONTENT, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_return_not_modified_for_unchanged_todos() {
+        let todo_repository = TodoRepositoryForMemory::default();
+        let label_repository = LabelRepositoryForMemory::default();
+        todo_repository
+            .create(CreateTodo::new("should_get_todos".to_string(), vec![]))
+            .await
+            .expect("faild create todo");
+        let app = create_app(
+            todo_repository.clone(),
+            label_repository,
+            logged_in(),
+            jwt::test_utils::verifier(),
+            CookieConfig::default(),
+        );
+
+        for path in ["/todos", "/todos/1", "/labels"] {
+            let req = build_todo_req_with_empty(Method::GET, path);
+            let res = app.clone().oneshot(req).await.unwrap();
+            let etag = res.headers()[header::ETAG].clone();
+
+            let mut req = build_todo_req_with_empty(Method::GET, path);
+            req.headers_mut().insert(header::IF_NONE_MATCH, etag);
+            let res = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(StatusCode::NOT_MODIFIED, res.status(), "{}", path);
+        }
+
+        // 変更があれば ETag が変わる
+        let req = build_todo_req_with_empty(Method::GET, "/todos");
+        let etag = app.clone().oneshot(req).await.unwrap().headers()[header::ETAG].clone();
+        todo_repository
+            .create(CreateTodo::new("should_get_todos 2".to_string(), vec![]))
+            .await
+            .expect("faild create todo");
+        let mut req = build_todo_req_with_empty(Method::GET, "/todos");
+        req.headers_mut().insert(header::IF_NONE_MATCH, etag);
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let page: TodoPage = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(2, page.items.len());
+    }
+
+    #[tokio::test]
+    async fn should_change_etag_of_children_when_parent_is_purged() {
+        let todo_repository = TodoRepositoryForMemory::default();
+        let label_repository = LabelRepositoryForMemory::default();
+        let app = create_app(
+            todo_repository,
+            label_repository,
+            logged_in(),
+            jwt::test_utils::verifier(),
+            CookieConfig::default(),
+        );
+        for body in [
+            r#"{ "text": "parent", "labels": [] }"#,
+            r#"{ "text": "child", "labels": [], "parent_id": 1 }"#,
+        ] {
+            let req = build_todo_req_with_json("/todos", Method::POST, body.to_string());
+            let res = app.clone().oneshot(req).await.unwrap();
+            assert_eq!(StatusCode::CREATED, res.status());
+        }
+        let req = build_todo_req_with_empty(Method::GET, "/todos/2");
+        let etag = app.clone().oneshot(req).await.unwrap().headers()[header::ETAG].clone();
+
+        let req = build_todo_req_with_empty(Method::DELETE, "/todos/1?purge=true");
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::NO_CONTENT, res.status());
+
+        // 親が外れた子を、古い ETag で 304 にしない
+        let mut req = build_todo_req_with_empty(Method::GET, "/todos/2");
+        req.headers_mut()
+            .insert(header::IF_NONE_MATCH, etag.clone());
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        assert_ne!(etag, res.headers()[header::ETAG]);
+        let todo = res_to_todo(res).await;
+        assert_eq!(None, todo.parent_id);
+
+        // 古い version での変更も受け付けない
+        let mut req = build_todo_req_with_json(
+            "/todos/2",
+            Method::PATCH,
+            r#"{ "text": "stale" }"#.to_string(),
+        );
+        req.headers_mut().insert(header::IF_MATCH, etag);
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::PRECONDITION_FAILED, res.status());
+    }
+
     #[tokio::test]
     async fn should_delete_todo() {
         let labels = vec![];
@@ -238,11 +1463,265 @@ mod test {
             .await
             .expect("faild create todo");
         let req = build_todo_req_with_empty(Method::DELETE, "/todos/1");
-        let res = create_app(todo_repository, label_repository)
-            .oneshot(req)
+        let res = create_app(
+            todo_repository,
+            label_repository,
+            logged_in(),
+            jwt::test_utils::verifier(),
+            CookieConfig::default(),
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+
+        assert_eq!(StatusCode::NO_CONTENT, res.status());
+    }
+    #[tokio::test]
+    async fn should_restore_and_purge_todo() {
+        let todo_repository = TodoRepositoryForMemory::default();
+        let label_repository = LabelRepositoryForMemory::default();
+        todo_repository
+            .create(CreateTodo::new("should_restore_todo".to_string(), vec![]))
             .await
-            .unwrap();
+            .expect("faild create todo");
+        let app = create_app(
+            todo_repository,
+            label_repository,
+            logged_in(),
+            jwt::test_utils::verifier(),
+            CookieConfig::default(),
+        );
 
+        let req = build_todo_req_with_empty(Method::DELETE, "/todos/1");
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::NO_CONTENT, res.status());
+        let req = build_todo_req_with_empty(Method::GET, "/todos/1");
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, res.status());
+
+        // trash
+        let req = build_todo_req_with_empty(Method::GET, "/todos/trash");
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let page: TodoPage = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(vec![1], page.items.iter().map(|t| t.id).collect::<Vec<_>>());
+
+        // restore
+        let req = build_todo_req_with_empty(Method::POST, "/todos/1/restore");
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!(r#""3""#, res.headers()[header::ETAG]);
+        let todo = res_to_todo(res).await;
+        assert_eq!(None, todo.deleted_at);
+        let req = build_todo_req_with_empty(Method::POST, "/todos/1/restore");
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, res.status());
+
+        // purge
+        let req = build_todo_req_with_empty(Method::DELETE, "/todos/1?purge=true");
+        let res = app.clone().oneshot(req).await.unwrap();
         assert_eq!(StatusCode::NO_CONTENT, res.status());
+        let req = build_todo_req_with_empty(Method::POST, "/todos/1/restore");
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_get_todo_history() {
+        let todo_repository = TodoRepositoryForMemory::default();
+        let label_repository = LabelRepositoryForMemory::default();
+        let app = create_app(
+            todo_repository,
+            label_repository,
+            logged_in(),
+            jwt::test_utils::verifier(),
+            CookieConfig::default(),
+        );
+
+        let req = build_todo_req_with_json(
+            "/todos",
+            Method::POST,
+            r#"{ "text": "before", "labels": [] }"#.to_string(),
+        );
+        app.clone().oneshot(req).await.unwrap();
+        let req = build_todo_req_with_json(
+            "/todos/1",
+            Method::PATCH,
+            r#"{ "text": "after" }"#.to_string(),
+        );
+        app.clone().oneshot(req).await.unwrap();
+        let req = build_todo_req_with_empty(Method::DELETE, "/todos/1?purge=true");
+        app.clone().oneshot(req).await.unwrap();
+
+        // 完全に削除した後も履歴は残る
+        let req = build_todo_req_with_empty(Method::GET, "/todos/1/history");
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::OK, res.status());
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let events: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(
+            serde_json::json!(["created", "updated", "purged"]),
+            serde_json::json!(events
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|event| &event["kind"])
+                .collect::<Vec<_>>())
+        );
+        assert_eq!(
+            serde_json::json!({ "text": { "before": "before", "after": "after" } }),
+            events[1]["changes"]
+        );
+        // 変更したのはログインしているユーザー
+        assert_eq!(serde_json::json!(1), events[1]["actor_id"]);
+
+        let req = build_todo_req_with_empty(Method::GET, "/todos/2/history");
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_find_label() {
+        let todo_repository = TodoRepositoryForMemory::default();
+        let label_repository = LabelRepositoryForMemory::default();
+        let expected = label_repository
+            .create(CreateLabel::new("should_find_label".to_string()))
+            .await
+            .expect("failed create label");
+        let req = build_todo_req_with_empty(Method::GET, "/labels/1");
+        let res = create_app(
+            todo_repository,
+            label_repository,
+            logged_in(),
+            jwt::test_utils::verifier(),
+            CookieConfig::default(),
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!(expected, res_to_label(res).await);
+    }
+
+    #[tokio::test]
+    async fn should_update_label() {
+        let todo_repository = TodoRepositoryForMemory::default();
+        let label_repository = LabelRepositoryForMemory::default();
+        label_repository
+            .create(CreateLabel::new("before_update_label".to_string()))
+            .await
+            .expect("failed create label");
+        let req = build_todo_req_with_json(
+            "/labels/1",
+            Method::PATCH,
+            r##"{ "name": "should_update_label", "color": "#336699" }"##.to_string(),
+        );
+        let res = create_app(
+            todo_repository,
+            label_repository,
+            logged_in(),
+            jwt::test_utils::verifier(),
+            CookieConfig::default(),
+        )
+        .oneshot(req)
+        .await
+        .unwrap();
+
+        assert_eq!(StatusCode::OK, res.status());
+        assert_eq!(
+            Label {
+                id: 1,
+                name: "should_update_label".to_string(),
+                color: Some("#336699".to_string()),
+                description: None,
+                created_at: Default::default(),
+            },
+            res_to_label(res).await
+        );
+    }
+
+    #[tokio::test]
+    async fn should_reject_invalid_label_update() {
+        let todo_repository = TodoRepositoryForMemory::default();
+        let label_repository = LabelRepositoryForMemory::default();
+        for name in ["label 1", "label 2"] {
+            label_repository
+                .create(CreateLabel::new(name.to_string()))
+                .await
+                .expect("failed create label");
+        }
+        let app = create_app(
+            todo_repository,
+            label_repository,
+            logged_in(),
+            jwt::test_utils::verifier(),
+            CookieConfig::default(),
+        );
+
+        let req = build_todo_req_with_json(
+            "/labels/1",
+            Method::PATCH,
+            r#"{ "color": "blue" }"#.to_string(),
+        );
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY, res.status());
+
+        let req = build_todo_req_with_json(
+            "/labels/1",
+            Method::PATCH,
+            r#"{ "name": "label 2" }"#.to_string(),
+        );
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::CONFLICT, res.status());
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(2, body["id"]);
+
+        let req = build_todo_req_with_json(
+            "/labels/99",
+            Method::PATCH,
+            r#"{ "name": "label 99" }"#.to_string(),
+        );
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::NOT_FOUND, res.status());
+    }
+
+    #[tokio::test]
+    async fn should_delete_label_in_use_only_with_force() {
+        let store = MemoryStore::default();
+        let todo_repository = TodoRepositoryForMemory::with_store(store.clone());
+        let label_repository = LabelRepositoryForMemory::with_store(store);
+        let label = label_repository
+            .create(CreateLabel::new("label".to_string()))
+            .await
+            .expect("failed create label");
+        todo_repository
+            .create(CreateTodo::new("todo".to_string(), vec![label.id]))
+            .await
+            .expect("failed create todo");
+        let app = create_app(
+            todo_repository,
+            label_repository,
+            logged_in(),
+            jwt::test_utils::verifier(),
+            CookieConfig::default(),
+        );
+
+        let req = build_todo_req_with_empty(Method::DELETE, "/labels/1");
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::CONFLICT, res.status());
+        let bytes = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        assert_eq!(serde_json::json!([1]), body["todos"]);
+
+        let req = build_todo_req_with_empty(Method::DELETE, "/labels/1?force=true");
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert_eq!(StatusCode::NO_CONTENT, res.status());
+
+        let req = build_todo_req_with_empty(Method::GET, "/todos/1");
+        let res = app.oneshot(req).await.unwrap();
+        assert!(res_to_todo(res).await.labels.is_empty());
     }
 }
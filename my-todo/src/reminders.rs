@@ -0,0 +1,410 @@
+pub mod sink;
+
+use crate::repositories::{
+    reminder::{Reminder, ReminderRepository},
+    RepositoryError,
+};
+use axum::async_trait;
+use chrono::{DateTime, Utc};
+use std::{env, sync::Arc, time::Duration};
+use thiserror::Error;
+use tokio::task::JoinHandle;
+
+pub use sink::{LogSink, SmtpSink, WebhookSink};
+
+/// 1 回の tick で送るリマインダーの上限
+const BATCH_SIZE: i64 = 100;
+const DEFAULT_INTERVAL_SECS: u64 = 60;
+/// 1 件の送信を待つ上限の既定値
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+/// 送信に失敗したとき、次に試みるまでの間隔。失敗するたびに倍にし、上限で止める
+const BACKOFF_BASE_SECS: i64 = 60;
+const BACKOFF_MAX_SECS: i64 = 60 * 60;
+
+/// スケジューラが使う時計。テストでは実時間を待たずに進められるものに差し替える
+#[async_trait]
+pub trait Clock: Send + Sync + 'static {
+    fn now(&self) -> DateTime<Utc>;
+    async fn sleep(&self, duration: Duration);
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+#[async_trait]
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    async fn sleep(&self, duration: Duration) {
+        tokio::time::sleep(duration).await
+    }
+}
+
+/// リマインダーの送り先
+#[async_trait]
+pub trait ReminderSink: Send + Sync + 'static {
+    async fn deliver(&self, reminder: &Reminder) -> Result<(), ReminderError>;
+}
+
+#[derive(Debug, Error)]
+pub enum ReminderError {
+    #[error("Webhook Error: [{0}]")]
+    Webhook(String),
+    #[error("Smtp Error: [{0}]")]
+    Smtp(String),
+    #[error("Timeout: [{0:?}]")]
+    Timeout(Duration),
+}
+
+/// 期限の来たリマインダーを定期的に送るバックグラウンドのタスク
+pub struct ReminderScheduler<R: ReminderRepository> {
+    repository: R,
+    sink: Arc<dyn ReminderSink>,
+    clock: Arc<dyn Clock>,
+    interval: Duration,
+    timeout: Duration,
+}
+
+impl<R: ReminderRepository> ReminderScheduler<R> {
+    pub fn new(
+        repository: R,
+        sink: Arc<dyn ReminderSink>,
+        clock: Arc<dyn Clock>,
+        interval: Duration,
+    ) -> Self {
+        ReminderScheduler {
+            repository,
+            sink,
+            clock,
+            interval,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// 期限の来たリマインダーを送り、送信済みにした件数を返す
+    /// 送信に失敗したものは送信済みにせず、backoff の分だけ待ってから送り直す
+    pub async fn tick(&self) -> Result<usize, RepositoryError> {
+        // 送信を待つ間に他のスケジューラが送り直さないよう、1 件ずつ取り出す
+        // 取り出したまま落ちた場合は、timeout の倍が過ぎれば送り直す
+        let lease = chrono::Duration::from_std(self.timeout * 2).unwrap();
+        let mut delivered = 0;
+        for _ in 0..BATCH_SIZE {
+            let now = self.clock.now();
+            let Some(reminder) = self.repository.claim(now, now + lease, 1).await?.pop() else {
+                break;
+            };
+            let res = tokio::time::timeout(self.timeout, self.sink.deliver(&reminder))
+                .await
+                .unwrap_or(Err(ReminderError::Timeout(self.timeout)));
+            if let Err(e) = res {
+                tracing::warn!(
+                    "failed to deliver reminder of todo {} (attempt {}): {}",
+                    reminder.todo_id,
+                    reminder.attempts,
+                    e
+                );
+                self.repository
+                    .defer(&reminder, self.clock.now() + backoff(reminder.attempts))
+                    .await?;
+                continue;
+            }
+            if self
+                .repository
+                .mark_delivered(&reminder, self.clock.now())
+                .await?
+            {
+                delivered += 1;
+            }
+        }
+        Ok(delivered)
+    }
+
+    pub fn spawn(self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                match self.tick().await {
+                    Ok(0) => {}
+                    Ok(delivered) => tracing::debug!("delivered {} reminders", delivered),
+                    Err(e) => tracing::error!("failed to scan reminders: {}", e),
+                }
+                self.clock.sleep(self.interval).await;
+            }
+        })
+    }
+}
+
+/// attempts 回目の送信に失敗したあと、次に試みるまでの間隔
+fn backoff(attempts: i32) -> chrono::Duration {
+    let exp = attempts.saturating_sub(1).clamp(0, 16) as u32;
+    chrono::Duration::seconds((BACKOFF_BASE_SECS << exp).min(BACKOFF_MAX_SECS))
+}
+
+/// `REMINDER_SINK` (log / webhook / smtp) に応じた送り先を作る
+///
+/// - webhook: `REMINDER_WEBHOOK_URL` に JSON を POST する。全ユーザーのリマインダーが
+///   同じ URL に届くため、owner_email で振り分ける自前の中継先だけを指定する
+/// - smtp: `REMINDER_SMTP_HOST` (`REMINDER_SMTP_PORT`, 既定は 25) から
+///   `REMINDER_MAIL_FROM` を差出人に、Todo の所有者のメールアドレス宛てに送る
+pub fn sink_from_env() -> anyhow::Result<Arc<dyn ReminderSink>> {
+    let sink: Arc<dyn ReminderSink> = match env::var("REMINDER_SINK").as_deref() {
+        Err(_) | Ok("log") => Arc::new(LogSink),
+        Ok("webhook") => Arc::new(WebhookSink::new(env::var("REMINDER_WEBHOOK_URL")?.parse()?)),
+        Ok("smtp") => Arc::new(SmtpSink::new(
+            &env::var("REMINDER_SMTP_HOST")?,
+            env::var("REMINDER_SMTP_PORT").map_or(Ok(25), |port| port.parse())?,
+            env::var("REMINDER_MAIL_FROM")?.parse()?,
+        )),
+        Ok(sink) => anyhow::bail!("REMINDER_SINK must be log, webhook or smtp: {}", sink),
+    };
+    Ok(sink)
+}
+
+/// `REMINDER_INTERVAL_SECS` で指定する。既定は 60 秒
+pub fn interval_from_env() -> anyhow::Result<Duration> {
+    let secs = env::var("REMINDER_INTERVAL_SECS")
+        .map_or(Ok(DEFAULT_INTERVAL_SECS), |secs| secs.parse())?;
+    Ok(Duration::from_secs(secs))
+}
+
+/// `REMINDER_TIMEOUT_SECS` で指定する。既定は 30 秒
+pub fn timeout_from_env() -> anyhow::Result<Duration> {
+    env::var("REMINDER_TIMEOUT_SECS").map_or(Ok(DEFAULT_TIMEOUT), |secs| {
+        Ok(Duration::from_secs(secs.parse()?))
+    })
+}
+
+#[cfg(test)]
+pub mod test_utils {
+    use super::*;
+    use std::sync::Mutex;
+    use tokio::sync::mpsc;
+
+    /// sleep すると、その分だけ即座に進む時計
+    #[derive(Debug)]
+    pub struct ManualClock(Mutex<DateTime<Utc>>);
+
+    impl ManualClock {
+        pub fn new(now: DateTime<Utc>) -> Self {
+            ManualClock(Mutex::new(now))
+        }
+
+        pub fn set(&self, now: DateTime<Utc>) {
+            *self.0.lock().unwrap() = now;
+        }
+    }
+
+    #[async_trait]
+    impl Clock for ManualClock {
+        fn now(&self) -> DateTime<Utc> {
+            *self.0.lock().unwrap()
+        }
+
+        async fn sleep(&self, duration: Duration) {
+            *self.0.lock().unwrap() += chrono::Duration::from_std(duration).unwrap();
+            tokio::task::yield_now().await;
+        }
+    }
+
+    /// 受け取ったリマインダーをチャンネルに流す。fail が true の間は送信に失敗する
+    #[derive(Debug)]
+    pub struct ChannelSink {
+        tx: mpsc::UnboundedSender<Reminder>,
+        pub fail: Mutex<bool>,
+    }
+
+    impl ChannelSink {
+        pub fn new() -> (Self, mpsc::UnboundedReceiver<Reminder>) {
+            let (tx, rx) = mpsc::unbounded_channel();
+            let sink = ChannelSink {
+                tx,
+                fail: Mutex::new(false),
+            };
+            (sink, rx)
+        }
+    }
+
+    #[async_trait]
+    impl ReminderSink for ChannelSink {
+        async fn deliver(&self, reminder: &Reminder) -> Result<(), ReminderError> {
+            if *self.fail.lock().unwrap() {
+                return Err(ReminderError::Webhook("unavailable".to_string()));
+            }
+            self.tx.send(reminder.clone()).unwrap();
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{test_utils::*, *};
+    use crate::repositories::{
+        reminder::test_utils::ReminderRepositoryForMemory,
+        test_utils::{MemoryStore, DEFAULT_OWNER},
+        todo::{test_utils::TodoRepositoryForMemory, CreateTodo, TodoRepository, UpdateTodo},
+        user::User,
+    };
+    use chrono::TimeZone;
+
+    fn at(hour: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 10, 1, hour, 0, 0).unwrap()
+    }
+
+    async fn setup(
+        remind_at: &[u32],
+    ) -> (
+        TodoRepositoryForMemory,
+        ReminderRepositoryForMemory,
+        Arc<ManualClock>,
+    ) {
+        let store = MemoryStore::default();
+        {
+            let mut tables = store.write();
+            let user = User {
+                id: DEFAULT_OWNER,
+                email: "me@example.com".to_string(),
+                created_at: tables.now,
+            };
+            tables.users.insert(user.id, (user, String::new()));
+        }
+        let todo_repository = TodoRepositoryForMemory::with_store(store.clone());
+        for hour in remind_at {
+            todo_repository
+                .create(
+                    CreateTodo::new(format!("remind at {}", hour), vec![])
+                        .with_remind_at(at(*hour)),
+                )
+                .await
+                .unwrap();
+        }
+        (
+            todo_repository,
+            ReminderRepositoryForMemory::with_store(store),
+            Arc::new(ManualClock::new(at(0))),
+        )
+    }
+
+    #[tokio::test]
+    async fn should_deliver_due_reminders_once() {
+        let (todo_repository, repository, clock) = setup(&[1, 3]).await;
+        let (sink, mut rx) = ChannelSink::new();
+        let scheduler =
+            ReminderScheduler::new(repository, Arc::new(sink), clock.clone(), Duration::ZERO);
+
+        assert_eq!(0, scheduler.tick().await.unwrap());
+
+        clock.set(at(2));
+        assert_eq!(1, scheduler.tick().await.unwrap());
+        let reminder = rx.recv().await.unwrap();
+        assert_eq!(
+            (1, "me@example.com"),
+            (reminder.todo_id, reminder.owner_email.as_str())
+        );
+        assert_eq!(0, scheduler.tick().await.unwrap());
+        let todo = todo_repository.find(1).await.unwrap();
+        assert_eq!(Some(at(2)), todo.reminded_at);
+
+        // 完了した Todo には送らない
+        let payload: UpdateTodo = serde_json::from_str(r#"{"completed": true}"#).unwrap();
+        todo_repository.update(2, payload, None).await.unwrap();
+        clock.set(at(4));
+        assert_eq!(0, scheduler.tick().await.unwrap());
+
+        // remind_at を変えると、もう一度送る
+        let payload: UpdateTodo =
+            serde_json::from_str(r#"{"remind_at": "2024-10-01T03:00:00Z"}"#).unwrap();
+        let todo = todo_repository.update(1, payload, None).await.unwrap();
+        assert_eq!(None, todo.reminded_at);
+        assert_eq!(1, scheduler.tick().await.unwrap());
+        assert_eq!(at(3), rx.recv().await.unwrap().remind_at);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn should_retry_failed_delivery() {
+        let (_, repository, clock) = setup(&[1]).await;
+        let (sink, mut rx) = ChannelSink::new();
+        let sink = Arc::new(sink);
+        let scheduler =
+            ReminderScheduler::new(repository, sink.clone(), clock.clone(), Duration::ZERO);
+        clock.set(at(2));
+
+        *sink.fail.lock().unwrap() = true;
+        assert_eq!(0, scheduler.tick().await.unwrap());
+        *sink.fail.lock().unwrap() = false;
+        // backoff の間は送り直さない
+        assert_eq!(0, scheduler.tick().await.unwrap());
+        clock.set(at(2) + backoff(1));
+        assert_eq!(1, scheduler.tick().await.unwrap());
+        let reminder = rx.recv().await.unwrap();
+        assert_eq!((1, 2), (reminder.todo_id, reminder.attempts));
+    }
+
+    /// 応答を返さない送り先
+    struct HangingSink;
+
+    #[async_trait]
+    impl ReminderSink for HangingSink {
+        async fn deliver(&self, _: &Reminder) -> Result<(), ReminderError> {
+            std::future::pending().await
+        }
+    }
+
+    #[tokio::test]
+    async fn should_give_up_hanging_delivery() {
+        let (todo_repository, repository, clock) = setup(&[1]).await;
+        let scheduler = ReminderScheduler::new(
+            repository.clone(),
+            Arc::new(HangingSink),
+            clock.clone(),
+            Duration::ZERO,
+        )
+        .with_timeout(Duration::from_millis(10));
+        clock.set(at(2));
+
+        assert_eq!(0, scheduler.tick().await.unwrap());
+        let todo = todo_repository.find(1).await.unwrap();
+        assert_eq!(None, todo.reminded_at);
+        // backoff が過ぎれば送り直す
+        clock.set(at(2) + backoff(1));
+        assert_eq!(
+            1,
+            repository.claim(clock.now(), at(3), 1).await.unwrap().len()
+        );
+    }
+
+    #[test]
+    fn should_back_off_exponentially() {
+        assert_eq!(chrono::Duration::minutes(1), backoff(1));
+        assert_eq!(chrono::Duration::minutes(4), backoff(3));
+        assert_eq!(chrono::Duration::hours(1), backoff(7));
+        assert_eq!(chrono::Duration::hours(1), backoff(i32::MAX));
+    }
+
+    #[tokio::test]
+    async fn should_deliver_in_background() {
+        let (_, repository, clock) = setup(&[5, 2]).await;
+        let (sink, mut rx) = ChannelSink::new();
+        // 1 時間ごとに見に行くが、時計は sleep で即座に進む
+        let handle = ReminderScheduler::new(
+            repository,
+            Arc::new(sink),
+            clock.clone(),
+            Duration::from_secs(60 * 60),
+        )
+        .spawn();
+
+        let reminder = rx.recv().await.unwrap();
+        assert_eq!((2, at(2)), (reminder.todo_id, reminder.remind_at));
+        let reminder = rx.recv().await.unwrap();
+        assert_eq!((1, at(5)), (reminder.todo_id, reminder.remind_at));
+        handle.abort();
+    }
+}
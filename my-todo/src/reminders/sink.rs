@@ -0,0 +1,229 @@
+use super::{ReminderError, ReminderSink};
+use crate::repositories::reminder::Reminder;
+use axum::async_trait;
+use hyper::{client::HttpConnector, header, Body, Client, Request, Uri};
+use lettre::{
+    message::{header::ContentType, Mailbox},
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use std::time::Duration;
+
+/// ログに出すだけの送り先
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LogSink;
+
+#[async_trait]
+impl ReminderSink for LogSink {
+    async fn deliver(&self, reminder: &Reminder) -> Result<(), ReminderError> {
+        tracing::info!(
+            "reminder: todo {} \"{}\" (remind at {})",
+            reminder.todo_id,
+            reminder.text,
+            reminder.remind_at
+        );
+        Ok(())
+    }
+}
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// リマインダーを JSON で POST する。2xx 以外は失敗とみなす
+/// 全ユーザーのリマインダーが届くため、owner_email を見て振り分ける自前の中継先を指定する
+#[derive(Debug, Clone)]
+pub struct WebhookSink {
+    client: Client<HttpConnector>,
+    uri: Uri,
+}
+
+impl WebhookSink {
+    pub fn new(uri: Uri) -> Self {
+        // 応答全体はスケジューラの timeout で打ち切る
+        let mut connector = HttpConnector::new();
+        connector.set_connect_timeout(Some(CONNECT_TIMEOUT));
+        WebhookSink {
+            client: Client::builder().build(connector),
+            uri,
+        }
+    }
+}
+
+#[async_trait]
+impl ReminderSink for WebhookSink {
+    async fn deliver(&self, reminder: &Reminder) -> Result<(), ReminderError> {
+        let body = serde_json::to_vec(reminder).unwrap();
+        let req = Request::post(&self.uri)
+            .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+            .body(Body::from(body))
+            .map_err(|e| ReminderError::Webhook(e.to_string()))?;
+        let res = self
+            .client
+            .request(req)
+            .await
+            .map_err(|e| ReminderError::Webhook(e.to_string()))?;
+        if !res.status().is_success() {
+            return Err(ReminderError::Webhook(format!(
+                "unexpected status {}",
+                res.status()
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// リマインダーを Todo の所有者にメールで送る
+/// 認証や TLS は使わないため、ローカルの SMTP リレーに渡す前提
+#[derive(Clone)]
+pub struct SmtpSink {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+}
+
+impl SmtpSink {
+    pub fn new(host: &str, port: u16, from: Mailbox) -> Self {
+        let transport = AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host)
+            .port(port)
+            .build();
+        SmtpSink { transport, from }
+    }
+}
+
+#[async_trait]
+impl ReminderSink for SmtpSink {
+    async fn deliver(&self, reminder: &Reminder) -> Result<(), ReminderError> {
+        let mut body = format!("{}\n\ntodo: {}\n", reminder.text, reminder.todo_id);
+        if let Some(due_at) = reminder.due_at {
+            body.push_str(&format!("due: {}\n", due_at));
+        }
+        let to: Mailbox = reminder
+            .owner_email
+            .parse()
+            .map_err(|e: lettre::address::AddressError| ReminderError::Smtp(e.to_string()))?;
+        let message = Message::builder()
+            .from(self.from.clone())
+            .to(to)
+            .subject(format!("Reminder: {}", reminder.text))
+            .header(ContentType::TEXT_PLAIN)
+            .body(body)
+            .map_err(|e| ReminderError::Smtp(e.to_string()))?;
+        self.transport
+            .send(message)
+            .await
+            .map_err(|e| ReminderError::Smtp(e.to_string()))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::repositories::due::Due;
+    use axum::{extract::Extension, http::StatusCode, routing::post, Json, Router};
+    use chrono::{NaiveDate, TimeZone, Utc};
+    use std::net::SocketAddr;
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::TcpListener,
+        sync::mpsc,
+    };
+
+    fn reminder() -> Reminder {
+        Reminder {
+            todo_id: 1,
+            owner_email: "me@example.com".to_string(),
+            text: "buy milk".to_string(),
+            remind_at: Utc.with_ymd_and_hms(2024, 10, 1, 9, 0, 0).unwrap(),
+            due_at: Some(Due::Date(NaiveDate::from_ymd_opt(2024, 10, 2).unwrap())),
+            attempts: 1,
+        }
+    }
+
+    /// 受け取った JSON をチャンネルに流し、status を返すローカルの Webhook
+    async fn serve_webhook(status: StatusCode) -> (Uri, mpsc::UnboundedReceiver<Reminder>) {
+        let (tx, rx) = mpsc::unbounded_channel::<Reminder>();
+        let app = Router::new()
+            .route(
+                "/hook",
+                post(
+                    move |Json(reminder): Json<Reminder>,
+                     Extension(tx): Extension<mpsc::UnboundedSender<Reminder>>| async move {
+                        tx.send(reminder).unwrap();
+                        status
+                    },
+                ),
+            )
+            .layer(Extension(tx));
+        let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+            .serve(app.into_make_service());
+        let uri = format!("http://{}/hook", server.local_addr())
+            .parse()
+            .unwrap();
+        tokio::spawn(server);
+        (uri, rx)
+    }
+
+    #[tokio::test]
+    async fn should_post_reminder_to_webhook() {
+        let (uri, mut rx) = serve_webhook(StatusCode::NO_CONTENT).await;
+        WebhookSink::new(uri).deliver(&reminder()).await.unwrap();
+        assert_eq!(reminder(), rx.recv().await.unwrap());
+
+        let (uri, _rx) = serve_webhook(StatusCode::INTERNAL_SERVER_ERROR).await;
+        let res = WebhookSink::new(uri).deliver(&reminder()).await;
+        assert!(matches!(res, Err(ReminderError::Webhook(_))));
+    }
+
+    /// 受け取ったメールの本文 (DATA) を返すだけのローカルの SMTP サーバー
+    async fn serve_smtp() -> (SocketAddr, mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = stream.into_split();
+            let mut lines = BufReader::new(reader).lines();
+            writer.write_all(b"220 localhost ESMTP\r\n").await.unwrap();
+            let mut data: Option<String> = None;
+            while let Some(line) = lines.next_line().await.unwrap() {
+                let reply: &[u8] = match (&mut data, line.as_str()) {
+                    (Some(body), ".") => {
+                        tx.send(std::mem::take(body)).unwrap();
+                        data = None;
+                        b"250 OK\r\n"
+                    }
+                    (Some(body), line) => {
+                        body.push_str(line);
+                        body.push('\n');
+                        continue;
+                    }
+                    (None, "DATA") => {
+                        data = Some(String::new());
+                        b"354 End data with <CR><LF>.<CR><LF>\r\n"
+                    }
+                    (None, "QUIT") => {
+                        writer.write_all(b"221 Bye\r\n").await.unwrap();
+                        break;
+                    }
+                    (None, _) => b"250 OK\r\n",
+                };
+                writer.write_all(reply).await.unwrap();
+            }
+        });
+        (addr, rx)
+    }
+
+    #[tokio::test]
+    async fn should_send_reminder_mail() {
+        let (addr, mut rx) = serve_smtp().await;
+        let sink = SmtpSink::new(
+            &addr.ip().to_string(),
+            addr.port(),
+            "todo@example.com".parse().unwrap(),
+        );
+        sink.deliver(&reminder()).await.unwrap();
+
+        let mail = rx.recv().await.unwrap();
+        assert!(mail.contains("To: me@example.com"));
+        assert!(mail.contains("Subject: Reminder: buy milk"));
+        assert!(mail.contains("due: 2024-10-02"));
+    }
+}
@@ -1,14 +1,163 @@
+pub mod dependency;
+pub mod due;
 pub mod label;
+pub mod recurrence;
+pub mod reminder;
 pub mod todo;
+pub mod todo_event;
+pub mod unit_of_work;
+pub mod user;
 
+use serde::Deserialize;
 use thiserror::Error;
 
 #[derive(Debug, Error)]
-enum RepositoryError {
+pub enum RepositoryError {
     #[error("Unexpected Error: [{0}]")]
     Unexpected(String),
     #[error("Not Found, id is {0}")]
     NotFound(i32),
     #[error("Duplicate data, id is {0}")]
     Duplicate(i32),
+    #[error("In use by todos {0:?}")]
+    InUse(Vec<i32>),
+    #[error("Unknown labels {0:?}")]
+    UnknownLabels(Vec<i32>),
+    #[error("Version mismatch, current version is {0}")]
+    VersionMismatch(i32),
+    #[error("Invalid parent {0}: {1}")]
+    InvalidParent(i32, ParentError),
+    #[error("Dependency cycle {0:?}")]
+    DependencyCycle(Vec<i32>),
+}
+
+/// Todo の親にできない理由
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum ParentError {
+    #[error("parent does not exist")]
+    NotFound,
+    #[error("parent is the todo itself or its descendant")]
+    Cycle,
+    #[error("tree must not be deeper than {0}")]
+    TooDeep(usize),
+}
+
+impl From<sqlx::Error> for RepositoryError {
+    fn from(e: sqlx::Error) -> Self {
+        RepositoryError::Unexpected(e.to_string())
+    }
+}
+
+/// キーがなければ変更なし、null なら値を消すために Option<Option<T>> へ読み込む
+fn deserialize_some<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+#[cfg(test)]
+pub mod test_utils {
+    use super::{
+        label::Label,
+        recurrence::RRule,
+        todo::TodoEntity,
+        todo_event::{Changes, TodoEvent, TodoEventKind},
+        unit_of_work::Commit,
+        user::{ApiToken, User},
+        RepositoryError,
+    };
+    use axum::async_trait;
+    use chrono::{DateTime, Utc};
+    use sqlx::types::Json;
+    use std::{
+        collections::{BTreeSet, HashMap},
+        sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard},
+    };
+
+    /// Memory 実装のリポジトリが既定で扱う所有者
+    /// UserRepositoryForMemory::with_session でログインするユーザーと同じにする
+    pub const DEFAULT_OWNER: i32 = 1;
+
+    #[derive(Debug, Default, Clone)]
+    pub struct MemoryTables {
+        pub todos: HashMap<i32, TodoEntity>,
+        /// Todo の id -> 所有者のユーザーの id
+        /// 完全に削除した Todo の履歴を所有者だけが読めるよう、削除しても残す
+        pub todo_owners: HashMap<i32, i32>,
+        pub labels: HashMap<i32, Label>,
+        /// ラベルの id -> 所有者のユーザーの id
+        pub label_owners: HashMap<i32, i32>,
+        pub todo_events: Vec<TodoEvent>,
+        /// 繰り返しの系列の id -> 繰り返し。止めた系列は None
+        pub series: HashMap<i32, Option<RRule>>,
+        /// Todo の id -> リマインダーの送信を試みた回数と、次に試みてよい日時
+        pub reminder_attempts: HashMap<i32, (i32, DateTime<Utc>)>,
+        /// (待つ Todo の id, 待たれる Todo の id) の組
+        pub dependencies: BTreeSet<(i32, i32)>,
+        /// ユーザーの id -> ユーザーとパスワードのハッシュ
+        pub users: HashMap<i32, (User, String)>,
+        /// トークンのハッシュ -> ユーザーの id と有効期限
+        pub sessions: HashMap<String, (i32, DateTime<Utc>)>,
+        /// API トークンの id -> ユーザーの id とトークンのハッシュ
+        pub api_tokens: HashMap<i32, (i32, String, ApiToken)>,
+        /// 作成日時などに使う現在日時。結果を比べやすいよう、テストで進めない限り固定する
+        pub now: DateTime<Utc>,
+    }
+
+    impl MemoryTables {
+        /// Db と同じく履歴に追記する。Db と同じく、変更したのは Todo の所有者とする
+        pub fn record_event(&mut self, todo_id: i32, kind: TodoEventKind, changes: Changes) {
+            let event = TodoEvent {
+                id: self.todo_events.len() as i32 + 1,
+                todo_id,
+                kind,
+                changes: Json(changes),
+                actor_id: self.todo_owners.get(&todo_id).copied(),
+                created_at: self.now,
+            };
+            self.todo_events.push(event);
+        }
+    }
+
+    /// Memory 実装の各リポジトリで共有するストア
+    /// Todo とラベルの関連を扱うため、同じストアを渡して使う
+    #[derive(Debug, Clone, Default)]
+    pub struct MemoryStore(Arc<RwLock<MemoryTables>>);
+
+    impl MemoryStore {
+        pub fn write(&self) -> RwLockWriteGuard<'_, MemoryTables> {
+            self.0.write().unwrap()
+        }
+
+        pub fn read(&self) -> RwLockReadGuard<'_, MemoryTables> {
+            self.0.read().unwrap()
+        }
+    }
+
+    /// Memory 実装の作業単位。ストアの写しに書き込み、commit でストアに書き戻す
+    /// 書き戻すと begin のあとに他で行った変更は失われるため、テストでだけ使う
+    #[derive(Debug)]
+    pub struct MemoryUnitOfWork {
+        store: MemoryStore,
+        pub tables: MemoryTables,
+    }
+
+    impl MemoryUnitOfWork {
+        pub fn begin(store: &MemoryStore) -> Self {
+            MemoryUnitOfWork {
+                store: store.clone(),
+                tables: store.read().clone(),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl Commit for MemoryUnitOfWork {
+        async fn commit(self) -> Result<(), RepositoryError> {
+            *self.store.write() = self.tables;
+            Ok(())
+        }
+    }
 }
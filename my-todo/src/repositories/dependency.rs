@@ -0,0 +1,332 @@
+use super::RepositoryError;
+use indoc::{formatdoc, indoc};
+use sqlx::{FromRow, PgConnection};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt,
+};
+
+/// Todo の依存関係のグラフ
+/// ゴミ箱にある Todo とその依存関係は含めず、依存関係のない Todo も含めない
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DependencyGraph {
+    pub nodes: Vec<GraphNode>,
+    /// (待つ Todo, 待たれる Todo) の組
+    pub edges: Vec<(i32, i32)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, FromRow)]
+pub struct GraphNode {
+    pub id: i32,
+    pub text: String,
+    pub completed: bool,
+    pub blocked: bool,
+}
+
+/// Graphviz の DOT 形式で書き出す
+/// 先に終わらせる Todo から待っている Todo へ矢印を引き、完了した Todo は灰色、
+/// 待っている Todo は赤で描く
+impl fmt::Display for DependencyGraph {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "digraph todos {{")?;
+        writeln!(f, "    rankdir=LR;")?;
+        writeln!(f, "    node [shape=box];")?;
+        for node in &self.nodes {
+            let mut attrs = vec![format!("label={}", quote(&node.text))];
+            if node.completed {
+                attrs.push("style=filled".to_string());
+                attrs.push("fillcolor=lightgray".to_string());
+            }
+            if node.blocked {
+                attrs.push("color=red".to_string());
+            }
+            writeln!(f, "    {} [{}];", node.id, attrs.join(", "))?;
+        }
+        for (todo_id, depends_on) in &self.edges {
+            writeln!(f, "    {} -> {};", depends_on, todo_id)?;
+        }
+        writeln!(f, "}}")
+    }
+}
+
+/// DOT の文字列にする。改行は DOT の改行として残す
+fn quote(text: &str) -> String {
+    let mut accm = String::with_capacity(text.len() + 2);
+    accm.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => accm.push_str(r#"\""#),
+            '\\' => accm.push_str(r"\\"),
+            '\n' => accm.push_str(r"\n"),
+            '\r' => {}
+            c => accm.push(c),
+        }
+    }
+    accm.push('"');
+    accm
+}
+
+/// table の Todo が、未完了でゴミ箱にもない Todo を待っているかどうかを blocked として返す列
+pub fn blocked_column(table: &str) -> String {
+    formatdoc!(
+        r#"
+            exists (
+                select 1 from todo_dependencies
+                    join todos blocker on blocker.id = todo_dependencies.depends_on
+                    where todo_dependencies.todo_id = {table}.id
+                        and not blocker.completed and blocker.deleted_at is null
+            ) as blocked
+        "#,
+        table = table,
+    )
+}
+
+/// id の Todo が other の Todo を待つようにする。既に待っていれば false を返す
+/// other がゴミ箱にあるか owner_id のユーザーの Todo でなければ NotFound を、
+/// 循環する場合は DependencyCycle を返す
+/// 追加が同時に起きて循環しないよう、同じユーザーの追加は直列にする
+pub async fn add(
+    conn: &mut PgConnection,
+    owner_id: i32,
+    id: i32,
+    other: i32,
+) -> Result<bool, RepositoryError> {
+    sqlx::query("select pg_advisory_xact_lock(hashtext('todo_dependencies'), $1)")
+        .bind(owner_id)
+        .execute(&mut *conn)
+        .await?;
+
+    sqlx::query(indoc!(
+        r#"
+            select id from todos where id = $1 and owner_id = $2 and deleted_at is null
+                for share
+        "#
+    ))
+    .bind(other)
+    .bind(owner_id)
+    .fetch_optional(&mut *conn)
+    .await?
+    .ok_or(RepositoryError::NotFound(other))?;
+
+    // other から依存をたどって id に着けば、追加すると循環する
+    // union で同じ Todo は一度しかたどらないため、菱形に依存していても辺の数に比例して終わる
+    let cyclic: bool = sqlx::query_scalar(indoc!(
+        r#"
+            with recursive reach (id) as (
+                select $2
+                union
+                select todo_dependencies.depends_on from todo_dependencies
+                    join reach on todo_dependencies.todo_id = reach.id
+            )
+            select exists (select 1 from reach where id = $1)
+        "#
+    ))
+    .bind(id)
+    .bind(other)
+    .fetch_one(&mut *conn)
+    .await?;
+    if cyclic {
+        // 循環したときだけ、other からたどれる依存を読んで循環の道を作る
+        let edges: Vec<(i32, i32)> = sqlx::query_as(indoc!(
+            r#"
+                with recursive reach (id) as (
+                    select $1
+                    union
+                    select todo_dependencies.depends_on from todo_dependencies
+                        join reach on todo_dependencies.todo_id = reach.id
+                )
+                select todo_dependencies.todo_id, todo_dependencies.depends_on
+                    from todo_dependencies
+                        join reach on todo_dependencies.todo_id = reach.id
+                    order by todo_dependencies.todo_id, todo_dependencies.depends_on
+            "#
+        ))
+        .bind(other)
+        .fetch_all(&mut *conn)
+        .await?;
+        let cycle = find_cycle(&edges, id, other).expect("reachable todo must have a path");
+        return Err(RepositoryError::DependencyCycle(cycle));
+    }
+
+    let res = sqlx::query(indoc!(
+        r#"
+            insert into todo_dependencies (todo_id, depends_on) values ($1, $2)
+                on conflict do nothing
+        "#
+    ))
+    .bind(id)
+    .bind(other)
+    .execute(conn)
+    .await?;
+    Ok(res.rows_affected() == 1)
+}
+
+/// id が other を待つと循環するとき、edges (待つ Todo, 待たれる Todo) をたどって
+/// id, other, ..., id と最短で一周する道を返す。循環しなければ None を返す
+/// 一度訪れた Todo は二度たどらないため、辺の数に比例して終わる
+pub fn find_cycle(edges: &[(i32, i32)], id: i32, other: i32) -> Option<Vec<i32>> {
+    let mut next: HashMap<i32, Vec<i32>> = HashMap::new();
+    for (todo_id, depends_on) in edges {
+        next.entry(*todo_id).or_default().push(*depends_on);
+    }
+    let mut prev: HashMap<i32, i32> = HashMap::new();
+    let mut visited = HashSet::from([other]);
+    let mut queue = VecDeque::from([other]);
+    while let Some(current) = queue.pop_front() {
+        if current == id {
+            let mut path = vec![current];
+            while let Some(before) = prev.get(path.last().unwrap()) {
+                path.push(*before);
+            }
+            path.push(id);
+            path.reverse();
+            return Some(path);
+        }
+        for depends_on in next.get(&current).into_iter().flatten() {
+            if visited.insert(*depends_on) {
+                prev.insert(*depends_on, current);
+                queue.push_back(*depends_on);
+            }
+        }
+    }
+    None
+}
+
+/// id の Todo が other の Todo を待たないようにする。待っていなければ NotFound を返す
+pub async fn remove(conn: &mut PgConnection, id: i32, other: i32) -> Result<(), RepositoryError> {
+    let res = sqlx::query(indoc!(
+        r#"
+            delete from todo_dependencies where todo_id = $1 and depends_on = $2
+        "#
+    ))
+    .bind(id)
+    .bind(other)
+    .execute(conn)
+    .await?;
+    if res.rows_affected() == 0 {
+        return Err(RepositoryError::NotFound(other));
+    }
+    Ok(())
+}
+
+/// id の Todo を待っている Todo の version を進める
+/// 完了やゴミ箱への移動で blocked が変わり、表現が変わるため ETag も変える
+pub async fn touch_dependents(conn: &mut PgConnection, id: i32) -> Result<(), RepositoryError> {
+    sqlx::query(indoc!(
+        r#"
+            update todos set version = version + 1
+                where id in (select todo_id from todo_dependencies where depends_on = $1)
+        "#
+    ))
+    .bind(id)
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+/// owner_id のユーザーの Todo だけで作る。依存関係は同じユーザーの Todo の間にしかない
+pub async fn graph(
+    conn: &mut PgConnection,
+    owner_id: i32,
+) -> Result<DependencyGraph, RepositoryError> {
+    let edges: Vec<(i32, i32)> = sqlx::query_as(indoc!(
+        r#"
+            select todo_dependencies.todo_id, todo_dependencies.depends_on
+                from todo_dependencies
+                    join todos a on a.id = todo_dependencies.todo_id
+                    join todos b on b.id = todo_dependencies.depends_on
+                where a.owner_id = $1 and a.deleted_at is null and b.deleted_at is null
+                order by todo_dependencies.todo_id, todo_dependencies.depends_on
+        "#
+    ))
+    .bind(owner_id)
+    .fetch_all(&mut *conn)
+    .await?;
+
+    let ids: Vec<i32> = edges.iter().flat_map(|(a, b)| [*a, *b]).collect();
+    let sql = formatdoc!(
+        r#"
+            select id, text, completed, {blocked} from todos
+                where id = any($1)
+                order by id
+        "#,
+        blocked = blocked_column("todos"),
+    );
+    let nodes = sqlx::query_as::<_, GraphNode>(&sql)
+        .bind(ids)
+        .fetch_all(conn)
+        .await?;
+
+    Ok(DependencyGraph { nodes, edges })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn should_render_dot() {
+        let node = |id: i32, text: &str, completed: bool, blocked: bool| GraphNode {
+            id,
+            text: text.to_string(),
+            completed,
+            blocked,
+        };
+        let graph = DependencyGraph {
+            nodes: vec![
+                node(1, "design", true, false),
+                node(2, r#"write "spec""#, false, false),
+                node(3, "ship\nit", false, true),
+            ],
+            edges: vec![(3, 1), (3, 2)],
+        };
+        assert_eq!(
+            indoc!(
+                r#"
+                    digraph todos {
+                        rankdir=LR;
+                        node [shape=box];
+                        1 [label="design", style=filled, fillcolor=lightgray];
+                        2 [label="write \"spec\""];
+                        3 [label="ship\nit", color=red];
+                        1 -> 3;
+                        2 -> 3;
+                    }
+                "#
+            ),
+            graph.to_string()
+        );
+        assert_eq!(
+            "digraph todos {\n    rankdir=LR;\n    node [shape=box];\n}\n",
+            DependencyGraph::default().to_string()
+        );
+    }
+
+    #[test]
+    fn should_find_shortest_cycle() {
+        let edges = [(2, 1), (3, 2), (3, 1)];
+        assert_eq!(Some(vec![1, 3, 1]), find_cycle(&edges, 1, 3));
+        assert_eq!(Some(vec![1, 1]), find_cycle(&edges, 1, 1));
+        assert_eq!(None, find_cycle(&edges, 3, 1));
+    }
+
+    #[test]
+    fn should_find_cycle_through_layered_diamonds() {
+        // 各層の 2 つの Todo が次の層の 2 つを待つ。道は 2^LAYERS 通りある
+        const LAYERS: i32 = 64;
+        let edges: Vec<(i32, i32)> = (0..LAYERS)
+            .flat_map(|layer| {
+                let [a, b] = [layer * 2, layer * 2 + 1];
+                [(a, a + 2), (a, b + 2), (b, a + 2), (b, b + 2)]
+            })
+            .collect();
+        let bottom = LAYERS * 2;
+        assert_eq!(None, find_cycle(&edges, 0, bottom));
+        let cycle = find_cycle(&edges, bottom, 0).unwrap();
+        assert_eq!(LAYERS as usize + 2, cycle.len());
+        assert_eq!(
+            (Some(&bottom), Some(&bottom)),
+            (cycle.first(), cycle.last())
+        );
+    }
+}
@@ -0,0 +1,260 @@
+use chrono::{DateTime, Datelike, NaiveDate, NaiveTime, SecondsFormat, TimeZone, Utc};
+use chrono_tz::Tz;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use validator::ValidationError;
+
+/// Todo の期限
+/// `"2024-10-17"` のような日付だけの期限と、`"2024-10-17T09:00:00+09:00"` のような
+/// タイムゾーン付きの日時の期限がある。日付だけの期限はクライアントのタイムゾーンの日付として扱う
+/// 日時の期限は時点だけを持ち、指定されたオフセットは残さない。UTC に直して保存し、
+/// `"2024-10-17T00:00:00Z"` のように返すため、表示するタイムゾーンはクライアントが決める
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Due {
+    Date(NaiveDate),
+    /// UTC に直した時点
+    At(DateTime<Utc>),
+}
+
+impl Due {
+    /// due_date と due_at のカラムから組み立てる。両方が入ることは制約で防いでいる
+    pub fn from_columns(date: Option<NaiveDate>, at: Option<DateTime<Utc>>) -> Option<Self> {
+        at.map(Due::At).or_else(|| date.map(Due::Date))
+    }
+
+    pub fn date(&self) -> Option<NaiveDate> {
+        match self {
+            Due::Date(date) => Some(*date),
+            Due::At(_) => None,
+        }
+    }
+
+    pub fn at(&self) -> Option<DateTime<Utc>> {
+        match self {
+            Due::Date(_) => None,
+            Due::At(at) => Some(*at),
+        }
+    }
+}
+
+impl fmt::Display for Due {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Due::Date(date) => date.fmt(f),
+            Due::At(at) => f.write_str(&at.to_rfc3339_opts(SecondsFormat::AutoSi, true)),
+        }
+    }
+}
+
+impl Serialize for Due {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Due {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        if let Ok(date) = NaiveDate::parse_from_str(&value, "%Y-%m-%d") {
+            return Ok(Due::Date(date));
+        }
+        DateTime::parse_from_rfc3339(&value)
+            .map(|at| Due::At(at.with_timezone(&Utc)))
+            .map_err(|_| {
+                de::Error::custom(format!(
+                    "due_at must be YYYY-MM-DD or RFC 3339 date-time with offset: {}",
+                    value
+                ))
+            })
+    }
+}
+
+/// 入力ミスで桁のずれた年を弾く
+pub fn validate_due(due: &Due) -> Result<(), ValidationError> {
+    let year = match due {
+        Due::Date(date) => date.year(),
+        Due::At(at) => at.year(),
+    };
+    if (1900..=9999).contains(&year) {
+        Ok(())
+    } else {
+        Err(ValidationError::new("due_at"))
+    }
+}
+
+/// `GET /todos?due=` で指定する期限の範囲
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DueView {
+    /// 期限を過ぎた未完了の Todo
+    Overdue,
+    /// 今日が期限の Todo
+    Today,
+    /// 今日から 7 日以内が期限の Todo
+    Week,
+}
+
+impl std::str::FromStr for DueView {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "overdue" => Ok(DueView::Overdue),
+            "today" => Ok(DueView::Today),
+            "week" => Ok(DueView::Week),
+            _ => Err(format!("due must be overdue, today or week: {}", value)),
+        }
+    }
+}
+
+/// 期限の範囲を日付と日時のそれぞれで表したもの。from は含み、before は含まない
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DueWindow {
+    pub from_date: Option<NaiveDate>,
+    pub before_date: NaiveDate,
+    pub from_at: Option<DateTime<Utc>>,
+    pub before_at: DateTime<Utc>,
+    pub incomplete_only: bool,
+}
+
+impl DueView {
+    /// tz での今日を基準に範囲を求める
+    pub fn window(self, tz: Tz, now: DateTime<Utc>) -> DueWindow {
+        let today = now.with_timezone(&tz).date_naive();
+        let days = |n: u64| today + chrono::Days::new(n);
+        match self {
+            DueView::Overdue => DueWindow {
+                from_date: None,
+                before_date: today,
+                from_at: None,
+                before_at: now,
+                incomplete_only: true,
+            },
+            DueView::Today => DueWindow {
+                from_date: Some(today),
+                before_date: days(1),
+                from_at: Some(start_of_day(tz, today)),
+                before_at: start_of_day(tz, days(1)),
+                incomplete_only: false,
+            },
+            DueView::Week => DueWindow {
+                from_date: Some(today),
+                before_date: days(7),
+                from_at: Some(start_of_day(tz, today)),
+                before_at: start_of_day(tz, days(7)),
+                incomplete_only: false,
+            },
+        }
+    }
+}
+
+// Db では SQL で絞り込むため、Memory 実装でだけ使う
+#[cfg(test)]
+impl DueWindow {
+    pub fn contains(&self, due: Option<&Due>, completed: bool) -> bool {
+        let in_range = match due {
+            Some(Due::Date(date)) => {
+                self.from_date.is_none_or(|from| from <= *date) && *date < self.before_date
+            }
+            Some(Due::At(at)) => {
+                self.from_at.is_none_or(|from| from <= *at) && *at < self.before_at
+            }
+            None => false,
+        };
+        in_range && !(self.incomplete_only && completed)
+    }
+}
+
+/// 夏時間の切り替えで 0 時が存在しない日は、UTC とみなした 0 時を使う
+fn start_of_day(tz: Tz, date: NaiveDate) -> DateTime<Utc> {
+    let midnight = date.and_time(NaiveTime::MIN);
+    tz.from_local_datetime(&midnight)
+        .earliest()
+        .unwrap_or_else(|| tz.from_utc_datetime(&midnight))
+        .with_timezone(&Utc)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn should_parse_due() {
+        let due: Due = serde_json::from_str(r#""2024-10-17""#).unwrap();
+        assert_eq!(
+            Due::Date(NaiveDate::from_ymd_opt(2024, 10, 17).unwrap()),
+            due
+        );
+        assert_eq!(r#""2024-10-17""#, serde_json::to_string(&due).unwrap());
+
+        let due: Due = serde_json::from_str(r#""2024-10-17T09:00:00+09:00""#).unwrap();
+        assert_eq!(
+            Due::At(Utc.with_ymd_and_hms(2024, 10, 17, 0, 0, 0).unwrap()),
+            due
+        );
+        assert_eq!(
+            r#""2024-10-17T00:00:00Z""#,
+            serde_json::to_string(&due).unwrap()
+        );
+
+        for value in [r#""2024-10-17T09:00:00""#, r#""tomorrow""#, "1"] {
+            assert!(serde_json::from_str::<Due>(value).is_err());
+        }
+        let due = Due::Date(NaiveDate::from_ymd_opt(24, 10, 17).unwrap());
+        assert!(validate_due(&due).is_err());
+    }
+
+    #[test]
+    fn should_normalize_due_at_to_utc() {
+        let instant = Due::At(Utc.with_ymd_and_hms(2024, 10, 17, 0, 0, 0).unwrap());
+        // 同じ時点ならオフセットが違っても同じ期限になり、どれも UTC で返す
+        for value in [
+            r#""2024-10-17T09:00:00+09:00""#,
+            r#""2024-10-16T19:00:00-05:00""#,
+            r#""2024-10-17T00:00:00+00:00""#,
+            r#""2024-10-17T00:00:00Z""#,
+        ] {
+            let due: Due = serde_json::from_str(value).unwrap();
+            assert_eq!(instant, due, "{}", value);
+            assert_eq!(
+                r#""2024-10-17T00:00:00Z""#,
+                serde_json::to_string(&due).unwrap()
+            );
+        }
+        // UTC に直すと日付が変わる場合も、日付は UTC のものになる
+        let due: Due = serde_json::from_str(r#""2024-10-17T08:00:00+09:00""#).unwrap();
+        assert_eq!(
+            r#""2024-10-16T23:00:00Z""#,
+            serde_json::to_string(&due).unwrap()
+        );
+    }
+
+    #[test]
+    fn should_compute_window_in_time_zone() {
+        let tokyo: Tz = "Asia/Tokyo".parse().unwrap();
+        // 東京では 10/17 の 08:00
+        let now = Utc.with_ymd_and_hms(2024, 10, 16, 23, 0, 0).unwrap();
+        let date = |day: u32| Due::Date(NaiveDate::from_ymd_opt(2024, 10, day).unwrap());
+        let at =
+            |day: u32, hour: u32| Due::At(Utc.with_ymd_and_hms(2024, 10, day, hour, 0, 0).unwrap());
+
+        let today = DueView::Today.window(tokyo, now);
+        assert!(today.contains(Some(&date(17)), false));
+        assert!(!today.contains(Some(&date(16)), false));
+        assert!(today.contains(Some(&at(16, 15)), true));
+        assert!(!today.contains(Some(&at(17, 15)), false));
+        assert!(!today.contains(None, false));
+        assert!(!DueView::Today
+            .window(Tz::UTC, now)
+            .contains(Some(&date(17)), false));
+
+        let overdue = DueView::Overdue.window(tokyo, now);
+        assert!(overdue.contains(Some(&date(16)), false));
+        assert!(!overdue.contains(Some(&date(16)), true));
+        assert!(!overdue.contains(Some(&date(17)), false));
+        assert!(overdue.contains(Some(&at(16, 22)), false));
+
+        let week = DueView::Week.window(tokyo, now);
+        assert!(week.contains(Some(&date(23)), false));
+        assert!(!week.contains(Some(&date(24)), false));
+    }
+}
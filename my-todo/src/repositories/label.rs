@@ -1,88 +1,416 @@
-use super::RepositoryError;
+use super::{
+    deserialize_some,
+    todo_event::{self, TodoEventKind},
+    unit_of_work::{Commit, UnitOfWork},
+    RepositoryError,
+};
 use axum::async_trait;
+use chrono::{DateTime, Utc};
 use indoc::indoc;
 use serde::{Deserialize, Serialize};
-use sqlx::PgPool;
+use sqlx::{PgConnection, PgPool};
+use std::collections::HashSet;
+use validator::{Validate, ValidationError};
 
 #[async_trait]
 pub trait LabelRepository: Clone + std::marker::Send + std::marker::Sync + 'static {
-    async fn create(&self, name: String) -> anyhow::Result<Label>;
-    async fn all(&self) -> anyhow::Result<Vec<Label>>;
-    async fn delete(&self, id: i32) -> anyhow::Result<()>;
+    /// create_in に渡す作業単位。TodoRepository::begin で始めたものを渡すと、Todo の操作とまとめられる
+    type UnitOfWork: Commit;
+    /// owner_id のユーザーのラベルだけを扱うリポジトリを返す
+    /// 他のユーザーのラベルは存在しないものとして扱い、名前の重複もユーザーごとに確かめる
+    fn for_owner(&self, owner_id: i32) -> Self;
+    async fn create(&self, payload: CreateLabel) -> Result<Label, RepositoryError>;
+    /// uow の中で create する。uow を commit するまで他からは見えない
+    async fn create_in(
+        &self,
+        uow: &mut Self::UnitOfWork,
+        payload: CreateLabel,
+    ) -> Result<Label, RepositoryError>;
+    async fn find(&self, id: i32) -> Result<Label, RepositoryError>;
+    async fn all(&self) -> Result<Vec<Label>, RepositoryError>;
+    async fn update(&self, id: i32, payload: UpdateLabel) -> Result<Label, RepositoryError>;
+    async fn delete(&self, id: i32, force: bool) -> Result<(), RepositoryError>;
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, sqlx::FromRow)]
 pub struct Label {
     pub id: i32,
     pub name: String,
+    pub color: Option<String>,
+    pub description: Option<String>,
+    pub created_at: DateTime<Utc>,
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Validate)]
+pub struct CreateLabel {
+    #[validate(length(min = 1, message = "Can not be empty"))]
+    #[validate(length(max = 100, message = "Over text length"))]
+    name: String,
+    #[validate(custom(function = "validate_color", message = "Must be #rrggbb"))]
+    color: Option<String>,
+    #[validate(length(max = 500, message = "Over text length"))]
+    description: Option<String>,
+}
+
+// CreateLabel と同じ検証を、指定された項目にだけ適用する
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Validate)]
+pub struct UpdateLabel {
+    #[validate(length(min = 1, message = "Can not be empty"))]
+    #[validate(length(max = 100, message = "Over text length"))]
+    name: Option<String>,
+    /// null を指定すると色を消す
+    #[serde(default, deserialize_with = "deserialize_some")]
+    #[validate(custom(function = "validate_color", message = "Must be #rrggbb"))]
+    color: Option<Option<String>>,
+    /// null を指定すると説明を消す
+    #[serde(default, deserialize_with = "deserialize_some")]
+    #[validate(length(max = 500, message = "Over text length"))]
+    description: Option<Option<String>>,
+}
+
+/// 一度に作るラベルに同じ名前がないか確かめる。重複すると作業単位ごと取り消されるため、
+/// 作る前に検証エラーとして返す
+pub fn validate_unique_names(labels: &[CreateLabel]) -> Result<(), ValidationError> {
+    let mut names = HashSet::new();
+    match labels
+        .iter()
+        .find(|label| !names.insert(label.name.as_str()))
+    {
+        Some(label) => {
+            let mut error = ValidationError::new("duplicate_name");
+            error.add_param("name".into(), &label.name);
+            Err(error)
+        }
+        None => Ok(()),
+    }
+}
+
+fn validate_color(color: &str) -> Result<(), ValidationError> {
+    match color.strip_prefix('#') {
+        Some(hex) if hex.len() == 6 && hex.chars().all(|c| c.is_ascii_hexdigit()) => Ok(()),
+        _ => Err(ValidationError::new("color")),
+    }
+}
+
+/// ユーザーごとにラベル名を一意にする制約
+const NAME_UNIQUE_CONSTRAINT: &str = "labels_owner_id_name_key";
+
 #[derive(Debug, Clone)]
 pub struct LabelRepositoryForDb {
     pool: PgPool,
+    owner_id: i32,
 }
 
 impl LabelRepositoryForDb {
+    /// for_owner で所有者を決めるまでは、どのラベルも見えず作れない
     pub fn new(pool: PgPool) -> Self {
-        Self { pool }
+        Self { pool, owner_id: 0 }
+    }
+
+    pub async fn update_in(
+        &self,
+        uow: &mut UnitOfWork,
+        id: i32,
+        payload: UpdateLabel,
+    ) -> Result<Label, RepositoryError> {
+        let old_label = find_label(uow.conn(), self.owner_id, id).await?;
+
+        if let Some(name) = &payload.name {
+            // 自分以外に同じ名前のラベルがあれば重複
+            let optional_label = sqlx::query_as::<_, Label>(indoc!(
+                r#"
+                    select * from labels where name = $1 and id <> $2 and owner_id = $3
+                "#
+            ))
+            .bind(name)
+            .bind(id)
+            .bind(self.owner_id)
+            .fetch_optional(uow.conn())
+            .await?;
+
+            if let Some(label) = optional_label {
+                return Err(RepositoryError::Duplicate(label.id));
+            }
+        }
+
+        let todos = todo_labels(uow.conn(), id).await?;
+        let name = payload.name.unwrap_or(old_label.name);
+        let label = sqlx::query_as::<_, Label>(indoc!(
+            r#"
+                update labels set name = $1, color = $2, description = $3
+                    where id = $4
+                returning *
+            "#
+        ))
+        .bind(&name)
+        .bind(payload.color.unwrap_or(old_label.color))
+        .bind(payload.description.unwrap_or(old_label.description))
+        .bind(id)
+        .fetch_one(uow.conn())
+        .await;
+        let label = match label {
+            Ok(label) => label,
+            Err(e) => return Err(self.duplicate_or(&name, e).await),
+        };
+
+        touch_todos(uow.conn(), self.owner_id, todos).await?;
+
+        Ok(label)
+    }
+
+    /// 確認してから書き込むまでの間に同じ名前のラベルができると、一意制約の違反になる
+    /// 違反なら先にできたラベルとの重複として扱う。uow のトランザクションは使えないため pool から読む
+    async fn duplicate_or(&self, name: &str, e: sqlx::Error) -> RepositoryError {
+        let violated = e
+            .as_database_error()
+            .and_then(|e| e.constraint())
+            .is_some_and(|constraint| constraint == NAME_UNIQUE_CONSTRAINT);
+        if !violated {
+            return e.into();
+        }
+        let id = sqlx::query_scalar(indoc!(
+            r#"
+                select id from labels where name = $1 and owner_id = $2
+            "#
+        ))
+        .bind(name)
+        .bind(self.owner_id)
+        .fetch_optional(&self.pool)
+        .await;
+        match id {
+            Ok(Some(id)) => RepositoryError::Duplicate(id),
+            _ => e.into(),
+        }
+    }
+
+    /// Todo に紐付いているラベルは削除せず InUse を返す
+    /// force が true の場合は Todo から外してから削除する
+    pub async fn delete_in(
+        &self,
+        uow: &mut UnitOfWork,
+        id: i32,
+        force: bool,
+    ) -> Result<(), RepositoryError> {
+        // 確認から削除までの間に Todo へ紐付けられないようにロックする
+        sqlx::query(indoc!(
+            r#"
+                select id from labels where id = $1 and owner_id = $2 for update
+            "#
+        ))
+        .bind(id)
+        .bind(self.owner_id)
+        .fetch_optional(uow.conn())
+        .await?
+        .ok_or(RepositoryError::NotFound(id))?;
+
+        let todos = todo_labels(uow.conn(), id).await?;
+        if !todos.is_empty() && !force {
+            return Err(RepositoryError::InUse(
+                todos.into_iter().map(|(todo_id, _)| todo_id).collect(),
+            ));
+        }
+
+        sqlx::query(indoc!(
+            r#"
+                delete from todo_labels where label_id = $1
+            "#
+        ))
+        .bind(id)
+        .execute(uow.conn())
+        .await?;
+
+        sqlx::query(indoc!(
+            r#"
+                delete from labels where id = $1
+            "#
+        ))
+        .bind(id)
+        .execute(uow.conn())
+        .await?;
+
+        touch_todos(uow.conn(), self.owner_id, todos).await?;
+
+        Ok(())
+    }
+}
+
+/// ラベルを紐付けている Todo と、その Todo に紐付いているラベル
+async fn todo_labels(
+    conn: &mut PgConnection,
+    id: i32,
+) -> Result<Vec<(i32, Vec<Label>)>, RepositoryError> {
+    let todo_ids: Vec<i32> = sqlx::query_scalar(indoc!(
+        r#"
+            select distinct todo_id from todo_labels where label_id = $1 order by todo_id
+        "#
+    ))
+    .bind(id)
+    .fetch_all(&mut *conn)
+    .await?;
+
+    let mut todos = vec![];
+    for todo_id in todo_ids {
+        todos.push((todo_id, labels_of(conn, todo_id).await?));
+    }
+    Ok(todos)
+}
+
+async fn labels_of(conn: &mut PgConnection, todo_id: i32) -> Result<Vec<Label>, RepositoryError> {
+    let labels = sqlx::query_as::<_, Label>(indoc!(
+        r#"
+            select labels.* from labels
+                inner join todo_labels on todo_labels.label_id = labels.id
+                where todo_labels.todo_id = $1
+                order by labels.id
+        "#
+    ))
+    .bind(todo_id)
+    .fetch_all(conn)
+    .await?;
+
+    Ok(labels)
+}
+
+/// ラベルを紐付けている Todo の表現が変わるため、Todo の version を上げて履歴に残す
+/// todos は変更前に todo_labels で読んだもの
+async fn touch_todos(
+    conn: &mut PgConnection,
+    owner_id: i32,
+    todos: Vec<(i32, Vec<Label>)>,
+) -> Result<(), RepositoryError> {
+    let todo_ids: Vec<i32> = todos.iter().map(|(todo_id, _)| *todo_id).collect();
+    sqlx::query(indoc!(
+        r#"
+            update todos set version = version + 1 where id = any($1)
+        "#
+    ))
+    .bind(todo_ids)
+    .execute(&mut *conn)
+    .await?;
+
+    for (todo_id, before) in todos {
+        let after = labels_of(conn, todo_id).await?;
+        todo_event::record(
+            conn,
+            owner_id,
+            owner_id,
+            todo_id,
+            TodoEventKind::Updated,
+            todo_event::label_diff(&before, &after),
+        )
+        .await?;
     }
+    Ok(())
+}
+
+async fn find_label(
+    conn: &mut PgConnection,
+    owner_id: i32,
+    id: i32,
+) -> Result<Label, RepositoryError> {
+    let label = sqlx::query_as::<_, Label>(indoc!(
+        r#"
+            select * from labels where id = $1 and owner_id = $2
+        "#
+    ))
+    .bind(id)
+    .bind(owner_id)
+    .fetch_one(conn)
+    .await
+    .map_err(|e| match e {
+        sqlx::Error::RowNotFound => RepositoryError::NotFound(id),
+        _ => RepositoryError::Unexpected(e.to_string()),
+    })?;
+
+    Ok(label)
 }
 
 #[async_trait]
 impl LabelRepository for LabelRepositoryForDb {
-    async fn create(&self, name: String) -> anyhow::Result<Label> {
+    type UnitOfWork = UnitOfWork;
+
+    fn for_owner(&self, owner_id: i32) -> Self {
+        Self {
+            pool: self.pool.clone(),
+            owner_id,
+        }
+    }
+
+    async fn create(&self, payload: CreateLabel) -> Result<Label, RepositoryError> {
+        let mut uow = UnitOfWork::begin(&self.pool).await?;
+        let label = self.create_in(&mut uow, payload).await?;
+        uow.commit().await?;
+        Ok(label)
+    }
+
+    async fn create_in(
+        &self,
+        uow: &mut UnitOfWork,
+        payload: CreateLabel,
+    ) -> Result<Label, RepositoryError> {
         let optional_label = sqlx::query_as::<_, Label>(indoc!(
             r#"
-                select * from labels where name = $1
+                select * from labels where name = $1 and owner_id = $2
             "#
         ))
-        .bind(name.clone())
-        .fetch_optional(&self.pool)
+        .bind(payload.name.clone())
+        .bind(self.owner_id)
+        .fetch_optional(uow.conn())
         .await?;
 
         if let Some(label) = optional_label {
-            return Err(RepositoryError::Duplicate(label.id).into());
+            return Err(RepositoryError::Duplicate(label.id));
         }
 
         let label = sqlx::query_as::<_, Label>(indoc!(
             r#"
-                insert into labels ( name ) values ( $1 ) returning *
+                insert into labels ( name, color, description, owner_id )
+                    values ( $1, $2, $3, $4 )
+                returning *
             "#
         ))
-        .bind(name.clone())
-        .fetch_one(&self.pool)
-        .await?;
+        .bind(&payload.name)
+        .bind(payload.color)
+        .bind(payload.description)
+        .bind(self.owner_id)
+        .fetch_one(uow.conn())
+        .await;
 
-        Ok(label)
+        match label {
+            Ok(label) => Ok(label),
+            Err(e) => Err(self.duplicate_or(&payload.name, e).await),
+        }
+    }
+
+    async fn find(&self, id: i32) -> Result<Label, RepositoryError> {
+        let mut conn = self.pool.acquire().await?;
+        find_label(&mut conn, self.owner_id, id).await
     }
 
-    async fn all(&self) -> anyhow::Result<Vec<Label>> {
+    async fn all(&self) -> Result<Vec<Label>, RepositoryError> {
         let labels = sqlx::query_as::<_, Label>(indoc!(
             r#"
-                select * from labels order by labels.id asc
+                select * from labels where owner_id = $1 order by labels.id asc
             "#
         ))
+        .bind(self.owner_id)
         .fetch_all(&self.pool)
         .await?;
 
         Ok(labels)
     }
 
-    async fn delete(&self, id: i32) -> anyhow::Result<()> {
-        sqlx::query(indoc!(
-            r#"
-                delete from labels where id = $1
-            "#
-        ))
-        .bind(id)
-        .execute(&self.pool)
-        .await
-        .map_err(|e| match e {
-            sqlx::Error::RowNotFound => RepositoryError::NotFound(id),
-            _ => RepositoryError::Unexpected(e.to_string()),
-        })?;
+    async fn update(&self, id: i32, payload: UpdateLabel) -> Result<Label, RepositoryError> {
+        let mut uow = UnitOfWork::begin(&self.pool).await?;
+        let label = self.update_in(&mut uow, id, payload).await?;
+        uow.commit().await?;
+        Ok(label)
+    }
 
-        Ok(())
+    async fn delete(&self, id: i32, force: bool) -> Result<(), RepositoryError> {
+        let mut uow = UnitOfWork::begin(&self.pool).await?;
+        self.delete_in(&mut uow, id, force).await?;
+        uow.commit().await
     }
 }
 
@@ -90,6 +418,7 @@ impl LabelRepository for LabelRepositoryForDb {
 #[cfg(feature = "database-test")]
 mod test {
     use super::*;
+    use crate::repositories::user::test_owner;
     use dotenv::dotenv;
     use sqlx::PgPool;
     use std::env;
@@ -102,58 +431,324 @@ mod test {
             .await
             .unwrap_or_else(|_| panic!("fail connect database, url is [{}]", database_url));
 
-        let repository = LabelRepositoryForDb::new(pool);
+        let owner = test_owner(&pool, "label@example.com").await;
+        let repository = LabelRepositoryForDb::new(pool).for_owner(owner);
         let label_text = "test_label";
 
         // crate
         let label = repository
-            .create(label_text.to_string())
+            .create(CreateLabel::new(label_text.to_string()))
             .await
             .expect("[crate] returned Err]");
         assert_eq!(label.name, label_text);
 
+        // find
+        let found = repository
+            .find(label.id)
+            .await
+            .expect("[find] returned Err]");
+        assert_eq!(label, found);
+
         // all
         let labels = repository.all().await.expect("[all] returned Err]");
         let label = labels.last().unwrap();
         assert_eq!(label.name, label_text);
 
+        // update
+        let updated_text = "test_label updated";
+        let updated = repository
+            .update(
+                label.id,
+                UpdateLabel {
+                    name: Some(updated_text.to_string()),
+                    color: Some(Some("#ff0000".to_string())),
+                    description: Some(Some("description".to_string())),
+                },
+            )
+            .await
+            .expect("[update] returned Err]");
+        assert_eq!(
+            Label {
+                id: label.id,
+                name: updated_text.to_string(),
+                color: Some("#ff0000".to_string()),
+                description: Some("description".to_string()),
+                created_at: label.created_at,
+            },
+            updated
+        );
+        let label = &updated;
+
+        // null を指定した項目だけ消す
+        let payload: UpdateLabel = serde_json::from_str(r#"{ "color": null }"#).unwrap();
+        let cleared = repository
+            .update(label.id, payload)
+            .await
+            .expect("[update] clear color returned Err");
+        assert_eq!(None, cleared.color);
+        assert_eq!(label.description, cleared.description);
+
         // delete
         repository
-            .delete(label.id)
+            .delete(label.id, false)
+            .await
+            .expect("[delete] returned Err");
+        let res = repository.find(label.id).await;
+        assert!(res.is_err());
+    }
+
+    #[tokio::test]
+    async fn owner_scenario() {
+        dotenv().ok();
+        let database_url = &env::var("DATABASE_URL").expect("undefined [DATABASE_URL]");
+        let pool = PgPool::connect(database_url)
+            .await
+            .unwrap_or_else(|_| panic!("fail connect database, url is [{}]", database_url));
+
+        let owner = test_owner(&pool, "label@example.com").await;
+        let repository = LabelRepositoryForDb::new(pool.clone()).for_owner(owner);
+        let other = LabelRepositoryForDb::new(pool.clone())
+            .for_owner(test_owner(&pool, "other@example.com").await);
+        let label_text = "[owner_scenario] label";
+        let label = repository
+            .create(CreateLabel::new(label_text.to_string()))
+            .await
+            .expect("[create] returned Err");
+
+        // 他のユーザーのラベルは見えず、変更も削除もできない
+        let res = other.find(label.id).await;
+        assert!(matches!(res, Err(RepositoryError::NotFound(_))));
+        let labels = other.all().await.expect("[all] returned Err");
+        assert!(labels.iter().all(|other_label| other_label.id != label.id));
+        let res = other
+            .update(label.id, UpdateLabel::new(Some("stolen".to_string()), None))
+            .await;
+        assert!(matches!(res, Err(RepositoryError::NotFound(_))));
+        let res = other.delete(label.id, true).await;
+        assert!(matches!(res, Err(RepositoryError::NotFound(_))));
+
+        // 名前の重複はユーザーごとに確かめる
+        let res = repository
+            .create(CreateLabel::new(label_text.to_string()))
+            .await;
+        assert!(matches!(res, Err(RepositoryError::Duplicate(_))));
+        let other_label = other
+            .create(CreateLabel::new(label_text.to_string()))
+            .await
+            .expect("[create] label of other user returned Err");
+        assert_ne!(label.id, other_label.id);
+        assert_eq!(label, repository.find(label.id).await.unwrap());
+
+        repository.delete(label.id, true).await.unwrap();
+        other.delete(other_label.id, true).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn concurrent_rename_scenario() {
+        dotenv().ok();
+        let database_url = &env::var("DATABASE_URL").expect("undefined [DATABASE_URL]");
+        let pool = PgPool::connect(database_url)
+            .await
+            .unwrap_or_else(|_| panic!("fail connect database, url is [{}]", database_url));
+
+        let owner = test_owner(&pool, "label@example.com").await;
+        let repository = LabelRepositoryForDb::new(pool.clone()).for_owner(owner);
+        let mut labels = vec![];
+        for name in [
+            "[concurrent_rename_scenario] 1",
+            "[concurrent_rename_scenario] 2",
+        ] {
+            labels.push(
+                repository
+                    .create(CreateLabel::new(name.to_string()))
+                    .await
+                    .expect("[create] returned Err"),
+            );
+        }
+        let renamed = "[concurrent_rename_scenario] renamed";
+
+        // 1 つ目の変更を commit する前に、2 つ目を同じ名前に変える
+        let mut uow = UnitOfWork::begin(&pool).await.unwrap();
+        repository
+            .update_in(
+                &mut uow,
+                labels[0].id,
+                UpdateLabel::new(Some(renamed.to_string()), None),
+            )
+            .await
+            .expect("[update_in] returned Err");
+        let other = {
+            let repository = repository.clone();
+            let id = labels[1].id;
+            tokio::spawn(async move {
+                repository
+                    .update(id, UpdateLabel::new(Some(renamed.to_string()), None))
+                    .await
+            })
+        };
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        uow.commit().await.unwrap();
+
+        // 一意制約の違反は 500 ではなく、先に変えたラベルとの重複になる
+        let res = other.await.unwrap();
+        assert!(matches!(
+            res,
+            Err(RepositoryError::Duplicate(id)) if id == labels[0].id
+        ));
+
+        for label in labels {
+            repository.delete(label.id, true).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn delete_in_use_scenario() {
+        dotenv().ok();
+        let database_url = &env::var("DATABASE_URL").expect("undefined [DATABASE_URL]");
+        let pool = PgPool::connect(database_url)
+            .await
+            .unwrap_or_else(|_| panic!("fail connect database, url is [{}]", database_url));
+
+        let owner = test_owner(&pool, "label@example.com").await;
+        let repository = LabelRepositoryForDb::new(pool.clone()).for_owner(owner);
+        let label = repository
+            .create(CreateLabel::new(
+                "[delete_in_use_scenario] label".to_string(),
+            ))
+            .await
+            .expect("[crate] returned Err]");
+
+        // todo data prepare
+        let (todo_id,): (i32,) = sqlx::query_as(indoc!(
+            r#"
+                insert into todos (text, owner_id) values ('[delete_in_use_scenario] todo', $1)
+                returning id
+            "#
+        ))
+        .bind(owner)
+        .fetch_one(&pool)
+        .await
+        .expect("Faild insert todo data.");
+        sqlx::query(indoc!(
+            r#"
+                insert into todo_labels (todo_id, label_id) values ($1, $2)
+            "#
+        ))
+        .bind(todo_id)
+        .bind(label.id)
+        .execute(&pool)
+        .await
+        .expect("Faild insert todo_labels data.");
+
+        // delete (in use)
+        let err = repository
+            .delete(label.id, false)
+            .await
+            .expect_err("[delete] label in use is deleted");
+        assert!(matches!(
+            &err,
+            RepositoryError::InUse(todos) if *todos == vec![todo_id]
+        ));
+        assert!(repository.find(label.id).await.is_ok());
+
+        // 名前の変更は紐付いている Todo の履歴に残る
+        repository
+            .update(
+                label.id,
+                UpdateLabel::new(Some("[delete_in_use_scenario] renamed".to_string()), None),
+            )
+            .await
+            .expect("[update] returned Err");
+        let history = || {
+            let pool = pool.clone();
+            async move {
+                todo_event::history(&mut pool.acquire().await.unwrap(), owner, todo_id)
+                    .await
+                    .expect("[history] returned Err")
+            }
+        };
+        let events = history().await;
+        assert_eq!(1, events.len());
+        assert_eq!(TodoEventKind::Updated, events[0].kind);
+        assert_eq!(Some(owner), events[0].actor_id);
+        let changes = &events[0].changes["labels"];
+        assert_eq!(
+            serde_json::json!("[delete_in_use_scenario] label"),
+            changes.before[0]["name"]
+        );
+        assert_eq!(
+            serde_json::json!("[delete_in_use_scenario] renamed"),
+            changes.after[0]["name"]
+        );
+
+        // delete (force)
+        repository
+            .delete(label.id, true)
             .await
             .expect("[delete] returned Err");
+        assert!(repository.find(label.id).await.is_err());
+
+        let rows = sqlx::query(indoc!(
+            r#"
+                select * from todo_labels where todo_id = $1
+            "#
+        ))
+        .bind(todo_id)
+        .fetch_all(&pool)
+        .await
+        .expect("[delete] todo_labels fetch error");
+        assert!(rows.is_empty());
+
+        // 外した Todo の version は上がり、履歴に残る
+        let version: i32 = sqlx::query_scalar("select version from todos where id = $1")
+            .bind(todo_id)
+            .fetch_one(&pool)
+            .await
+            .expect("[delete] todos fetch error");
+        assert_eq!(3, version);
+        let events = history().await;
+        assert_eq!(2, events.len());
+        assert_eq!(TodoEventKind::Updated, events[1].kind);
+        let changes = &events[1].changes["labels"];
+        assert_eq!(serde_json::json!(label.id), changes.before[0]["id"]);
+        assert_eq!(serde_json::json!([]), changes.after);
+
+        // delete (not found)
+        let err = repository
+            .delete(label.id, false)
+            .await
+            .expect_err("[delete] deleted label is deleted again");
+        assert!(matches!(&err, RepositoryError::NotFound(_)));
     }
 }
 
 #[cfg(test)]
 pub mod test_utils {
     use super::*;
-    use anyhow::Context;
-    use std::{
-        collections::HashMap,
-        sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard},
+    use crate::repositories::test_utils::{
+        MemoryStore, MemoryTables, MemoryUnitOfWork, DEFAULT_OWNER,
     };
 
-    type LabelDatas = HashMap<i32, Label>;
-
     #[derive(Debug, Clone)]
     pub struct LabelRepositoryForMemory {
-        store: Arc<RwLock<LabelDatas>>,
+        store: MemoryStore,
+        owner_id: i32,
     }
 
     impl LabelRepositoryForMemory {
         pub fn new() -> Self {
-            LabelRepositoryForMemory {
-                store: Arc::default(),
-            }
+            Self::with_store(MemoryStore::default())
         }
 
-        fn write_store_ref(&self) -> RwLockWriteGuard<LabelDatas> {
-            self.store.write().unwrap()
+        pub fn with_store(store: MemoryStore) -> Self {
+            LabelRepositoryForMemory {
+                store,
+                owner_id: DEFAULT_OWNER,
+            }
         }
 
-        fn read_store_ref(&self) -> RwLockReadGuard<LabelDatas> {
-            self.store.read().unwrap()
+        fn owns(&self, tables: &MemoryTables, id: i32) -> bool {
+            tables.label_owners.get(&id) == Some(&self.owner_id)
         }
     }
 
@@ -163,33 +758,200 @@ pub mod test_utils {
         }
     }
 
+    impl CreateLabel {
+        pub fn new(name: String) -> Self {
+            Self {
+                name,
+                color: None,
+                description: None,
+            }
+        }
+    }
+
+    impl UpdateLabel {
+        pub fn new(name: Option<String>, color: Option<String>) -> Self {
+            Self {
+                name,
+                color: color.map(Some),
+                description: None,
+            }
+        }
+    }
+
+    // Db と同じく、ラベルを紐付けている Todo と、その Todo に紐付いているラベルを id 順に返す
+    fn todo_labels(tables: &MemoryTables, id: i32) -> Vec<(i32, Vec<Label>)> {
+        let mut todos: Vec<(i32, Vec<Label>)> = tables
+            .todos
+            .values()
+            .filter(|todo| todo.labels.iter().any(|label| label.id == id))
+            .map(|todo| (todo.id, labels_of(tables, todo.id)))
+            .collect();
+        todos.sort_by_key(|(todo_id, _)| *todo_id);
+        todos
+    }
+
+    fn labels_of(tables: &MemoryTables, todo_id: i32) -> Vec<Label> {
+        let mut labels: Vec<Label> = tables.todos[&todo_id]
+            .labels
+            .iter()
+            .filter_map(|label| tables.labels.get(&label.id).cloned())
+            .collect();
+        labels.sort_by_key(|label| label.id);
+        labels
+    }
+
+    // Db と同じく、ラベルを紐付けている Todo の version を上げて履歴に残す
+    fn touch_todos(tables: &mut MemoryTables, todos: Vec<(i32, Vec<Label>)>) {
+        for (todo_id, before) in todos {
+            if let Some(todo) = tables.todos.get_mut(&todo_id) {
+                todo.version += 1;
+            }
+            let after = labels_of(tables, todo_id);
+            tables.record_event(
+                todo_id,
+                TodoEventKind::Updated,
+                todo_event::label_diff(&before, &after),
+            );
+        }
+    }
+
+    // Db と同じく、同じユーザーの自分以外のラベルに同じ名前があれば重複とする
+    fn check_duplicate(
+        tables: &MemoryTables,
+        owner_id: i32,
+        name: &str,
+        id: Option<i32>,
+    ) -> Result<(), RepositoryError> {
+        match tables.labels.values().find(|label| {
+            label.name == name
+                && Some(label.id) != id
+                && tables.label_owners.get(&label.id) == Some(&owner_id)
+        }) {
+            Some(label) => Err(RepositoryError::Duplicate(label.id)),
+            None => Ok(()),
+        }
+    }
+
+    fn create_label(
+        tables: &mut MemoryTables,
+        owner_id: i32,
+        payload: CreateLabel,
+    ) -> Result<Label, RepositoryError> {
+        check_duplicate(tables, owner_id, &payload.name, None)?;
+        let id = (tables.labels.len() + 1) as i32;
+        let label = Label {
+            id,
+            name: payload.name,
+            color: payload.color,
+            description: payload.description,
+            created_at: tables.now,
+        };
+        tables.labels.insert(id, label.clone());
+        tables.label_owners.insert(id, owner_id);
+        Ok(label)
+    }
+
     #[async_trait]
     impl LabelRepository for LabelRepositoryForMemory {
-        async fn create(&self, name: String) -> anyhow::Result<Label> {
-            let mut store = self.write_store_ref();
-            let id = (store.len() + 1) as i32;
-            let label = Label {
-                id,
-                name: name.clone(),
-            };
-            store.insert(id, label.clone());
+        type UnitOfWork = MemoryUnitOfWork;
+
+        fn for_owner(&self, owner_id: i32) -> Self {
+            LabelRepositoryForMemory {
+                store: self.store.clone(),
+                owner_id,
+            }
+        }
+
+        async fn create(&self, payload: CreateLabel) -> Result<Label, RepositoryError> {
+            create_label(&mut self.store.write(), self.owner_id, payload)
+        }
+
+        async fn create_in(
+            &self,
+            uow: &mut MemoryUnitOfWork,
+            payload: CreateLabel,
+        ) -> Result<Label, RepositoryError> {
+            create_label(&mut uow.tables, self.owner_id, payload)
+        }
+
+        async fn find(&self, id: i32) -> Result<Label, RepositoryError> {
+            let tables = self.store.read();
+            let label = tables
+                .labels
+                .get(&id)
+                .filter(|_| self.owns(&tables, id))
+                .cloned()
+                .ok_or(RepositoryError::NotFound(id))?;
             Ok(label)
         }
 
-        async fn all(&self) -> anyhow::Result<Vec<Label>> {
-            Ok(Vec::from_iter(self.read_store_ref().values().cloned()))
+        async fn all(&self) -> Result<Vec<Label>, RepositoryError> {
+            let tables = self.store.read();
+            let labels = tables
+                .labels
+                .values()
+                .filter(|label| self.owns(&tables, label.id))
+                .cloned();
+            Ok(Vec::from_iter(labels))
+        }
+
+        async fn update(&self, id: i32, payload: UpdateLabel) -> Result<Label, RepositoryError> {
+            let mut tables = self.store.write();
+            let mut label = tables
+                .labels
+                .get(&id)
+                .filter(|_| self.owns(&tables, id))
+                .ok_or(RepositoryError::NotFound(id))?
+                .clone();
+            if let Some(name) = payload.name {
+                check_duplicate(&tables, self.owner_id, &name, Some(id))?;
+                label.name = name;
+            }
+            if let Some(color) = payload.color {
+                label.color = color;
+            }
+            if let Some(description) = payload.description {
+                label.description = description;
+            }
+            let todos = todo_labels(&tables, id);
+            tables.labels.insert(id, label.clone());
+            touch_todos(&mut tables, todos);
+            Ok(label)
         }
 
-        async fn delete(&self, id: i32) -> anyhow::Result<()> {
-            self.write_store_ref()
-                .remove(&id)
-                .context(RepositoryError::NotFound(id))?;
+        // 書き込みロックを保持したまま確認と削除を行う
+        async fn delete(&self, id: i32, force: bool) -> Result<(), RepositoryError> {
+            let mut tables = self.store.write();
+            if !self.owns(&tables, id) {
+                return Err(RepositoryError::NotFound(id));
+            }
+
+            let todos = todo_labels(&tables, id);
+            if !todos.is_empty() && !force {
+                return Err(RepositoryError::InUse(
+                    todos.into_iter().map(|(todo_id, _)| todo_id).collect(),
+                ));
+            }
+
+            for todo in tables.todos.values_mut() {
+                todo.labels.retain(|label| label.id != id);
+            }
+            tables.labels.remove(&id);
+            tables.label_owners.remove(&id);
+            touch_todos(&mut tables, todos);
             Ok(())
         }
     }
 
     mod test {
-        use super::{Label, LabelRepository, LabelRepositoryForMemory};
+        use super::{CreateLabel, Label, LabelRepository, LabelRepositoryForMemory, UpdateLabel};
+        use crate::repositories::{
+            test_utils::{MemoryStore, DEFAULT_OWNER},
+            todo::{test_utils::TodoRepositoryForMemory, CreateTodo, TodoRepository},
+            todo_event::TodoEventKind,
+            RepositoryError,
+        };
+        use serde_json::json;
 
         #[tokio::test]
         async fn label_crud_scenario() {
@@ -199,18 +961,248 @@ pub mod test_utils {
 
             // create
             repository
-                .create(name.clone())
+                .create(CreateLabel::new(name.clone()))
                 .await
                 .expect("failed create label");
 
+            // find
+            assert_eq!(
+                Label {
+                    id,
+                    name: name.clone(),
+                    color: None,
+                    description: None,
+                    created_at: Default::default(),
+                },
+                repository.find(id).await.expect("failed find label")
+            );
+
+            // update
+            let name = "test2".to_string();
+            let color = Some("#00ff00".to_string());
+            assert_eq!(
+                Label {
+                    id,
+                    name: name.clone(),
+                    color: color.clone(),
+                    description: None,
+                    created_at: Default::default(),
+                },
+                repository
+                    .update(id, UpdateLabel::new(Some(name.clone()), color.clone()))
+                    .await
+                    .expect("failed update label")
+            );
+
             // all
             assert_eq!(
-                [Label { id, name }].to_vec(),
+                [Label {
+                    id,
+                    name,
+                    color,
+                    description: None,
+                    created_at: Default::default(),
+                }]
+                .to_vec(),
                 repository.all().await.expect("faild get all label")
             );
 
             // delete
-            assert!(repository.delete(id).await.is_ok());
+            assert!(repository.delete(id, false).await.is_ok());
+        }
+
+        #[tokio::test]
+        async fn label_clear_scenario() {
+            let repository = LabelRepositoryForMemory::new();
+            let mut payload = CreateLabel::new("label".to_string());
+            payload.color = Some("#00ff00".to_string());
+            payload.description = Some("description".to_string());
+            let label = repository.create(payload).await.unwrap();
+
+            // キーがなければそのまま、null なら消す
+            let payload: UpdateLabel = serde_json::from_str(r#"{ "name": "renamed" }"#).unwrap();
+            let label = repository.update(label.id, payload).await.unwrap();
+            assert_eq!("renamed", label.name);
+            assert_eq!(Some("#00ff00".to_string()), label.color);
+            assert_eq!(Some("description".to_string()), label.description);
+
+            let payload: UpdateLabel =
+                serde_json::from_str(r#"{ "color": null, "description": null }"#).unwrap();
+            let label = repository.update(label.id, payload).await.unwrap();
+            assert_eq!("renamed", label.name);
+            assert_eq!(None, label.color);
+            assert_eq!(None, label.description);
+        }
+
+        #[tokio::test]
+        async fn label_delete_in_use_scenario() {
+            let store = MemoryStore::default();
+            let repository = LabelRepositoryForMemory::with_store(store.clone());
+            let todo_repository = TodoRepositoryForMemory::with_store(store);
+            let label = repository
+                .create(CreateLabel::new("label".to_string()))
+                .await
+                .expect("failed create label");
+            let todo = todo_repository
+                .create(CreateTodo::new("todo".to_string(), vec![label.id]))
+                .await
+                .expect("failed create todo");
+
+            let err = repository
+                .delete(label.id, false)
+                .await
+                .expect_err("label in use is deleted");
+            assert!(matches!(
+                &err,
+                RepositoryError::InUse(todos) if *todos == vec![todo.id]
+            ));
+            assert!(repository.find(label.id).await.is_ok());
+
+            // ラベルの変更は紐付いている Todo の version を上げる
+            repository
+                .update(
+                    label.id,
+                    UpdateLabel::new(Some("renamed".to_string()), None),
+                )
+                .await
+                .expect("failed update label");
+            assert_eq!(2, todo_repository.find(todo.id).await.unwrap().version);
+            // 紐付いている Todo の履歴にも、誰がどう変えたかを残す
+            let event = todo_repository
+                .history(todo.id)
+                .await
+                .unwrap()
+                .pop()
+                .unwrap();
+            assert_eq!(TodoEventKind::Updated, event.kind);
+            assert_eq!(Some(DEFAULT_OWNER), event.actor_id);
+            assert_eq!(json!("label"), event.changes["labels"].before[0]["name"]);
+            assert_eq!(json!("renamed"), event.changes["labels"].after[0]["name"]);
+
+            repository
+                .delete(label.id, true)
+                .await
+                .expect("failed delete label");
+            assert!(repository.find(label.id).await.is_err());
+            let todo = todo_repository.find(todo.id).await.unwrap();
+            assert!(todo.labels.is_empty());
+            assert_eq!(3, todo.version);
+            let event = todo_repository
+                .history(todo.id)
+                .await
+                .unwrap()
+                .pop()
+                .unwrap();
+            assert_eq!(TodoEventKind::Updated, event.kind);
+            assert_eq!(json!(label.id), event.changes["labels"].before[0]["id"]);
+            assert_eq!(json!([]), event.changes["labels"].after);
+
+            let err = repository
+                .delete(label.id, false)
+                .await
+                .expect_err("deleted label is deleted again");
+            assert!(matches!(&err, RepositoryError::NotFound(_)));
+        }
+
+        #[tokio::test]
+        async fn label_duplicate_scenario() {
+            let repository = LabelRepositoryForMemory::new();
+            let label_1 = repository
+                .create(CreateLabel::new("label 1".to_string()))
+                .await
+                .expect("failed create label");
+            let label_2 = repository
+                .create(CreateLabel::new("label 2".to_string()))
+                .await
+                .expect("failed create label");
+
+            let err = repository
+                .create(CreateLabel::new("label 1".to_string()))
+                .await
+                .expect_err("duplicate label is created");
+            assert!(matches!(
+                &err,
+                RepositoryError::Duplicate(id) if *id == label_1.id
+            ));
+
+            let err = repository
+                .update(
+                    label_2.id,
+                    UpdateLabel::new(Some("label 1".to_string()), None),
+                )
+                .await
+                .expect_err("duplicate label is updated");
+            assert!(matches!(
+                &err,
+                RepositoryError::Duplicate(id) if *id == label_1.id
+            ));
+
+            // 自分自身の名前はそのまま使える
+            assert!(repository
+                .update(
+                    label_1.id,
+                    UpdateLabel::new(Some("label 1".to_string()), None)
+                )
+                .await
+                .is_ok());
+        }
+
+        #[tokio::test]
+        async fn label_owner_scenario() {
+            let store = MemoryStore::default();
+            let repository = LabelRepositoryForMemory::with_store(store.clone());
+            let other = repository.for_owner(2);
+            let label = repository
+                .create(CreateLabel::new("label".to_string()))
+                .await
+                .expect("failed create label");
+
+            // 他のユーザーのラベルは見えず、変更も削除もできない
+            assert!(matches!(
+                other.find(label.id).await,
+                Err(RepositoryError::NotFound(_))
+            ));
+            assert!(other.all().await.unwrap().is_empty());
+            let res = other
+                .update(label.id, UpdateLabel::new(Some("x".to_string()), None))
+                .await;
+            assert!(matches!(res, Err(RepositoryError::NotFound(_))));
+            let res = other.delete(label.id, true).await;
+            assert!(matches!(res, Err(RepositoryError::NotFound(_))));
+
+            // 名前の重複はユーザーごとに確かめる
+            let other_label = other
+                .create(CreateLabel::new("label".to_string()))
+                .await
+                .expect("failed create label of other user");
+            assert_ne!(label.id, other_label.id);
+            assert_eq!(vec![label.clone()], repository.all().await.unwrap());
+            assert_eq!(vec![other_label], other.all().await.unwrap());
+            assert_eq!(label, repository.find(label.id).await.unwrap());
+        }
+
+        #[test]
+        fn label_validation() {
+            use validator::Validate;
+
+            let mut payload = CreateLabel::new("label".to_string());
+            assert!(payload.validate().is_ok());
+            payload.color = Some("#12abEF".to_string());
+            assert!(payload.validate().is_ok());
+            for color in ["red", "#12345", "#1234567", "#gggggg"] {
+                payload.color = Some(color.to_string());
+                assert!(payload.validate().is_err());
+            }
+
+            assert!(UpdateLabel::new(None, None).validate().is_ok());
+            assert!(UpdateLabel::new(Some("".to_string()), None)
+                .validate()
+                .is_err());
+            assert!(UpdateLabel::new(None, Some("blue".to_string()))
+                .validate()
+                .is_err());
+            let payload: UpdateLabel = serde_json::from_str(r#"{ "color": null }"#).unwrap();
+            assert!(payload.validate().is_ok());
         }
     }
 }
@@ -0,0 +1,359 @@
+use super::due::Due;
+use chrono::{DateTime, Datelike, NaiveDate, NaiveDateTime, NaiveTime, Utc, Weekday};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::{fmt, str::FromStr};
+
+/// 次の日付を探す範囲。これを超えても見つからなければ繰り返しを終える
+const MAX_SCAN_DAYS: u32 = 366 * 10;
+const MAX_INTERVAL: u32 = 99;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Frequency {
+    Daily,
+    Weekly,
+    Monthly,
+}
+
+/// iCalendar (RFC 5545) の RRULE のうち FREQ (DAILY / WEEKLY / MONTHLY), INTERVAL,
+/// BYDAY (序数なし), COUNT, UNTIL に対応したもの
+/// `"FREQ=WEEKLY;BYDAY=MO,TH;COUNT=10"` のような文字列でやり取りする
+///
+/// 曜日や日付は、期限が日時の場合も UTC の日付で数える
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RRule {
+    freq: Frequency,
+    interval: u32,
+    by_day: Vec<Weekday>,
+    count: Option<u32>,
+    until: Option<Due>,
+}
+
+/// Todo が属する繰り返しの系列
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Recurrence {
+    pub series_id: i32,
+    /// 系列の何番目か (1 始まり)
+    pub occurrence: i32,
+    /// 繰り返しを止めた系列では None
+    pub rrule: Option<RRule>,
+}
+
+impl RRule {
+    /// occurrence 番目の期限が current のとき、次の期限を返す
+    /// COUNT や UNTIL を超える場合は None を返す
+    /// 日時の期限はオフセットを持たないため、UTC の日付と時刻のまま次の日に進める
+    pub fn next(&self, occurrence: i32, current: Due) -> Option<Due> {
+        if self.count.is_some_and(|count| occurrence >= count as i32) {
+            return None;
+        }
+        let next = match current {
+            Due::Date(date) => Due::Date(self.next_date(date)?),
+            Due::At(at) => {
+                let date = self.next_date(at.date_naive())?;
+                Due::At(date.and_time(at.time()).and_utc())
+            }
+        };
+        match self.until {
+            Some(until) if start_of(next) > end_of(until) => None,
+            _ => Some(next),
+        }
+    }
+
+    fn next_date(&self, anchor: NaiveDate) -> Option<NaiveDate> {
+        anchor
+            .iter_days()
+            .skip(1)
+            .take(MAX_SCAN_DAYS as usize)
+            .find(|date| self.in_period(anchor, *date) && self.on_day(anchor, *date))
+    }
+
+    /// date が INTERVAL ごとの日・週・月に入っているか
+    fn in_period(&self, anchor: NaiveDate, date: NaiveDate) -> bool {
+        let periods = match self.freq {
+            Frequency::Daily => (date - anchor).num_days(),
+            Frequency::Weekly => (week_start(date) - week_start(anchor)).num_days() / 7,
+            Frequency::Monthly => {
+                i64::from(date.year() * 12 + date.month() as i32)
+                    - i64::from(anchor.year() * 12 + anchor.month() as i32)
+            }
+        };
+        periods % i64::from(self.interval) == 0
+    }
+
+    /// BYDAY がなければ、最初の期限と同じ曜日 (WEEKLY) や日 (MONTHLY) を使う
+    fn on_day(&self, anchor: NaiveDate, date: NaiveDate) -> bool {
+        if !self.by_day.is_empty() {
+            return self.by_day.contains(&date.weekday());
+        }
+        match self.freq {
+            Frequency::Daily => true,
+            Frequency::Weekly => date.weekday() == anchor.weekday(),
+            // 31 日のように存在しない月は飛ばす
+            Frequency::Monthly => date.day() == anchor.day(),
+        }
+    }
+}
+
+fn week_start(date: NaiveDate) -> NaiveDate {
+    date - chrono::Days::new(date.weekday().num_days_from_monday() as u64)
+}
+
+fn start_of(due: Due) -> NaiveDateTime {
+    match due {
+        Due::Date(date) => date.and_time(NaiveTime::MIN),
+        Due::At(at) => at.naive_utc(),
+    }
+}
+
+fn end_of(due: Due) -> NaiveDateTime {
+    match due {
+        Due::Date(date) => date.and_hms_opt(23, 59, 59).unwrap(),
+        Due::At(at) => at.naive_utc(),
+    }
+}
+
+const WEEKDAYS: [(&str, Weekday); 7] = [
+    ("MO", Weekday::Mon),
+    ("TU", Weekday::Tue),
+    ("WE", Weekday::Wed),
+    ("TH", Weekday::Thu),
+    ("FR", Weekday::Fri),
+    ("SA", Weekday::Sat),
+    ("SU", Weekday::Sun),
+];
+
+impl FromStr for RRule {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let rule = value.strip_prefix("RRULE:").unwrap_or(value);
+        let mut freq = None;
+        let mut rrule = RRule {
+            freq: Frequency::Daily,
+            interval: 1,
+            by_day: vec![],
+            count: None,
+            until: None,
+        };
+        for part in rule.split(';') {
+            let (key, value) = part
+                .split_once('=')
+                .ok_or_else(|| format!("rrule part must be KEY=VALUE: {}", part))?;
+            match key.to_ascii_uppercase().as_str() {
+                "FREQ" => {
+                    freq = Some(match value.to_ascii_uppercase().as_str() {
+                        "DAILY" => Frequency::Daily,
+                        "WEEKLY" => Frequency::Weekly,
+                        "MONTHLY" => Frequency::Monthly,
+                        _ => {
+                            return Err(format!("FREQ must be DAILY, WEEKLY or MONTHLY: {}", value))
+                        }
+                    })
+                }
+                "INTERVAL" => {
+                    rrule.interval = value
+                        .parse()
+                        .ok()
+                        .filter(|interval| (1..=MAX_INTERVAL).contains(interval))
+                        .ok_or_else(|| {
+                            format!("INTERVAL must be 1 to {}: {}", MAX_INTERVAL, value)
+                        })?;
+                }
+                "BYDAY" => {
+                    for day in value.split(',') {
+                        let weekday = WEEKDAYS
+                            .iter()
+                            .find(|(name, _)| name.eq_ignore_ascii_case(day))
+                            .map(|(_, weekday)| *weekday)
+                            .ok_or_else(|| format!("BYDAY must be MO to SU: {}", day))?;
+                        if !rrule.by_day.contains(&weekday) {
+                            rrule.by_day.push(weekday);
+                        }
+                    }
+                }
+                "COUNT" => {
+                    rrule.count = Some(
+                        value
+                            .parse()
+                            .ok()
+                            .filter(|count| *count >= 1)
+                            .ok_or_else(|| format!("COUNT must be positive: {}", value))?,
+                    );
+                }
+                "UNTIL" => rrule.until = Some(parse_until(value)?),
+                _ => return Err(format!("unsupported rrule part: {}", key)),
+            }
+        }
+        rrule.freq = freq.ok_or("rrule must have FREQ")?;
+        if rrule.count.is_some() && rrule.until.is_some() {
+            return Err("rrule can not have both COUNT and UNTIL".to_string());
+        }
+        Ok(rrule)
+    }
+}
+
+/// `20241231` または `20241231T235959Z`
+fn parse_until(value: &str) -> Result<Due, String> {
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y%m%d") {
+        return Ok(Due::Date(date));
+    }
+    NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%SZ")
+        .map(|at| Due::At(at.and_utc()))
+        .map_err(|_| format!("UNTIL must be YYYYMMDD or YYYYMMDDTHHMMSSZ: {}", value))
+}
+
+impl fmt::Display for RRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let freq = match self.freq {
+            Frequency::Daily => "DAILY",
+            Frequency::Weekly => "WEEKLY",
+            Frequency::Monthly => "MONTHLY",
+        };
+        write!(f, "FREQ={}", freq)?;
+        if self.interval != 1 {
+            write!(f, ";INTERVAL={}", self.interval)?;
+        }
+        if !self.by_day.is_empty() {
+            let days: Vec<&str> = self
+                .by_day
+                .iter()
+                .filter_map(|day| WEEKDAYS.iter().find(|(_, weekday)| weekday == day))
+                .map(|(name, _)| *name)
+                .collect();
+            write!(f, ";BYDAY={}", days.join(","))?;
+        }
+        if let Some(count) = self.count {
+            write!(f, ";COUNT={}", count)?;
+        }
+        match self.until {
+            Some(Due::Date(date)) => write!(f, ";UNTIL={}", date.format("%Y%m%d"))?,
+            Some(Due::At(at)) => write!(f, ";UNTIL={}", at.format("%Y%m%dT%H%M%SZ"))?,
+            None => {}
+        }
+        Ok(())
+    }
+}
+
+impl Serialize for RRule {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for RRule {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+/// 次の回の通知日時。期限との間隔を保ったままずらす
+pub fn shift_remind_at(
+    remind_at: Option<DateTime<Utc>>,
+    current: Due,
+    next: Due,
+) -> Option<DateTime<Utc>> {
+    remind_at.map(|remind_at| remind_at + (start_of(next) - start_of(current)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn date(month: u32, day: u32) -> Due {
+        Due::Date(NaiveDate::from_ymd_opt(2024, month, day).unwrap())
+    }
+
+    fn occurrences(rule: &str, first: Due, n: usize) -> Vec<Due> {
+        let rule: RRule = rule.parse().unwrap();
+        let mut dues = vec![first];
+        while dues.len() < n {
+            match rule.next(dues.len() as i32, *dues.last().unwrap()) {
+                Some(next) => dues.push(next),
+                None => break,
+            }
+        }
+        dues
+    }
+
+    #[test]
+    fn should_parse_rrule() {
+        let rule: RRule = "RRULE:freq=weekly;byday=mo,th,mo;interval=2;count=3"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            "FREQ=WEEKLY;INTERVAL=2;BYDAY=MO,TH;COUNT=3",
+            rule.to_string()
+        );
+        let rule: RRule = "FREQ=DAILY;UNTIL=20241231T235959Z".parse().unwrap();
+        assert_eq!("FREQ=DAILY;UNTIL=20241231T235959Z", rule.to_string());
+
+        for rule in [
+            "",
+            "BYDAY=MO",
+            "FREQ=YEARLY",
+            "FREQ=DAILY;INTERVAL=0",
+            "FREQ=WEEKLY;BYDAY=1MO",
+            "FREQ=DAILY;COUNT=0",
+            "FREQ=DAILY;COUNT=2;UNTIL=20241231",
+            "FREQ=DAILY;UNTIL=2024-12-31",
+            "FREQ=DAILY;BYHOUR=9",
+        ] {
+            assert!(rule.parse::<RRule>().is_err(), "{}", rule);
+        }
+    }
+
+    #[test]
+    fn should_compute_next_occurrence() {
+        // 2024-10-01 は火曜日
+        assert_eq!(
+            vec![date(10, 1), date(10, 3), date(10, 5)],
+            occurrences("FREQ=DAILY;INTERVAL=2", date(10, 1), 3)
+        );
+        assert_eq!(
+            vec![date(10, 1), date(10, 8), date(10, 15)],
+            occurrences("FREQ=WEEKLY", date(10, 1), 3)
+        );
+        assert_eq!(
+            vec![date(10, 1), date(10, 3), date(10, 14), date(10, 17)],
+            occurrences("FREQ=WEEKLY;INTERVAL=2;BYDAY=MO,TH", date(10, 1), 4)
+        );
+        assert_eq!(
+            vec![
+                Due::Date(NaiveDate::from_ymd_opt(2024, 1, 31).unwrap()),
+                date(3, 31),
+                date(5, 31)
+            ],
+            occurrences(
+                "FREQ=MONTHLY",
+                Due::Date(NaiveDate::from_ymd_opt(2024, 1, 31).unwrap()),
+                3
+            )
+        );
+        assert_eq!(
+            vec![date(10, 1), date(10, 2)],
+            occurrences("FREQ=DAILY;COUNT=2", date(10, 1), 5)
+        );
+        assert_eq!(
+            vec![date(10, 1), date(10, 8)],
+            occurrences("FREQ=WEEKLY;UNTIL=20241014", date(10, 1), 5)
+        );
+
+        // 日時の期限は時刻を保つ
+        let at = |day: u32| Due::At(Utc.with_ymd_and_hms(2024, 10, day, 9, 30, 0).unwrap());
+        assert_eq!(
+            vec![at(1), at(2)],
+            occurrences("FREQ=DAILY;UNTIL=20241002T093000Z", at(1), 5)
+        );
+        assert_eq!(
+            Some(Utc.with_ymd_and_hms(2024, 10, 7, 8, 0, 0).unwrap()),
+            shift_remind_at(
+                Some(Utc.with_ymd_and_hms(2024, 9, 30, 8, 0, 0).unwrap()),
+                date(10, 1),
+                date(10, 8)
+            )
+        );
+    }
+}
@@ -0,0 +1,353 @@
+use super::{due::Due, RepositoryError};
+use axum::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+use indoc::indoc;
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+
+#[async_trait]
+pub trait ReminderRepository: Clone + std::marker::Send + std::marker::Sync + 'static {
+    /// remind_at が now 以前で未送信のリマインダーを、remind_at の古い順に取り出す
+    /// 完了した Todo とゴミ箱にある Todo、次に試みる日時が来ていないものは対象外
+    /// 取り出したものは until まで取り出せなくなるため、複数のスケジューラで二重に送らない
+    /// 送信中に落ちても、until を過ぎればもう一度取り出せる
+    /// 送り先を所有者ごとに分けられるよう、所有者のメールアドレスも返す
+    async fn claim(
+        &self,
+        now: DateTime<Utc>,
+        until: DateTime<Utc>,
+        limit: i64,
+    ) -> Result<Vec<Reminder>, RepositoryError>;
+    /// 送信済みにする。既に送信済みの場合や、取得後に remind_at が変わった場合は false を返す
+    async fn mark_delivered(
+        &self,
+        reminder: &Reminder,
+        at: DateTime<Utc>,
+    ) -> Result<bool, RepositoryError>;
+    /// 送信に失敗したものを、at まで取り出さないようにする
+    /// 既に送信済みの場合や、取得後に remind_at が変わった場合は false を返す
+    async fn defer(&self, reminder: &Reminder, at: DateTime<Utc>) -> Result<bool, RepositoryError>;
+}
+
+/// 送信するリマインダーの内容
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Reminder {
+    pub todo_id: i32,
+    /// Todo の所有者のメールアドレス。リマインダーはこの宛先に送る
+    pub owner_email: String,
+    pub text: String,
+    pub remind_at: DateTime<Utc>,
+    pub due_at: Option<Due>,
+    /// 送信を試みた回数。今回の分を含む
+    pub attempts: i32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, FromRow)]
+struct ReminderFromRow {
+    id: i32,
+    email: String,
+    text: String,
+    remind_at: DateTime<Utc>,
+    due_date: Option<NaiveDate>,
+    due_at: Option<DateTime<Utc>>,
+    remind_attempts: i32,
+}
+
+impl From<ReminderFromRow> for Reminder {
+    fn from(row: ReminderFromRow) -> Self {
+        Reminder {
+            todo_id: row.id,
+            owner_email: row.email,
+            text: row.text,
+            remind_at: row.remind_at,
+            due_at: Due::from_columns(row.due_date, row.due_at),
+            attempts: row.remind_attempts,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ReminderRepositoryForDb {
+    pool: PgPool,
+}
+
+impl ReminderRepositoryForDb {
+    pub fn new(pool: PgPool) -> Self {
+        ReminderRepositoryForDb { pool }
+    }
+}
+
+#[async_trait]
+impl ReminderRepository for ReminderRepositoryForDb {
+    async fn claim(
+        &self,
+        now: DateTime<Utc>,
+        until: DateTime<Utc>,
+        limit: i64,
+    ) -> Result<Vec<Reminder>, RepositoryError> {
+        // 他のスケジューラが取り出している最中の行は飛ばす
+        let rows = sqlx::query_as::<_, ReminderFromRow>(indoc!(
+            r#"
+                update todos set remind_attempts = remind_attempts + 1,
+                        remind_next_attempt_at = $2
+                    from users
+                    where users.id = todos.owner_id and todos.id in (
+                        select id from todos
+                            where remind_at <= $1 and reminded_at is null
+                                and deleted_at is null and not completed
+                                and (remind_next_attempt_at is null
+                                    or remind_next_attempt_at <= $1)
+                            order by remind_at, id
+                            limit $3
+                            for update skip locked
+                    )
+                returning todos.id, users.email, todos.text, todos.remind_at,
+                    todos.due_date, todos.due_at, todos.remind_attempts
+            "#
+        ))
+        .bind(now)
+        .bind(until)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut reminders: Vec<Reminder> = rows.into_iter().map(Reminder::from).collect();
+        reminders.sort_by_key(|reminder| (reminder.remind_at, reminder.todo_id));
+        Ok(reminders)
+    }
+
+    async fn mark_delivered(
+        &self,
+        reminder: &Reminder,
+        at: DateTime<Utc>,
+    ) -> Result<bool, RepositoryError> {
+        // 表現が変わるため version も進め、ETag を変える
+        let res = sqlx::query(indoc!(
+            r#"
+                update todos set reminded_at = $3, version = version + 1
+                    where id = $1 and remind_at = $2 and reminded_at is null
+            "#
+        ))
+        .bind(reminder.todo_id)
+        .bind(reminder.remind_at)
+        .bind(at)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(res.rows_affected() == 1)
+    }
+
+    async fn defer(&self, reminder: &Reminder, at: DateTime<Utc>) -> Result<bool, RepositoryError> {
+        let res = sqlx::query(indoc!(
+            r#"
+                update todos set remind_next_attempt_at = $3
+                    where id = $1 and remind_at = $2 and reminded_at is null
+            "#
+        ))
+        .bind(reminder.todo_id)
+        .bind(reminder.remind_at)
+        .bind(at)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(res.rows_affected() == 1)
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "database-test")]
+mod test {
+    use super::*;
+    use crate::repositories::todo::{CreateTodo, TodoRepository, TodoRepositoryForDb};
+    use crate::repositories::user::test_owner;
+    use chrono::{Duration, SubsecRound};
+    use dotenv::dotenv;
+    use std::env;
+
+    #[tokio::test]
+    async fn reminder_scenario() {
+        dotenv().ok();
+        let database_url = &env::var("DATABASE_URL").expect("undefined [DATABASE_URL");
+        let pool = PgPool::connect(database_url)
+            .await
+            .unwrap_or_else(|_| panic!("fail connect database, url is [{}]", database_url));
+
+        let owner = test_owner(&pool, "reminder@example.com").await;
+        let todo_repository = TodoRepositoryForDb::new(pool.clone()).for_owner(owner);
+        let repository = ReminderRepositoryForDb::new(pool.clone());
+        // 他のテストのリマインダーと混ざらないよう、遠い未来の日時を使う
+        // Postgres はマイクロ秒までしか持たないため切り捨てておく
+        let now = (Utc::now() + Duration::days(365 * 100)).trunc_subsecs(6);
+        let create = |text: &str, remind_at: DateTime<Utc>| {
+            let todo_repository = todo_repository.clone();
+            let payload = CreateTodo::new(format!("[reminder_scenario] {}", text), vec![])
+                .with_remind_at(remind_at);
+            async move { todo_repository.create(payload).await.unwrap() }
+        };
+        let later = create("later", now - Duration::minutes(1)).await;
+        let earlier = create("earlier", now - Duration::minutes(2)).await;
+        let future = create("future", now + Duration::minutes(1)).await;
+
+        let ids = [later.id, earlier.id, future.id];
+        let claim = |now: DateTime<Utc>| {
+            let repository = repository.clone();
+            async move {
+                let reminders = repository
+                    .claim(now, now + Duration::minutes(5), 100)
+                    .await
+                    .unwrap();
+                reminders
+                    .into_iter()
+                    .filter(|reminder| ids.contains(&reminder.todo_id))
+                    .collect::<Vec<_>>()
+            }
+        };
+        let claimed = claim(now).await;
+        assert_eq!(
+            vec![(earlier.id, 1), (later.id, 1)],
+            claimed
+                .iter()
+                .map(|reminder| (reminder.todo_id, reminder.attempts))
+                .collect::<Vec<_>>()
+        );
+        assert!(claimed
+            .iter()
+            .all(|reminder| reminder.owner_email == "reminder@example.com"));
+
+        // 取り出している間は、他のスケジューラからは取り出せない
+        assert!(claim(now).await.is_empty());
+
+        // 一度だけ送信済みにできる
+        let reminder = &claimed[0];
+        assert!(repository.mark_delivered(reminder, now).await.unwrap());
+        assert!(!repository.mark_delivered(reminder, now).await.unwrap());
+        assert!(!repository.defer(reminder, now).await.unwrap());
+        let todo = todo_repository.find(earlier.id).await.unwrap();
+        assert_eq!(Some(now), todo.reminded_at);
+        assert_eq!(earlier.version + 1, todo.version);
+
+        // 失敗したものは、指定した日時が来るまで取り出さない
+        let reminder = &claimed[1];
+        assert!(repository
+            .defer(reminder, now + Duration::minutes(1))
+            .await
+            .unwrap());
+        assert!(claim(now).await.is_empty());
+        let claimed = claim(now + Duration::minutes(1)).await;
+        assert_eq!(
+            vec![(later.id, 2), (future.id, 1)],
+            claimed
+                .iter()
+                .map(|reminder| (reminder.todo_id, reminder.attempts))
+                .collect::<Vec<_>>()
+        );
+
+        for todo in [later, earlier, future] {
+            todo_repository.purge(todo.id, None).await.unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test_utils {
+    use super::*;
+    use crate::repositories::test_utils::MemoryStore;
+
+    #[derive(Debug, Clone)]
+    pub struct ReminderRepositoryForMemory {
+        store: MemoryStore,
+    }
+
+    impl ReminderRepositoryForMemory {
+        pub fn with_store(store: MemoryStore) -> Self {
+            ReminderRepositoryForMemory { store }
+        }
+    }
+
+    #[async_trait]
+    impl ReminderRepository for ReminderRepositoryForMemory {
+        async fn claim(
+            &self,
+            now: DateTime<Utc>,
+            until: DateTime<Utc>,
+            limit: i64,
+        ) -> Result<Vec<Reminder>, RepositoryError> {
+            let mut tables = self.store.write();
+            let tables = &mut *tables;
+            let mut reminders: Vec<Reminder> = tables
+                .todos
+                .values()
+                .filter(|todo| {
+                    todo.reminded_at.is_none() && todo.deleted_at.is_none() && !todo.completed
+                })
+                .filter(|todo| {
+                    tables
+                        .reminder_attempts
+                        .get(&todo.id)
+                        .is_none_or(|(_, next_attempt_at)| *next_attempt_at <= now)
+                })
+                .filter_map(|todo| {
+                    let remind_at = todo.remind_at.filter(|remind_at| *remind_at <= now)?;
+                    let (owner, _) = tables.users.get(tables.todo_owners.get(&todo.id)?)?;
+                    let attempts = tables
+                        .reminder_attempts
+                        .get(&todo.id)
+                        .map_or(0, |(attempts, _)| *attempts);
+                    Some(Reminder {
+                        todo_id: todo.id,
+                        owner_email: owner.email.clone(),
+                        text: todo.text.clone(),
+                        remind_at,
+                        due_at: todo.due_at,
+                        attempts: attempts + 1,
+                    })
+                })
+                .collect();
+            reminders.sort_by_key(|reminder| (reminder.remind_at, reminder.todo_id));
+            reminders.truncate(limit as usize);
+            for reminder in &reminders {
+                tables
+                    .reminder_attempts
+                    .insert(reminder.todo_id, (reminder.attempts, until));
+            }
+            Ok(reminders)
+        }
+
+        async fn mark_delivered(
+            &self,
+            reminder: &Reminder,
+            at: DateTime<Utc>,
+        ) -> Result<bool, RepositoryError> {
+            let mut tables = self.store.write();
+            let todo = tables.todos.get_mut(&reminder.todo_id).filter(|todo| {
+                todo.remind_at == Some(reminder.remind_at) && todo.reminded_at.is_none()
+            });
+            match todo {
+                Some(todo) => {
+                    todo.reminded_at = Some(at);
+                    todo.version += 1;
+                    Ok(true)
+                }
+                None => Ok(false),
+            }
+        }
+
+        async fn defer(
+            &self,
+            reminder: &Reminder,
+            at: DateTime<Utc>,
+        ) -> Result<bool, RepositoryError> {
+            let mut tables = self.store.write();
+            let pending = tables.todos.get(&reminder.todo_id).is_some_and(|todo| {
+                todo.remind_at == Some(reminder.remind_at) && todo.reminded_at.is_none()
+            });
+            if !pending {
+                return Ok(false);
+            }
+            tables
+                .reminder_attempts
+                .insert(reminder.todo_id, (reminder.attempts, at));
+            Ok(true)
+        }
+    }
+}
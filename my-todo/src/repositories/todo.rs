@@ -1,19 +1,76 @@
+use crate::repositories::label::Label;
 use axum::async_trait;
-use indoc::indoc;
+use chrono::{DateTime, NaiveDate, Utc};
+use chrono_tz::Tz;
+use indoc::{formatdoc, indoc};
 use serde::{Deserialize, Serialize};
-use sqlx::{FromRow, PgPool};
-use validator::Validate;
-use crate::repositories::label::Label;
+use sqlx::{
+    postgres::{PgArguments, PgRow},
+    query::QueryAs,
+    FromRow, PgConnection, PgPool, Postgres,
+};
+use validator::{Validate, ValidationError};
 
-use super::RepositoryError;
+use super::{
+    dependency::{self, DependencyGraph},
+    deserialize_some,
+    due::{self, Due, DueView, DueWindow},
+    recurrence::{self, RRule, Recurrence},
+    todo_event::{self, TodoEvent, TodoEventKind},
+    unit_of_work::{Commit, UnitOfWork},
+    ParentError, RepositoryError,
+};
 
 #[async_trait]
 pub trait TodoRepository: Clone + std::marker::Send + std::marker::Sync + 'static {
-    async fn create(&self, payload: CreateTodo) -> anyhow::Result<TodoEntity>;
-    async fn find(&self, id: i32) -> anyhow::Result<TodoEntity>;
-    async fn all(&self) -> anyhow::Result<Vec<TodoEntity>>;
-    async fn update(&self, id: i32, payload: UpdateTodo) -> anyhow::Result<TodoEntity>;
-    async fn delete(&self, id: i32) -> anyhow::Result<()>;
+    /// create_in に渡す作業単位。LabelRepository と同じものを使うと、ラベルの操作とまとめられる
+    type UnitOfWork: Commit;
+    /// owner_id のユーザーの Todo だけを扱うリポジトリを返す
+    /// 他のユーザーの Todo やラベルは存在しないものとして扱う
+    fn for_owner(&self, owner_id: i32) -> Self;
+    /// create_in に渡す作業単位を始める。commit するまで他からは見えない
+    async fn begin(&self) -> Result<Self::UnitOfWork, RepositoryError>;
+    async fn create(&self, payload: CreateTodo) -> Result<TodoEntity, RepositoryError>;
+    /// uow の中で create する。同じ uow で作ったラベルを labels に指定できる
+    async fn create_in(
+        &self,
+        uow: &mut Self::UnitOfWork,
+        payload: CreateTodo,
+    ) -> Result<TodoEntity, RepositoryError>;
+    async fn find(&self, id: i32) -> Result<TodoEntity, RepositoryError>;
+    async fn all(
+        &self,
+        filter: TodoFilter,
+        pagination: Pagination,
+    ) -> Result<TodoPage, RepositoryError>;
+    async fn search(&self, query: SearchQuery) -> Result<Vec<TodoSearchHit>, RepositoryError>;
+    /// version が指定された場合は、現在の version と一致するときだけ更新する
+    async fn update(
+        &self,
+        id: i32,
+        payload: UpdateTodo,
+        version: Option<i32>,
+    ) -> Result<TodoEntity, RepositoryError>;
+    /// ゴミ箱へ移す。ラベルの紐付けは残し、restore で元に戻せる
+    /// version が指定された場合は、現在の version と一致するときだけ削除する
+    async fn delete(&self, id: i32, version: Option<i32>) -> Result<(), RepositoryError>;
+    /// ゴミ箱にある Todo を返す。並び順は all と同じく pagination の sort で決まる
+    async fn trash(&self, pagination: Pagination) -> Result<TodoPage, RepositoryError>;
+    async fn restore(&self, id: i32) -> Result<TodoEntity, RepositoryError>;
+    /// ゴミ箱にあるかどうかに関わらず、Todo を完全に削除する
+    async fn purge(&self, id: i32, version: Option<i32>) -> Result<(), RepositoryError>;
+    /// 変更履歴を古い順に返す。完全に削除した Todo の履歴も返す
+    async fn history(&self, id: i32) -> Result<Vec<TodoEvent>, RepositoryError>;
+    /// 手動の並び順 (`?sort=position`) で、指定した Todo の直前か直後に移す
+    /// 並び順は Todo の内容ではないため、version は進めず履歴にも残さない
+    async fn reorder(&self, id: i32, payload: MoveTodo) -> Result<TodoEntity, RepositoryError>;
+    /// 子の Todo を手動の並び順で返す。孫以下は children にネストする
+    async fn children(&self, id: i32) -> Result<Vec<TodoEntity>, RepositoryError>;
+    /// id の Todo が other の Todo の完了を待つようにする。既に待っていれば何もしない
+    /// 循環する場合は DependencyCycle を返す
+    async fn add_dependency(&self, id: i32, other: i32) -> Result<TodoEntity, RepositoryError>;
+    async fn remove_dependency(&self, id: i32, other: i32) -> Result<TodoEntity, RepositoryError>;
+    async fn dependency_graph(&self) -> Result<DependencyGraph, RepositoryError>;
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, FromRow)]
@@ -21,8 +78,47 @@ pub struct TodoWithLabelFromRow {
     id: i32,
     text: String,
     completed: bool,
+    version: i32,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    completed_at: Option<DateTime<Utc>>,
+    deleted_at: Option<DateTime<Utc>>,
+    due_date: Option<NaiveDate>,
+    due_at: Option<DateTime<Utc>>,
+    remind_at: Option<DateTime<Utc>>,
+    reminded_at: Option<DateTime<Utc>>,
+    series_id: Option<i32>,
+    occurrence: Option<i32>,
+    rrule: Option<String>,
+    priority: Priority,
+    position: i64,
+    parent_id: Option<i32>,
+    blocked: bool,
     label_id: Option<i32>,
     label_name: Option<String>,
+    label_color: Option<String>,
+    label_description: Option<String>,
+    label_created_at: Option<DateTime<Utc>>,
+}
+
+impl TodoWithLabelFromRow {
+    fn label(&self) -> Option<Label> {
+        Some(Label {
+            id: self.label_id?,
+            name: self.label_name.clone().unwrap(),
+            color: self.label_color.clone(),
+            description: self.label_description.clone(),
+            created_at: self.label_created_at.unwrap(),
+        })
+    }
+
+    fn recurrence(&self) -> Option<Recurrence> {
+        Some(Recurrence {
+            series_id: self.series_id?,
+            occurrence: self.occurrence?,
+            rrule: self.rrule.as_deref().and_then(|rrule| rrule.parse().ok()),
+        })
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
@@ -30,7 +126,50 @@ pub struct TodoEntity {
     pub id: i32,
     pub text: String,
     pub completed: bool,
+    /// 更新のたびに 1 ずつ増える。楽観的排他制御に使う
+    pub version: i32,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    /// 未完了から完了にした日時。未完了に戻すと None になる
+    pub completed_at: Option<DateTime<Utc>>,
+    /// ゴミ箱に移した日時
+    pub deleted_at: Option<DateTime<Utc>>,
+    /// 日時の期限は UTC で返す
+    pub due_at: Option<Due>,
+    /// この日時を過ぎるとリマインダーを送る
+    pub remind_at: Option<DateTime<Utc>>,
+    /// リマインダーを送った日時。remind_at を変えると None に戻る
+    pub reminded_at: Option<DateTime<Utc>>,
+    /// 繰り返しの Todo の場合に、属する系列
+    pub recurrence: Option<Recurrence>,
+    pub priority: Priority,
+    /// 手動の並び順。移動のたびに他の Todo の値も変わりうるため返さない
+    #[serde(skip)]
+    pub position: i64,
+    /// 親の Todo。親を含めて MAX_DEPTH 段までネストできる
+    pub parent_id: Option<i32>,
+    /// 未完了でゴミ箱にもない Todo を待っている
+    pub blocked: bool,
     pub labels: Vec<Label>,
+    /// 木として返すときだけ入る子の Todo
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<TodoEntity>,
+}
+
+/// Todo の優先度
+/// Postgres の enum と同じく、宣言順に none が最も低く urgent が最も高い
+#[derive(
+    Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, sqlx::Type,
+)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "todo_priority", rename_all = "lowercase")]
+pub enum Priority {
+    #[default]
+    None,
+    Low,
+    Medium,
+    High,
+    Urgent,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, FromRow)]
@@ -38,6 +177,7 @@ pub struct TodoFromRow {
     id: i32,
     text: String,
     completed: bool,
+    version: i32,
 }
 
 fn fold_entities(rows: Vec<TodoWithLabelFromRow>) -> Vec<TodoEntity> {
@@ -48,50 +188,496 @@ fn fold_entities(rows: Vec<TodoWithLabelFromRow>) -> Vec<TodoEntity> {
         for todo in todos {
             // id が一致 = Todo に紐づくラベルが複数存在している
             if todo.id == row.id {
-                todo.labels.push(Label {
-                    id: row.label_id.unwrap(),
-                    name: row.label_name.clone().unwrap(),
-                });
+                todo.labels.push(row.label().unwrap());
                 continue 'outer;
             }
         }
 
         // Todo の id に一致がなかったときのみ到着、TodoEntity を作成
-        let labels = if row.label_id.is_some() {
-            vec![Label {
-                id: row.label_id.unwrap(),
-                name: row.label_name.clone().unwrap()
-            }]
-        } else {
-            vec![]
-        };
+        let labels = row.label().into_iter().collect();
 
         accm.push(TodoEntity {
             id: row.id,
             text: row.text.clone(),
             completed: row.completed,
+            version: row.version,
+            created_at: row.created_at,
+            updated_at: row.updated_at,
+            completed_at: row.completed_at,
+            deleted_at: row.deleted_at,
+            due_at: Due::from_columns(row.due_date, row.due_at),
+            remind_at: row.remind_at,
+            reminded_at: row.reminded_at,
+            recurrence: row.recurrence(),
+            priority: row.priority,
+            position: row.position,
+            parent_id: row.parent_id,
+            blocked: row.blocked,
             labels,
+            children: vec![],
         });
     }
 
     accm
 }
 
+/// todos のそれぞれに、descendants の中から子を再帰的に children として付ける
+/// descendants の並び順がそのまま children の並び順になる
+fn build_tree(mut todos: Vec<TodoEntity>, descendants: &[TodoEntity]) -> Vec<TodoEntity> {
+    for todo in todos.iter_mut() {
+        let children = descendants
+            .iter()
+            .filter(|child| child.parent_id == Some(todo.id))
+            .cloned()
+            .collect();
+        todo.children = build_tree(children, descendants);
+    }
+    todos
+}
+
+/// 根の Todo を 1 段目として数えた、木の深さの上限
+pub const MAX_DEPTH: usize = 5;
+
+/// id の Todo (新規作成なら None) を parent の子にできるか確かめる
+/// ancestors は parent から根までの id、height は id の Todo を根とする部分木の段数
+fn check_parent(
+    id: Option<i32>,
+    parent: i32,
+    ancestors: &[i32],
+    height: usize,
+) -> Result<(), RepositoryError> {
+    if ancestors.is_empty() {
+        Err(RepositoryError::InvalidParent(
+            parent,
+            ParentError::NotFound,
+        ))
+    } else if id.is_some_and(|id| ancestors.contains(&id)) {
+        Err(RepositoryError::InvalidParent(parent, ParentError::Cycle))
+    } else if ancestors.len() + height > MAX_DEPTH {
+        Err(RepositoryError::InvalidParent(
+            parent,
+            ParentError::TooDeep(MAX_DEPTH),
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// `GET /todos` の絞り込み条件
+/// `?completed=true&label=1&label=2&label_match=all&text=foo` のように指定する
+/// 期限は `?due=today&tz=Asia/Tokyo` のように、クライアントのタイムゾーンでの今日を基準に絞り込む
+/// `?tree=true` では親のない Todo だけを絞り込んでページングし、子孫は絞り込まずに children に入れる
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "Vec<(String, String)>")]
+pub struct TodoFilter {
+    completed: Option<bool>,
+    labels: Vec<i32>,
+    label_match: LabelMatch,
+    text: Option<String>,
+    due: Option<DueView>,
+    /// 省略時は UTC
+    tz: Option<Tz>,
+    tree: bool,
+}
+
+impl TodoFilter {
+    fn due_window(&self, now: DateTime<Utc>) -> Option<DueWindow> {
+        self.due
+            .map(|due| due.window(self.tz.unwrap_or(Tz::UTC), now))
+    }
+}
+
+/// 複数のラベルを指定したときに、いずれかに一致 (any) か全てに一致 (all) か
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum LabelMatch {
+    #[default]
+    Any,
+    All,
+}
+
+// label は繰り返し指定できるため、キーと値の組から組み立てる
+impl TryFrom<Vec<(String, String)>> for TodoFilter {
+    type Error = String;
+
+    fn try_from(params: Vec<(String, String)>) -> Result<Self, Self::Error> {
+        let mut filter = TodoFilter::default();
+        for (key, value) in params {
+            match key.as_str() {
+                "completed" => {
+                    let completed = value
+                        .parse()
+                        .map_err(|_| format!("completed must be true or false: {}", value))?;
+                    filter.completed = Some(completed);
+                }
+                "label" => {
+                    let label = value
+                        .parse()
+                        .map_err(|_| format!("label must be label id: {}", value))?;
+                    if !filter.labels.contains(&label) {
+                        filter.labels.push(label);
+                    }
+                }
+                "label_match" => {
+                    filter.label_match = match value.as_str() {
+                        "any" => LabelMatch::Any,
+                        "all" => LabelMatch::All,
+                        _ => return Err(format!("label_match must be any or all: {}", value)),
+                    };
+                }
+                "text" if !value.is_empty() => filter.text = Some(value),
+                "due" => filter.due = Some(value.parse()?),
+                "tz" => {
+                    let tz = value
+                        .parse()
+                        .map_err(|_| format!("tz must be IANA time zone name: {}", value))?;
+                    filter.tz = Some(tz);
+                }
+                "tree" => {
+                    filter.tree = value
+                        .parse()
+                        .map_err(|_| format!("tree must be true or false: {}", value))?;
+                }
+                // ページングなど他のクエリは無視する
+                _ => {}
+            }
+        }
+        Ok(filter)
+    }
+}
+
+// $1 .. $10 に TodoFilter の値を bind して使う
+const TODO_FILTER_CONDITION: &str = indoc!(
+    r#"
+        ($1::boolean is null or todos.completed = $1)
+        and ($2::text is null or strpos(lower(todos.text), lower($2)) > 0)
+        and (
+            cardinality($3::integer[]) = 0
+            or (
+                select count(distinct todo_labels.label_id) from todo_labels
+                    where todo_labels.todo_id = todos.id and todo_labels.label_id = any($3)
+            ) >= case when $4 then cardinality($3) else 1 end
+        )
+        and (
+            $6::date is null
+            or (
+                todos.due_date < $6 and ($5::date is null or todos.due_date >= $5)
+                or todos.due_at < $8 and ($7::timestamptz is null or todos.due_at >= $7)
+            ) and not ($9 and todos.completed)
+        )
+        and (not $10 or todos.parent_id is null)
+    "#
+);
+
+fn bind_filter<'q, O>(
+    query: QueryAs<'q, Postgres, O, PgArguments>,
+    filter: &'q TodoFilter,
+    now: DateTime<Utc>,
+) -> QueryAs<'q, Postgres, O, PgArguments>
+where
+    O: for<'r> FromRow<'r, PgRow>,
+{
+    let window = filter.due_window(now);
+    query
+        .bind(filter.completed)
+        .bind(filter.text.as_deref())
+        .bind(&filter.labels)
+        .bind(filter.label_match == LabelMatch::All)
+        .bind(window.and_then(|window| window.from_date))
+        .bind(window.map(|window| window.before_date))
+        .bind(window.and_then(|window| window.from_at))
+        .bind(window.map(|window| window.before_at))
+        .bind(window.is_some_and(|window| window.incomplete_only))
+        .bind(filter.tree)
+}
+
+const DEFAULT_PAGE_LIMIT: i64 = 50;
+const MAX_PAGE_LIMIT: i64 = 200;
+
+/// `GET /todos?limit=&after=&sort=` のクエリ
+/// after には前ページの next_cursor (最後の Todo の id) を渡し、sort は前ページと同じものを指定する
+/// id 以外で並べている場合、after の Todo を完全に削除していると続きのページは空になる
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+pub struct Pagination {
+    limit: Option<i64>,
+    after: Option<i32>,
+    #[serde(default)]
+    sort: TodoSort,
+}
+
+impl Pagination {
+    pub fn limit(&self) -> i64 {
+        self.limit
+            .unwrap_or(DEFAULT_PAGE_LIMIT)
+            .clamp(1, MAX_PAGE_LIMIT)
+    }
+}
+
+/// 並び替えに使える Todo の列
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortKey {
+    Id,
+    Priority,
+    CreatedAt,
+    UpdatedAt,
+    /// 手動の並び順
+    Position,
+}
+
+impl SortKey {
+    fn column(self) -> &'static str {
+        match self {
+            SortKey::Id => "id",
+            SortKey::Priority => "priority",
+            SortKey::CreatedAt => "created_at",
+            SortKey::UpdatedAt => "updated_at",
+            SortKey::Position => "position",
+        }
+    }
+}
+
+impl std::str::FromStr for SortKey {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "id" => Ok(SortKey::Id),
+            "priority" => Ok(SortKey::Priority),
+            "created_at" => Ok(SortKey::CreatedAt),
+            "updated_at" => Ok(SortKey::UpdatedAt),
+            "position" => Ok(SortKey::Position),
+            _ => Err(format!(
+                "sort key must be id, priority, created_at, updated_at or position: {}",
+                value
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct SortField {
+    key: SortKey,
+    descending: bool,
+}
+
+/// `?sort=-priority,created_at` のようにカンマ区切りでキーを並べ、"-" を付けたキーは降順にする
+/// 順序が一意に決まるよう、id を含まない場合は最後に id の降順を補う
+/// 指定しない場合は id の降順
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct TodoSort(Vec<SortField>);
+
+impl TryFrom<String> for TodoSort {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let mut fields: Vec<SortField> = vec![];
+        for key in value.split(',').filter(|key| !key.is_empty()) {
+            let (key, descending) = match key.strip_prefix('-') {
+                Some(key) => (key, true),
+                None => (key, false),
+            };
+            let key: SortKey = key.parse()?;
+            if fields.iter().any(|field| field.key == key) {
+                return Err(format!("sort key must not be repeated: {}", key.column()));
+            }
+            fields.push(SortField { key, descending });
+        }
+        Ok(TodoSort(fields))
+    }
+}
+
+impl TodoSort {
+    fn fields(&self) -> Vec<SortField> {
+        let mut fields = self.0.clone();
+        if !fields.iter().any(|field| field.key == SortKey::Id) {
+            fields.push(SortField {
+                key: SortKey::Id,
+                descending: true,
+            });
+        }
+        fields
+    }
+
+    /// table の列で並べる order by 句
+    fn order_by(&self, table: &str) -> String {
+        self.fields()
+            .iter()
+            .map(|field| {
+                let order = if field.descending { "desc" } else { "asc" };
+                format!("{}.{} {}", table, field.key.column(), order)
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// table の行が cursor の行より後ろに並ぶ条件
+    /// `a > c.a or (a = c.a and b < c.b) or ...` のように、前のキーが等しい場合に次のキーで比べる
+    fn after(&self, table: &str, cursor: &str) -> String {
+        let fields = self.fields();
+        (0..fields.len())
+            .map(|i| {
+                let conditions: Vec<String> = fields[..=i]
+                    .iter()
+                    .enumerate()
+                    .map(|(j, field)| {
+                        let op = match (j == i, field.descending) {
+                            (false, _) => "=",
+                            (true, false) => ">",
+                            (true, true) => "<",
+                        };
+                        let column = field.key.column();
+                        format!("{}.{} {} {}.{}", table, column, op, cursor, column)
+                    })
+                    .collect();
+                format!("({})", conditions.join(" and "))
+            })
+            .collect::<Vec<_>>()
+            .join(" or ")
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct TodoPage {
+    pub items: Vec<TodoEntity>,
+    pub next_cursor: Option<i32>,
+    pub total: i64,
+}
+
+/// limit + 1 件取得した結果から 1 ページ分を切り出す
+/// limit を超えていれば次のページが存在する
+fn paginate(mut items: Vec<TodoEntity>, limit: i64, total: i64) -> TodoPage {
+    let limit = limit as usize;
+    let next_cursor = if items.len() > limit {
+        items.truncate(limit);
+        items.last().map(|todo| todo.id)
+    } else {
+        None
+    };
+
+    TodoPage {
+        items,
+        next_cursor,
+        total,
+    }
+}
+
+/// `GET /todos/search?q=&limit=` のクエリ
+/// q は websearch_to_tsquery の書式 (空白区切りで AND、"-" で除外など) で解釈する
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct SearchQuery {
+    q: String,
+    limit: Option<i64>,
+}
+
+impl SearchQuery {
+    pub fn limit(&self) -> i64 {
+        self.limit
+            .unwrap_or(DEFAULT_PAGE_LIMIT)
+            .clamp(1, MAX_PAGE_LIMIT)
+    }
+}
+
+/// 検索結果の Todo に ts_rank のスコアと ts_headline の抜粋を加えたもの
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct TodoSearchHit {
+    #[serde(flatten)]
+    pub todo: TodoEntity,
+    pub rank: f32,
+    pub snippet: String,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Validate)]
 pub struct CreateTodo {
     #[validate(length(min = 1, message = "Can not be Empty"))]
     #[validate(length(max = 100, message = "Over text length"))]
     text: String,
     labels: Vec<i32>,
+    /// 日時は UTC に直して保存する
+    #[serde(default)]
+    #[validate(custom(function = "due::validate_due", message = "Out of range"))]
+    due_at: Option<Due>,
+    #[serde(default)]
+    remind_at: Option<DateTime<Utc>>,
+    /// 指定すると、完了にしたときに次の回を作る
+    #[serde(default)]
+    rrule: Option<RRule>,
+    #[serde(default)]
+    priority: Priority,
+    #[serde(default)]
+    parent_id: Option<i32>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Validate)]
+impl CreateTodo {
+    /// labels に加えて、id のラベルを紐付ける
+    pub fn add_label(&mut self, id: i32) {
+        self.labels.push(id);
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq, Eq, Validate)]
 pub struct UpdateTodo {
     #[validate(length(min = 1, message = "Can not be Empty"))]
     #[validate(length(max = 100, message = "Over text length"))]
     text: Option<String>,
     completed: Option<bool>,
     labels: Option<Vec<i32>>,
+    /// null を指定すると期限を消す。日時は UTC に直して保存する
+    #[serde(default, deserialize_with = "deserialize_some")]
+    #[validate(custom(function = "due::validate_due", message = "Out of range"))]
+    due_at: Option<Option<Due>>,
+    /// null を指定するとリマインダーを止める
+    #[serde(default, deserialize_with = "deserialize_some")]
+    remind_at: Option<Option<DateTime<Utc>>>,
+    /// 系列の繰り返しを変える。null を指定すると繰り返しを止める
+    #[serde(default, deserialize_with = "deserialize_some")]
+    rrule: Option<Option<RRule>>,
+    priority: Option<Priority>,
+    /// null を指定すると親のない Todo にする
+    #[serde(default, deserialize_with = "deserialize_some")]
+    parent_id: Option<Option<i32>>,
+    /// completed を true にするときに、未完了の子孫もまとめて完了にする
+    #[serde(default)]
+    complete_children: bool,
+}
+
+/// `POST /todos/:id/move` の本文
+/// `{"before": 3}` なら Todo 3 の直前、`{"after": 3}` なら直後に移す
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Validate)]
+#[validate(schema(function = "validate_move", message = "Specify either before or after"))]
+pub struct MoveTodo {
+    before: Option<i32>,
+    after: Option<i32>,
+}
+
+fn validate_move(payload: &MoveTodo) -> Result<(), ValidationError> {
+    match (payload.before, payload.after) {
+        (Some(_), None) | (None, Some(_)) => Ok(()),
+        _ => Err(ValidationError::new("neighbour")),
+    }
+}
+
+impl MoveTodo {
+    /// 隣にする Todo の id と、その直前に移すかどうか
+    fn neighbour(&self) -> (i32, bool) {
+        match (self.before, self.after) {
+            (Some(before), _) => (before, true),
+            (None, after) => (after.unwrap(), false),
+        }
+    }
+}
+
+/// 新しい Todo は末尾に、先頭や末尾への移動はこの間隔を空けて置く
+const POSITION_GAP: i64 = 1024;
+
+/// lower と upper の間に置く位置。間に空きがなければ None を返すため、振り直してから求め直す
+/// 片方がない場合は先頭か末尾への移動になる
+fn position_between(lower: Option<i64>, upper: Option<i64>) -> Option<i64> {
+    match (lower, upper) {
+        (Some(lower), Some(upper)) => (upper - lower >= 2).then(|| lower + (upper - lower) / 2),
+        (Some(lower), None) => Some(lower + POSITION_GAP),
+        (None, Some(upper)) => Some(upper - POSITION_GAP),
+        (None, None) => Some(POSITION_GAP),
+    }
 }
 
 #[cfg(test)]
@@ -101,110 +687,189 @@ impl TodoEntity {
             id,
             text,
             completed: false,
+            version: 1,
+            created_at: DateTime::default(),
+            updated_at: DateTime::default(),
+            completed_at: None,
+            deleted_at: None,
+            due_at: None,
+            remind_at: None,
+            reminded_at: None,
+            recurrence: None,
+            priority: Priority::None,
+            position: 0,
+            parent_id: None,
+            blocked: false,
             labels: vec![],
+            children: vec![],
         }
     }
 }
 
-#[derive(Debug, Clone)]
-pub struct TodoRepositoryForDb {
-    pool: PgPool,
+/// 作成する Todo の内容。繰り返しの次の回もこれで作る
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct NewTodo {
+    text: String,
+    labels: Vec<i32>,
+    due_at: Option<Due>,
+    remind_at: Option<DateTime<Utc>>,
+    priority: Priority,
+    parent_id: Option<i32>,
+    /// 系列の id と何番目か
+    series: Option<(i32, i32)>,
 }
 
-impl TodoRepositoryForDb {
-    pub fn new(pool: PgPool) -> Self {
-        TodoRepositoryForDb { pool }
-    }
+/// 繰り返しの Todo を完了したときに作る次の回。系列が終わっていれば None を返す
+/// 期限のない Todo は、完了した日 (today) を起点にする
+fn next_occurrence(todo: &TodoEntity, today: NaiveDate) -> Option<NewTodo> {
+    let recurrence = todo.recurrence.as_ref()?;
+    let current = todo.due_at.unwrap_or(Due::Date(today));
+    let due_at = recurrence
+        .rrule
+        .as_ref()?
+        .next(recurrence.occurrence, current)?;
+    Some(NewTodo {
+        text: todo.text.clone(),
+        labels: todo.labels.iter().map(|label| label.id).collect(),
+        due_at: Some(due_at),
+        remind_at: recurrence::shift_remind_at(todo.remind_at, current, due_at),
+        priority: todo.priority,
+        parent_id: todo.parent_id,
+        series: Some((recurrence.series_id, recurrence.occurrence + 1)),
+    })
 }
 
-#[async_trait]
-impl TodoRepository for TodoRepositoryForDb {
-    async fn create(&self, payload: CreateTodo) -> anyhow::Result<TodoEntity> {
-        let tx = self.pool.begin().await?;
+/// 同じラベルを二重に紐付けないよう、指定順を保ったまま重複を取り除く
+fn dedup_labels(labels: Vec<i32>) -> Vec<i32> {
+    let mut accm: Vec<i32> = Vec::with_capacity(labels.len());
+    for id in labels {
+        if !accm.contains(&id) {
+            accm.push(id);
+        }
+    }
+    accm
+}
 
-        let row = sqlx::query_as::<_, TodoFromRow>(indoc!(
-            r#"
-                insert into todos (text, completed) values ($1, false)
-                returning *
-            "#,
-        ))
-        .bind(payload.text.clone())
-        .fetch_one(&self.pool)
-        .await?;
+/// 存在しないか owner_id のユーザーのものでないラベルの id があれば UnknownLabels を返す
+/// コミットまでにラベルが削除されないよう、共有ロックを取る
+async fn check_labels(
+    conn: &mut PgConnection,
+    owner_id: i32,
+    labels: &[i32],
+) -> Result<(), RepositoryError> {
+    let found: Vec<i32> = sqlx::query_scalar(indoc!(
+        r#"
+            select id from labels where id = any($1) and owner_id = $2 for share
+        "#
+    ))
+    .bind(labels)
+    .bind(owner_id)
+    .fetch_all(conn)
+    .await?;
 
-        sqlx::query(indoc! {
-            r#"
-                insert into todo_labels (todo_id, label_id)
-                    select $1, id from unnest($2) as t(id)
-            "#
-        })
-        .bind(row.id)
-        .bind(payload.labels)
-        .execute(&self.pool)
-        .await?;
+    let unknown: Vec<i32> = labels
+        .iter()
+        .filter(|id| !found.contains(id))
+        .copied()
+        .collect();
+    if !unknown.is_empty() {
+        return Err(RepositoryError::UnknownLabels(unknown));
+    }
+    Ok(())
+}
 
-        tx.commit().await?;
+#[derive(Debug, Clone)]
+pub struct TodoRepositoryForDb {
+    pool: PgPool,
+    owner_id: i32,
+}
 
-        let todo = self.find(row.id).await?;        
-        Ok(todo)
+impl TodoRepositoryForDb {
+    /// for_owner で所有者を決めるまでは、どの Todo も見えず作れない
+    pub fn new(pool: PgPool) -> Self {
+        TodoRepositoryForDb { pool, owner_id: 0 }
     }
 
-    async fn find(&self, id: i32) -> anyhow::Result<TodoEntity> {
-        let items = sqlx::query_as::<_, TodoWithLabelFromRow>(indoc!(
-            r#"
-                select todos.*, labels.id as label_id, labels.name as label_name
-                    from todos
-                        left outer join todo_labels t1 on todos.id = t1.todo_id
-                        left outer join labels on labels.id = t1.label_id
-                    where todos.id = $1
-            "#,
-        ))
-        .bind(id)
-        .fetch_all(&self.pool)
-        .await
-        .map_err(|e| match e {
-            sqlx::Error::RowNotFound => RepositoryError::NotFound(id),
-            _ => RepositoryError::Unexpected(e.to_string()),
-        })?;
-
-        let todos = fold_entities(items);
-        let todo = todos.first().ok_or(RepositoryError::NotFound(id))?;
-        Ok(todo.clone())
+    pub async fn update_in(
+        &self,
+        uow: &mut UnitOfWork,
+        id: i32,
+        payload: UpdateTodo,
+        version: Option<i32>,
+    ) -> Result<TodoEntity, RepositoryError> {
+        let complete_children = payload.complete_children && payload.completed == Some(true);
+        let todo = self.update_one(uow, id, payload, version).await?;
+        if complete_children {
+            for child in open_descendants(uow.conn(), id).await? {
+                let payload = UpdateTodo {
+                    completed: Some(true),
+                    ..UpdateTodo::default()
+                };
+                self.update_one(uow, child, payload, None).await?;
+            }
+        }
+        Ok(todo)
     }
 
-    async fn all(&self) -> anyhow::Result<Vec<TodoEntity>> {
-        let items = sqlx::query_as::<_, TodoWithLabelFromRow>(indoc!(
-                r#"
-                    select todos.*, labels.id as label_id, labels.name as label_name
-                        from todos
-                            left outer join todo_labels t1 on todos.id = t1.todo_id
-                            left outer join labels on labels.id = t1.label_id
-                        order by todos.id desc
-                "#
-            ))
-            .fetch_all(&self.pool)
-            .await?;
+    async fn update_one(
+        &self,
+        uow: &mut UnitOfWork,
+        id: i32,
+        payload: UpdateTodo,
+        version: Option<i32>,
+    ) -> Result<TodoEntity, RepositoryError> {
+        lock_todo(uow.conn(), self.owner_id, id, version, Some(false)).await?;
 
-        Ok(fold_entities(items))
-    }
+        let old_todo = find_todo(uow.conn(), self.owner_id, id).await?;
+        let labels = payload.labels.map(dedup_labels);
+        if let Some(labels) = &labels {
+            check_labels(uow.conn(), self.owner_id, labels).await?;
+        }
+        if let Some(Some(parent)) = payload.parent_id {
+            validate_parent(uow.conn(), self.owner_id, Some(id), parent).await?;
+        }
+        let due_at = payload.due_at.unwrap_or(old_todo.due_at);
+        let todo_completed = payload.completed.unwrap_or(old_todo.completed);
 
-    async fn update(&self, id: i32, payload: UpdateTodo) -> anyhow::Result<TodoEntity> {
         // todo update
-        let tx = self.pool.begin().await?;
-
-        let old_todo = self.find(id).await?;
         sqlx::query(indoc!(
             r#"
-                update todos set text = $1, completed = $2 where id = $3
+                update todos set text = $1, completed = $2, version = version + 1,
+                        updated_at = now(),
+                        completed_at = case
+                            when not $2 then null
+                            when not completed then now()
+                            else completed_at
+                        end,
+                        due_date = $3, due_at = $4, remind_at = $5,
+                        reminded_at = case
+                            when remind_at is distinct from $5 then null
+                            else reminded_at
+                        end,
+                        remind_attempts = case
+                            when remind_at is distinct from $5 then 0
+                            else remind_attempts
+                        end,
+                        remind_next_attempt_at = case
+                            when remind_at is distinct from $5 then null
+                            else remind_next_attempt_at
+                        end,
+                        priority = $6, parent_id = $7
+                    where id = $8
             "#
         ))
-        .bind(payload.text.unwrap_or(old_todo.text))
-        .bind(payload.completed.unwrap_or(old_todo.completed))
+        .bind(payload.text.unwrap_or_else(|| old_todo.text.clone()))
+        .bind(todo_completed)
+        .bind(due_at.and_then(|due| due.date()))
+        .bind(due_at.and_then(|due| due.at()))
+        .bind(payload.remind_at.unwrap_or(old_todo.remind_at))
+        .bind(payload.priority.unwrap_or(old_todo.priority))
+        .bind(payload.parent_id.unwrap_or(old_todo.parent_id))
         .bind(id)
-        .execute(&self.pool)
+        .execute(uow.conn())
         .await?;
 
-        if let Some(labels) = payload.labels {
+        if let Some(labels) = labels {
             // todo's label update
             // 一度関連するレコードを削除
             sqlx::query(indoc!(
@@ -213,7 +878,7 @@ impl TodoRepository for TodoRepositoryForDb {
                 "#
             ))
             .bind(id)
-            .execute(&self.pool)
+            .execute(uow.conn())
             .await?;
 
             sqlx::query(indoc!(
@@ -224,32 +889,159 @@ impl TodoRepository for TodoRepositoryForDb {
             ))
             .bind(id)
             .bind(labels)
-            .execute(&self.pool)
+            .execute(uow.conn())
             .await?;
         };
 
-        tx.commit().await?;
+        if let Some(rrule) = payload.rrule {
+            set_rrule(uow.conn(), &old_todo, rrule).await?;
+        }
+
+        if old_todo.completed != todo_completed {
+            dependency::touch_dependents(uow.conn(), id).await?;
+        }
+
+        let todo = find_todo(uow.conn(), self.owner_id, id).await?;
+        todo_event::record(
+            uow.conn(),
+            self.owner_id,
+            self.owner_id,
+            id,
+            TodoEventKind::Updated,
+            todo_event::diff(Some(&old_todo), Some(&todo)),
+        )
+        .await?;
+
+        // 繰り返しの Todo を完了にしたら、同じトランザクションで次の回を作る
+        if !old_todo.completed && todo.completed {
+            if let Some(next) = next_occurrence(&todo, Utc::now().date_naive()) {
+                create_occurrence(uow.conn(), self.owner_id, next).await?;
+            }
+        }
+        Ok(todo)
+    }
+
+    pub async fn delete_in(
+        &self,
+        uow: &mut UnitOfWork,
+        id: i32,
+        version: Option<i32>,
+    ) -> Result<(), RepositoryError> {
+        lock_todo(uow.conn(), self.owner_id, id, version, Some(false)).await?;
+        let old_todo = find_todo(uow.conn(), self.owner_id, id).await?;
+
+        sqlx::query(indoc!(
+            r#"
+                update todos set deleted_at = now(), version = version + 1 where id = $1
+            "#
+        ))
+        .bind(id)
+        .execute(uow.conn())
+        .await?;
+        dependency::touch_dependents(uow.conn(), id).await?;
+
+        let todo = fetch_todo(uow.conn(), self.owner_id, id, Some(true)).await?;
+        todo_event::record(
+            uow.conn(),
+            self.owner_id,
+            self.owner_id,
+            id,
+            TodoEventKind::Deleted,
+            todo_event::diff(Some(&old_todo), Some(&todo)),
+        )
+        .await
+    }
+
+    pub async fn restore_in(
+        &self,
+        uow: &mut UnitOfWork,
+        id: i32,
+    ) -> Result<TodoEntity, RepositoryError> {
+        lock_todo(uow.conn(), self.owner_id, id, None, Some(true)).await?;
+        let old_todo = fetch_todo(uow.conn(), self.owner_id, id, Some(true)).await?;
+
+        // ゴミ箱にある間に削除されたラベルは、ラベル側で紐付けが外れている
+        sqlx::query(indoc!(
+            r#"
+                update todos set deleted_at = null, version = version + 1 where id = $1
+            "#
+        ))
+        .bind(id)
+        .execute(uow.conn())
+        .await?;
+        dependency::touch_dependents(uow.conn(), id).await?;
 
-        let todo = self.find(id).await?;
+        let todo = find_todo(uow.conn(), self.owner_id, id).await?;
+        todo_event::record(
+            uow.conn(),
+            self.owner_id,
+            self.owner_id,
+            id,
+            TodoEventKind::Restored,
+            todo_event::diff(Some(&old_todo), Some(&todo)),
+        )
+        .await?;
         Ok(todo)
     }
 
-    async fn delete(&self, id: i32) -> anyhow::Result<()> {
-        let tx = self.pool.begin().await?;
+    pub async fn purge_in(
+        &self,
+        uow: &mut UnitOfWork,
+        id: i32,
+        version: Option<i32>,
+    ) -> Result<(), RepositoryError> {
+        lock_todo(uow.conn(), self.owner_id, id, version, None).await?;
+        let old_todo = fetch_todo(uow.conn(), self.owner_id, id, None).await?;
 
         // todo's label delete
-        sqlx::query(indoc!(    
+        sqlx::query(indoc!(
             r#"
                 delete from todo_labels where todo_id = $1
             "#
         ))
         .bind(id)
-        .execute(&self.pool)
-        .await
-        .map_err(|e| match e {
-            sqlx::Error::RowNotFound => RepositoryError::NotFound(id),
-            _ => RepositoryError::Unexpected(e.to_string())
-        })?;
+        .execute(uow.conn())
+        .await?;
+
+        // 依存関係は外部キーで消えるため、消える前に待っている Todo の version を進める
+        dependency::touch_dependents(uow.conn(), id).await?;
+
+        // 外部キーに任せると子の version が変わらないため、先に親を外して変更を記録する
+        let child_ids: Vec<i32> = sqlx::query_scalar(indoc!(
+            r#"
+                select id from todos where parent_id = $1 and owner_id = $2 order by id
+            "#
+        ))
+        .bind(id)
+        .bind(self.owner_id)
+        .fetch_all(uow.conn())
+        .await?;
+        let mut old_children = vec![];
+        for child_id in child_ids {
+            old_children.push(fetch_todo(uow.conn(), self.owner_id, child_id, None).await?);
+        }
+        sqlx::query(indoc!(
+            r#"
+                update todos set parent_id = null, version = version + 1, updated_at = now()
+                    where parent_id = $1 and owner_id = $2
+            "#
+        ))
+        .bind(id)
+        .bind(self.owner_id)
+        .execute(uow.conn())
+        .await?;
+        for old_child in old_children {
+            let child = fetch_todo(uow.conn(), self.owner_id, old_child.id, None).await?;
+            todo_event::record(
+                uow.conn(),
+                self.owner_id,
+                self.owner_id,
+                child.id,
+                TodoEventKind::Updated,
+                todo_event::diff(Some(&old_child), Some(&child)),
+            )
+            .await?;
+        }
 
         // todo delete
         sqlx::query(indoc!(
@@ -258,79 +1050,893 @@ impl TodoRepository for TodoRepositoryForDb {
             "#
         ))
         .bind(id)
-        .execute(&self.pool)
+        .execute(uow.conn())
+        .await?;
+
+        // 完全に削除した内容を残すため、削除前の値を記録する
+        todo_event::record(
+            uow.conn(),
+            self.owner_id,
+            self.owner_id,
+            id,
+            TodoEventKind::Purged,
+            todo_event::diff(Some(&old_todo), None),
+        )
         .await
-        .map_err(|e| match e {
-            sqlx::Error::RowNotFound => RepositoryError::NotFound(id),
-            _ => RepositoryError::Unexpected(e.to_string()),
-        })?;
+    }
 
-        tx.commit().await?;
+    pub async fn reorder_in(
+        &self,
+        uow: &mut UnitOfWork,
+        id: i32,
+        payload: MoveTodo,
+    ) -> Result<TodoEntity, RepositoryError> {
+        // 隣の Todo の位置を読んでから書き込むまでの間に他の移動が割り込まないよう、
+        // 同じユーザーの移動は直列にする
+        sqlx::query("select pg_advisory_xact_lock(hashtext('todos.position'), $1)")
+            .bind(self.owner_id)
+            .execute(uow.conn())
+            .await?;
+        lock_todo(uow.conn(), self.owner_id, id, None, Some(false)).await?;
 
-        Ok(())
+        let (neighbour, before) = payload.neighbour();
+        if neighbour != id {
+            let (lower, upper) =
+                neighbour_positions(uow.conn(), self.owner_id, id, neighbour, before).await?;
+            let position = match position_between(lower, upper) {
+                Some(position) => position,
+                None => {
+                    rebalance_positions(uow.conn(), self.owner_id).await?;
+                    let (lower, upper) =
+                        neighbour_positions(uow.conn(), self.owner_id, id, neighbour, before)
+                            .await?;
+                    position_between(lower, upper).unwrap()
+                }
+            };
+            sqlx::query(indoc!(
+                r#"
+                    update todos set position = $2 where id = $1
+                "#
+            ))
+            .bind(id)
+            .bind(position)
+            .execute(uow.conn())
+            .await?;
+        }
+        find_todo(uow.conn(), self.owner_id, id).await
     }
-}
 
-#[cfg(test)]
-#[cfg(feature = "database-test")]
-mod test {
-    use super::*;
-    use dotenv::dotenv;
-    use sqlx::PgPool;
-    use std::env;
+    /// 依存関係を変えると blocked が変わりうるため、待つ側の version を進める
+    /// 依存関係は Todo の項目ではないため、履歴には残さない
+    pub async fn add_dependency_in(
+        &self,
+        uow: &mut UnitOfWork,
+        id: i32,
+        other: i32,
+    ) -> Result<TodoEntity, RepositoryError> {
+        lock_todo(uow.conn(), self.owner_id, id, None, Some(false)).await?;
+        if dependency::add(uow.conn(), self.owner_id, id, other).await? {
+            bump_version(uow.conn(), id).await?;
+        }
+        find_todo(uow.conn(), self.owner_id, id).await
+    }
 
-    #[test]
-    fn fold_entties_test() {
-        let label_1 = Label {
-            id: 1,
-            name: String::from("label 1"),
-        };
-        let label_2 = Label {
-            id: 2,
-            name: String::from("label 2"),
-        };
+    pub async fn remove_dependency_in(
+        &self,
+        uow: &mut UnitOfWork,
+        id: i32,
+        other: i32,
+    ) -> Result<TodoEntity, RepositoryError> {
+        lock_todo(uow.conn(), self.owner_id, id, None, Some(false)).await?;
+        dependency::remove(uow.conn(), id, other).await?;
+        bump_version(uow.conn(), id).await?;
+        find_todo(uow.conn(), self.owner_id, id).await
+    }
 
-        let rows = vec![
-            TodoWithLabelFromRow {
-                id: 1,
-                text: String::from("todo 1"),
+    /// trashed が true ならゴミ箱にある Todo、false ならそれ以外の Todo を返す
+    async fn list(
+        &self,
+        filter: &TodoFilter,
+        pagination: &Pagination,
+        trashed: bool,
+    ) -> Result<TodoPage, RepositoryError> {
+        let limit = pagination.limit();
+        // ラベルとの join で行が増えるため、先に Todo だけでページを確定させる
+        // 前ページの最後の Todo (after_todo) の値と比べて、続きから取得する
+        // 完全に削除されていても id では比べられるよう、id には $12 をそのまま使う
+        let sql = formatdoc!(
+            r#"
+                with after_todo as (
+                    select $12::integer as id, todos.priority, todos.created_at,
+                            todos.updated_at, todos.position
+                        from (select 1) as one
+                            left outer join todos on todos.id = $12 and todos.owner_id = $14
+                ),
+                page as (
+                    select todos.* from todos
+                            left outer join after_todo on true
+                        where {filter}
+                            and todos.owner_id = $14
+                            and (todos.deleted_at is not null) = $11
+                            and ($12::integer is null or {after})
+                        order by {order}
+                        limit $13
+                )
+                select page.*, {blocked}, labels.id as label_id, labels.name as label_name,
+                        labels.color as label_color, labels.description as label_description,
+                        labels.created_at as label_created_at, todo_series.rrule
+                    from page
+                        left outer join todo_labels t1 on page.id = t1.todo_id
+                        left outer join labels on labels.id = t1.label_id
+                        left outer join todo_series on todo_series.id = page.series_id
+                    order by {page_order}
+            "#,
+            filter = TODO_FILTER_CONDITION,
+            after = pagination.sort.after("todos", "after_todo"),
+            order = pagination.sort.order_by("todos"),
+            page_order = pagination.sort.order_by("page"),
+            blocked = dependency::blocked_column("page"),
+        );
+        // 間に他のトランザクションが入っても、ページと件数と子孫が同じ時点の内容になるようにする
+        let mut uow = UnitOfWork::begin_snapshot(&self.pool).await?;
+        let now = Utc::now();
+        let items = bind_filter(sqlx::query_as::<_, TodoWithLabelFromRow>(&sql), filter, now)
+            .bind(trashed)
+            .bind(pagination.after)
+            .bind(limit + 1)
+            .bind(self.owner_id)
+            .fetch_all(uow.conn())
+            .await?;
+
+        let sql = formatdoc!(
+            r#"
+                select count(*) from todos
+                    where {filter} and todos.owner_id = $12
+                        and (todos.deleted_at is not null) = $11
+            "#,
+            filter = TODO_FILTER_CONDITION,
+        );
+        let (total,) = bind_filter(sqlx::query_as::<_, (i64,)>(&sql), filter, now)
+            .bind(trashed)
+            .bind(self.owner_id)
+            .fetch_one(uow.conn())
+            .await?;
+
+        let mut page = paginate(fold_entities(items), limit, total);
+        if filter.tree {
+            let ids: Vec<i32> = page.items.iter().map(|todo| todo.id).collect();
+            let descendants = fetch_descendants(uow.conn(), &ids).await?;
+            page.items = build_tree(page.items, &descendants);
+        }
+        uow.commit().await?;
+        Ok(page)
+    }
+}
+
+/// 更新が終わるまで他のトランザクションから更新されないよう行をロックする
+/// owner_id のユーザーの Todo でないか、trashed が指定されていてゴミ箱にあるかどうかが
+/// 一致しなければ NotFound を返す
+/// version が指定されていて現在の version と異なる場合は VersionMismatch を返す
+async fn lock_todo(
+    conn: &mut PgConnection,
+    owner_id: i32,
+    id: i32,
+    version: Option<i32>,
+    trashed: Option<bool>,
+) -> Result<(), RepositoryError> {
+    let current: i32 = sqlx::query_scalar(indoc!(
+        r#"
+            select version from todos
+                where id = $1 and owner_id = $3
+                    and ($2::boolean is null or (deleted_at is not null) = $2)
+                for update
+        "#
+    ))
+    .bind(id)
+    .bind(trashed)
+    .bind(owner_id)
+    .fetch_optional(conn)
+    .await?
+    .ok_or(RepositoryError::NotFound(id))?;
+
+    match version {
+        Some(version) if version != current => Err(RepositoryError::VersionMismatch(current)),
+        _ => Ok(()),
+    }
+}
+
+async fn bump_version(conn: &mut PgConnection, id: i32) -> Result<(), RepositoryError> {
+    sqlx::query("update todos set version = version + 1 where id = $1")
+        .bind(id)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// 移動先の前後の位置。before が true なら neighbour とその 1 つ前、false なら 1 つ後
+/// ゴミ箱にある Todo も、戻したときに元の位置に並ぶよう位置を持ち続ける
+/// 並び順はユーザーごとのため、owner_id のユーザーの Todo だけを見る
+async fn neighbour_positions(
+    conn: &mut PgConnection,
+    owner_id: i32,
+    id: i32,
+    neighbour: i32,
+    before: bool,
+) -> Result<(Option<i64>, Option<i64>), RepositoryError> {
+    let position: i64 = sqlx::query_scalar(indoc!(
+        r#"
+            select position from todos where id = $1 and owner_id = $2 and deleted_at is null
+        "#
+    ))
+    .bind(neighbour)
+    .bind(owner_id)
+    .fetch_optional(&mut *conn)
+    .await?
+    .ok_or(RepositoryError::NotFound(neighbour))?;
+
+    let sql = if before {
+        "select max(position) from todos where position < $1 and id <> $2 and owner_id = $3"
+    } else {
+        "select min(position) from todos where position > $1 and id <> $2 and owner_id = $3"
+    };
+    let other: Option<i64> = sqlx::query_scalar(sql)
+        .bind(position)
+        .bind(id)
+        .bind(owner_id)
+        .fetch_one(conn)
+        .await?;
+
+    if before {
+        Ok((other, Some(position)))
+    } else {
+        Ok((Some(position), other))
+    }
+}
+
+/// parent から根までをたどり、id の Todo を parent の子にできるか確かめる
+/// owner_id のユーザーの Todo でなければ、parent は存在しないものとして扱う
+/// 親の付け替えが同時に起きて循環しないよう、同じユーザーの付け替えは直列にする
+async fn validate_parent(
+    conn: &mut PgConnection,
+    owner_id: i32,
+    id: Option<i32>,
+    parent: i32,
+) -> Result<(), RepositoryError> {
+    sqlx::query("select pg_advisory_xact_lock(hashtext('todos.parent_id'), $1)")
+        .bind(owner_id)
+        .execute(&mut *conn)
+        .await?;
+
+    let ancestors: Vec<i32> = sqlx::query_scalar(indoc!(
+        r#"
+            with recursive ancestors as (
+                select id, parent_id from todos
+                    where id = $1 and owner_id = $2 and deleted_at is null
+                union all
+                select todos.id, todos.parent_id from todos
+                    join ancestors on todos.id = ancestors.parent_id
+            )
+            select id from ancestors
+        "#
+    ))
+    .bind(parent)
+    .bind(owner_id)
+    .fetch_all(&mut *conn)
+    .await?;
+
+    let height: Option<i32> = match id {
+        Some(id) if !ancestors.contains(&id) => {
+            sqlx::query_scalar(indoc!(
+                r#"
+                with recursive subtree as (
+                    select id, 1 as depth from todos where id = $1
+                    union all
+                    select todos.id, subtree.depth + 1 from todos
+                        join subtree on todos.parent_id = subtree.id
+                )
+                select max(depth) from subtree
+            "#
+            ))
+            .bind(id)
+            .fetch_one(conn)
+            .await?
+        }
+        _ => None,
+    };
+    check_parent(id, parent, &ancestors, height.unwrap_or(1) as usize)
+}
+
+/// ids の Todo の子孫を、ゴミ箱にあるものとその子孫を除いて手動の並び順で返す
+async fn fetch_descendants(
+    conn: &mut PgConnection,
+    ids: &[i32],
+) -> Result<Vec<TodoEntity>, RepositoryError> {
+    let sql = formatdoc!(
+        r#"
+            with recursive tree as (
+                select * from todos where parent_id = any($1) and deleted_at is null
+                union all
+                select todos.* from todos
+                    join tree on todos.parent_id = tree.id
+                    where todos.deleted_at is null
+            )
+            select tree.*, {blocked}, labels.id as label_id, labels.name as label_name,
+                    labels.color as label_color, labels.description as label_description,
+                    labels.created_at as label_created_at, todo_series.rrule
+                from tree
+                    left outer join todo_labels t1 on tree.id = t1.todo_id
+                    left outer join labels on labels.id = t1.label_id
+                    left outer join todo_series on todo_series.id = tree.series_id
+                order by tree.position, tree.id desc
+        "#,
+        blocked = dependency::blocked_column("tree"),
+    );
+    let items = sqlx::query_as::<_, TodoWithLabelFromRow>(&sql)
+        .bind(ids)
+        .fetch_all(conn)
+        .await?;
+
+    Ok(fold_entities(items))
+}
+
+/// id の Todo の未完了の子孫を、親から順に返す
+async fn open_descendants(conn: &mut PgConnection, id: i32) -> Result<Vec<i32>, RepositoryError> {
+    let ids = sqlx::query_scalar(indoc!(
+        r#"
+            with recursive tree as (
+                select id, completed, 1 as depth from todos
+                    where parent_id = $1 and deleted_at is null
+                union all
+                select todos.id, todos.completed, tree.depth + 1 from todos
+                    join tree on todos.parent_id = tree.id
+                    where todos.deleted_at is null
+            )
+            select id from tree where not completed order by depth, id
+        "#
+    ))
+    .bind(id)
+    .fetch_all(conn)
+    .await?;
+    Ok(ids)
+}
+
+/// owner_id のユーザーの全ての Todo の位置を、今の並び順のまま POSITION_GAP 間隔に振り直す
+async fn rebalance_positions(
+    conn: &mut PgConnection,
+    owner_id: i32,
+) -> Result<(), RepositoryError> {
+    sqlx::query(indoc!(
+        r#"
+            update todos set position = ranked.rank * $1
+                from (
+                    select id, row_number() over (order by position, id desc) as rank from todos
+                        where owner_id = $2
+                ) as ranked
+                where todos.id = ranked.id
+        "#
+    ))
+    .bind(POSITION_GAP)
+    .bind(owner_id)
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+async fn insert_todo(
+    conn: &mut PgConnection,
+    owner_id: i32,
+    new: NewTodo,
+) -> Result<TodoEntity, RepositoryError> {
+    let (series_id, occurrence) = new.series.unzip();
+    let row = sqlx::query_as::<_, TodoFromRow>(indoc!(
+        r#"
+            insert into todos
+                    (text, completed, due_date, due_at, remind_at, priority, series_id, occurrence,
+                        position, parent_id, owner_id)
+                values (
+                    $1, false, $2, $3, $4, $5, $6, $7,
+                    (select coalesce(max(position), 0) + $8 from todos where owner_id = $10), $9, $10
+                )
+            returning *
+        "#,
+    ))
+    .bind(new.text)
+    .bind(new.due_at.and_then(|due| due.date()))
+    .bind(new.due_at.and_then(|due| due.at()))
+    .bind(new.remind_at)
+    .bind(new.priority)
+    .bind(series_id)
+    .bind(occurrence)
+    .bind(POSITION_GAP)
+    .bind(new.parent_id)
+    .bind(owner_id)
+    .fetch_one(&mut *conn)
+    .await?;
+
+    sqlx::query(indoc! {
+        r#"
+            insert into todo_labels (todo_id, label_id)
+                select $1, id from unnest($2) as t(id)
+        "#
+    })
+    .bind(row.id)
+    .bind(new.labels)
+    .execute(&mut *conn)
+    .await?;
+
+    let todo = find_todo(conn, owner_id, row.id).await?;
+    todo_event::record(
+        conn,
+        owner_id,
+        owner_id,
+        todo.id,
+        TodoEventKind::Created,
+        todo_event::diff(None, Some(&todo)),
+    )
+    .await?;
+    Ok(todo)
+}
+
+async fn create_series(conn: &mut PgConnection, rrule: &RRule) -> Result<i32, RepositoryError> {
+    let series_id = sqlx::query_scalar(indoc!(
+        r#"
+            insert into todo_series (rrule) values ($1) returning id
+        "#
+    ))
+    .bind(rrule.to_string())
+    .fetch_one(conn)
+    .await?;
+    Ok(series_id)
+}
+
+/// 系列に属していれば系列の繰り返しを変え、属していなければ新しい系列の 1 回目にする
+/// 繰り返しは同じ系列のすべての回の表現に含まれるため、他の回の version も上げる
+async fn set_rrule(
+    conn: &mut PgConnection,
+    todo: &TodoEntity,
+    rrule: Option<RRule>,
+) -> Result<(), RepositoryError> {
+    match (&todo.recurrence, rrule) {
+        (Some(recurrence), rrule) => {
+            sqlx::query(indoc!(
+                r#"
+                    with series as (
+                        update todo_series set rrule = $2 where id = $1 returning id
+                    )
+                    update todos set version = version + 1
+                        where series_id in (select id from series) and id <> $3
+                "#
+            ))
+            .bind(recurrence.series_id)
+            .bind(rrule.map(|rrule| rrule.to_string()))
+            .bind(todo.id)
+            .execute(conn)
+            .await?;
+        }
+        (None, Some(rrule)) => {
+            let series_id = create_series(&mut *conn, &rrule).await?;
+            sqlx::query(indoc!(
+                r#"
+                    update todos set series_id = $2, occurrence = 1 where id = $1
+                "#
+            ))
+            .bind(todo.id)
+            .bind(series_id)
+            .execute(conn)
+            .await?;
+        }
+        (None, None) => {}
+    }
+    Ok(())
+}
+
+/// 未完了に戻してから再び完了にした場合などに、同じ回を二重に作らない
+async fn create_occurrence(
+    conn: &mut PgConnection,
+    owner_id: i32,
+    next: NewTodo,
+) -> Result<(), RepositoryError> {
+    let (series_id, occurrence) = next.series.unwrap();
+    let exists: bool = sqlx::query_scalar(indoc!(
+        r#"
+            select exists (select 1 from todos where series_id = $1 and occurrence = $2)
+        "#
+    ))
+    .bind(series_id)
+    .bind(occurrence)
+    .fetch_one(&mut *conn)
+    .await?;
+    if !exists {
+        insert_todo(conn, owner_id, next).await?;
+    }
+    Ok(())
+}
+
+async fn find_todo(
+    conn: &mut PgConnection,
+    owner_id: i32,
+    id: i32,
+) -> Result<TodoEntity, RepositoryError> {
+    fetch_todo(conn, owner_id, id, Some(false)).await
+}
+
+/// owner_id のユーザーの Todo のうち、trashed が指定された場合は、
+/// ゴミ箱にあるかどうかが一致する Todo だけを返す
+async fn fetch_todo(
+    conn: &mut PgConnection,
+    owner_id: i32,
+    id: i32,
+    trashed: Option<bool>,
+) -> Result<TodoEntity, RepositoryError> {
+    let sql = formatdoc!(
+        r#"
+            select todos.*, {blocked}, labels.id as label_id, labels.name as label_name,
+                    labels.color as label_color, labels.description as label_description,
+                        labels.created_at as label_created_at, todo_series.rrule
+                from todos
+                    left outer join todo_labels t1 on todos.id = t1.todo_id
+                    left outer join labels on labels.id = t1.label_id
+                    left outer join todo_series on todo_series.id = todos.series_id
+                where todos.id = $1 and todos.owner_id = $3
+                    and ($2::boolean is null or (todos.deleted_at is not null) = $2)
+        "#,
+        blocked = dependency::blocked_column("todos"),
+    );
+    let items = sqlx::query_as::<_, TodoWithLabelFromRow>(&sql)
+        .bind(id)
+        .bind(trashed)
+        .bind(owner_id)
+        .fetch_all(conn)
+        .await?;
+
+    let todos = fold_entities(items);
+    let todo = todos.first().ok_or(RepositoryError::NotFound(id))?;
+    Ok(todo.clone())
+}
+
+#[async_trait]
+impl TodoRepository for TodoRepositoryForDb {
+    type UnitOfWork = UnitOfWork;
+
+    fn for_owner(&self, owner_id: i32) -> Self {
+        TodoRepositoryForDb {
+            pool: self.pool.clone(),
+            owner_id,
+        }
+    }
+
+    async fn begin(&self) -> Result<UnitOfWork, RepositoryError> {
+        UnitOfWork::begin(&self.pool).await
+    }
+
+    async fn create(&self, payload: CreateTodo) -> Result<TodoEntity, RepositoryError> {
+        let mut uow = UnitOfWork::begin(&self.pool).await?;
+        let todo = self.create_in(&mut uow, payload).await?;
+        uow.commit().await?;
+        Ok(todo)
+    }
+
+    async fn create_in(
+        &self,
+        uow: &mut UnitOfWork,
+        payload: CreateTodo,
+    ) -> Result<TodoEntity, RepositoryError> {
+        let labels = dedup_labels(payload.labels);
+        check_labels(uow.conn(), self.owner_id, &labels).await?;
+        if let Some(parent) = payload.parent_id {
+            validate_parent(uow.conn(), self.owner_id, None, parent).await?;
+        }
+
+        let series = match payload.rrule {
+            Some(rrule) => Some((create_series(uow.conn(), &rrule).await?, 1)),
+            None => None,
+        };
+        insert_todo(
+            uow.conn(),
+            self.owner_id,
+            NewTodo {
+                text: payload.text,
+                labels,
+                due_at: payload.due_at,
+                remind_at: payload.remind_at,
+                priority: payload.priority,
+                parent_id: payload.parent_id,
+                series,
+            },
+        )
+        .await
+    }
+
+    async fn find(&self, id: i32) -> Result<TodoEntity, RepositoryError> {
+        let mut conn = self.pool.acquire().await?;
+        find_todo(&mut conn, self.owner_id, id).await
+    }
+
+    async fn all(
+        &self,
+        filter: TodoFilter,
+        pagination: Pagination,
+    ) -> Result<TodoPage, RepositoryError> {
+        self.list(&filter, &pagination, false).await
+    }
+
+    async fn search(&self, query: SearchQuery) -> Result<Vec<TodoSearchHit>, RepositoryError> {
+        let hits = sqlx::query_as::<_, (i32, f32, String)>(indoc!(
+            r#"
+                select todos.id, ts_rank(todos.text_search, query) as rank,
+                    ts_headline('simple', todos.text, query) as snippet
+                    from todos, websearch_to_tsquery('simple', $1) query
+                    where todos.text_search @@ query and todos.owner_id = $3
+                        and todos.deleted_at is null
+                    order by rank desc, todos.id desc
+                    limit $2
+            "#
+        ))
+        .bind(query.q.clone())
+        .bind(query.limit())
+        .bind(self.owner_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let ids: Vec<i32> = hits.iter().map(|(id, _, _)| *id).collect();
+        let sql = formatdoc!(
+            r#"
+                select todos.*, {blocked}, labels.id as label_id, labels.name as label_name,
+                        labels.color as label_color, labels.description as label_description,
+                        labels.created_at as label_created_at, todo_series.rrule
+                    from todos
+                        left outer join todo_labels t1 on todos.id = t1.todo_id
+                        left outer join labels on labels.id = t1.label_id
+                        left outer join todo_series on todo_series.id = todos.series_id
+                    where todos.id = any($1)
+            "#,
+            blocked = dependency::blocked_column("todos"),
+        );
+        let items = sqlx::query_as::<_, TodoWithLabelFromRow>(&sql)
+            .bind(ids)
+            .fetch_all(&self.pool)
+            .await?;
+        let todos = fold_entities(items);
+
+        // ランク順を保ったまま Todo を紐付ける
+        let hits = hits
+            .into_iter()
+            .filter_map(|(id, rank, snippet)| {
+                let todo = todos.iter().find(|todo| todo.id == id)?.clone();
+                Some(TodoSearchHit {
+                    todo,
+                    rank,
+                    snippet,
+                })
+            })
+            .collect();
+        Ok(hits)
+    }
+
+    async fn update(
+        &self,
+        id: i32,
+        payload: UpdateTodo,
+        version: Option<i32>,
+    ) -> Result<TodoEntity, RepositoryError> {
+        let mut uow = UnitOfWork::begin(&self.pool).await?;
+        let todo = self.update_in(&mut uow, id, payload, version).await?;
+        uow.commit().await?;
+        Ok(todo)
+    }
+
+    async fn delete(&self, id: i32, version: Option<i32>) -> Result<(), RepositoryError> {
+        let mut uow = UnitOfWork::begin(&self.pool).await?;
+        self.delete_in(&mut uow, id, version).await?;
+        uow.commit().await
+    }
+
+    async fn trash(&self, pagination: Pagination) -> Result<TodoPage, RepositoryError> {
+        self.list(&TodoFilter::default(), &pagination, true).await
+    }
+
+    async fn restore(&self, id: i32) -> Result<TodoEntity, RepositoryError> {
+        let mut uow = UnitOfWork::begin(&self.pool).await?;
+        let todo = self.restore_in(&mut uow, id).await?;
+        uow.commit().await?;
+        Ok(todo)
+    }
+
+    async fn purge(&self, id: i32, version: Option<i32>) -> Result<(), RepositoryError> {
+        let mut uow = UnitOfWork::begin(&self.pool).await?;
+        self.purge_in(&mut uow, id, version).await?;
+        uow.commit().await
+    }
+
+    async fn history(&self, id: i32) -> Result<Vec<TodoEvent>, RepositoryError> {
+        let mut conn = self.pool.acquire().await?;
+        todo_event::history(&mut conn, self.owner_id, id).await
+    }
+
+    async fn reorder(&self, id: i32, payload: MoveTodo) -> Result<TodoEntity, RepositoryError> {
+        let mut uow = UnitOfWork::begin(&self.pool).await?;
+        let todo = self.reorder_in(&mut uow, id, payload).await?;
+        uow.commit().await?;
+        Ok(todo)
+    }
+
+    async fn children(&self, id: i32) -> Result<Vec<TodoEntity>, RepositoryError> {
+        let mut conn = self.pool.acquire().await?;
+        let todo = find_todo(&mut conn, self.owner_id, id).await?;
+        let descendants = fetch_descendants(&mut conn, &[id]).await?;
+        let todo = build_tree(vec![todo], &descendants).pop().unwrap();
+        Ok(todo.children)
+    }
+
+    async fn add_dependency(&self, id: i32, other: i32) -> Result<TodoEntity, RepositoryError> {
+        let mut uow = UnitOfWork::begin(&self.pool).await?;
+        let todo = self.add_dependency_in(&mut uow, id, other).await?;
+        uow.commit().await?;
+        Ok(todo)
+    }
+
+    async fn remove_dependency(&self, id: i32, other: i32) -> Result<TodoEntity, RepositoryError> {
+        let mut uow = UnitOfWork::begin(&self.pool).await?;
+        let todo = self.remove_dependency_in(&mut uow, id, other).await?;
+        uow.commit().await?;
+        Ok(todo)
+    }
+
+    async fn dependency_graph(&self) -> Result<DependencyGraph, RepositoryError> {
+        let mut conn = self.pool.acquire().await?;
+        dependency::graph(&mut conn, self.owner_id).await
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "database-test")]
+mod test {
+    use super::*;
+    use crate::repositories::user::test_owner;
+    use dotenv::dotenv;
+    use serde_json::json;
+    use sqlx::PgPool;
+    use std::env;
+
+    #[test]
+    fn fold_entties_test() {
+        let label_1 = Label {
+            id: 1,
+            name: String::from("label 1"),
+            color: Some(String::from("#ff0000")),
+            description: None,
+            created_at: Default::default(),
+        };
+        let label_2 = Label {
+            id: 2,
+            name: String::from("label 2"),
+            color: None,
+            description: Some(String::from("description")),
+            created_at: Default::default(),
+        };
+
+        let rows = vec![
+            TodoWithLabelFromRow {
+                id: 1,
+                text: String::from("todo 1"),
                 completed: false,
+                version: 1,
+                created_at: Default::default(),
+                updated_at: Default::default(),
+                completed_at: None,
+                deleted_at: None,
+                due_date: None,
+                due_at: None,
+                remind_at: None,
+                reminded_at: None,
+                series_id: None,
+                occurrence: None,
+                rrule: None,
+                priority: Priority::None,
+                position: 0,
+                parent_id: None,
+                blocked: false,
                 label_id: Some(label_1.id),
                 label_name: Some(label_1.name.clone()),
+                label_color: label_1.color.clone(),
+                label_description: label_1.description.clone(),
+                label_created_at: Some(label_1.created_at),
             },
             TodoWithLabelFromRow {
                 id: 1,
                 text: String::from("todo 1"),
                 completed: false,
+                version: 1,
+                created_at: Default::default(),
+                updated_at: Default::default(),
+                completed_at: None,
+                deleted_at: None,
+                due_date: None,
+                due_at: None,
+                remind_at: None,
+                reminded_at: None,
+                series_id: None,
+                occurrence: None,
+                rrule: None,
+                priority: Priority::None,
+                position: 0,
+                parent_id: None,
+                blocked: false,
                 label_id: Some(label_2.id),
                 label_name: Some(label_2.name.clone()),
+                label_color: label_2.color.clone(),
+                label_description: label_2.description.clone(),
+                label_created_at: Some(label_2.created_at),
             },
             TodoWithLabelFromRow {
                 id: 2,
                 text: String::from("todo 2"),
                 completed: false,
+                version: 1,
+                created_at: Default::default(),
+                updated_at: Default::default(),
+                completed_at: None,
+                deleted_at: None,
+                due_date: None,
+                due_at: None,
+                remind_at: None,
+                reminded_at: None,
+                series_id: None,
+                occurrence: None,
+                rrule: None,
+                priority: Priority::None,
+                position: 0,
+                parent_id: None,
+                blocked: false,
                 label_id: Some(label_1.id),
                 label_name: Some(label_1.name.clone()),
+                label_color: label_1.color.clone(),
+                label_description: label_1.description.clone(),
+                label_created_at: Some(label_1.created_at),
             },
         ];
         let res = fold_entities(rows);
-        assert_eq!(vec![
+        assert_eq!(
+            vec![
                 TodoEntity {
                     id: 1,
                     text: String::from("todo 1"),
                     completed: false,
-                    labels: vec![
-                        label_1.clone(),
-                        label_2.clone(),
-                    ]
+                    version: 1,
+                    created_at: Default::default(),
+                    updated_at: Default::default(),
+                    completed_at: None,
+                    deleted_at: None,
+                    due_at: None,
+                    remind_at: None,
+                    reminded_at: None,
+                    recurrence: None,
+                    priority: Priority::None,
+                    position: 0,
+                    parent_id: None,
+                    blocked: false,
+                    children: vec![],
+                    labels: vec![label_1.clone(), label_2.clone(),]
                 },
                 TodoEntity {
                     id: 2,
                     text: String::from("todo 2"),
                     completed: false,
-                    labels: vec![
-                        label_1.clone(),
-                    ],
+                    version: 1,
+                    created_at: Default::default(),
+                    updated_at: Default::default(),
+                    completed_at: None,
+                    deleted_at: None,
+                    due_at: None,
+                    remind_at: None,
+                    reminded_at: None,
+                    recurrence: None,
+                    priority: Priority::None,
+                    position: 0,
+                    parent_id: None,
+                    blocked: false,
+                    children: vec![],
+                    labels: vec![label_1.clone(),],
                 },
             ] as Vec<TodoEntity>,
             res
@@ -345,17 +1951,19 @@ mod test {
             .await
             .unwrap_or_else(|_| panic!("fail connect database, url is [{}]", database_url));
 
-        let repository = TodoRepositoryForDb::new(pool.clone());
+        let owner = test_owner(&pool, "todo@example.com").await;
+        let repository = TodoRepositoryForDb::new(pool.clone()).for_owner(owner);
         let todo_text = "[crud_scenario] text";
 
         // label data prepare
         let label_name = String::from("test label");
         let option_label = sqlx::query_as::<_, Label>(indoc!(
             r#"
-                select * from labels where name = $1
+                select * from labels where name = $1 and owner_id = $2
             "#
         ))
         .bind(label_name.clone())
+        .bind(owner)
         .fetch_optional(&pool)
         .await
         .expect("Faild to prepare label data.");
@@ -365,21 +1973,33 @@ mod test {
         } else {
             sqlx::query_as::<_, Label>(indoc!(
                 r#"
-                    insert into labels ( name ) values ( $1 )
+                    insert into labels ( name, owner_id ) values ( $1, $2 )
                     returning *
                 "#
             ))
             .bind(label_name.clone())
+            .bind(owner)
             .fetch_one(&pool)
             .await
             .expect("Faild insert label data.")
         };
 
+        // create (unknown label)
+        let err = repository
+            .create(CreateTodo::new(todo_text.to_string(), vec![label_1.id, -1]))
+            .await
+            .expect_err("[create] todo with unknown label is created");
+        assert!(matches!(err, RepositoryError::UnknownLabels(ids) if ids == vec![-1]));
+
         // create
         let created = repository
-            .create(CreateTodo::new(todo_text.to_string(), vec![label_1.id]))
+            .create(CreateTodo::new(
+                todo_text.to_string(),
+                vec![label_1.id, label_1.id],
+            ))
             .await
             .expect("[create] returned Err");
+        assert_eq!(vec![label_1.clone()], created.labels);
         assert_eq!(created.text, todo_text);
         assert!(!created.completed);
         assert_eq!(*created.labels.first().unwrap(), label_1);
@@ -392,9 +2012,60 @@ mod test {
         assert_eq!(created, todo);
 
         // all
-        let todos = repository.all().await.expect("[all] returned Err]");
-        let todo = todos.first().unwrap();
+        let page = repository
+            .all(TodoFilter::default(), Pagination::default())
+            .await
+            .expect("[all] returned Err]");
+        let todo = page.items.first().unwrap();
         assert_eq!(created, *todo);
+        assert!(page.total >= 1);
+
+        // all (cursor)
+        let page = repository
+            .all(
+                TodoFilter::default(),
+                Pagination::new(Some(1), Some(created.id + 1)),
+            )
+            .await
+            .expect("[all] returned Err]");
+        assert_eq!(vec![created.clone()], page.items);
+
+        // all (filter)
+        let filter = TodoFilter::try_from(vec![
+            ("completed".to_string(), "false".to_string()),
+            ("label".to_string(), label_1.id.to_string()),
+            ("text".to_string(), "CRUD_SCENARIO".to_string()),
+        ])
+        .unwrap();
+        let page = repository
+            .all(filter, Pagination::default())
+            .await
+            .expect("[all] returned Err]");
+        assert!(page.items.contains(&created));
+        let filter = TodoFilter::try_from(vec![(
+            "text".to_string(),
+            "[crud_scenario] not exists".to_string(),
+        )])
+        .unwrap();
+        let page = repository
+            .all(filter, Pagination::default())
+            .await
+            .expect("[all] returned Err]");
+        assert!(page.items.is_empty());
+        assert_eq!(0, page.total);
+
+        // search
+        let hits = repository
+            .search(SearchQuery::new("crud_scenario".to_string(), None))
+            .await
+            .expect("[search] returned Err");
+        let hit = hits
+            .iter()
+            .find(|hit| hit.todo.id == created.id)
+            .expect("[search] created todo is not found");
+        assert_eq!(created, hit.todo);
+        assert!(hit.rank > 0.0);
+        assert!(hit.snippet.contains("<b>scenario</b>"));
 
         // update
         let updated_text = "[crud_scenario] updated text";
@@ -405,7 +2076,14 @@ mod test {
                     text: Some(updated_text.to_string()),
                     completed: Some(true),
                     labels: Some(vec![]),
+                    due_at: None,
+                    remind_at: None,
+                    rrule: None,
+                    priority: None,
+                    parent_id: None,
+                    complete_children: false,
                 },
+                Some(created.version),
             )
             .await
             .expect("[update] returned Err");
@@ -413,14 +2091,79 @@ mod test {
         assert_eq!(todo.text, updated_text);
         assert!(todo.completed);
         assert!(todo.labels.is_empty());
+        assert_eq!(created.version + 1, todo.version);
+        assert_eq!(created.created_at, todo.created_at);
+        assert!(todo.updated_at >= created.updated_at);
+        assert!(todo.completed_at.is_some());
 
-        // delete
+        // update (stale version)
+        let res = repository
+            .update(
+                todo.id,
+                UpdateTodo {
+                    text: Some("[crud_scenario] stale".to_string()),
+                    completed: None,
+                    labels: None,
+                    due_at: None,
+                    remind_at: None,
+                    rrule: None,
+                    priority: None,
+                    parent_id: None,
+                    complete_children: false,
+                },
+                Some(created.version),
+            )
+            .await;
+        assert!(matches!(res, Err(RepositoryError::VersionMismatch(v)) if v == todo.version));
+        let res = repository.delete(todo.id, Some(created.version)).await;
+        assert!(matches!(res, Err(RepositoryError::VersionMismatch(_))));
+        assert_eq!(todo, repository.find(todo.id).await.unwrap());
+
+        // delete (ゴミ箱へ移す)
         repository
-            .delete(todo.id)
+            .delete(todo.id, Some(todo.version))
             .await
             .expect("[delete] returned Err");
         let res = repository.find(created.id).await;
-        assert!(res.is_err());
+        assert!(matches!(res, Err(RepositoryError::NotFound(_))));
+        let page = repository
+            .all(TodoFilter::default(), Pagination::default())
+            .await
+            .expect("[all] returned Err");
+        assert!(page.items.iter().all(|item| item.id != todo.id));
+        let trash = repository
+            .trash(Pagination::default())
+            .await
+            .expect("[trash] returned Err");
+        let trashed = trash
+            .items
+            .iter()
+            .find(|item| item.id == todo.id)
+            .expect("[trash] deleted todo is not found");
+        assert!(trashed.deleted_at.is_some());
+        let res = repository.delete(todo.id, None).await;
+        assert!(matches!(res, Err(RepositoryError::NotFound(_))));
+
+        // restore
+        let restored = repository
+            .restore(todo.id)
+            .await
+            .expect("[restore] returned Err");
+        assert_eq!(None, restored.deleted_at);
+        assert_eq!(todo.version + 2, restored.version);
+        assert_eq!(restored, repository.find(todo.id).await.unwrap());
+        let res = repository.restore(todo.id).await;
+        assert!(matches!(res, Err(RepositoryError::NotFound(_))));
+
+        // purge
+        repository
+            .delete(todo.id, None)
+            .await
+            .expect("[delete] returned Err");
+        repository
+            .purge(todo.id, None)
+            .await
+            .expect("[purge] returned Err");
 
         let todo_rows = sqlx::query(indoc!(
             r#"
@@ -444,103 +2187,1639 @@ mod test {
         .expect("[delete] todo_labels fetch error");
         assert!(rows.is_empty());
 
-    }
-}
-
-#[cfg(test)]
-pub mod test_utils {
-    use super::*;
-    use anyhow::Context;
-    use std::{
-        collections::HashMap,
-        sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard},
-    };
+        // purge (not found)
+        let res = repository.purge(todo.id, None).await;
+        assert!(matches!(res, Err(RepositoryError::NotFound(_))));
 
-    impl CreateTodo {
-        pub fn new(text: String, labels: Vec<i32>) -> Self {
-            Self { text, labels }
-        }
+        // history
+        let events = repository
+            .history(todo.id)
+            .await
+            .expect("[history] returned Err");
+        let kinds: Vec<TodoEventKind> = events.iter().map(|event| event.kind).collect();
+        assert_eq!(
+            vec![
+                TodoEventKind::Created,
+                TodoEventKind::Updated,
+                TodoEventKind::Deleted,
+                TodoEventKind::Restored,
+                TodoEventKind::Deleted,
+                TodoEventKind::Purged,
+            ],
+            kinds
+        );
+        let changes = &events[1].changes;
+        assert_eq!(json!(todo_text), changes["text"].before);
+        assert_eq!(json!(updated_text), changes["text"].after);
+        assert_eq!(json!(label_1.id), changes["labels"].before[0]["id"]);
+        assert_eq!(json!([]), changes["labels"].after);
+        assert!(events.iter().all(|event| event.actor_id == Some(owner)));
+        assert!(events[2].changes["deleted_at"].after.is_string());
+        assert_eq!(json!(null), events[5].changes["text"].after);
     }
 
-    type TodoDatas = HashMap<i32, TodoEntity>;
+    #[tokio::test]
+    async fn due_scenario() {
+        dotenv().ok();
+        let database_url = &env::var("DATABASE_URL").expect("undefined [DATABASE_URL");
+        let pool = PgPool::connect(database_url)
+            .await
+            .unwrap_or_else(|_| panic!("fail connect database, url is [{}]", database_url));
 
-    #[derive(Debug, Clone)]
-    pub struct TodoRepositoryForMemory {
-        store: Arc<RwLock<TodoDatas>>,
-    }
+        let owner = test_owner(&pool, "todo@example.com").await;
+        let repository = TodoRepositoryForDb::new(pool.clone()).for_owner(owner);
+        let today = Utc::now().date_naive();
+        let create = |text: &str, due_at: Due| {
+            let repository = repository.clone();
+            let payload = CreateTodo {
+                due_at: Some(due_at),
+                ..CreateTodo::new(format!("[due_scenario] {}", text), vec![])
+            };
+            async move {
+                repository
+                    .create(payload)
+                    .await
+                    .expect("[create] returned Err")
+            }
+        };
+        let overdue = create("overdue", Due::Date(today.pred_opt().unwrap())).await;
+        let past = create("past", Due::At(Utc::now() - chrono::Duration::hours(1))).await;
+        let upcoming = create("upcoming", Due::Date(today.succ_opt().unwrap())).await;
+        assert_eq!(Some(Due::Date(today.pred_opt().unwrap())), overdue.due_at);
+        assert_eq!(overdue, repository.find(overdue.id).await.unwrap());
 
-    impl TodoRepositoryForMemory {
-        pub fn new() -> Self {
-            TodoRepositoryForMemory {
-                store: Arc::default(),
+        let ids = |due: &str| {
+            let filter = TodoFilter::try_from(vec![
+                ("due".to_string(), due.to_string()),
+                ("tz".to_string(), "UTC".to_string()),
+                ("text".to_string(), "[due_scenario]".to_string()),
+            ])
+            .unwrap();
+            let repository = repository.clone();
+            async move {
+                let page = repository
+                    .all(filter, Pagination::default())
+                    .await
+                    .expect("[all] returned Err");
+                page.items.iter().map(|todo| todo.id).collect::<Vec<_>>()
             }
-        }
+        };
+        assert_eq!(vec![past.id, overdue.id], ids("overdue").await);
+        let week = ids("week").await;
+        assert!(week.contains(&upcoming.id));
+        assert!(!week.contains(&overdue.id));
 
-        fn write_store_ref(&self) -> RwLockWriteGuard<TodoDatas> {
-            self.store.write().unwrap()
-        }
+        // 完了したものは overdue から外れ、null で期限が消える
+        repository
+            .update(
+                overdue.id,
+                UpdateTodo {
+                    text: None,
+                    completed: Some(true),
+                    labels: None,
+                    due_at: None,
+                    remind_at: None,
+                    rrule: None,
+                    priority: None,
+                    parent_id: None,
+                    complete_children: false,
+                },
+                None,
+            )
+            .await
+            .expect("[update] returned Err");
+        let todo = repository
+            .update(
+                past.id,
+                UpdateTodo {
+                    text: None,
+                    completed: None,
+                    labels: None,
+                    due_at: Some(None),
+                    remind_at: None,
+                    rrule: None,
+                    priority: None,
+                    parent_id: None,
+                    complete_children: false,
+                },
+                None,
+            )
+            .await
+            .expect("[update] returned Err");
+        assert_eq!(None, todo.due_at);
+        assert!(ids("overdue").await.is_empty());
 
-        fn read_store_ref(&self) -> RwLockReadGuard<TodoDatas> {
-            self.store.read().unwrap()
+        for todo in [overdue, past, upcoming] {
+            repository.purge(todo.id, None).await.unwrap();
         }
     }
 
-    impl Default for TodoRepositoryForMemory {
+    #[tokio::test]
+    async fn recurrence_scenario() {
+        use crate::repositories::label::{CreateLabel, LabelRepository, LabelRepositoryForDb};
+
+        dotenv().ok();
+        let database_url = &env::var("DATABASE_URL").expect("undefined [DATABASE_URL");
+        let pool = PgPool::connect(database_url)
+            .await
+            .unwrap_or_else(|_| panic!("fail connect database, url is [{}]", database_url));
+
+        let owner = test_owner(&pool, "todo@example.com").await;
+        let label_repository = LabelRepositoryForDb::new(pool.clone()).for_owner(owner);
+        let repository = TodoRepositoryForDb::new(pool.clone()).for_owner(owner);
+        let label = label_repository
+            .create(CreateLabel::new("[recurrence_scenario]".to_string()))
+            .await
+            .expect("[create label] returned Err");
+        let payload: CreateTodo = serde_json::from_value(json!({
+            "text": "[recurrence_scenario] pay rent",
+            "labels": [label.id],
+            "due_at": "2024-01-31",
+            "rrule": "FREQ=MONTHLY;UNTIL=20240331",
+        }))
+        .unwrap();
+        let first = repository
+            .create(payload)
+            .await
+            .expect("[create] returned Err");
+        assert_eq!(first, repository.find(first.id).await.unwrap());
+        let series_id = first.recurrence.as_ref().unwrap().series_id;
+
+        let ids = || {
+            let filter = TodoFilter::try_from(vec![(
+                "text".to_string(),
+                "[recurrence_scenario]".to_string(),
+            )])
+            .unwrap();
+            let repository = repository.clone();
+            async move {
+                let page = repository
+                    .all(filter, Pagination::default())
+                    .await
+                    .expect("[all] returned Err");
+                page.items.iter().map(|todo| todo.id).collect::<Vec<_>>()
+            }
+        };
+        let complete = |id: i32| {
+            let repository = repository.clone();
+            let payload: UpdateTodo = serde_json::from_str(r#"{"completed": true}"#).unwrap();
+            async move {
+                repository
+                    .update(id, payload, None)
+                    .await
+                    .expect("[update] returned Err")
+            }
+        };
+
+        // 完了と同じトランザクションで次の回ができる。31 日のない月は飛ばす
+        complete(first.id).await;
+        let ids_after_first = ids().await;
+        assert_eq!(2, ids_after_first.len());
+        let second = repository.find(ids_after_first[0]).await.unwrap();
+        assert_eq!(vec![label.clone()], second.labels);
+        assert_eq!(
+            Some(Due::Date(NaiveDate::from_ymd_opt(2024, 3, 31).unwrap())),
+            second.due_at
+        );
+        assert_eq!(
+            Some(Recurrence {
+                series_id,
+                occurrence: 2,
+                rrule: Some("FREQ=MONTHLY;UNTIL=20240331".parse().unwrap()),
+            }),
+            second.recurrence
+        );
+
+        // UNTIL を過ぎる回は作らない
+        complete(second.id).await;
+        assert_eq!(2, ids().await.len());
+
+        // 止めた系列は、完了を戻してやり直しても次の回を作らない
+        // 他の回の表現も変わるため、その version も上がる
+        let second_version = repository.find(second.id).await.unwrap().version;
+        let payload: UpdateTodo =
+            serde_json::from_str(r#"{"completed": false, "rrule": null}"#).unwrap();
+        let todo = repository
+            .update(first.id, payload, None)
+            .await
+            .expect("[update] returned Err");
+        assert_eq!(None, todo.recurrence.unwrap().rrule);
+        let second = repository.find(second.id).await.unwrap();
+        assert_eq!(None, second.recurrence.unwrap().rrule);
+        assert_eq!(second_version + 1, second.version);
+        complete(first.id).await;
+        assert_eq!(2, ids().await.len());
+
+        for id in [first.id, second.id] {
+            repository.purge(id, None).await.unwrap();
+        }
+        label_repository.delete(label.id, false).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn sort_scenario() {
+        dotenv().ok();
+        let database_url = &env::var("DATABASE_URL").expect("undefined [DATABASE_URL");
+        let pool = PgPool::connect(database_url)
+            .await
+            .unwrap_or_else(|_| panic!("fail connect database, url is [{}]", database_url));
+
+        let owner = test_owner(&pool, "todo@example.com").await;
+        let repository = TodoRepositoryForDb::new(pool.clone()).for_owner(owner);
+        let mut todos = vec![];
+        for priority in [
+            Priority::Low,
+            Priority::Urgent,
+            Priority::None,
+            Priority::Urgent,
+        ] {
+            let payload = CreateTodo {
+                priority,
+                ..CreateTodo::new("[sort_scenario]".to_string(), vec![])
+            };
+            let todo = repository
+                .create(payload)
+                .await
+                .expect("[create] returned Err");
+            assert_eq!(priority, todo.priority);
+            todos.push(todo.id);
+        }
+
+        let page = |sort: &str, after: Option<i32>| {
+            let filter =
+                TodoFilter::try_from(vec![("text".to_string(), "[sort_scenario]".to_string())])
+                    .unwrap();
+            let pagination = Pagination::new(Some(2), after).with_sort(sort);
+            let repository = repository.clone();
+            async move {
+                repository
+                    .all(filter, pagination)
+                    .await
+                    .expect("[all] returned Err")
+            }
+        };
+        let ids = |page: &TodoPage| page.items.iter().map(|todo| todo.id).collect::<Vec<_>>();
+
+        let first = page("-priority,created_at", None).await;
+        assert_eq!(vec![todos[1], todos[3]], ids(&first));
+        assert_eq!(Some(todos[3]), first.next_cursor);
+        let second = page("-priority,created_at", first.next_cursor).await;
+        assert_eq!(vec![todos[0], todos[2]], ids(&second));
+        assert_eq!(None, second.next_cursor);
+
+        // 同じ優先度は id の降順
+        let todo = repository
+            .update(
+                todos[2],
+                serde_json::from_str(r#"{"priority": "urgent"}"#).unwrap(),
+                None,
+            )
+            .await
+            .expect("[update] returned Err");
+        assert_eq!(Priority::Urgent, todo.priority);
+        let first = page("-priority", None).await;
+        assert_eq!(vec![todos[3], todos[2]], ids(&first));
+        let second = page("-priority", first.next_cursor).await;
+        assert_eq!(vec![todos[1], todos[0]], ids(&second));
+
+        for id in todos {
+            repository.purge(id, None).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn reorder_scenario() {
+        dotenv().ok();
+        let database_url = &env::var("DATABASE_URL").expect("undefined [DATABASE_URL");
+        let pool = PgPool::connect(database_url)
+            .await
+            .unwrap_or_else(|_| panic!("fail connect database, url is [{}]", database_url));
+
+        let owner = test_owner(&pool, "todo@example.com").await;
+        let repository = TodoRepositoryForDb::new(pool.clone()).for_owner(owner);
+        let mut todos = vec![];
+        for _ in 0..3 {
+            let todo = repository
+                .create(CreateTodo::new("[reorder_scenario]".to_string(), vec![]))
+                .await
+                .expect("[create] returned Err");
+            todos.push(todo.id);
+        }
+        let order = || {
+            let filter =
+                TodoFilter::try_from(vec![("text".to_string(), "[reorder_scenario]".to_string())])
+                    .unwrap();
+            let repository = repository.clone();
+            async move {
+                let page = repository
+                    .all(filter, Pagination::new(None, None).with_sort("position"))
+                    .await
+                    .expect("[all] returned Err");
+                page.items.iter().map(|todo| todo.id).collect::<Vec<_>>()
+            }
+        };
+        let move_to = |id: i32, payload: serde_json::Value| {
+            let repository = repository.clone();
+            let payload: MoveTodo = serde_json::from_value(payload).unwrap();
+            async move { repository.reorder(id, payload).await }
+        };
+
+        assert_eq!(todos, order().await);
+        let todo = move_to(todos[2], json!({ "before": todos[0] }))
+            .await
+            .expect("[reorder] returned Err");
+        assert_eq!(1, todo.version);
+        assert_eq!(vec![todos[2], todos[0], todos[1]], order().await);
+
+        // 同じ隙間への移動を繰り返すと、振り直しが起きても並び順は保たれる
+        for i in 0..20 {
+            let id = todos[i % 2];
+            move_to(id, json!({ "after": todos[2] }))
+                .await
+                .expect("[reorder] returned Err");
+        }
+        assert_eq!(vec![todos[2], todos[1], todos[0]], order().await);
+
+        let res = move_to(todos[0], json!({ "after": todos[0] + 100 })).await;
+        assert!(matches!(res, Err(RepositoryError::NotFound(id)) if id == todos[0] + 100));
+
+        for id in todos {
+            repository.purge(id, None).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn tree_scenario() {
+        dotenv().ok();
+        let database_url = &env::var("DATABASE_URL").expect("undefined [DATABASE_URL");
+        let pool = PgPool::connect(database_url)
+            .await
+            .unwrap_or_else(|_| panic!("fail connect database, url is [{}]", database_url));
+
+        let owner = test_owner(&pool, "todo@example.com").await;
+        let repository = TodoRepositoryForDb::new(pool.clone()).for_owner(owner);
+        let create = |parent_id: Option<i32>| {
+            let repository = repository.clone();
+            let payload = CreateTodo {
+                parent_id,
+                ..CreateTodo::new("[tree_scenario]".to_string(), vec![])
+            };
+            async move { repository.create(payload).await }
+        };
+        let root = create(None).await.expect("[create] returned Err");
+        let child = create(Some(root.id)).await.expect("[create] returned Err");
+        let grandchild = create(Some(child.id)).await.expect("[create] returned Err");
+        let sibling = create(Some(root.id)).await.expect("[create] returned Err");
+        assert_eq!(grandchild, repository.find(grandchild.id).await.unwrap());
+
+        let children = repository.children(root.id).await.unwrap();
+        let ids = |todos: &[TodoEntity]| todos.iter().map(|todo| todo.id).collect::<Vec<_>>();
+        assert_eq!(vec![child.id, sibling.id], ids(&children));
+        assert_eq!(vec![grandchild.id], ids(&children[0].children));
+
+        let filter = TodoFilter::try_from(vec![
+            ("text".to_string(), "[tree_scenario]".to_string()),
+            ("tree".to_string(), "true".to_string()),
+        ])
+        .unwrap();
+        let page = repository
+            .all(filter, Pagination::default())
+            .await
+            .expect("[all] returned Err");
+        assert_eq!(vec![root.id], ids(&page.items));
+        assert_eq!(children, page.items[0].children);
+
+        let payload: UpdateTodo =
+            serde_json::from_value(json!({ "parent_id": grandchild.id })).unwrap();
+        let res = repository.update(root.id, payload, None).await;
+        assert!(matches!(
+            res,
+            Err(RepositoryError::InvalidParent(_, ParentError::Cycle))
+        ));
+        let res = create(Some(root.id + 100)).await;
+        assert!(matches!(
+            res,
+            Err(RepositoryError::InvalidParent(_, ParentError::NotFound))
+        ));
+
+        // 子孫も同じトランザクションで完了にする
+        let payload: UpdateTodo =
+            serde_json::from_value(json!({ "completed": true, "complete_children": true }))
+                .unwrap();
+        repository
+            .update(root.id, payload, None)
+            .await
+            .expect("[update] returned Err");
+        for todo in [&child, &grandchild, &sibling] {
+            let todo = repository.find(todo.id).await.unwrap();
+            assert!(todo.completed);
+            assert_eq!(2, todo.version);
+        }
+
+        // 親を完全に削除すると、子は親のない Todo になり、version が上がって履歴に残る
+        repository.purge(root.id, None).await.unwrap();
+        let todo = repository.find(child.id).await.unwrap();
+        assert_eq!(None, todo.parent_id);
+        assert_eq!(3, todo.version);
+        let event = repository.history(child.id).await.unwrap().pop().unwrap();
+        assert_eq!(TodoEventKind::Updated, event.kind);
+        assert_eq!(
+            json!({ "parent_id": { "before": root.id, "after": null } }),
+            json!(event.changes.0)
+        );
+        for todo in [child, grandchild, sibling] {
+            repository.purge(todo.id, None).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn dependency_scenario() {
+        dotenv().ok();
+        let database_url = &env::var("DATABASE_URL").expect("undefined [DATABASE_URL");
+        let pool = PgPool::connect(database_url)
+            .await
+            .unwrap_or_else(|_| panic!("fail connect database, url is [{}]", database_url));
+
+        let owner = test_owner(&pool, "todo@example.com").await;
+        let repository = TodoRepositoryForDb::new(pool.clone()).for_owner(owner);
+        let mut todos = vec![];
+        for text in ["design", "implement", "release"] {
+            let payload = CreateTodo::new(format!("[dependency_scenario] {}", text), vec![]);
+            todos.push(
+                repository
+                    .create(payload)
+                    .await
+                    .expect("[create] returned Err"),
+            );
+        }
+        let [design, implement, release] = [todos[0].id, todos[1].id, todos[2].id];
+
+        let todo = repository.add_dependency(implement, design).await.unwrap();
+        assert_eq!((true, 2), (todo.blocked, todo.version));
+        let todo = repository.add_dependency(implement, design).await.unwrap();
+        assert_eq!(2, todo.version);
+        repository.add_dependency(release, implement).await.unwrap();
+        assert_eq!(todo, repository.find(implement).await.unwrap());
+
+        let res = repository.add_dependency(design, release).await;
+        assert!(matches!(
+            res,
+            Err(RepositoryError::DependencyCycle(path)) if path == vec![design, release, implement, design]
+        ));
+        let res = repository.add_dependency(design, design).await;
+        assert!(matches!(
+            res,
+            Err(RepositoryError::DependencyCycle(path)) if path == vec![design, design]
+        ));
+
+        let payload: UpdateTodo = serde_json::from_value(json!({ "completed": true })).unwrap();
+        repository.update(design, payload, None).await.unwrap();
+        let todo = repository.find(implement).await.unwrap();
+        assert_eq!((false, 3), (todo.blocked, todo.version));
+
+        let graph = repository.dependency_graph().await.unwrap();
+        let ids = [design, implement, release];
+        let edges: Vec<(i32, i32)> = graph
+            .edges
+            .into_iter()
+            .filter(|(todo_id, _)| ids.contains(todo_id))
+            .collect();
+        assert_eq!(vec![(implement, design), (release, implement)], edges);
+        let blocked: Vec<i32> = graph
+            .nodes
+            .iter()
+            .filter(|node| ids.contains(&node.id) && node.blocked)
+            .map(|node| node.id)
+            .collect();
+        assert_eq!(vec![release], blocked);
+
+        repository.delete(implement, None).await.unwrap();
+        assert!(!repository.find(release).await.unwrap().blocked);
+        repository.restore(implement).await.unwrap();
+        let todo = repository
+            .remove_dependency(release, implement)
+            .await
+            .unwrap();
+        assert!(!todo.blocked);
+        let res = repository.remove_dependency(release, implement).await;
+        assert!(matches!(res, Err(RepositoryError::NotFound(id)) if id == implement));
+
+        for id in ids {
+            repository.purge(id, None).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn dependency_diamond_scenario() {
+        dotenv().ok();
+        let database_url = &env::var("DATABASE_URL").expect("undefined [DATABASE_URL");
+        let pool = PgPool::connect(database_url)
+            .await
+            .unwrap_or_else(|_| panic!("fail connect database, url is [{}]", database_url));
+
+        // 各層の 2 つの Todo が次の層の 2 つを待つ。上から下への道は 2^LAYERS 通りある
+        const LAYERS: usize = 24;
+        let owner = test_owner(&pool, "todo@example.com").await;
+        let repository = TodoRepositoryForDb::new(pool.clone()).for_owner(owner);
+        let mut ids = vec![];
+        for i in 0..LAYERS * 2 + 2 {
+            let payload = CreateTodo::new(format!("[dependency_diamond_scenario] {}", i), vec![]);
+            ids.push(repository.create(payload).await.unwrap().id);
+        }
+        for layer in (0..LAYERS).rev() {
+            for todo in &ids[layer * 2..layer * 2 + 2] {
+                for other in &ids[layer * 2 + 2..layer * 2 + 4] {
+                    repository.add_dependency(*todo, *other).await.unwrap();
+                }
+            }
+        }
+
+        let (top, bottom) = (ids[0], ids[LAYERS * 2]);
+        let res = repository.add_dependency(bottom, top).await;
+        assert!(matches!(
+            res,
+            Err(RepositoryError::DependencyCycle(path))
+                if path.len() == LAYERS + 2 && path[..2] == [bottom, top] && path[LAYERS + 1] == bottom
+        ));
+
+        for id in ids {
+            repository.purge(id, None).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn owner_scenario() {
+        use crate::repositories::label::{CreateLabel, LabelRepository, LabelRepositoryForDb};
+
+        dotenv().ok();
+        let database_url = &env::var("DATABASE_URL").expect("undefined [DATABASE_URL");
+        let pool = PgPool::connect(database_url)
+            .await
+            .unwrap_or_else(|_| panic!("fail connect database, url is [{}]", database_url));
+
+        let owner = test_owner(&pool, "todo@example.com").await;
+        let label_repository = LabelRepositoryForDb::new(pool.clone()).for_owner(owner);
+        let repository = TodoRepositoryForDb::new(pool.clone()).for_owner(owner);
+        let other = TodoRepositoryForDb::new(pool.clone())
+            .for_owner(test_owner(&pool, "other@example.com").await);
+
+        let label = label_repository
+            .create(CreateLabel::new("[owner_scenario] label".to_string()))
+            .await
+            .expect("[create] label returned Err");
+        let todo = repository
+            .create(CreateTodo::new(
+                "[owner_scenario] mine".to_string(),
+                vec![label.id],
+            ))
+            .await
+            .expect("[create] returned Err");
+        let theirs = other
+            .create(CreateTodo::new(
+                "[owner_scenario] theirs".to_string(),
+                vec![],
+            ))
+            .await
+            .expect("[create] returned Err");
+        // 並び順はユーザーごとに持つ
+        assert_eq!(POSITION_GAP, theirs.position);
+
+        // 他のユーザーの Todo は見えない
+        let res = other.find(todo.id).await;
+        assert!(matches!(res, Err(RepositoryError::NotFound(_))));
+        let page = other
+            .all(TodoFilter::default(), Pagination::new(Some(100), None))
+            .await
+            .unwrap();
+        assert!(page.items.iter().all(|item| item.id != todo.id));
+        assert!(page.items.iter().any(|item| item.id == theirs.id));
+        let hits = other
+            .search(SearchQuery::new("owner_scenario".to_string(), None))
+            .await
+            .unwrap();
+        assert_eq!(
+            vec![theirs.id],
+            hits.iter().map(|hit| hit.todo.id).collect::<Vec<_>>()
+        );
+        let res = other.history(todo.id).await;
+        assert!(matches!(res, Err(RepositoryError::NotFound(_))));
+        let res = other.children(todo.id).await;
+        assert!(matches!(res, Err(RepositoryError::NotFound(_))));
+
+        // 他のユーザーの Todo は変更できない
+        let payload: UpdateTodo = serde_json::from_value(json!({ "text": "stolen" })).unwrap();
+        let res = other.update(todo.id, payload, None).await;
+        assert!(matches!(res, Err(RepositoryError::NotFound(_))));
+        let res = other.delete(todo.id, None).await;
+        assert!(matches!(res, Err(RepositoryError::NotFound(_))));
+        let res = other.purge(todo.id, None).await;
+        assert!(matches!(res, Err(RepositoryError::NotFound(_))));
+        let payload: MoveTodo = serde_json::from_value(json!({ "before": todo.id })).unwrap();
+        let res = other.reorder(theirs.id, payload).await;
+        assert!(matches!(res, Err(RepositoryError::NotFound(id)) if id == todo.id));
+        let res = other.add_dependency(theirs.id, todo.id).await;
+        assert!(matches!(res, Err(RepositoryError::NotFound(id)) if id == todo.id));
+        let res = other.add_dependency(todo.id, theirs.id).await;
+        assert!(matches!(res, Err(RepositoryError::NotFound(id)) if id == todo.id));
+
+        // 他のユーザーのラベルと親は使えない
+        let res = other
+            .create(CreateTodo::new(
+                "[owner_scenario] theirs".to_string(),
+                vec![label.id],
+            ))
+            .await;
+        assert!(matches!(res, Err(RepositoryError::UnknownLabels(ids)) if ids == vec![label.id]));
+        let payload: UpdateTodo = serde_json::from_value(json!({ "parent_id": todo.id })).unwrap();
+        let res = other.update(theirs.id, payload, None).await;
+        assert!(matches!(
+            res,
+            Err(RepositoryError::InvalidParent(_, ParentError::NotFound))
+        ));
+
+        // ゴミ箱と完全に削除した Todo の履歴も見えない
+        repository.delete(todo.id, None).await.unwrap();
+        let page = other.trash(Pagination::new(Some(100), None)).await.unwrap();
+        assert!(page.items.iter().all(|item| item.id != todo.id));
+        let res = other.restore(todo.id).await;
+        assert!(matches!(res, Err(RepositoryError::NotFound(_))));
+        repository.purge(todo.id, None).await.unwrap();
+        let res = other.history(todo.id).await;
+        assert!(matches!(res, Err(RepositoryError::NotFound(_))));
+        assert_eq!(3, repository.history(todo.id).await.unwrap().len());
+        assert_eq!(theirs, other.find(theirs.id).await.unwrap());
+
+        other.purge(theirs.id, None).await.unwrap();
+        label_repository.delete(label.id, true).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn unit_of_work_rollback_scenario() {
+        use crate::repositories::label::{CreateLabel, LabelRepository, LabelRepositoryForDb};
+
+        dotenv().ok();
+        let database_url = &env::var("DATABASE_URL").expect("undefined [DATABASE_URL");
+        let pool = PgPool::connect(database_url)
+            .await
+            .unwrap_or_else(|_| panic!("fail connect database, url is [{}]", database_url));
+
+        let owner = test_owner(&pool, "todo@example.com").await;
+        let repository = TodoRepositoryForDb::new(pool.clone()).for_owner(owner);
+
+        let label_repository = LabelRepositoryForDb::new(pool.clone()).for_owner(owner);
+
+        // ラベルと Todo をまとめて作る
+        let mut uow = repository.begin().await.expect("[begin] returned Err");
+        let label = label_repository
+            .create_in(
+                &mut uow,
+                CreateLabel::new("[unit_of_work] label".to_string()),
+            )
+            .await
+            .expect("[create_in] returned Err");
+        let committed = repository
+            .create_in(
+                &mut uow,
+                CreateTodo::new("[unit_of_work] committed".to_string(), vec![label.id]),
+            )
+            .await
+            .expect("[create_in] returned Err");
+        // commit するまでは他からは見えない
+        assert!(matches!(
+            repository.find(committed.id).await,
+            Err(RepositoryError::NotFound(_))
+        ));
+        uow.commit().await.expect("[commit] returned Err");
+        let todo = repository.find(committed.id).await.unwrap();
+        assert_eq!(vec![label.clone()], todo.labels);
+
+        // ラベルの作成に失敗したら、先に作った Todo も取り消される
+        let mut uow = repository.begin().await.expect("[begin] returned Err");
+        let todo = repository
+            .create_in(
+                &mut uow,
+                CreateTodo::new("[unit_of_work] rolled back".to_string(), vec![]),
+            )
+            .await
+            .expect("[create_in] returned Err");
+        let res = label_repository
+            .create_in(
+                &mut uow,
+                CreateLabel::new("[unit_of_work] label".to_string()),
+            )
+            .await;
+        assert!(matches!(res, Err(RepositoryError::Duplicate(id)) if id == label.id));
+        drop(uow);
+        assert!(matches!(
+            repository.find(todo.id).await,
+            Err(RepositoryError::NotFound(_))
+        ));
+
+        // Todo の作成に失敗したら、先に作ったラベルも取り消される
+        let mut uow = repository.begin().await.expect("[begin] returned Err");
+        let other = label_repository
+            .create_in(
+                &mut uow,
+                CreateLabel::new("[unit_of_work] other".to_string()),
+            )
+            .await
+            .expect("[create_in] returned Err");
+        let res = repository
+            .create_in(
+                &mut uow,
+                CreateTodo::new(
+                    "[unit_of_work] unknown label".to_string(),
+                    vec![other.id, -1],
+                ),
+            )
+            .await;
+        assert!(matches!(res, Err(RepositoryError::UnknownLabels(_))));
+        drop(uow);
+        assert!(matches!(
+            label_repository.find(other.id).await,
+            Err(RepositoryError::NotFound(_))
+        ));
+
+        repository.purge(committed.id, None).await.unwrap();
+        label_repository.delete(label.id, false).await.unwrap();
+    }
+}
+
+#[cfg(test)]
+pub mod test_utils {
+    use super::*;
+    use crate::repositories::dependency::GraphNode;
+    use crate::repositories::test_utils::{
+        MemoryStore, MemoryTables, MemoryUnitOfWork, DEFAULT_OWNER,
+    };
+    use std::{cmp::Ordering, collections::BTreeSet};
+
+    impl CreateTodo {
+        pub fn new(text: String, labels: Vec<i32>) -> Self {
+            Self {
+                text,
+                labels,
+                due_at: None,
+                remind_at: None,
+                rrule: None,
+                priority: Priority::None,
+                parent_id: None,
+            }
+        }
+    }
+
+    impl CreateTodo {
+        pub fn with_remind_at(self, remind_at: DateTime<Utc>) -> Self {
+            Self {
+                remind_at: Some(remind_at),
+                ..self
+            }
+        }
+    }
+
+    impl Pagination {
+        pub fn new(limit: Option<i64>, after: Option<i32>) -> Self {
+            Self {
+                limit,
+                after,
+                sort: TodoSort::default(),
+            }
+        }
+
+        pub fn with_sort(self, sort: &str) -> Self {
+            Self {
+                sort: sort.to_string().try_into().unwrap(),
+                ..self
+            }
+        }
+    }
+
+    impl TodoSort {
+        fn compare(&self, a: &TodoEntity, b: &TodoEntity) -> Ordering {
+            self.fields()
+                .iter()
+                .fold(Ordering::Equal, |ordering, field| {
+                    ordering.then_with(|| {
+                        let ordering = match field.key {
+                            SortKey::Id => a.id.cmp(&b.id),
+                            SortKey::Priority => a.priority.cmp(&b.priority),
+                            SortKey::CreatedAt => a.created_at.cmp(&b.created_at),
+                            SortKey::UpdatedAt => a.updated_at.cmp(&b.updated_at),
+                            SortKey::Position => a.position.cmp(&b.position),
+                        };
+                        if field.descending {
+                            ordering.reverse()
+                        } else {
+                            ordering
+                        }
+                    })
+                })
+        }
+
+        // Db と同じく、after の Todo がなければ id だけで並べている場合に限って続きを返す
+        fn is_after(&self, todo: &TodoEntity, after: i32, tables: &MemoryTables) -> bool {
+            match tables.todos.get(&after) {
+                Some(cursor) => self.compare(todo, cursor) == Ordering::Greater,
+                None => match self.fields()[..] {
+                    [SortField {
+                        key: SortKey::Id,
+                        descending,
+                    }] => (todo.id < after) == descending,
+                    _ => false,
+                },
+            }
+        }
+    }
+
+    impl SearchQuery {
+        pub fn new(q: String, limit: Option<i64>) -> Self {
+            Self { q, limit }
+        }
+    }
+
+    impl TodoFilter {
+        fn matches(&self, todo: &TodoEntity, now: DateTime<Utc>) -> bool {
+            let has_label = |id: &i32| todo.labels.iter().any(|label| label.id == *id);
+            self.completed
+                .is_none_or(|completed| todo.completed == completed)
+                && self
+                    .text
+                    .as_ref()
+                    .is_none_or(|text| todo.text.to_lowercase().contains(&text.to_lowercase()))
+                && (self.labels.is_empty()
+                    || match self.label_match {
+                        LabelMatch::Any => self.labels.iter().any(has_label),
+                        LabelMatch::All => self.labels.iter().all(has_label),
+                    })
+                && self
+                    .due_window(now)
+                    .is_none_or(|window| window.contains(todo.due_at.as_ref(), todo.completed))
+                && (!self.tree || todo.parent_id.is_none())
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct TodoRepositoryForMemory {
+        store: MemoryStore,
+        owner_id: i32,
+    }
+
+    impl TodoRepositoryForMemory {
+        pub fn new() -> Self {
+            Self::with_store(MemoryStore::default())
+        }
+
+        pub fn with_store(store: MemoryStore) -> Self {
+            TodoRepositoryForMemory {
+                store,
+                owner_id: DEFAULT_OWNER,
+            }
+        }
+    }
+
+    impl Default for TodoRepositoryForMemory {
         fn default() -> Self {
             Self::new()
         }
     }
 
+    fn owns(tables: &MemoryTables, owner_id: i32, id: i32) -> bool {
+        tables.todo_owners.get(&id) == Some(&owner_id)
+    }
+
+    // Db と同じく、存在しないか他のユーザーのラベルがあれば UnknownLabels を返す
+    fn check_labels(
+        tables: &MemoryTables,
+        owner_id: i32,
+        ids: Vec<i32>,
+    ) -> Result<Vec<Label>, RepositoryError> {
+        let ids = dedup_labels(ids);
+        let unknown: Vec<i32> = ids
+            .iter()
+            .filter(|id| tables.label_owners.get(id) != Some(&owner_id))
+            .copied()
+            .collect();
+        if !unknown.is_empty() {
+            return Err(RepositoryError::UnknownLabels(unknown));
+        }
+        Ok(find_labels(tables, &ids))
+    }
+
+    // Db の join と同じく、存在するラベルだけを紐付ける
+    fn find_labels(tables: &MemoryTables, ids: &[i32]) -> Vec<Label> {
+        ids.iter()
+            .filter_map(|id| tables.labels.get(id).cloned())
+            .collect()
+    }
+
+    // Db と同じく、version が異なる場合は VersionMismatch を返す
+    fn check_version(todo: &TodoEntity, version: Option<i32>) -> Result<(), RepositoryError> {
+        match version {
+            Some(version) if version != todo.version => {
+                Err(RepositoryError::VersionMismatch(todo.version))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // ラベルの更新を反映するため、読み出す度にラベルを引き直す
+    // 系列の繰り返しと blocked も他の Todo から変わるため、同じく引き直す
+    fn load(tables: &MemoryTables, todo: &TodoEntity) -> TodoEntity {
+        let ids: Vec<i32> = todo.labels.iter().map(|label| label.id).collect();
+        let recurrence = todo.recurrence.clone().map(|recurrence| Recurrence {
+            rrule: tables.series[&recurrence.series_id].clone(),
+            ..recurrence
+        });
+        TodoEntity {
+            labels: find_labels(tables, &ids),
+            recurrence,
+            blocked: is_blocked(tables, todo.id),
+            ..todo.clone()
+        }
+    }
+
+    // Db と同じく、未完了でゴミ箱にもない Todo を待っていれば blocked
+    fn is_blocked(tables: &MemoryTables, id: i32) -> bool {
+        tables
+            .dependencies
+            .range((id, i32::MIN)..=(id, i32::MAX))
+            .filter_map(|(_, other)| tables.todos.get(other))
+            .any(|other| !other.completed && other.deleted_at.is_none())
+    }
+
+    // Db と同じく、id の Todo を待っている Todo の version を進める
+    fn touch_dependents(tables: &mut MemoryTables, id: i32) {
+        let dependents: Vec<i32> = tables
+            .dependencies
+            .iter()
+            .filter(|(_, other)| *other == id)
+            .map(|(todo_id, _)| *todo_id)
+            .collect();
+        for todo_id in dependents {
+            if let Some(todo) = tables.todos.get_mut(&todo_id) {
+                todo.version += 1;
+            }
+        }
+    }
+
+    // Db と同じく、other から依存をたどって id に戻ってくれば、その道筋を返す
+    fn find_cycle(tables: &MemoryTables, id: i32, other: i32) -> Option<Vec<i32>> {
+        let edges: Vec<(i32, i32)> = tables.dependencies.iter().copied().collect();
+        dependency::find_cycle(&edges, id, other)
+    }
+
+    fn create_todo(
+        tables: &mut MemoryTables,
+        owner_id: i32,
+        payload: CreateTodo,
+    ) -> Result<TodoEntity, RepositoryError> {
+        // 系列を作る前にラベルと親を確かめる
+        check_labels(tables, owner_id, payload.labels.clone())?;
+        if let Some(parent) = payload.parent_id {
+            validate_parent(tables, owner_id, None, parent)?;
+        }
+        let series = payload.rrule.map(|rrule| (create_series(tables, rrule), 1));
+        insert_todo(
+            tables,
+            owner_id,
+            NewTodo {
+                text: payload.text,
+                labels: payload.labels,
+                due_at: payload.due_at,
+                remind_at: payload.remind_at,
+                priority: payload.priority,
+                parent_id: payload.parent_id,
+                series,
+            },
+        )
+    }
+
+    // Db と同じく、作成したことを履歴に追記する
+    fn insert_todo(
+        tables: &mut MemoryTables,
+        owner_id: i32,
+        new: NewTodo,
+    ) -> Result<TodoEntity, RepositoryError> {
+        // purge で歯抜けになるため、最大の id の次を振る
+        let id = tables.todos.keys().max().unwrap_or(&0) + 1;
+        let mut todo = TodoEntity::new(id, new.text);
+        todo.labels = check_labels(tables, owner_id, new.labels)?;
+        todo.due_at = new.due_at;
+        todo.remind_at = new.remind_at;
+        todo.priority = new.priority;
+        todo.parent_id = new.parent_id;
+        todo.position = tables
+            .todos
+            .values()
+            .filter(|todo| owns(tables, owner_id, todo.id))
+            .map(|todo| todo.position)
+            .max()
+            .unwrap_or(0)
+            + POSITION_GAP;
+        todo.recurrence = new.series.map(|(series_id, occurrence)| Recurrence {
+            series_id,
+            occurrence,
+            rrule: tables.series[&series_id].clone(),
+        });
+        todo.created_at = tables.now;
+        todo.updated_at = tables.now;
+        tables.todos.insert(id, todo.clone());
+        tables.todo_owners.insert(id, owner_id);
+        record(tables, TodoEventKind::Created, None, Some(&todo));
+        Ok(todo)
+    }
+
+    // Db と同じく、同じユーザーのゴミ箱にある Todo も含めて前後の位置を求める
+    fn neighbour_positions(
+        tables: &MemoryTables,
+        owner_id: i32,
+        id: i32,
+        neighbour: i32,
+        before: bool,
+    ) -> Result<(Option<i64>, Option<i64>), RepositoryError> {
+        let position = get_todo(tables, owner_id, neighbour, Some(false))?.position;
+        let others = tables
+            .todos
+            .values()
+            .filter(|todo| todo.id != id && owns(tables, owner_id, todo.id));
+        if before {
+            let lower = others
+                .map(|todo| todo.position)
+                .filter(|other| *other < position)
+                .max();
+            Ok((lower, Some(position)))
+        } else {
+            let upper = others
+                .map(|todo| todo.position)
+                .filter(|other| *other > position)
+                .min();
+            Ok((Some(position), upper))
+        }
+    }
+
+    // Db と同じく、同じユーザーの Todo を、同じ位置なら id の降順に並べたまま振り直す
+    fn rebalance_positions(tables: &mut MemoryTables, owner_id: i32) {
+        let owners = &tables.todo_owners;
+        let mut todos: Vec<&mut TodoEntity> = tables
+            .todos
+            .values_mut()
+            .filter(|todo| owners.get(&todo.id) == Some(&owner_id))
+            .collect();
+        todos.sort_by_key(|todo| (todo.position, std::cmp::Reverse(todo.id)));
+        for (rank, todo) in todos.into_iter().enumerate() {
+            todo.position = (rank as i64 + 1) * POSITION_GAP;
+        }
+    }
+
+    fn create_series(tables: &mut MemoryTables, rrule: RRule) -> i32 {
+        let series_id = tables.series.len() as i32 + 1;
+        tables.series.insert(series_id, Some(rrule));
+        series_id
+    }
+
+    // Db と同じく sort の順に並べ、after の Todo より後ろから返す
+    fn list(
+        tables: &MemoryTables,
+        owner_id: i32,
+        filter: &TodoFilter,
+        pagination: &Pagination,
+        trashed: bool,
+    ) -> TodoPage {
+        let limit = pagination.limit();
+        let mut todos: Vec<TodoEntity> = tables
+            .todos
+            .values()
+            .filter(|todo| todo.deleted_at.is_some() == trashed)
+            .filter(|todo| owns(tables, owner_id, todo.id))
+            .map(|todo| load(tables, todo))
+            .filter(|todo| filter.matches(todo, tables.now))
+            .collect();
+        todos.sort_by(|a, b| pagination.sort.compare(a, b));
+        let total = todos.len() as i64;
+        let items = todos
+            .into_iter()
+            .filter(|todo| {
+                pagination
+                    .after
+                    .is_none_or(|after| pagination.sort.is_after(todo, after, tables))
+            })
+            .take(limit as usize + 1)
+            .collect();
+        let mut page = paginate(items, limit, total);
+        if filter.tree {
+            let ids: Vec<i32> = page.items.iter().map(|todo| todo.id).collect();
+            page.items = build_tree(page.items, &descendants(tables, &ids));
+        }
+        page
+    }
+
+    // Db と同じく 1 件ずつ更新し、繰り返しの Todo を完了にしたら次の回を作る
+    fn update_one(
+        tables: &mut MemoryTables,
+        owner_id: i32,
+        id: i32,
+        payload: UpdateTodo,
+        version: Option<i32>,
+    ) -> Result<TodoEntity, RepositoryError> {
+        let old_todo = load(tables, get_todo(tables, owner_id, id, Some(false))?);
+        let mut todo = old_todo.clone();
+        check_version(&todo, version)?;
+        if let Some(Some(parent)) = payload.parent_id {
+            validate_parent(tables, owner_id, Some(id), parent)?;
+        }
+        if let Some(text) = payload.text {
+            todo.text = text;
+        }
+        if let Some(completed) = payload.completed {
+            // Db と同じく、未完了から完了にしたときだけ完了日時を入れる
+            todo.completed_at = match (todo.completed, completed) {
+                (_, false) => None,
+                (false, true) => Some(tables.now),
+                (true, true) => todo.completed_at,
+            };
+            todo.completed = completed;
+        }
+        if let Some(labels) = payload.labels {
+            todo.labels = check_labels(tables, owner_id, labels)?;
+        }
+        if let Some(due_at) = payload.due_at {
+            todo.due_at = due_at;
+        }
+        if let Some(priority) = payload.priority {
+            todo.priority = priority;
+        }
+        if let Some(parent_id) = payload.parent_id {
+            todo.parent_id = parent_id;
+        }
+        if let Some(remind_at) = payload.remind_at {
+            if remind_at != todo.remind_at {
+                todo.reminded_at = None;
+                tables.reminder_attempts.remove(&id);
+            }
+            todo.remind_at = remind_at;
+        }
+        match (&mut todo.recurrence, payload.rrule) {
+            (Some(recurrence), Some(rrule)) => {
+                // Db と同じく、同じ系列の他の回の version も上げる
+                tables.series.insert(recurrence.series_id, rrule.clone());
+                recurrence.rrule = rrule;
+                let series_id = recurrence.series_id;
+                for other in tables.todos.values_mut() {
+                    if other.id != id
+                        && other.recurrence.as_ref().map(|r| r.series_id) == Some(series_id)
+                    {
+                        other.version += 1;
+                    }
+                }
+            }
+            (None, Some(Some(rrule))) => {
+                let series_id = create_series(tables, rrule.clone());
+                todo.recurrence = Some(Recurrence {
+                    series_id,
+                    occurrence: 1,
+                    rrule: Some(rrule),
+                });
+            }
+            _ => {}
+        }
+        todo.version += 1;
+        todo.updated_at = tables.now;
+        tables.todos.insert(todo.id, todo.clone());
+        if old_todo.completed != todo.completed {
+            touch_dependents(tables, id);
+        }
+        record(tables, TodoEventKind::Updated, Some(&old_todo), Some(&todo));
+
+        // Db と同じく、同じ回を二重に作らない
+        if !old_todo.completed && todo.completed {
+            if let Some(next) = next_occurrence(&todo, tables.now.date_naive()) {
+                let exists = tables.todos.values().any(|todo| {
+                    todo.recurrence
+                        .as_ref()
+                        .map(|r| (r.series_id, r.occurrence))
+                        == next.series
+                });
+                if !exists {
+                    insert_todo(tables, owner_id, next)?;
+                }
+            }
+        }
+        Ok(todo)
+    }
+
+    // Db と同じく、parent から根までをたどって確かめる
+    fn validate_parent(
+        tables: &MemoryTables,
+        owner_id: i32,
+        id: Option<i32>,
+        parent: i32,
+    ) -> Result<(), RepositoryError> {
+        let mut ancestors = vec![];
+        let mut next = get_todo(tables, owner_id, parent, Some(false)).ok();
+        while let Some(todo) = next {
+            ancestors.push(todo.id);
+            next = todo.parent_id.and_then(|id| tables.todos.get(&id));
+        }
+        fn height(tables: &MemoryTables, id: i32) -> usize {
+            let children = tables
+                .todos
+                .values()
+                .filter(|todo| todo.parent_id == Some(id));
+            1 + children
+                .map(|todo| height(tables, todo.id))
+                .max()
+                .unwrap_or(0)
+        }
+        let height = match id {
+            Some(id) if !ancestors.contains(&id) => height(tables, id),
+            _ => 1,
+        };
+        check_parent(id, parent, &ancestors, height)
+    }
+
+    // Db と同じく、ゴミ箱にあるものとその子孫を除いて手動の並び順で返す
+    fn descendants(tables: &MemoryTables, ids: &[i32]) -> Vec<TodoEntity> {
+        let mut accm: Vec<TodoEntity> = vec![];
+        let mut parents = ids.to_vec();
+        while !parents.is_empty() {
+            let children: Vec<TodoEntity> = tables
+                .todos
+                .values()
+                .filter(|todo| todo.deleted_at.is_none())
+                .filter(|todo| todo.parent_id.is_some_and(|id| parents.contains(&id)))
+                .map(|todo| load(tables, todo))
+                .collect();
+            parents = children.iter().map(|todo| todo.id).collect();
+            accm.extend(children);
+        }
+        accm.sort_by_key(|todo| (todo.position, std::cmp::Reverse(todo.id)));
+        accm
+    }
+
+    // Db と同じく、未完了の子孫を親から順に返す
+    fn open_descendants(tables: &MemoryTables, id: i32) -> Vec<i32> {
+        let mut accm = vec![];
+        let mut parents = vec![id];
+        while !parents.is_empty() {
+            let mut children: Vec<&TodoEntity> = tables
+                .todos
+                .values()
+                .filter(|todo| todo.deleted_at.is_none())
+                .filter(|todo| todo.parent_id.is_some_and(|id| parents.contains(&id)))
+                .collect();
+            children.sort_by_key(|todo| todo.id);
+            parents = children.iter().map(|todo| todo.id).collect();
+            accm.extend(
+                children
+                    .iter()
+                    .filter(|todo| !todo.completed)
+                    .map(|todo| todo.id),
+            );
+        }
+        accm
+    }
+
+    // Db と同じく、変更前後の差分を履歴に追記する
+    fn record(
+        tables: &mut MemoryTables,
+        kind: TodoEventKind,
+        before: Option<&TodoEntity>,
+        after: Option<&TodoEntity>,
+    ) {
+        let todo_id = before.or(after).map(|todo| todo.id).unwrap();
+        tables.record_event(todo_id, kind, todo_event::diff(before, after));
+    }
+
+    // Db と同じく、他のユーザーの Todo か、ゴミ箱にあるかどうかが一致しなければ NotFound を返す
+    fn get_todo(
+        tables: &MemoryTables,
+        owner_id: i32,
+        id: i32,
+        trashed: Option<bool>,
+    ) -> Result<&TodoEntity, RepositoryError> {
+        tables
+            .todos
+            .get(&id)
+            .filter(|todo| owns(tables, owner_id, todo.id))
+            .filter(|todo| trashed.is_none_or(|trashed| todo.deleted_at.is_some() == trashed))
+            .ok_or(RepositoryError::NotFound(id))
+    }
+
     #[async_trait]
     impl TodoRepository for TodoRepositoryForMemory {
-        async fn create(&self, payload: CreateTodo) -> anyhow::Result<TodoEntity> {
-            let mut store = self.write_store_ref();
-            let id = (store.len() + 1) as i32;
-            let todo = TodoEntity::new(id, payload.text.clone());
-            store.insert(id, todo.clone());
-            Ok(todo)
+        type UnitOfWork = MemoryUnitOfWork;
+
+        fn for_owner(&self, owner_id: i32) -> Self {
+            TodoRepositoryForMemory {
+                store: self.store.clone(),
+                owner_id,
+            }
         }
 
-        async fn find(&self, id: i32) -> anyhow::Result<TodoEntity> {
-            let store = self.read_store_ref();
-            let todo = store
-                .get(&id)
-                .cloned()
-                .ok_or(RepositoryError::NotFound(id))?; 
-            Ok(todo)
+        async fn begin(&self) -> Result<MemoryUnitOfWork, RepositoryError> {
+            Ok(MemoryUnitOfWork::begin(&self.store))
+        }
+
+        async fn create(&self, payload: CreateTodo) -> Result<TodoEntity, RepositoryError> {
+            create_todo(&mut self.store.write(), self.owner_id, payload)
         }
 
-        async fn all(&self) -> anyhow::Result<Vec<TodoEntity>> {
-            Ok(Vec::from_iter(self.read_store_ref().values().cloned()))
+        async fn create_in(
+            &self,
+            uow: &mut MemoryUnitOfWork,
+            payload: CreateTodo,
+        ) -> Result<TodoEntity, RepositoryError> {
+            create_todo(&mut uow.tables, self.owner_id, payload)
         }
 
-        async fn update(&self, id: i32, payload: UpdateTodo) -> anyhow::Result<TodoEntity> {
-            let mut store = self.write_store_ref();
-            let mut todo = store
-                .get(&id)
-                .context(RepositoryError::NotFound(id))?
-                .clone();
-            if let Some(text) = payload.text {
-                todo.text = text;
+        async fn find(&self, id: i32) -> Result<TodoEntity, RepositoryError> {
+            let tables = self.store.read();
+            let todo = get_todo(&tables, self.owner_id, id, Some(false))?;
+            Ok(load(&tables, todo))
+        }
+
+        async fn all(
+            &self,
+            filter: TodoFilter,
+            pagination: Pagination,
+        ) -> Result<TodoPage, RepositoryError> {
+            Ok(list(
+                &self.store.read(),
+                self.owner_id,
+                &filter,
+                &pagination,
+                false,
+            ))
+        }
+
+        // 空白区切りの語を全て含む Todo を、一致した語の数が多い順に返す
+        async fn search(&self, query: SearchQuery) -> Result<Vec<TodoSearchHit>, RepositoryError> {
+            let tables = self.store.read();
+            let terms: Vec<String> = query
+                .q
+                .split_whitespace()
+                .map(|term| term.to_lowercase())
+                .collect();
+            if terms.is_empty() {
+                return Ok(vec![]);
             }
-            if let Some(completed) = payload.completed {
-                todo.completed = completed;
+
+            let mut hits: Vec<TodoSearchHit> = tables
+                .todos
+                .values()
+                .filter(|todo| todo.deleted_at.is_none() && owns(&tables, self.owner_id, todo.id))
+                .filter_map(|todo| {
+                    let words: Vec<&str> = todo.text.split_whitespace().collect();
+                    let is_match = |word: &str| terms.contains(&word.to_lowercase());
+                    if !terms
+                        .iter()
+                        .all(|term| words.iter().any(|word| word.to_lowercase() == *term))
+                    {
+                        return None;
+                    }
+                    let rank = words.iter().filter(|word| is_match(word)).count() as f32;
+                    let snippet = words
+                        .iter()
+                        .map(|word| {
+                            if is_match(word) {
+                                format!("<b>{}</b>", word)
+                            } else {
+                                word.to_string()
+                            }
+                        })
+                        .collect::<Vec<_>>()
+                        .join(" ");
+                    Some(TodoSearchHit {
+                        todo: load(&tables, todo),
+                        rank,
+                        snippet,
+                    })
+                })
+                .collect();
+            hits.sort_by(|a, b| b.rank.total_cmp(&a.rank).then(b.todo.id.cmp(&a.todo.id)));
+            hits.truncate(query.limit() as usize);
+            Ok(hits)
+        }
+
+        async fn update(
+            &self,
+            id: i32,
+            payload: UpdateTodo,
+            version: Option<i32>,
+        ) -> Result<TodoEntity, RepositoryError> {
+            let mut tables = self.store.write();
+            let complete_children = payload.complete_children && payload.completed == Some(true);
+            let todo = update_one(&mut tables, self.owner_id, id, payload, version)?;
+            if complete_children {
+                for child in open_descendants(&tables, id) {
+                    let payload = UpdateTodo {
+                        completed: Some(true),
+                        ..UpdateTodo::default()
+                    };
+                    update_one(&mut tables, self.owner_id, child, payload, None)?;
+                }
             }
-            store.insert(todo.id, todo.clone());
             Ok(todo)
         }
 
-        async fn delete(&self, id: i32) -> anyhow::Result<()> {
-            self.write_store_ref()
-                .remove(&id)
-                .context(RepositoryError::NotFound(id))?;
+        async fn delete(&self, id: i32, version: Option<i32>) -> Result<(), RepositoryError> {
+            let mut tables = self.store.write();
+            let old_todo = load(&tables, get_todo(&tables, self.owner_id, id, Some(false))?);
+            check_version(&old_todo, version)?;
+            let todo = TodoEntity {
+                deleted_at: Some(tables.now),
+                version: old_todo.version + 1,
+                ..old_todo.clone()
+            };
+            tables.todos.insert(id, todo.clone());
+            touch_dependents(&mut tables, id);
+            record(
+                &mut tables,
+                TodoEventKind::Deleted,
+                Some(&old_todo),
+                Some(&todo),
+            );
+            Ok(())
+        }
+
+        async fn trash(&self, pagination: Pagination) -> Result<TodoPage, RepositoryError> {
+            Ok(list(
+                &self.store.read(),
+                self.owner_id,
+                &TodoFilter::default(),
+                &pagination,
+                true,
+            ))
+        }
+
+        async fn restore(&self, id: i32) -> Result<TodoEntity, RepositoryError> {
+            let mut tables = self.store.write();
+            let old_todo = load(&tables, get_todo(&tables, self.owner_id, id, Some(true))?);
+            let todo = TodoEntity {
+                deleted_at: None,
+                version: old_todo.version + 1,
+                ..old_todo.clone()
+            };
+            tables.todos.insert(id, todo.clone());
+            touch_dependents(&mut tables, id);
+            record(
+                &mut tables,
+                TodoEventKind::Restored,
+                Some(&old_todo),
+                Some(&todo),
+            );
+            Ok(todo)
+        }
+
+        async fn purge(&self, id: i32, version: Option<i32>) -> Result<(), RepositoryError> {
+            let mut tables = self.store.write();
+            let old_todo = load(&tables, get_todo(&tables, self.owner_id, id, None)?);
+            check_version(&old_todo, version)?;
+            touch_dependents(&mut tables, id);
+            tables.todos.remove(&id);
+            // Db の on delete cascade と同じく、依存関係も消える
+            tables
+                .dependencies
+                .retain(|(todo_id, other)| *todo_id != id && *other != id);
+            // Db と同じく、子は親のない Todo になり、version を進めて変更を記録する
+            let mut child_ids: Vec<i32> = tables
+                .todos
+                .values()
+                .filter(|todo| todo.parent_id == Some(id))
+                .map(|todo| todo.id)
+                .collect();
+            child_ids.sort_unstable();
+            for child_id in child_ids {
+                let old_child = load(&tables, &tables.todos[&child_id]);
+                let mut child = old_child.clone();
+                child.parent_id = None;
+                child.version += 1;
+                child.updated_at = tables.now;
+                tables.todos.insert(child_id, child.clone());
+                record(
+                    &mut tables,
+                    TodoEventKind::Updated,
+                    Some(&old_child),
+                    Some(&child),
+                );
+            }
+            record(&mut tables, TodoEventKind::Purged, Some(&old_todo), None);
             Ok(())
         }
+
+        async fn history(&self, id: i32) -> Result<Vec<TodoEvent>, RepositoryError> {
+            let tables = self.store.read();
+            // 完全に削除した Todo も、所有者は todo_owners に残っている
+            if !owns(&tables, self.owner_id, id) {
+                return Err(RepositoryError::NotFound(id));
+            }
+            let events: Vec<TodoEvent> = tables
+                .todo_events
+                .iter()
+                .filter(|event| event.todo_id == id)
+                .cloned()
+                .collect();
+            Ok(events)
+        }
+
+        async fn reorder(&self, id: i32, payload: MoveTodo) -> Result<TodoEntity, RepositoryError> {
+            let mut tables = self.store.write();
+            get_todo(&tables, self.owner_id, id, Some(false))?;
+            let (neighbour, before) = payload.neighbour();
+            if neighbour != id {
+                let (lower, upper) =
+                    neighbour_positions(&tables, self.owner_id, id, neighbour, before)?;
+                let position = match position_between(lower, upper) {
+                    Some(position) => position,
+                    None => {
+                        rebalance_positions(&mut tables, self.owner_id);
+                        let (lower, upper) =
+                            neighbour_positions(&tables, self.owner_id, id, neighbour, before)?;
+                        position_between(lower, upper).unwrap()
+                    }
+                };
+                tables.todos.get_mut(&id).unwrap().position = position;
+            }
+            Ok(load(&tables, &tables.todos[&id]))
+        }
+
+        async fn children(&self, id: i32) -> Result<Vec<TodoEntity>, RepositoryError> {
+            let tables = self.store.read();
+            let todo = load(&tables, get_todo(&tables, self.owner_id, id, Some(false))?);
+            let todo = build_tree(vec![todo], &descendants(&tables, &[id]))
+                .pop()
+                .unwrap();
+            Ok(todo.children)
+        }
+
+        async fn add_dependency(&self, id: i32, other: i32) -> Result<TodoEntity, RepositoryError> {
+            let mut tables = self.store.write();
+            get_todo(&tables, self.owner_id, id, Some(false))?;
+            get_todo(&tables, self.owner_id, other, Some(false))?;
+            if let Some(cycle) = find_cycle(&tables, id, other) {
+                return Err(RepositoryError::DependencyCycle(cycle));
+            }
+            if tables.dependencies.insert((id, other)) {
+                tables.todos.get_mut(&id).unwrap().version += 1;
+            }
+            Ok(load(&tables, &tables.todos[&id]))
+        }
+
+        async fn remove_dependency(
+            &self,
+            id: i32,
+            other: i32,
+        ) -> Result<TodoEntity, RepositoryError> {
+            let mut tables = self.store.write();
+            get_todo(&tables, self.owner_id, id, Some(false))?;
+            if !tables.dependencies.remove(&(id, other)) {
+                return Err(RepositoryError::NotFound(other));
+            }
+            tables.todos.get_mut(&id).unwrap().version += 1;
+            Ok(load(&tables, &tables.todos[&id]))
+        }
+
+        async fn dependency_graph(&self) -> Result<DependencyGraph, RepositoryError> {
+            let tables = self.store.read();
+            let alive = |id: &i32| get_todo(&tables, self.owner_id, *id, Some(false)).is_ok();
+            let edges: Vec<(i32, i32)> = tables
+                .dependencies
+                .iter()
+                .filter(|(todo_id, other)| alive(todo_id) && alive(other))
+                .copied()
+                .collect();
+            let ids: BTreeSet<i32> = edges.iter().flat_map(|(a, b)| [*a, *b]).collect();
+            let nodes = ids
+                .into_iter()
+                .map(|id| {
+                    let todo = load(&tables, &tables.todos[&id]);
+                    GraphNode {
+                        id,
+                        text: todo.text,
+                        completed: todo.completed,
+                        blocked: todo.blocked,
+                    }
+                })
+                .collect();
+            Ok(DependencyGraph { nodes, edges })
+        }
     }
 
     mod test {
         use super::{CreateTodo, TodoEntity, TodoRepository, TodoRepositoryForMemory};
-        use crate::repositories::todo::UpdateTodo;
+        use crate::repositories::{
+            due::Due,
+            label::{test_utils::LabelRepositoryForMemory, CreateLabel, LabelRepository},
+            recurrence::Recurrence,
+            test_utils::MemoryStore,
+            todo::{
+                MoveTodo, Pagination, Priority, SearchQuery, TodoFilter, TodoSort, UpdateTodo,
+                MAX_DEPTH, POSITION_GAP,
+            },
+            todo_event::TodoEventKind,
+            ParentError, RepositoryError,
+        };
+        use chrono::{DateTime, TimeZone, Utc};
 
         #[tokio::test]
         async fn todo_crud_scenario() {
@@ -551,7 +3830,15 @@ pub mod test_utils {
 
             // create
             let labels = vec![];
-            let todo = CreateTodo { text: text.clone(), labels };
+            let todo = CreateTodo {
+                text: text.clone(),
+                labels,
+                due_at: None,
+                remind_at: None,
+                rrule: None,
+                priority: Priority::None,
+                parent_id: None,
+            };
             repository.create(todo).await.expect("failed create todo");
 
             // find
@@ -561,6 +3848,20 @@ pub mod test_utils {
                     id,
                     text: text.clone(),
                     completed,
+                    version: 1,
+                    created_at: Default::default(),
+                    updated_at: Default::default(),
+                    completed_at: None,
+                    deleted_at: None,
+                    due_at: None,
+                    remind_at: None,
+                    reminded_at: None,
+                    recurrence: None,
+                    priority: Priority::None,
+                    position: 1024,
+                    parent_id: None,
+                    blocked: false,
+                    children: vec![],
                     labels: vec![],
                 },
                 todo
@@ -574,6 +3875,20 @@ pub mod test_utils {
                     id,
                     text: text.clone(),
                     completed,
+                    version: 2,
+                    created_at: Default::default(),
+                    updated_at: Default::default(),
+                    completed_at: Some(Default::default()),
+                    deleted_at: None,
+                    due_at: None,
+                    remind_at: None,
+                    reminded_at: None,
+                    recurrence: None,
+                    priority: Priority::None,
+                    position: 1024,
+                    parent_id: None,
+                    blocked: false,
+                    children: vec![],
                     labels: vec![],
                 },
                 repository
@@ -583,7 +3898,14 @@ pub mod test_utils {
                             text: Some(text.clone()),
                             completed: Some(completed),
                             labels: Some(vec![]),
-                        }
+                            due_at: None,
+                            remind_at: None,
+                            rrule: None,
+                            priority: None,
+                            parent_id: None,
+                            complete_children: false,
+                        },
+                        Some(1),
                     )
                     .await
                     .unwrap()
@@ -595,14 +3917,1021 @@ pub mod test_utils {
                     id,
                     text: text.clone(),
                     completed,
+                    version: 2,
+                    created_at: Default::default(),
+                    updated_at: Default::default(),
+                    completed_at: Some(Default::default()),
+                    deleted_at: None,
+                    due_at: None,
+                    remind_at: None,
+                    reminded_at: None,
+                    recurrence: None,
+                    priority: Priority::None,
+                    position: 1024,
+                    parent_id: None,
+                    blocked: false,
+                    children: vec![],
                     labels: vec![],
                 }]
                 .to_vec(),
-                repository.all().await.expect("faild get all todo")
+                repository
+                    .all(TodoFilter::default(), Pagination::default())
+                    .await
+                    .expect("faild get all todo")
+                    .items
             );
 
+            // delete (stale version)
+            assert!(matches!(
+                repository.delete(id, Some(1)).await,
+                Err(RepositoryError::VersionMismatch(2))
+            ));
+
             // delete
-            assert!(repository.delete(id).await.is_ok());
+            assert!(repository.delete(id, Some(2)).await.is_ok());
+        }
+
+        #[tokio::test]
+        async fn todo_timestamps_scenario() {
+            let store = MemoryStore::default();
+            let repository = TodoRepositoryForMemory::with_store(store.clone());
+            let at = |hour: u32| Utc.with_ymd_and_hms(2024, 10, 1, hour, 0, 0).unwrap();
+            let update = |completed: Option<bool>| UpdateTodo {
+                text: Some("updated".to_string()),
+                completed,
+                labels: None,
+                due_at: None,
+                remind_at: None,
+                rrule: None,
+                priority: None,
+                parent_id: None,
+                complete_children: false,
+            };
+
+            store.write().now = at(1);
+            let todo = repository
+                .create(CreateTodo::new("todo".to_string(), vec![]))
+                .await
+                .unwrap();
+            assert_eq!((at(1), at(1), None), timestamps(&todo));
+
+            // 完了にした時だけ completed_at が入る
+            store.write().now = at(2);
+            let todo = repository
+                .update(1, update(Some(true)), None)
+                .await
+                .unwrap();
+            assert_eq!((at(1), at(2), Some(at(2))), timestamps(&todo));
+
+            store.write().now = at(3);
+            let todo = repository.update(1, update(None), None).await.unwrap();
+            assert_eq!((at(1), at(3), Some(at(2))), timestamps(&todo));
+            let todo = repository
+                .update(1, update(Some(true)), None)
+                .await
+                .unwrap();
+            assert_eq!((at(1), at(3), Some(at(2))), timestamps(&todo));
+
+            // 未完了に戻すと消える
+            let todo = repository
+                .update(1, update(Some(false)), None)
+                .await
+                .unwrap();
+            assert_eq!((at(1), at(3), None), timestamps(&todo));
+        }
+
+        fn timestamps(todo: &TodoEntity) -> (DateTime<Utc>, DateTime<Utc>, Option<DateTime<Utc>>) {
+            (todo.created_at, todo.updated_at, todo.completed_at)
+        }
+
+        #[tokio::test]
+        async fn todo_due_scenario() {
+            let store = MemoryStore::default();
+            let repository = TodoRepositoryForMemory::with_store(store.clone());
+            let due =
+                |value: &str| -> Due { serde_json::from_str(&format!("\"{}\"", value)).unwrap() };
+            // 東京では 10/17 の 08:00
+            store.write().now = Utc.with_ymd_and_hms(2024, 10, 16, 23, 0, 0).unwrap();
+            for (text, due_at) in [
+                ("yesterday", Some(due("2024-10-16"))),
+                ("this morning", Some(due("2024-10-17T07:00:00+09:00"))),
+                ("today", Some(due("2024-10-17"))),
+                ("next week", Some(due("2024-10-24"))),
+                ("no due", None),
+            ] {
+                repository
+                    .create(CreateTodo {
+                        due_at,
+                        ..CreateTodo::new(text.to_string(), vec![])
+                    })
+                    .await
+                    .expect("failed create todo");
+            }
+
+            let ids = |due: &str, tz: &str| {
+                let filter = TodoFilter::try_from(vec![
+                    ("due".to_string(), due.to_string()),
+                    ("tz".to_string(), tz.to_string()),
+                ])
+                .unwrap();
+                let repository = repository.clone();
+                async move {
+                    let page = repository
+                        .all(filter, Pagination::default())
+                        .await
+                        .expect("failed get all todo");
+                    page.items.iter().map(|todo| todo.id).collect::<Vec<_>>()
+                }
+            };
+
+            assert_eq!(vec![2, 1], ids("overdue", "Asia/Tokyo").await);
+            assert_eq!(vec![3, 2], ids("today", "Asia/Tokyo").await);
+            assert_eq!(vec![3, 2], ids("week", "Asia/Tokyo").await);
+            // UTC ではまだ 10/16
+            assert_eq!(vec![2, 1], ids("today", "UTC").await);
+            assert_eq!(vec![3, 2, 1], ids("week", "UTC").await);
+
+            // 完了すると overdue から外れ、null を指定すると期限が消える
+            repository
+                .update(
+                    1,
+                    UpdateTodo {
+                        text: None,
+                        completed: Some(true),
+                        labels: None,
+                        due_at: None,
+                        remind_at: None,
+                        rrule: None,
+                        priority: None,
+                        parent_id: None,
+                        complete_children: false,
+                    },
+                    None,
+                )
+                .await
+                .unwrap();
+            assert_eq!(vec![2], ids("overdue", "Asia/Tokyo").await);
+            let payload: UpdateTodo = serde_json::from_str(r#"{"due_at": null}"#).unwrap();
+            let todo = repository.update(2, payload, None).await.unwrap();
+            assert_eq!(None, todo.due_at);
+            assert!(ids("overdue", "Asia/Tokyo").await.is_empty());
+            let payload: UpdateTodo = serde_json::from_str(r#"{"text": "kept"}"#).unwrap();
+            let todo = repository.update(3, payload, None).await.unwrap();
+            assert_eq!(Some(due("2024-10-17")), todo.due_at);
+        }
+
+        #[tokio::test]
+        async fn todo_recurrence_scenario() {
+            let store = MemoryStore::default();
+            let label_repository = LabelRepositoryForMemory::with_store(store.clone());
+            let repository = TodoRepositoryForMemory::with_store(store.clone());
+            // 2024-10-01 は火曜日
+            store.write().now = Utc.with_ymd_and_hms(2024, 10, 1, 12, 0, 0).unwrap();
+            let label = label_repository
+                .create(CreateLabel::new("chore".to_string()))
+                .await
+                .unwrap();
+            let payload = |json: &str| -> UpdateTodo { serde_json::from_str(json).unwrap() };
+            let complete = |id: i32, completed: bool| {
+                let repository = repository.clone();
+                let payload = payload(&format!(r#"{{"completed": {}}}"#, completed));
+                async move { repository.update(id, payload, None).await.unwrap() }
+            };
+            let create: CreateTodo = serde_json::from_value(serde_json::json!({
+                "text": "take out the trash",
+                "labels": [label.id],
+                "due_at": "2024-10-01",
+                "remind_at": "2024-09-30T20:00:00Z",
+                "rrule": "FREQ=WEEKLY;COUNT=3",
+            }))
+            .unwrap();
+            let first = repository.create(create).await.unwrap();
+            assert_eq!(
+                Some(Recurrence {
+                    series_id: 1,
+                    occurrence: 1,
+                    rrule: Some("FREQ=WEEKLY;COUNT=3".parse().unwrap()),
+                }),
+                first.recurrence
+            );
+
+            // 完了にすると、同じラベルで次の回ができる
+            complete(first.id, true).await;
+            let second = repository.find(2).await.unwrap();
+            assert_eq!(first.text, second.text);
+            assert_eq!(vec![label], second.labels);
+            assert_eq!(Some(due("2024-10-08")), second.due_at);
+            assert_eq!(
+                Some(Utc.with_ymd_and_hms(2024, 10, 7, 20, 0, 0).unwrap()),
+                second.remind_at
+            );
+            assert_eq!(
+                Some((1, 2)),
+                second.recurrence.map(|r| (r.series_id, r.occurrence))
+            );
+
+            // 完了し直しても二重には作らない
+            complete(first.id, false).await;
+            complete(first.id, true).await;
+            assert_eq!(2, all_ids(&repository).await.len());
+
+            // 系列の繰り返しは、どの回からでも変えられる。他の回の version も上がる
+            let first_version = repository.find(1).await.unwrap().version;
+            let todo = repository
+                .update(
+                    2,
+                    payload(r#"{"rrule": "FREQ=WEEKLY;BYDAY=MO;COUNT=3"}"#),
+                    None,
+                )
+                .await
+                .unwrap();
+            assert_eq!(
+                Some("FREQ=WEEKLY;BYDAY=MO;COUNT=3".parse().unwrap()),
+                todo.recurrence.unwrap().rrule
+            );
+            assert_eq!(
+                repository.find(2).await.unwrap().recurrence,
+                repository
+                    .find(1)
+                    .await
+                    .unwrap()
+                    .recurrence
+                    .map(|r| Recurrence { occurrence: 2, ..r })
+            );
+            assert_eq!(first_version + 1, repository.find(1).await.unwrap().version);
+            complete(2, true).await;
+            let third = repository.find(3).await.unwrap();
+            assert_eq!(Some(due("2024-10-14")), third.due_at);
+
+            // COUNT に達したら終わる
+            complete(3, true).await;
+            assert_eq!(vec![3, 2, 1], all_ids(&repository).await);
+
+            // 期限がなければ完了した日を起点にし、null で繰り返しを止められる
+            let create: CreateTodo = serde_json::from_value(serde_json::json!({
+                "text": "water the plants",
+                "labels": [],
+                "rrule": "FREQ=DAILY",
+            }))
+            .unwrap();
+            let todo = repository.create(create).await.unwrap();
+            complete(todo.id, true).await;
+            let next = repository.find(5).await.unwrap();
+            assert_eq!(Some(due("2024-10-02")), next.due_at);
+            let next = repository
+                .update(next.id, payload(r#"{"rrule": null}"#), None)
+                .await
+                .unwrap();
+            assert_eq!(None, next.recurrence.unwrap().rrule);
+            complete(next.id, true).await;
+            assert_eq!(5, all_ids(&repository).await.len());
+        }
+
+        fn due(value: &str) -> Due {
+            serde_json::from_value(serde_json::json!(value)).unwrap()
+        }
+
+        async fn all_ids(repository: &TodoRepositoryForMemory) -> Vec<i32> {
+            let page = repository
+                .all(TodoFilter::default(), Pagination::default())
+                .await
+                .unwrap();
+            page.items.iter().map(|todo| todo.id).collect()
+        }
+
+        #[tokio::test]
+        async fn todo_trash_scenario() {
+            let store = MemoryStore::default();
+            let label_repository = LabelRepositoryForMemory::with_store(store.clone());
+            let repository = TodoRepositoryForMemory::with_store(store);
+            let label = label_repository
+                .create(CreateLabel::new("label".to_string()))
+                .await
+                .unwrap();
+            let todo = repository
+                .create(CreateTodo::new("trash".to_string(), vec![label.id]))
+                .await
+                .unwrap();
+            repository
+                .create(CreateTodo::new("keep".to_string(), vec![]))
+                .await
+                .unwrap();
+
+            // delete (ゴミ箱へ移す)
+            repository.delete(todo.id, None).await.unwrap();
+            assert!(matches!(
+                repository.find(todo.id).await,
+                Err(RepositoryError::NotFound(_))
+            ));
+            let page = repository
+                .all(TodoFilter::default(), Pagination::default())
+                .await
+                .unwrap();
+            assert_eq!(vec![2], page.items.iter().map(|t| t.id).collect::<Vec<_>>());
+            let trash = repository.trash(Pagination::default()).await.unwrap();
+            assert_eq!(1, trash.total);
+            assert!(trash.items[0].deleted_at.is_some());
+            assert!(matches!(
+                repository
+                    .update(
+                        todo.id,
+                        UpdateTodo {
+                            text: None,
+                            completed: Some(true),
+                            labels: None,
+                            due_at: None,
+                            remind_at: None,
+                            rrule: None,
+                            priority: None,
+                            parent_id: None,
+                            complete_children: false,
+                        },
+                        None,
+                    )
+                    .await,
+                Err(RepositoryError::NotFound(_))
+            ));
+
+            // restore (ラベルの紐付けも戻る)
+            let restored = repository.restore(todo.id).await.unwrap();
+            assert_eq!(None, restored.deleted_at);
+            assert_eq!(vec![label], restored.labels);
+            assert!(matches!(
+                repository.restore(todo.id).await,
+                Err(RepositoryError::NotFound(_))
+            ));
+
+            // purge
+            repository.purge(todo.id, None).await.unwrap();
+            assert!(repository
+                .trash(Pagination::default())
+                .await
+                .unwrap()
+                .items
+                .is_empty());
+            assert!(matches!(
+                repository.purge(todo.id, None).await,
+                Err(RepositoryError::NotFound(_))
+            ));
+
+            // purge 後に作った Todo は既存の id と重ならない
+            let created = repository
+                .create(CreateTodo::new("new".to_string(), vec![]))
+                .await
+                .unwrap();
+            assert_eq!(3, created.id);
+        }
+
+        #[tokio::test]
+        async fn todo_pagination_scenario() {
+            let repository = TodoRepositoryForMemory::new();
+            for i in 1..=5 {
+                repository
+                    .create(CreateTodo::new(format!("todo {}", i), vec![]))
+                    .await
+                    .expect("failed create todo");
+            }
+
+            // first page
+            let page = repository
+                .all(TodoFilter::default(), Pagination::new(Some(2), None))
+                .await
+                .expect("failed get first page");
+            assert_eq!(
+                vec![5, 4],
+                page.items.iter().map(|todo| todo.id).collect::<Vec<_>>()
+            );
+            assert_eq!(Some(4), page.next_cursor);
+            assert_eq!(5, page.total);
+
+            // second page
+            let page = repository
+                .all(
+                    TodoFilter::default(),
+                    Pagination::new(Some(2), page.next_cursor),
+                )
+                .await
+                .expect("failed get second page");
+            assert_eq!(
+                vec![3, 2],
+                page.items.iter().map(|todo| todo.id).collect::<Vec<_>>()
+            );
+            assert_eq!(Some(2), page.next_cursor);
+
+            // last page
+            let page = repository
+                .all(
+                    TodoFilter::default(),
+                    Pagination::new(Some(2), page.next_cursor),
+                )
+                .await
+                .expect("failed get last page");
+            assert_eq!(
+                vec![1],
+                page.items.iter().map(|todo| todo.id).collect::<Vec<_>>()
+            );
+            assert_eq!(None, page.next_cursor);
+        }
+
+        #[test]
+        fn should_parse_sort() {
+            let parse = |value: &str| TodoSort::try_from(value.to_string());
+            assert_eq!(Ok(TodoSort::default()), parse(""));
+            assert_eq!("todos.id desc", TodoSort::default().order_by("todos"));
+
+            let sort = parse("priority,-created_at").unwrap();
+            assert_eq!(
+                "todos.priority asc, todos.created_at desc, todos.id desc",
+                sort.order_by("todos")
+            );
+            assert_eq!(
+                concat!(
+                    "(t.priority > c.priority)",
+                    " or (t.priority = c.priority and t.created_at < c.created_at)",
+                    " or (t.priority = c.priority and t.created_at = c.created_at and t.id < c.id)"
+                ),
+                sort.after("t", "c")
+            );
+            assert_eq!("t.id asc", parse("id").unwrap().order_by("t"));
+
+            for value in ["title", "priority,-priority", "--id"] {
+                assert!(parse(value).is_err(), "{}", value);
+            }
+        }
+
+        #[tokio::test]
+        async fn todo_sort_scenario() {
+            let store = MemoryStore::default();
+            let repository = TodoRepositoryForMemory::with_store(store.clone());
+            for (hour, priority) in [
+                (1, Priority::Low),
+                (2, Priority::Urgent),
+                (3, Priority::None),
+                (4, Priority::Urgent),
+                (5, Priority::Low),
+            ] {
+                store.write().now = Utc.with_ymd_and_hms(2024, 10, 1, hour, 0, 0).unwrap();
+                let todo = repository
+                    .create(CreateTodo {
+                        priority,
+                        ..CreateTodo::new(format!("todo {}", hour), vec![])
+                    })
+                    .await
+                    .expect("failed create todo");
+                assert_eq!(priority, todo.priority);
+            }
+
+            let pages = |sort: &str| {
+                let repository = repository.clone();
+                let pagination = Pagination::new(Some(2), None).with_sort(sort);
+                async move {
+                    let mut pages = vec![];
+                    let mut pagination = pagination;
+                    loop {
+                        let page = repository
+                            .all(TodoFilter::default(), pagination.clone())
+                            .await
+                            .expect("failed get all todo");
+                        pages.push(page.items.iter().map(|todo| todo.id).collect::<Vec<_>>());
+                        match page.next_cursor {
+                            Some(after) => pagination.after = Some(after),
+                            None => return pages,
+                        }
+                    }
+                }
+            };
+
+            assert_eq!(
+                vec![vec![2, 4], vec![1, 5], vec![3]],
+                pages("-priority,created_at").await
+            );
+            assert_eq!(
+                vec![vec![3, 5], vec![1, 4], vec![2]],
+                pages("priority").await
+            );
+            assert_eq!(vec![vec![5, 4], vec![3, 2], vec![1]], pages("").await);
+
+            let payload: UpdateTodo = serde_json::from_str(r#"{"priority": "high"}"#).unwrap();
+            let todo = repository.update(3, payload, None).await.unwrap();
+            assert_eq!(Priority::High, todo.priority);
+            assert_eq!(
+                vec![vec![4, 2], vec![3, 5], vec![1]],
+                pages("-priority").await
+            );
+            assert!(serde_json::from_str::<UpdateTodo>(r#"{"priority": "asap"}"#).is_err());
+        }
+
+        #[tokio::test]
+        async fn todo_reorder_scenario() {
+            let repository = TodoRepositoryForMemory::new();
+            for i in 1..=4 {
+                repository
+                    .create(CreateTodo::new(format!("todo {}", i), vec![]))
+                    .await
+                    .expect("failed create todo");
+            }
+            let order = || {
+                let repository = repository.clone();
+                async move {
+                    let page = repository
+                        .all(
+                            TodoFilter::default(),
+                            Pagination::new(None, None).with_sort("position"),
+                        )
+                        .await
+                        .expect("failed get all todo");
+                    page.items.iter().map(|todo| todo.id).collect::<Vec<_>>()
+                }
+            };
+            let move_to = |id: i32, json: &str| {
+                let repository = repository.clone();
+                let payload: MoveTodo = serde_json::from_str(json).unwrap();
+                async move { repository.reorder(id, payload).await }
+            };
+
+            // 新しい Todo は末尾に並ぶ
+            assert_eq!(vec![1, 2, 3, 4], order().await);
+            let todo = move_to(4, r#"{"before": 1}"#).await.unwrap();
+            assert_eq!(1, todo.version);
+            assert_eq!(vec![4, 1, 2, 3], order().await);
+            move_to(1, r#"{"after": 3}"#).await.unwrap();
+            assert_eq!(vec![4, 2, 3, 1], order().await);
+            move_to(2, r#"{"before": 2}"#).await.unwrap();
+            assert_eq!(vec![4, 2, 3, 1], order().await);
+
+            // 並び順はユーザーごとに持つ
+            let other = repository.for_owner(2);
+            let theirs = other
+                .create(CreateTodo::new("theirs".to_string(), vec![]))
+                .await
+                .unwrap();
+            assert_eq!(POSITION_GAP, theirs.position);
+
+            // 間が詰まったら振り直す。他のユーザーの Todo は振り直さない
+            for i in 0..20 {
+                let id = if i % 2 == 0 { 3 } else { 1 };
+                move_to(id, r#"{"after": 4}"#).await.unwrap();
+            }
+            assert_eq!(vec![4, 1, 3, 2], order().await);
+            assert_eq!(theirs, other.find(theirs.id).await.unwrap());
+
+            let res = move_to(1, r#"{"after": 99}"#).await;
+            assert!(matches!(res, Err(RepositoryError::NotFound(id)) if id == 99));
+            repository.delete(2, None).await.unwrap();
+            let res = move_to(1, r#"{"before": 2}"#).await;
+            assert!(matches!(res, Err(RepositoryError::NotFound(id)) if id == 2));
+            let res = move_to(2, r#"{"before": 1}"#).await;
+            assert!(matches!(res, Err(RepositoryError::NotFound(id)) if id == 2));
+
+            // ゴミ箱から戻すと元の位置に並ぶ
+            repository.restore(2).await.unwrap();
+            assert_eq!(vec![4, 1, 3, 2], order().await);
+        }
+
+        #[tokio::test]
+        async fn todo_tree_scenario() {
+            let repository = TodoRepositoryForMemory::new();
+            let create = |text: &str, parent_id: Option<i32>| {
+                let repository = repository.clone();
+                let payload = CreateTodo {
+                    parent_id,
+                    ..CreateTodo::new(text.to_string(), vec![])
+                };
+                async move { repository.create(payload).await }
+            };
+            let update = |id: i32, json: &str| {
+                let repository = repository.clone();
+                let payload: UpdateTodo = serde_json::from_str(json).unwrap();
+                async move { repository.update(id, payload, None).await }
+            };
+            let ids = |todos: &[TodoEntity]| todos.iter().map(|todo| todo.id).collect::<Vec<_>>();
+
+            create("project", None).await.unwrap();
+            create("design", Some(1)).await.unwrap();
+            create("release", Some(1)).await.unwrap();
+            let todo = create("draw", Some(2)).await.unwrap();
+            assert_eq!(Some(2), todo.parent_id);
+
+            let children = repository.children(1).await.unwrap();
+            assert_eq!(vec![2, 3], ids(&children));
+            assert_eq!(vec![4], ids(&children[0].children));
+            assert!(repository.children(4).await.unwrap().is_empty());
+
+            let tree =
+                TodoFilter::try_from(vec![("tree".to_string(), "true".to_string())]).unwrap();
+            let page = repository.all(tree, Pagination::default()).await.unwrap();
+            assert_eq!((vec![1], 1), (ids(&page.items), page.total));
+            assert_eq!(vec![2, 3], ids(&page.items[0].children));
+            let page = repository
+                .all(TodoFilter::default(), Pagination::default())
+                .await
+                .unwrap();
+            assert_eq!(4, page.items.len());
+            assert!(page.items.iter().all(|todo| todo.children.is_empty()));
+
+            // 循環と深すぎる木は作れない
+            for (id, parent) in [(1, 4), (2, 2)] {
+                let res = update(id, &format!(r#"{{"parent_id": {}}}"#, parent)).await;
+                assert!(matches!(
+                    res,
+                    Err(RepositoryError::InvalidParent(p, ParentError::Cycle)) if p == parent
+                ));
+            }
+            create("sketch", Some(4)).await.unwrap();
+            create("pencil", Some(5)).await.unwrap();
+            let res = create("lead", Some(6)).await;
+            assert!(matches!(
+                res,
+                Err(RepositoryError::InvalidParent(
+                    6,
+                    ParentError::TooDeep(MAX_DEPTH)
+                ))
+            ));
+            let res = update(2, r#"{"parent_id": 3}"#).await;
+            assert!(matches!(
+                res,
+                Err(RepositoryError::InvalidParent(3, ParentError::TooDeep(_)))
+            ));
+            let res = create("orphan", Some(99)).await;
+            assert!(matches!(
+                res,
+                Err(RepositoryError::InvalidParent(99, ParentError::NotFound))
+            ));
+            let todo = update(3, r#"{"parent_id": null}"#).await.unwrap();
+            assert_eq!(None, todo.parent_id);
+            update(3, r#"{"parent_id": 1}"#).await.unwrap();
+
+            // 親を完了にするときに、未完了の子孫もまとめて完了にできる
+            update(4, r#"{"completed": true}"#).await.unwrap();
+            let todo = update(2, r#"{"completed": true}"#).await.unwrap();
+            assert!(todo.completed);
+            assert!(!repository.find(5).await.unwrap().completed);
+            update(1, r#"{"completed": true, "complete_children": true}"#)
+                .await
+                .unwrap();
+            for id in 1..=6 {
+                assert!(repository.find(id).await.unwrap().completed, "{}", id);
+            }
+            assert_eq!(2, repository.history(4).await.unwrap().len());
+
+            // ゴミ箱にある子とその子孫は木に含めない
+            repository.delete(2, None).await.unwrap();
+            assert_eq!(vec![3], ids(&repository.children(1).await.unwrap()));
+            let res = create("under trash", Some(2)).await;
+            assert!(matches!(
+                res,
+                Err(RepositoryError::InvalidParent(2, ParentError::NotFound))
+            ));
+
+            // 親を完全に削除すると、子は親のない Todo になり、version が上がって履歴に残る
+            let version = repository.find(3).await.unwrap().version;
+            repository.purge(1, None).await.unwrap();
+            let todo = repository.find(3).await.unwrap();
+            assert_eq!(None, todo.parent_id);
+            assert_eq!(version + 1, todo.version);
+            let event = repository.history(3).await.unwrap().pop().unwrap();
+            assert_eq!(TodoEventKind::Updated, event.kind);
+            assert_eq!(
+                serde_json::json!({ "parent_id": { "before": 1, "after": null } }),
+                serde_json::json!(event.changes.0)
+            );
+        }
+
+        #[test]
+        fn should_validate_move() {
+            use validator::Validate;
+
+            for json in [r#"{"before": 1}"#, r#"{"after": 1}"#] {
+                let payload: MoveTodo = serde_json::from_str(json).unwrap();
+                assert!(payload.validate().is_ok());
+            }
+            for json in ["{}", r#"{"before": 1, "after": 2}"#] {
+                let payload: MoveTodo = serde_json::from_str(json).unwrap();
+                assert!(payload.validate().is_err());
+            }
+        }
+
+        #[tokio::test]
+        async fn todo_dependency_scenario() {
+            let repository = TodoRepositoryForMemory::new();
+            for text in ["design", "implement", "release"] {
+                repository
+                    .create(CreateTodo::new(text.to_string(), vec![]))
+                    .await
+                    .unwrap();
+            }
+
+            let todo = repository.add_dependency(2, 1).await.unwrap();
+            assert_eq!((true, 2), (todo.blocked, todo.version));
+            // 既に待っていれば何も変えない
+            let todo = repository.add_dependency(2, 1).await.unwrap();
+            assert_eq!(2, todo.version);
+            repository.add_dependency(3, 2).await.unwrap();
+            assert!(!repository.find(1).await.unwrap().blocked);
+
+            for (id, other, cycle) in [(1, 3, vec![1, 3, 2, 1]), (1, 1, vec![1, 1])] {
+                let res = repository.add_dependency(id, other).await;
+                assert!(matches!(
+                    res,
+                    Err(RepositoryError::DependencyCycle(path)) if path == cycle
+                ));
+            }
+            let res = repository.add_dependency(1, 99).await;
+            assert!(matches!(res, Err(RepositoryError::NotFound(99))));
+
+            // 待たれている Todo を完了にすると、待っている Todo の version も進む
+            let payload: UpdateTodo = serde_json::from_str(r#"{"completed": true}"#).unwrap();
+            repository.update(1, payload, None).await.unwrap();
+            let todo = repository.find(2).await.unwrap();
+            assert_eq!((false, 3), (todo.blocked, todo.version));
+
+            let graph = repository.dependency_graph().await.unwrap();
+            assert_eq!(vec![(2, 1), (3, 2)], graph.edges);
+            let nodes: Vec<(i32, bool, bool)> = graph
+                .nodes
+                .iter()
+                .map(|node| (node.id, node.completed, node.blocked))
+                .collect();
+            assert_eq!(
+                vec![(1, true, false), (2, false, false), (3, false, true)],
+                nodes
+            );
+
+            // ゴミ箱にある Todo は待たず、グラフにも含めない
+            repository.delete(2, None).await.unwrap();
+            assert!(!repository.find(3).await.unwrap().blocked);
+            let graph = repository.dependency_graph().await.unwrap();
+            assert!(graph.edges.is_empty() && graph.nodes.is_empty());
+            repository.restore(2).await.unwrap();
+            assert!(repository.find(3).await.unwrap().blocked);
+
+            let todo = repository.remove_dependency(3, 2).await.unwrap();
+            assert!(!todo.blocked);
+            let res = repository.remove_dependency(3, 2).await;
+            assert!(matches!(res, Err(RepositoryError::NotFound(2))));
+
+            repository.purge(1, None).await.unwrap();
+            let graph = repository.dependency_graph().await.unwrap();
+            assert!(graph.edges.is_empty());
+        }
+
+        #[tokio::test]
+        async fn todo_labels_scenario() {
+            let store = MemoryStore::default();
+            let label_repository = LabelRepositoryForMemory::with_store(store.clone());
+            let repository = TodoRepositoryForMemory::with_store(store);
+            let label = label_repository
+                .create(CreateLabel::new("label".to_string()))
+                .await
+                .unwrap();
+
+            // 存在しないラベル
+            let err = repository
+                .create(CreateTodo::new("todo".to_string(), vec![label.id, 99, 98]))
+                .await
+                .expect_err("todo with unknown labels is created");
+            assert!(matches!(err, RepositoryError::UnknownLabels(ids) if ids == vec![99, 98]));
+            assert!(repository.find(1).await.is_err());
+
+            // 重複したラベル
+            let todo = repository
+                .create(CreateTodo::new(
+                    "todo".to_string(),
+                    vec![label.id, label.id],
+                ))
+                .await
+                .expect("failed create todo");
+            assert_eq!(vec![label.clone()], todo.labels);
+
+            let err = repository
+                .update(
+                    todo.id,
+                    UpdateTodo {
+                        text: None,
+                        completed: None,
+                        labels: Some(vec![99]),
+                        due_at: None,
+                        remind_at: None,
+                        rrule: None,
+                        priority: None,
+                        parent_id: None,
+                        complete_children: false,
+                    },
+                    None,
+                )
+                .await
+                .expect_err("todo with unknown labels is updated");
+            assert!(matches!(err, RepositoryError::UnknownLabels(ids) if ids == vec![99]));
+            assert_eq!(vec![label], repository.find(todo.id).await.unwrap().labels);
+        }
+
+        #[tokio::test]
+        async fn todo_owner_scenario() {
+            let store = MemoryStore::default();
+            let label_repository = LabelRepositoryForMemory::with_store(store.clone());
+            let repository = TodoRepositoryForMemory::with_store(store);
+            let other = repository.for_owner(2);
+            let label = label_repository
+                .create(CreateLabel::new("label".to_string()))
+                .await
+                .unwrap();
+            let todo = repository
+                .create(CreateTodo::new("mine".to_string(), vec![label.id]))
+                .await
+                .unwrap();
+            let theirs = other
+                .create(CreateTodo::new("theirs".to_string(), vec![]))
+                .await
+                .unwrap();
+
+            // 他のユーザーの Todo は見えない
+            assert!(matches!(
+                other.find(todo.id).await,
+                Err(RepositoryError::NotFound(_))
+            ));
+            assert_eq!(vec![theirs.id], all_ids(&other).await);
+            let hits = other
+                .search(SearchQuery::new("mine".to_string(), None))
+                .await
+                .unwrap();
+            assert!(hits.is_empty());
+            assert!(matches!(
+                other.history(todo.id).await,
+                Err(RepositoryError::NotFound(_))
+            ));
+            assert!(matches!(
+                other.children(todo.id).await,
+                Err(RepositoryError::NotFound(_))
+            ));
+
+            // 他のユーザーの Todo は変更できない
+            let payload: UpdateTodo = serde_json::from_str(r#"{"text": "stolen"}"#).unwrap();
+            let res = other.update(todo.id, payload, None).await;
+            assert!(matches!(res, Err(RepositoryError::NotFound(_))));
+            let res = other.delete(todo.id, None).await;
+            assert!(matches!(res, Err(RepositoryError::NotFound(_))));
+            let res = other.purge(todo.id, None).await;
+            assert!(matches!(res, Err(RepositoryError::NotFound(_))));
+            let payload: MoveTodo = serde_json::from_str(r#"{"before": 1}"#).unwrap();
+            let res = other.reorder(theirs.id, payload).await;
+            assert!(matches!(res, Err(RepositoryError::NotFound(1))));
+            let res = other.add_dependency(theirs.id, todo.id).await;
+            assert!(matches!(res, Err(RepositoryError::NotFound(_))));
+            let res = other.add_dependency(todo.id, theirs.id).await;
+            assert!(matches!(res, Err(RepositoryError::NotFound(_))));
+
+            // 他のユーザーのラベルと親は使えない
+            let res = other
+                .create(CreateTodo::new("theirs".to_string(), vec![label.id]))
+                .await;
+            assert!(
+                matches!(res, Err(RepositoryError::UnknownLabels(ids)) if ids == vec![label.id])
+            );
+            let payload: UpdateTodo =
+                serde_json::from_value(serde_json::json!({ "parent_id": todo.id })).unwrap();
+            let res = other.update(theirs.id, payload, None).await;
+            assert!(matches!(
+                res,
+                Err(RepositoryError::InvalidParent(_, ParentError::NotFound))
+            ));
+
+            // ゴミ箱と完全に削除した Todo の履歴も見えない
+            repository.delete(todo.id, None).await.unwrap();
+            let page = other.trash(Pagination::default()).await.unwrap();
+            assert!(page.items.is_empty());
+            let res = other.restore(todo.id).await;
+            assert!(matches!(res, Err(RepositoryError::NotFound(_))));
+            repository.purge(todo.id, None).await.unwrap();
+            assert!(matches!(
+                other.history(todo.id).await,
+                Err(RepositoryError::NotFound(_))
+            ));
+            assert_eq!(3, repository.history(todo.id).await.unwrap().len());
+            assert_eq!(theirs, other.find(theirs.id).await.unwrap());
+        }
+
+        #[tokio::test]
+        async fn todo_filter_scenario() {
+            let store = MemoryStore::default();
+            let label_repository = LabelRepositoryForMemory::with_store(store.clone());
+            let repository = TodoRepositoryForMemory::with_store(store);
+            let label_1 = label_repository
+                .create(CreateLabel::new("label 1".to_string()))
+                .await
+                .unwrap();
+            let label_2 = label_repository
+                .create(CreateLabel::new("label 2".to_string()))
+                .await
+                .unwrap();
+            for (text, labels) in [
+                ("Buy milk", vec![label_1.id]),
+                ("buy eggs", vec![label_1.id, label_2.id]),
+                ("Write report", vec![label_2.id]),
+            ] {
+                repository
+                    .create(CreateTodo::new(text.to_string(), labels))
+                    .await
+                    .expect("failed create todo");
+            }
+            repository
+                .update(
+                    3,
+                    UpdateTodo {
+                        text: None,
+                        completed: Some(true),
+                        labels: None,
+                        due_at: None,
+                        remind_at: None,
+                        rrule: None,
+                        priority: None,
+                        parent_id: None,
+                        complete_children: false,
+                    },
+                    None,
+                )
+                .await
+                .expect("failed update todo");
+
+            let ids = |filter: &[(&str, &str)]| {
+                let filter = TodoFilter::try_from(
+                    filter
+                        .iter()
+                        .map(|(k, v)| (k.to_string(), v.to_string()))
+                        .collect::<Vec<_>>(),
+                )
+                .unwrap();
+                let repository = repository.clone();
+                async move {
+                    let page = repository
+                        .all(filter, Pagination::default())
+                        .await
+                        .expect("failed get all todo");
+                    page.items.iter().map(|todo| todo.id).collect::<Vec<_>>()
+                }
+            };
+
+            assert_eq!(vec![3], ids(&[("completed", "true")]).await);
+            assert_eq!(vec![2, 1], ids(&[("completed", "false")]).await);
+            assert_eq!(vec![2, 1], ids(&[("text", "BUY")]).await);
+            assert_eq!(vec![2, 1], ids(&[("label", "1")]).await);
+            assert_eq!(vec![3, 2, 1], ids(&[("label", "1"), ("label", "2")]).await);
+            assert_eq!(
+                vec![2],
+                ids(&[("label", "1"), ("label", "2"), ("label_match", "all")]).await
+            );
+            assert_eq!(
+                vec![3],
+                ids(&[("label", "2"), ("text", "report"), ("completed", "true")]).await
+            );
+        }
+
+        #[test]
+        fn todo_filter_rejects_invalid_query() {
+            for (key, value) in [
+                ("completed", "yes"),
+                ("label", "first"),
+                ("label_match", "some"),
+                ("due", "tomorrow"),
+                ("tz", "Mars/Olympus"),
+            ] {
+                assert!(TodoFilter::try_from(vec![(key.to_string(), value.to_string())]).is_err());
+            }
+        }
+
+        #[tokio::test]
+        async fn todo_search_scenario() {
+            let repository = TodoRepositoryForMemory::new();
+            for text in ["Buy milk", "milk tea and milk", "Write report"] {
+                repository
+                    .create(CreateTodo::new(text.to_string(), vec![]))
+                    .await
+                    .expect("failed create todo");
+            }
+
+            let hits = repository
+                .search(SearchQuery::new("MILK".to_string(), None))
+                .await
+                .expect("failed search todo");
+            assert_eq!(
+                vec![2, 1],
+                hits.iter().map(|hit| hit.todo.id).collect::<Vec<_>>()
+            );
+            assert_eq!("<b>milk</b> tea and <b>milk</b>", hits[0].snippet);
+
+            let hits = repository
+                .search(SearchQuery::new("buy milk".to_string(), None))
+                .await
+                .expect("failed search todo");
+            assert_eq!(
+                vec![1],
+                hits.iter().map(|hit| hit.todo.id).collect::<Vec<_>>()
+            );
+
+            let hits = repository
+                .search(SearchQuery::new("coffee".to_string(), None))
+                .await
+                .expect("failed search todo");
+            assert!(hits.is_empty());
         }
     }
 }
@@ -0,0 +1,221 @@
+use super::{label::Label, todo::TodoEntity, RepositoryError};
+use chrono::{DateTime, Utc};
+use indoc::indoc;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::{types::Json, FromRow, PgConnection};
+use std::collections::BTreeMap;
+
+/// Todo に対する操作の種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "todo_event_kind", rename_all = "snake_case")]
+pub enum TodoEventKind {
+    Created,
+    Updated,
+    Deleted,
+    Restored,
+    Purged,
+}
+
+/// 項目ごとの変更前と変更後の値
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FieldChange {
+    pub before: Value,
+    pub after: Value,
+}
+
+pub type Changes = BTreeMap<String, FieldChange>;
+
+/// `todo_events` に追記される Todo の変更履歴
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, FromRow)]
+pub struct TodoEvent {
+    pub id: i32,
+    pub todo_id: i32,
+    pub kind: TodoEventKind,
+    pub changes: Json<Changes>,
+    /// 変更したユーザー。記録を始める前の履歴では None
+    pub actor_id: Option<i32>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// 変更前後の Todo を比べ、値が変わった項目だけを返す
+/// 作成時は before、完全な削除時は after に None を渡す
+/// ラベルは名前や色の変更も分かるよう、作成日時を除いた内容で比べる
+pub fn diff(before: Option<&TodoEntity>, after: Option<&TodoEntity>) -> Changes {
+    fn fields(todo: Option<&TodoEntity>) -> [(&'static str, Value); 9] {
+        [
+            ("text", json!(todo.map(|todo| &todo.text))),
+            ("completed", json!(todo.map(|todo| todo.completed))),
+            (
+                "labels",
+                todo.map_or(Value::Null, |todo| labels(&todo.labels)),
+            ),
+            ("deleted_at", json!(todo.and_then(|todo| todo.deleted_at))),
+            ("due_at", json!(todo.and_then(|todo| todo.due_at))),
+            ("remind_at", json!(todo.and_then(|todo| todo.remind_at))),
+            (
+                "rrule",
+                json!(todo.and_then(|todo| todo
+                    .recurrence
+                    .as_ref()
+                    .and_then(|recurrence| recurrence.rrule.as_ref()))),
+            ),
+            ("priority", json!(todo.map(|todo| todo.priority))),
+            ("parent_id", json!(todo.and_then(|todo| todo.parent_id))),
+        ]
+    }
+
+    fields(before)
+        .into_iter()
+        .zip(fields(after))
+        .filter(|((_, before), (_, after))| before != after)
+        .map(|((name, before), (_, after))| (name.to_string(), FieldChange { before, after }))
+        .collect()
+}
+
+/// ラベルの変更や削除で、Todo に紐付いたラベルが変わったときの差分
+pub fn label_diff(before: &[Label], after: &[Label]) -> Changes {
+    let (before, after) = (labels(before), labels(after));
+    if before == after {
+        return Changes::new();
+    }
+    Changes::from([("labels".to_string(), FieldChange { before, after })])
+}
+
+fn labels(labels: &[Label]) -> Value {
+    labels
+        .iter()
+        .map(|label| {
+            json!({
+                "id": label.id,
+                "name": label.name,
+                "color": label.color,
+                "description": label.description,
+            })
+        })
+        .collect()
+}
+
+/// actor_id は変更したユーザー。リポジトリはログインしているユーザーを所有者として使うため、
+/// 今は owner_id と同じになる
+pub async fn record(
+    conn: &mut PgConnection,
+    owner_id: i32,
+    actor_id: i32,
+    todo_id: i32,
+    kind: TodoEventKind,
+    changes: Changes,
+) -> Result<(), RepositoryError> {
+    sqlx::query(indoc!(
+        r#"
+            insert into todo_events (todo_id, kind, changes, owner_id, actor_id)
+                values ($1, $2, $3, $4, $5)
+        "#
+    ))
+    .bind(todo_id)
+    .bind(kind)
+    .bind(Json(changes))
+    .bind(owner_id)
+    .bind(actor_id)
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
+
+/// 古い順に返す。owner_id のユーザーの履歴も Todo もなければ NotFound を返す
+pub async fn history(
+    conn: &mut PgConnection,
+    owner_id: i32,
+    todo_id: i32,
+) -> Result<Vec<TodoEvent>, RepositoryError> {
+    let events = sqlx::query_as::<_, TodoEvent>(indoc!(
+        r#"
+            select id, todo_id, kind, changes, actor_id, created_at from todo_events
+                where todo_id = $1 and owner_id = $2
+                order by id
+        "#
+    ))
+    .bind(todo_id)
+    .bind(owner_id)
+    .fetch_all(&mut *conn)
+    .await?;
+
+    if events.is_empty() {
+        // 履歴を記録する前から存在する Todo は、空の履歴を返す
+        sqlx::query("select id from todos where id = $1 and owner_id = $2")
+            .bind(todo_id)
+            .bind(owner_id)
+            .fetch_optional(conn)
+            .await?
+            .ok_or(RepositoryError::NotFound(todo_id))?;
+    }
+    Ok(events)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::repositories::label::Label;
+
+    #[test]
+    fn should_diff_changed_fields() {
+        let label = Label {
+            id: 1,
+            name: "label".to_string(),
+            color: None,
+            description: None,
+            created_at: Default::default(),
+        };
+        let before = TodoEntity::new(1, "before".to_string());
+        let after = TodoEntity {
+            text: "after".to_string(),
+            labels: vec![label],
+            version: 2,
+            ..before.clone()
+        };
+
+        let changes = diff(Some(&before), Some(&after));
+        assert_eq!(
+            vec!["labels", "text"],
+            changes.keys().map(String::as_str).collect::<Vec<_>>()
+        );
+        assert_eq!(json!([]), changes["labels"].before);
+        assert_eq!(
+            json!([{ "id": 1, "name": "label", "color": null, "description": null }]),
+            changes["labels"].after
+        );
+        assert_eq!(json!("before"), changes["text"].before);
+
+        let changes = diff(None, Some(&before));
+        assert_eq!(json!(null), changes["text"].before);
+        assert_eq!(json!(false), changes["completed"].after);
+        assert!(!changes.contains_key("deleted_at"));
+
+        assert!(diff(Some(&before), Some(&before)).is_empty());
+    }
+
+    #[test]
+    fn should_diff_labels() {
+        let label = Label {
+            id: 1,
+            name: "label".to_string(),
+            color: None,
+            description: None,
+            created_at: Default::default(),
+        };
+        let renamed = Label {
+            name: "renamed".to_string(),
+            ..label.clone()
+        };
+
+        let labels = vec![label];
+        let changes = label_diff(&labels, &[renamed]);
+        assert_eq!(json!("label"), changes["labels"].before[0]["name"]);
+        assert_eq!(json!("renamed"), changes["labels"].after[0]["name"]);
+        let changes = label_diff(&labels, &[]);
+        assert_eq!(json!([]), changes["labels"].after);
+        assert!(label_diff(&labels, &labels).is_empty());
+    }
+}
@@ -0,0 +1,109 @@
+use super::RepositoryError;
+use axum::async_trait;
+use sqlx::{PgConnection, PgPool, Postgres, Transaction};
+
+/// リポジトリの `*_in` のメソッドに渡し、複数の操作をまとめる作業単位
+/// commit せずに drop した場合は、まとめた操作をすべて取り消す
+///
+/// ```ignore
+/// let mut uow = todo_repository.begin().await?;
+/// let label = label_repository.create_in(&mut uow, create_label).await?;
+/// let todo = todo_repository.create_in(&mut uow, create_todo).await?;
+/// uow.commit().await?;
+/// ```
+#[async_trait]
+pub trait Commit: Send + Sync + 'static {
+    async fn commit(self) -> Result<(), RepositoryError>;
+}
+
+/// Db 実装の作業単位。複数のリポジトリ操作を 1 つのトランザクションにまとめる
+#[derive(Debug)]
+pub struct UnitOfWork {
+    tx: Transaction<'static, Postgres>,
+}
+
+impl UnitOfWork {
+    pub async fn begin(pool: &PgPool) -> Result<Self, RepositoryError> {
+        let tx = pool.begin().await?;
+        Ok(Self { tx })
+    }
+
+    /// 読み取りだけを行う作業単位。どのクエリも最初のクエリの時点のスナップショットを読むため、
+    /// ページと件数のように別々のクエリで読んだ結果が食い違わない
+    pub async fn begin_snapshot(pool: &PgPool) -> Result<Self, RepositoryError> {
+        let mut uow = Self::begin(pool).await?;
+        sqlx::query("set transaction isolation level repeatable read, read only")
+            .execute(uow.conn())
+            .await?;
+        Ok(uow)
+    }
+
+    pub fn conn(&mut self) -> &mut PgConnection {
+        &mut self.tx
+    }
+
+    pub async fn commit(self) -> Result<(), RepositoryError> {
+        self.tx.commit().await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Commit for UnitOfWork {
+    async fn commit(self) -> Result<(), RepositoryError> {
+        UnitOfWork::commit(self).await
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "database-test")]
+mod test {
+    use super::*;
+    use crate::repositories::user::test_owner;
+    use dotenv::dotenv;
+    use std::env;
+
+    async fn count(conn: &mut PgConnection, owner: i32) -> i64 {
+        sqlx::query_scalar("select count(*) from todos where owner_id = $1")
+            .bind(owner)
+            .fetch_one(conn)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn snapshot_scenario() {
+        dotenv().ok();
+        let database_url = &env::var("DATABASE_URL").expect("undefined [DATABASE_URL]");
+        let pool = PgPool::connect(database_url)
+            .await
+            .unwrap_or_else(|_| panic!("fail connect database, url is [{}]", database_url));
+        let owner = test_owner(&pool, "snapshot@example.com").await;
+        let mut uow = UnitOfWork::begin_snapshot(&pool).await.unwrap();
+        let before = count(uow.conn(), owner).await;
+        let id: i32 = sqlx::query_scalar(
+            "insert into todos (text, owner_id) values ('[snapshot_scenario]', $1) returning id",
+        )
+        .bind(owner)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+
+        // 最初のクエリの後に他で追加された行は見えず、書き込みもできない
+        assert_eq!(before, count(uow.conn(), owner).await);
+        let res = sqlx::query("delete from todos where id = $1")
+            .bind(id)
+            .execute(uow.conn())
+            .await;
+        assert!(res.is_err());
+        drop(uow);
+
+        let mut conn = pool.acquire().await.unwrap();
+        assert_eq!(before + 1, count(&mut conn, owner).await);
+        sqlx::query("delete from todos where id = $1")
+            .bind(id)
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+}
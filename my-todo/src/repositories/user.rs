@@ -0,0 +1,846 @@
+use super::RepositoryError;
+use axum::async_trait;
+use chrono::{DateTime, Utc};
+use indoc::indoc;
+use serde::{Deserialize, Serialize};
+use sqlx::{FromRow, PgPool};
+use std::{fmt, str::FromStr};
+use validator::Validate;
+
+#[async_trait]
+pub trait UserRepository: Clone + std::marker::Send + std::marker::Sync + 'static {
+    /// 同じメールアドレスのユーザーがいれば何もせず None を返す
+    async fn create(
+        &self,
+        email: String,
+        password_hash: String,
+    ) -> Result<Option<User>, RepositoryError>;
+    async fn find(&self, id: i32) -> Result<Option<User>, RepositoryError>;
+    /// パスワードを照合するため、ユーザーとパスワードのハッシュを返す
+    async fn find_by_email(&self, email: &str) -> Result<Option<(User, String)>, RepositoryError>;
+    /// token_hash のセッションを作る。同じユーザーの期限切れのセッションはここで消す
+    async fn create_session(
+        &self,
+        user_id: i32,
+        token_hash: String,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(), RepositoryError>;
+    /// now の時点で期限内のセッションがあれば、そのユーザーを返す
+    async fn find_by_session(
+        &self,
+        token_hash: &str,
+        now: DateTime<Utc>,
+    ) -> Result<Option<User>, RepositoryError>;
+    /// セッションがなくてもエラーにしない
+    async fn delete_session(&self, token_hash: &str) -> Result<(), RepositoryError>;
+    /// API トークンを作る。トークンそのものは保存せず、prefix とハッシュだけを保存する
+    async fn create_token(
+        &self,
+        user_id: i32,
+        payload: CreateToken,
+        prefix: String,
+        token_hash: String,
+    ) -> Result<ApiToken, RepositoryError>;
+    async fn all_tokens(&self, user_id: i32) -> Result<Vec<ApiToken>, RepositoryError>;
+    /// 他のユーザーのトークンは NotFound にする
+    async fn delete_token(&self, user_id: i32, id: i32) -> Result<(), RepositoryError>;
+    /// token_hash の API トークンがあれば、last_used_at を now にしてユーザーとスコープを返す
+    async fn use_token(
+        &self,
+        token_hash: &str,
+        now: DateTime<Utc>,
+    ) -> Result<Option<(User, Vec<Scope>)>, RepositoryError>;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, FromRow)]
+pub struct User {
+    pub id: i32,
+    pub email: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, FromRow)]
+struct UserFromRow {
+    id: i32,
+    email: String,
+    password_hash: String,
+    created_at: DateTime<Utc>,
+}
+
+impl From<UserFromRow> for (User, String) {
+    fn from(row: UserFromRow) -> Self {
+        let user = User {
+            id: row.id,
+            email: row.email,
+            created_at: row.created_at,
+        };
+        (user, row.password_hash)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Validate)]
+pub struct RegisterUser {
+    #[validate(email(message = "Must be an email address"))]
+    #[validate(length(max = 254, message = "Over text length"))]
+    pub email: String,
+    #[validate(length(min = 8, message = "Must be at least 8 characters"))]
+    #[validate(length(max = 128, message = "Over text length"))]
+    pub password: String,
+}
+
+/// 登録後に変えたパスワードの条件で弾かないよう、ログインでは長さを検証しない
+#[derive(Debug, Clone, Deserialize, Validate)]
+pub struct LoginUser {
+    pub email: String,
+    pub password: String,
+}
+
+/// API トークンで使える操作
+/// 読み取りと書き込みは別のスコープで、書き込みのスコープでは読み取れない
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Scope {
+    #[serde(rename = "todos:read")]
+    TodosRead,
+    #[serde(rename = "todos:write")]
+    TodosWrite,
+    #[serde(rename = "labels:read")]
+    LabelsRead,
+    #[serde(rename = "labels:write")]
+    LabelsWrite,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::TodosRead => "todos:read",
+            Scope::TodosWrite => "todos:write",
+            Scope::LabelsRead => "labels:read",
+            Scope::LabelsWrite => "labels:write",
+        }
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [
+            Scope::TodosRead,
+            Scope::TodosWrite,
+            Scope::LabelsRead,
+            Scope::LabelsWrite,
+        ]
+        .into_iter()
+        .find(|scope| scope.as_str() == s)
+        .ok_or(format!("unknown scope: {}", s))
+    }
+}
+
+/// API トークンの一覧で返す情報。トークンそのものは作ったときにしか返さない
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ApiToken {
+    pub id: i32,
+    pub name: String,
+    pub prefix: String,
+    pub scopes: Vec<Scope>,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, FromRow)]
+struct ApiTokenFromRow {
+    id: i32,
+    name: String,
+    prefix: String,
+    scopes: Vec<String>,
+    created_at: DateTime<Utc>,
+    last_used_at: Option<DateTime<Utc>>,
+}
+
+impl From<ApiTokenFromRow> for ApiToken {
+    fn from(row: ApiTokenFromRow) -> Self {
+        ApiToken {
+            id: row.id,
+            name: row.name,
+            prefix: row.prefix,
+            // 後から無くしたスコープは読み飛ばす
+            scopes: row.scopes.iter().filter_map(|s| s.parse().ok()).collect(),
+            created_at: row.created_at,
+            last_used_at: row.last_used_at,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Validate)]
+pub struct CreateToken {
+    #[validate(length(min = 1, message = "Can not be empty"))]
+    #[validate(length(max = 100, message = "Over text length"))]
+    pub name: String,
+    #[validate(length(min = 1, message = "Can not be empty"))]
+    pub scopes: Vec<Scope>,
+}
+
+impl CreateToken {
+    #[cfg(test)]
+    pub fn new(name: &str, scopes: Vec<Scope>) -> Self {
+        CreateToken {
+            name: name.to_string(),
+            scopes,
+        }
+    }
+
+    /// 重複を除き、並びを揃えたスコープ
+    fn scopes(&self) -> Vec<Scope> {
+        let mut scopes = self.scopes.clone();
+        scopes.sort();
+        scopes.dedup();
+        scopes
+    }
+}
+
+/// 大文字と小文字の違いで別のユーザーにならないよう、小文字にして保存し比べる
+pub fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+/// Db のテストで Todo やラベルの所有者にするユーザーの id を返す。いなければ作る
+#[cfg(all(test, feature = "database-test"))]
+pub async fn test_owner(pool: &PgPool, email: &str) -> i32 {
+    sqlx::query_scalar(indoc!(
+        r#"
+            insert into users (email, password_hash) values ($1, '')
+                on conflict (email) do update set email = excluded.email
+            returning id
+        "#
+    ))
+    .bind(email)
+    .fetch_one(pool)
+    .await
+    .expect("failed create test owner")
+}
+
+#[derive(Debug, Clone)]
+pub struct UserRepositoryForDb {
+    pool: PgPool,
+}
+
+impl UserRepositoryForDb {
+    pub fn new(pool: PgPool) -> Self {
+        UserRepositoryForDb { pool }
+    }
+}
+
+#[async_trait]
+impl UserRepository for UserRepositoryForDb {
+    async fn create(
+        &self,
+        email: String,
+        password_hash: String,
+    ) -> Result<Option<User>, RepositoryError> {
+        // 同時に登録されても一意制約の違反にならないよう、重複は on conflict で確かめる
+        let user = sqlx::query_as::<_, User>(indoc!(
+            r#"
+                insert into users (email, password_hash) values ($1, $2)
+                    on conflict (email) do nothing
+                returning id, email, created_at
+            "#
+        ))
+        .bind(email)
+        .bind(password_hash)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(user)
+    }
+
+    async fn find(&self, id: i32) -> Result<Option<User>, RepositoryError> {
+        let user = sqlx::query_as::<_, User>(indoc!(
+            r#"
+                select id, email, created_at from users where id = $1
+            "#
+        ))
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(user)
+    }
+
+    async fn find_by_email(&self, email: &str) -> Result<Option<(User, String)>, RepositoryError> {
+        let row = sqlx::query_as::<_, UserFromRow>(indoc!(
+            r#"
+                select * from users where email = $1
+            "#
+        ))
+        .bind(email)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(Into::into))
+    }
+
+    async fn create_session(
+        &self,
+        user_id: i32,
+        token_hash: String,
+        expires_at: DateTime<Utc>,
+    ) -> Result<(), RepositoryError> {
+        sqlx::query(indoc!(
+            r#"
+                delete from sessions where user_id = $1 and expires_at <= now()
+            "#
+        ))
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+
+        sqlx::query(indoc!(
+            r#"
+                insert into sessions (id, user_id, expires_at) values ($1, $2, $3)
+            "#
+        ))
+        .bind(token_hash)
+        .bind(user_id)
+        .bind(expires_at)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn find_by_session(
+        &self,
+        token_hash: &str,
+        now: DateTime<Utc>,
+    ) -> Result<Option<User>, RepositoryError> {
+        let user = sqlx::query_as::<_, User>(indoc!(
+            r#"
+                select users.id, users.email, users.created_at from sessions
+                    join users on users.id = sessions.user_id
+                    where sessions.id = $1 and sessions.expires_at > $2
+            "#
+        ))
+        .bind(token_hash)
+        .bind(now)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(user)
+    }
+
+    async fn delete_session(&self, token_hash: &str) -> Result<(), RepositoryError> {
+        sqlx::query(indoc!(
+            r#"
+                delete from sessions where id = $1
+            "#
+        ))
+        .bind(token_hash)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn create_token(
+        &self,
+        user_id: i32,
+        payload: CreateToken,
+        prefix: String,
+        token_hash: String,
+    ) -> Result<ApiToken, RepositoryError> {
+        let scopes: Vec<String> = payload.scopes().iter().map(Scope::to_string).collect();
+        let row = sqlx::query_as::<_, ApiTokenFromRow>(indoc!(
+            r#"
+                insert into api_tokens (user_id, name, prefix, token_hash, scopes)
+                    values ($1, $2, $3, $4, $5)
+                returning id, name, prefix, scopes, created_at, last_used_at
+            "#
+        ))
+        .bind(user_id)
+        .bind(payload.name)
+        .bind(prefix)
+        .bind(token_hash)
+        .bind(scopes)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(row.into())
+    }
+
+    async fn all_tokens(&self, user_id: i32) -> Result<Vec<ApiToken>, RepositoryError> {
+        let rows = sqlx::query_as::<_, ApiTokenFromRow>(indoc!(
+            r#"
+                select id, name, prefix, scopes, created_at, last_used_at from api_tokens
+                    where user_id = $1
+                    order by id
+            "#
+        ))
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(Into::into).collect())
+    }
+
+    async fn delete_token(&self, user_id: i32, id: i32) -> Result<(), RepositoryError> {
+        let res = sqlx::query(indoc!(
+            r#"
+                delete from api_tokens where id = $1 and user_id = $2
+            "#
+        ))
+        .bind(id)
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+        if res.rows_affected() == 0 {
+            return Err(RepositoryError::NotFound(id));
+        }
+        Ok(())
+    }
+
+    async fn use_token(
+        &self,
+        token_hash: &str,
+        now: DateTime<Utc>,
+    ) -> Result<Option<(User, Vec<Scope>)>, RepositoryError> {
+        let row: Option<(i32, String, DateTime<Utc>, Vec<String>)> = sqlx::query_as(indoc!(
+            r#"
+                with used as (
+                    update api_tokens set last_used_at = $2 where token_hash = $1
+                    returning user_id, scopes
+                )
+                select users.id, users.email, users.created_at, used.scopes from used
+                    join users on users.id = used.user_id
+            "#
+        ))
+        .bind(token_hash)
+        .bind(now)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(|(id, email, created_at, scopes)| {
+            let user = User {
+                id,
+                email,
+                created_at,
+            };
+            let scopes = scopes.iter().filter_map(|s| s.parse().ok()).collect();
+            (user, scopes)
+        }))
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "database-test")]
+mod test {
+    use super::*;
+    use chrono::{Duration, SubsecRound};
+    use dotenv::dotenv;
+    use std::env;
+
+    #[tokio::test]
+    async fn user_scenario() {
+        dotenv().ok();
+        let database_url = &env::var("DATABASE_URL").expect("undefined [DATABASE_URL]");
+        let pool = PgPool::connect(database_url)
+            .await
+            .unwrap_or_else(|_| panic!("fail connect database, url is [{}]", database_url));
+
+        let repository = UserRepositoryForDb::new(pool.clone());
+        let email = "user_scenario@example.com";
+        sqlx::query("delete from users where email = $1")
+            .bind(email)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let user = repository
+            .create(email.to_string(), "hash".to_string())
+            .await
+            .expect("[create] returned Err")
+            .expect("[create] returned None");
+        assert_eq!(email, user.email);
+        let res = repository
+            .create(email.to_string(), "other".to_string())
+            .await
+            .expect("[create] returned Err");
+        assert_eq!(None, res);
+
+        let found = repository.find_by_email(email).await.unwrap();
+        assert_eq!(Some((user.clone(), "hash".to_string())), found);
+        assert_eq!(Some(user.clone()), repository.find(user.id).await.unwrap());
+        assert_eq!(
+            None,
+            repository
+                .find_by_email("nobody@example.com")
+                .await
+                .unwrap()
+        );
+
+        // 期限内のセッションだけが有効
+        let now = Utc::now().trunc_subsecs(6);
+        let expires_at = now + Duration::hours(1);
+        repository
+            .create_session(user.id, "[user_scenario] token".to_string(), expires_at)
+            .await
+            .unwrap();
+        let found = repository
+            .find_by_session("[user_scenario] token", now)
+            .await
+            .unwrap();
+        assert_eq!(Some(user.clone()), found);
+        let found = repository
+            .find_by_session("[user_scenario] token", expires_at)
+            .await
+            .unwrap();
+        assert_eq!(None, found);
+
+        repository
+            .delete_session("[user_scenario] token")
+            .await
+            .unwrap();
+        let found = repository
+            .find_by_session("[user_scenario] token", now)
+            .await
+            .unwrap();
+        assert_eq!(None, found);
+
+        sqlx::query("delete from users where id = $1")
+            .bind(user.id)
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn api_token_scenario() {
+        dotenv().ok();
+        let database_url = &env::var("DATABASE_URL").expect("undefined [DATABASE_URL]");
+        let pool = PgPool::connect(database_url)
+            .await
+            .unwrap_or_else(|_| panic!("fail connect database, url is [{}]", database_url));
+
+        let repository = UserRepositoryForDb::new(pool.clone());
+        let email = "api_token_scenario@example.com";
+        sqlx::query("delete from users where email = $1")
+            .bind(email)
+            .execute(&pool)
+            .await
+            .unwrap();
+        let user = repository
+            .create(email.to_string(), "hash".to_string())
+            .await
+            .unwrap()
+            .unwrap();
+
+        let payload = CreateToken::new(
+            "ci",
+            vec![Scope::TodosWrite, Scope::TodosRead, Scope::TodosWrite],
+        );
+        let token = repository
+            .create_token(
+                user.id,
+                payload,
+                "mt_0123".to_string(),
+                "[api_token_scenario] hash".to_string(),
+            )
+            .await
+            .expect("[create_token] returned Err");
+        assert_eq!("ci", token.name);
+        assert_eq!(vec![Scope::TodosRead, Scope::TodosWrite], token.scopes);
+        assert_eq!(None, token.last_used_at);
+
+        // 使うと last_used_at が記録される
+        let now = Utc::now().trunc_subsecs(6);
+        let used = repository
+            .use_token("[api_token_scenario] hash", now)
+            .await
+            .unwrap();
+        assert_eq!(Some((user.clone(), token.scopes.clone())), used);
+        let tokens = repository.all_tokens(user.id).await.unwrap();
+        assert_eq!(1, tokens.len());
+        assert_eq!(Some(now), tokens[0].last_used_at);
+        assert_eq!(None, repository.use_token("other", now).await.unwrap());
+
+        // 他のユーザーのトークンは消せない
+        let res = repository.delete_token(user.id + 1, token.id).await;
+        assert!(matches!(res, Err(RepositoryError::NotFound(id)) if id == token.id));
+        repository.delete_token(user.id, token.id).await.unwrap();
+        assert!(repository.all_tokens(user.id).await.unwrap().is_empty());
+        assert_eq!(
+            None,
+            repository
+                .use_token("[api_token_scenario] hash", now)
+                .await
+                .unwrap()
+        );
+
+        sqlx::query("delete from users where id = $1")
+            .bind(user.id)
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+}
+
+#[cfg(test)]
+pub mod test_utils {
+    use super::*;
+    use crate::{auth, repositories::test_utils::MemoryStore};
+
+    #[derive(Debug, Clone)]
+    pub struct UserRepositoryForMemory {
+        store: MemoryStore,
+    }
+
+    impl UserRepositoryForMemory {
+        pub fn new() -> Self {
+            Self::with_store(MemoryStore::default())
+        }
+
+        pub fn with_store(store: MemoryStore) -> Self {
+            UserRepositoryForMemory { store }
+        }
+
+        /// パスワードのハッシュを作らずに、token のセッションでログインしているユーザーを作る
+        pub fn with_session(token: &str) -> Self {
+            let repository = Self::new();
+            {
+                let mut tables = repository.store.write();
+                let user = User {
+                    id: 1,
+                    email: "test@example.com".to_string(),
+                    created_at: tables.now,
+                };
+                tables.users.insert(user.id, (user, String::new()));
+                tables
+                    .sessions
+                    .insert(auth::hash_token(token), (1, DateTime::<Utc>::MAX_UTC));
+            }
+            repository
+        }
+    }
+
+    impl Default for UserRepositoryForMemory {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    #[async_trait]
+    impl UserRepository for UserRepositoryForMemory {
+        async fn create(
+            &self,
+            email: String,
+            password_hash: String,
+        ) -> Result<Option<User>, RepositoryError> {
+            let mut tables = self.store.write();
+            if tables.users.values().any(|(user, _)| user.email == email) {
+                return Ok(None);
+            }
+            let user = User {
+                id: tables.users.len() as i32 + 1,
+                email,
+                created_at: tables.now,
+            };
+            tables.users.insert(user.id, (user.clone(), password_hash));
+            Ok(Some(user))
+        }
+
+        async fn find(&self, id: i32) -> Result<Option<User>, RepositoryError> {
+            let tables = self.store.read();
+            Ok(tables.users.get(&id).map(|(user, _)| user.clone()))
+        }
+
+        async fn find_by_email(
+            &self,
+            email: &str,
+        ) -> Result<Option<(User, String)>, RepositoryError> {
+            let tables = self.store.read();
+            let found = tables.users.values().find(|(user, _)| user.email == email);
+            Ok(found.cloned())
+        }
+
+        async fn create_session(
+            &self,
+            user_id: i32,
+            token_hash: String,
+            expires_at: DateTime<Utc>,
+        ) -> Result<(), RepositoryError> {
+            let mut tables = self.store.write();
+            let now = tables.now;
+            tables
+                .sessions
+                .retain(|_, (id, expires_at)| *id != user_id || *expires_at > now);
+            tables.sessions.insert(token_hash, (user_id, expires_at));
+            Ok(())
+        }
+
+        async fn find_by_session(
+            &self,
+            token_hash: &str,
+            now: DateTime<Utc>,
+        ) -> Result<Option<User>, RepositoryError> {
+            let tables = self.store.read();
+            let user = tables
+                .sessions
+                .get(token_hash)
+                .filter(|(_, expires_at)| *expires_at > now)
+                .and_then(|(user_id, _)| tables.users.get(user_id))
+                .map(|(user, _)| user.clone());
+            Ok(user)
+        }
+
+        async fn delete_session(&self, token_hash: &str) -> Result<(), RepositoryError> {
+            self.store.write().sessions.remove(token_hash);
+            Ok(())
+        }
+
+        async fn create_token(
+            &self,
+            user_id: i32,
+            payload: CreateToken,
+            prefix: String,
+            token_hash: String,
+        ) -> Result<ApiToken, RepositoryError> {
+            let mut tables = self.store.write();
+            let token = ApiToken {
+                id: tables.api_tokens.keys().max().unwrap_or(&0) + 1,
+                name: payload.name.clone(),
+                prefix,
+                scopes: payload.scopes(),
+                created_at: tables.now,
+                last_used_at: None,
+            };
+            tables
+                .api_tokens
+                .insert(token.id, (user_id, token_hash, token.clone()));
+            Ok(token)
+        }
+
+        async fn all_tokens(&self, user_id: i32) -> Result<Vec<ApiToken>, RepositoryError> {
+            let tables = self.store.read();
+            let tokens = tables
+                .api_tokens
+                .values()
+                .filter(|(id, _, _)| *id == user_id)
+                .map(|(_, _, token)| token.clone())
+                .collect();
+            Ok(tokens)
+        }
+
+        async fn delete_token(&self, user_id: i32, id: i32) -> Result<(), RepositoryError> {
+            let mut tables = self.store.write();
+            match tables.api_tokens.get(&id) {
+                Some((owner, _, _)) if *owner == user_id => {
+                    tables.api_tokens.remove(&id);
+                    Ok(())
+                }
+                _ => Err(RepositoryError::NotFound(id)),
+            }
+        }
+
+        async fn use_token(
+            &self,
+            token_hash: &str,
+            now: DateTime<Utc>,
+        ) -> Result<Option<(User, Vec<Scope>)>, RepositoryError> {
+            let mut tables = self.store.write();
+            let token = tables
+                .api_tokens
+                .values_mut()
+                .find(|(_, hash, _)| hash == token_hash);
+            let (user_id, scopes) = match token {
+                Some((user_id, _, token)) => {
+                    token.last_used_at = Some(now);
+                    (*user_id, token.scopes.clone())
+                }
+                None => return Ok(None),
+            };
+            let user = tables.users.get(&user_id).map(|(user, _)| user.clone());
+            Ok(user.map(|user| (user, scopes)))
+        }
+    }
+
+    mod test {
+        use super::*;
+        use chrono::{Duration, TimeZone};
+        use serde_json::json;
+
+        #[tokio::test]
+        async fn user_scenario() {
+            let repository = UserRepositoryForMemory::new();
+            let user = repository
+                .create("a@example.com".to_string(), "hash".to_string())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(1, user.id);
+            let res = repository
+                .create("a@example.com".to_string(), "other".to_string())
+                .await
+                .unwrap();
+            assert_eq!(None, res);
+            let found = repository.find_by_email("a@example.com").await.unwrap();
+            assert_eq!(Some((user.clone(), "hash".to_string())), found);
+            assert_eq!(Some(user.clone()), repository.find(1).await.unwrap());
+            assert_eq!(None, repository.find(2).await.unwrap());
+
+            let now = Utc.with_ymd_and_hms(2024, 10, 1, 0, 0, 0).unwrap();
+            let expires_at = now + Duration::hours(1);
+            repository
+                .create_session(user.id, "token".to_string(), expires_at)
+                .await
+                .unwrap();
+            let found = repository.find_by_session("token", now).await.unwrap();
+            assert_eq!(Some(user), found);
+            let found = repository
+                .find_by_session("token", expires_at)
+                .await
+                .unwrap();
+            assert_eq!(None, found);
+            assert_eq!(
+                None,
+                repository.find_by_session("other", now).await.unwrap()
+            );
+
+            repository.delete_session("token").await.unwrap();
+            assert_eq!(
+                None,
+                repository.find_by_session("token", now).await.unwrap()
+            );
+        }
+
+        #[tokio::test]
+        async fn api_token_scenario() {
+            let repository = UserRepositoryForMemory::with_session("session");
+            let payload = CreateToken::new("ci", vec![Scope::LabelsWrite, Scope::TodosRead]);
+            let token = repository
+                .create_token(1, payload, "mt_0123".to_string(), "hash".to_string())
+                .await
+                .unwrap();
+            assert_eq!(1, token.id);
+            assert_eq!(vec![Scope::TodosRead, Scope::LabelsWrite], token.scopes);
+
+            let now = Utc.with_ymd_and_hms(2024, 10, 1, 0, 0, 0).unwrap();
+            let (user, scopes) = repository.use_token("hash", now).await.unwrap().unwrap();
+            assert_eq!(1, user.id);
+            assert_eq!(token.scopes, scopes);
+            let tokens = repository.all_tokens(1).await.unwrap();
+            assert_eq!(Some(now), tokens[0].last_used_at);
+            assert!(repository.all_tokens(2).await.unwrap().is_empty());
+
+            let res = repository.delete_token(2, token.id).await;
+            assert!(matches!(res, Err(RepositoryError::NotFound(1))));
+            repository.delete_token(1, token.id).await.unwrap();
+            assert_eq!(None, repository.use_token("hash", now).await.unwrap());
+        }
+
+        #[test]
+        fn should_parse_scope() {
+            for scope in ["todos:read", "todos:write", "labels:read", "labels:write"] {
+                let parsed: Scope = scope.parse().unwrap();
+                assert_eq!(scope, parsed.to_string());
+                assert_eq!(json!(scope), json!(parsed));
+            }
+            assert!("todos:delete".parse::<Scope>().is_err());
+        }
+    }
+}